@@ -0,0 +1,105 @@
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::codecs::webp::WebPDecoder;
+use image::{Delay, Frame, ImageResult, RgbaImage};
+use std::io::Cursor;
+
+/// Returns true when the buffer holds a WebP image with more than one frame.
+pub fn is_animated_webp(buffer: &[u8]) -> bool {
+    // Ask the decoder whether the extended header carries an animation.
+    WebPDecoder::new(Cursor::new(buffer))
+        .map(|decoder| decoder.has_animation())
+        .unwrap_or(false)
+}
+
+/// The frames of a decoded animation, each a full canvas, and how often it plays.
+pub struct Animation {
+    pub frames: Vec<Frame>,
+    /// Number of times the animation plays, 0 for forever.
+    pub loop_count: u16,
+}
+
+/// Decodes every frame (with its timing) and the loop count from an animated WebP buffer.
+/// libwebp is used here because it composites lossy frames with the correct colors.
+pub fn decode_webp_frames(buffer: &[u8]) -> Result<Animation, String> {
+    // Decode the full animation into RGBA canvases.
+    let animation = webp::AnimDecoder::new(buffer).decode()?;
+
+    let mut frames = Vec::with_capacity(animation.len());
+    let mut previous_end = 0;
+    for frame in &animation {
+        // libwebp reports the time at which each frame ends, turn that into a delay.
+        let delay = (frame.get_time_ms() - previous_end).max(0) as u32;
+        previous_end = frame.get_time_ms();
+
+        // Frames are requested as RGBA, but expand RGB canvases just in case.
+        let rgba = match frame.get_layout() {
+            webp::PixelLayout::Rgba => frame.get_image().to_vec(),
+            webp::PixelLayout::Rgb => frame
+                .get_image()
+                .chunks_exact(3)
+                .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 255])
+                .collect(),
+        };
+        let canvas = RgbaImage::from_raw(frame.width(), frame.height(), rgba)
+            .ok_or("animation frame has an unexpected size")?;
+        frames.push(Frame::from_parts(
+            canvas,
+            0,
+            0,
+            Delay::from_numer_denom_ms(delay, 1),
+        ));
+    }
+    Ok(Animation {
+        frames,
+        // GIF cannot store more loops than this; play longer animations forever instead.
+        loop_count: u16::try_from(animation.loop_count).unwrap_or(0),
+    })
+}
+
+/// Encodes the frames as an animated GIF that loops `loop_count` times, or forever for 0.
+pub fn encode_gif(frames: Vec<Frame>, loop_count: u16) -> ImageResult<Vec<u8>> {
+    // Write the frames into an in-memory buffer.
+    let mut bytes = Vec::new();
+    {
+        let mut encoder = GifEncoder::new(&mut bytes);
+        encoder.set_repeat(match loop_count {
+            0 => Repeat::Infinite,
+            count => Repeat::Finite(count),
+        })?;
+        encoder.encode_frames(frames)?;
+    }
+    Ok(bytes)
+}
+
+/// Encodes the frames as a lossless animated WebP that loops `loop_count` times, or forever for 0.
+pub fn encode_webp(frames: &[Frame], loop_count: u16) -> Result<Vec<u8>, String> {
+    // The canvas size is taken from the first frame.
+    let first = frames.first().ok_or("animation has no frames")?;
+    let (width, height) = first.buffer().dimensions();
+
+    // Encode losslessly, matching the still WebP output.
+    let mut config = webp::WebPConfig::new().map_err(|_| "could not initialize WebP config")?;
+    config.lossless = 1;
+
+    // Add each frame at its start time (the sum of the delays before it).
+    let mut encoder = webp::AnimEncoder::new(width, height, &config);
+    encoder.set_loop_count(loop_count.into());
+    let mut timestamp = 0i32;
+    for frame in frames {
+        let buffer = frame.buffer();
+        encoder.add_frame(webp::AnimFrame::from_rgba(
+            buffer.as_raw(),
+            buffer.width(),
+            buffer.height(),
+            timestamp,
+        ));
+        let (numerator, denominator) = frame.delay().numer_denom_ms();
+        timestamp += (numerator / denominator.max(1)) as i32;
+    }
+
+    // Assemble the animation into a RIFF container.
+    let memory = encoder
+        .try_encode()
+        .map_err(|e| format!("could not encode animated WebP: {:?}", e))?;
+    Ok(memory.to_vec())
+}
@@ -0,0 +1,59 @@
+use crossbeam_channel::{Receiver, Sender};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// One encoded output on its way into the archive.
+struct Entry {
+    path: PathBuf,
+    bytes: Vec<u8>,
+    mode: u32,
+}
+
+/// Handle the workers use to add encoded outputs to a tar stream; cheap to clone.
+#[derive(Clone, Debug)]
+pub struct ArchiveSink {
+    sender: Sender<Entry>,
+}
+
+impl ArchiveSink {
+    /// Queues an output for the archive under its relative path.
+    pub fn add(&self, path: &Path, bytes: Vec<u8>, mode: u32) -> Result<(), String> {
+        self.sender
+            .send(Entry {
+                path: path.to_path_buf(),
+                bytes,
+                mode,
+            })
+            .map_err(|_| "the archive writer has stopped".to_string())
+    }
+}
+
+/// Starts a thread that writes every queued output as a tar entry to `writer`, one at a time.
+/// The archive is finished once every `ArchiveSink` clone has been dropped; join the handle to wait for it.
+pub fn stream_tar<W: Write + Send + 'static>(
+    writer: W,
+) -> (ArchiveSink, JoinHandle<std::io::Result<()>>) {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let handle = std::thread::spawn(move || write_tar(writer, receiver));
+    (ArchiveSink { sender }, handle)
+}
+
+/// Writes queued entries into a tar archive until the channel closes.
+fn write_tar<W: Write>(writer: W, receiver: Receiver<Entry>) -> std::io::Result<()> {
+    let mut builder = tar::Builder::new(writer);
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    for entry in receiver {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(entry.bytes.len() as u64);
+        header.set_mode(entry.mode);
+        header.set_mtime(mtime);
+        header.set_entry_type(tar::EntryType::Regular);
+        builder.append_data(&mut header, &entry.path, entry.bytes.as_slice())?;
+    }
+    builder.into_inner()?.flush()
+}
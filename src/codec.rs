@@ -0,0 +1,256 @@
+use image::DynamicImage;
+use std::cell::OnceCell;
+
+/// Encodes the image as lossy WebP through libwebp, with separate color and alpha quality (0-100).
+pub fn encode_lossy_webp(
+    img: &DynamicImage,
+    quality: u8,
+    alpha_quality: u8,
+) -> Result<Vec<u8>, String> {
+    // Set up a lossy encoder configuration with the requested qualities.
+    let mut config = webp::WebPConfig::new().map_err(|_| "could not initialize WebP config")?;
+    config.lossless = 0;
+    config.quality = quality as f32;
+    config.alpha_quality = alpha_quality as i32;
+
+    // Encode RGBA when there is an alpha channel, RGB otherwise.
+    let memory = if img.color().has_alpha() {
+        let rgba = img.to_rgba8();
+        webp::Encoder::from_rgba(rgba.as_raw(), rgba.width(), rgba.height())
+            .encode_advanced(&config)
+    } else {
+        let rgb = img.to_rgb8();
+        webp::Encoder::from_rgb(rgb.as_raw(), rgb.width(), rgb.height()).encode_advanced(&config)
+    }
+    .map_err(|e| format!("could not encode WebP: {:?}", e))?;
+    Ok(memory.to_vec())
+}
+
+/// Runs `decode` with the decoder's worker threads capped at `threads`; 0 leaves them uncapped.
+/// jpeg-decoder spreads the color components over the current rayon pool, which outside of one
+/// (e.g. on pipeline threads) is the global pool with a thread per core. Running it in a small
+/// pool owned by the calling thread keeps each decode to `threads`, however many files are
+/// decoded at once.
+pub fn with_decode_threads<T, F>(threads: usize, decode: F) -> T
+where
+    T: Send,
+    F: FnOnce() -> T + Send,
+{
+    thread_local! {
+        static POOL: OnceCell<Option<rayon::ThreadPool>> = const { OnceCell::new() };
+    }
+    if threads == 0 {
+        return decode();
+    }
+    POOL.with(|pool| {
+        let pool = pool.get_or_init(|| {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .thread_name(|index| format!("rico-decode-{}", index))
+                .build()
+                .ok()
+        });
+        // Decode in place if the pool could not be started rather than failing the file.
+        match pool {
+            Some(pool) => pool.install(decode),
+            None => decode(),
+        }
+    })
+}
+
+/// Decodes a JPEG with zune-jpeg in its non-strict mode, which recovers from some
+/// corrupt or truncated streams the primary decoder rejects.
+pub fn decode_jpeg_lenient(bytes: &[u8]) -> Result<DynamicImage, String> {
+    use zune_jpeg::zune_core::bytestream::ZCursor;
+    use zune_jpeg::zune_core::colorspace::ColorSpace;
+    use zune_jpeg::zune_core::options::DecoderOptions;
+
+    // Always ask for RGB so grayscale and CMYK sources come out the same way.
+    let options = DecoderOptions::default()
+        .set_strict_mode(false)
+        .jpeg_set_out_colorspace(ColorSpace::RGB);
+    let mut decoder = zune_jpeg::JpegDecoder::new_with_options(ZCursor::new(bytes), options);
+    let pixels = decoder
+        .decode()
+        .map_err(|e| format!("could not decode JPEG: {:?}", e))?;
+    let info = decoder.info().ok_or("could not read JPEG headers")?;
+    image::RgbImage::from_raw(info.width as u32, info.height as u32, pixels)
+        .map(DynamicImage::ImageRgb8)
+        .ok_or_else(|| "decoded JPEG has an unexpected size".into())
+}
+
+/// Returns true for a four-component (CMYK) JPEG without an Adobe APP14 marker.
+/// The primary decoder assumes the Adobe convention of inverted CMYK for every such file,
+/// so plain CMYK without the marker comes out with inverted colors.
+pub fn is_unmarked_cmyk_jpeg(bytes: &[u8]) -> bool {
+    if !bytes.starts_with(&[0xFF, 0xD8]) {
+        return false;
+    }
+    let mut adobe = false;
+    let mut components = None;
+    let mut pos = 2;
+    // Walk the marker segments up to the start of the scan data; APP14 may come either side
+    // of the frame header.
+    while let Some(&[0xFF, marker, high, low]) = bytes.get(pos..pos + 4) {
+        if marker == 0xFF {
+            // Fill byte before a marker.
+            pos += 1;
+            continue;
+        }
+        let length = usize::from(u16::from_be_bytes([high, low]));
+        let contents = bytes.get(pos + 4..pos + 2 + length).unwrap_or_default();
+        match marker {
+            // APP14 "Adobe" says the components are stored inverted.
+            0xEE if contents.starts_with(b"Adobe") => adobe = true,
+            // SOF0-SOF15, except DHT (C4), JPG (C8) and DAC (CC): the component count follows
+            // the sample precision and the dimensions.
+            0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                components = contents.get(5).copied();
+            }
+            0xDA | 0xD9 => break,
+            _ => {}
+        }
+        pos += 2 + length;
+    }
+    components == Some(4) && !adobe
+}
+
+/// Decodes a CMYK JPEG stored without inversion (see `is_unmarked_cmyk_jpeg`) to RGB.
+pub fn decode_unmarked_cmyk_jpeg(bytes: &[u8]) -> Result<DynamicImage, String> {
+    let mut decoder = jpeg_decoder::Decoder::new(bytes);
+    let pixels = decoder
+        .decode()
+        .map_err(|e| format!("could not decode JPEG: {}", e))?;
+    let info = decoder.info().ok_or("could not read JPEG headers")?;
+    if info.pixel_format != jpeg_decoder::PixelFormat::CMYK32 {
+        return Err("JPEG is not CMYK".into());
+    }
+    // jpeg-decoder hands back 255 minus each stored value, which for plain CMYK is already
+    // the fraction of each ink left out: red = (1 - C)(1 - K), and so on.
+    let rgb = pixels
+        .chunks_exact(4)
+        .flat_map(|cmyk| {
+            let k = u16::from(cmyk[3]);
+            [0, 1, 2].map(|i| (u16::from(cmyk[i]) * k / 255) as u8)
+        })
+        .collect();
+    image::RgbImage::from_raw(u32::from(info.width), u32::from(info.height), rgb)
+        .map(DynamicImage::ImageRgb8)
+        .ok_or_else(|| "decoded JPEG has an unexpected size".into())
+}
+
+/// Encodes a black-and-white image (see `transform::bilevel`) as a 1-bit grayscale PNG;
+/// gray levels from 128 up are written as white.
+pub fn encode_bilevel_png(img: &image::GrayImage) -> Result<Vec<u8>, String> {
+    // Pack each row into bytes, most significant bit first, padding the last byte.
+    let row_bytes = (img.width() as usize).div_ceil(8);
+    let mut bits = vec![0u8; row_bytes * img.height() as usize];
+    for (x, y, pixel) in img.enumerate_pixels() {
+        if pixel[0] >= 128 {
+            bits[y as usize * row_bytes + x as usize / 8] |= 0x80 >> (x % 8);
+        }
+    }
+
+    let mut bytes = Vec::new();
+    let mut encoder = png::Encoder::new(&mut bytes, img.width(), img.height());
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::One);
+    let mut writer = encoder
+        .write_header()
+        .map_err(|e| format!("could not write 1-bit PNG: {}", e))?;
+    writer
+        .write_image_data(&bits)
+        .map_err(|e| format!("could not write 1-bit PNG: {}", e))?;
+    writer
+        .finish()
+        .map_err(|e| format!("could not write 1-bit PNG: {}", e))?;
+    Ok(bytes)
+}
+
+/// Longest side images are downscaled to before computing their BlurHash.
+const BLURHASH_SAMPLE_SIZE: u32 = 64;
+
+/// Computes the BlurHash placeholder string of an image with 4x3 components.
+pub fn blurhash(img: &DynamicImage) -> Result<String, String> {
+    // The hash only keeps a few low frequencies, so a small thumbnail is plenty.
+    let sample = img
+        .thumbnail(BLURHASH_SAMPLE_SIZE, BLURHASH_SAMPLE_SIZE)
+        .to_rgba8();
+    blurhash::encode(4, 3, sample.width(), sample.height(), sample.as_raw())
+        .map_err(|e| format!("could not encode BlurHash: {}", e))
+}
+
+/// Compression scheme for TIFF output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TiffCompression {
+    /// Raw, uncompressed strips.
+    #[default]
+    None,
+    /// Lempel-Ziv-Welch compression.
+    Lzw,
+    /// zlib/Deflate compression.
+    Deflate,
+    /// Run-length PackBits compression.
+    Packbits,
+}
+
+impl TiffCompression {
+    /// Parses the `--tiff-compression` value.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "none" => Ok(TiffCompression::None),
+            "lzw" => Ok(TiffCompression::Lzw),
+            "deflate" => Ok(TiffCompression::Deflate),
+            "packbits" => Ok(TiffCompression::Packbits),
+            _ => Err(format!(
+                "expected none, lzw, deflate or packbits but got '{}'",
+                value
+            )),
+        }
+    }
+}
+
+/// Encodes the image as an 8-bit RGB(A) TIFF with the given compression.
+pub fn encode_tiff(img: &DynamicImage, compression: TiffCompression) -> Result<Vec<u8>, String> {
+    use tiff::encoder::compression::{Deflate, Lzw, Packbits, Uncompressed};
+
+    match compression {
+        TiffCompression::None => write_tiff(img, Uncompressed),
+        TiffCompression::Lzw => write_tiff(img, Lzw),
+        TiffCompression::Deflate => write_tiff(img, Deflate::default()),
+        TiffCompression::Packbits => write_tiff(img, Packbits),
+    }
+}
+
+/// Writes the pixels into an in-memory TIFF using the given compressor.
+fn write_tiff<D: tiff::encoder::compression::Compression>(
+    img: &DynamicImage,
+    compression: D,
+) -> Result<Vec<u8>, String> {
+    use tiff::encoder::{colortype, TiffEncoder};
+
+    let mut cursor = std::io::Cursor::new(Vec::new());
+    let mut encoder =
+        TiffEncoder::new(&mut cursor).map_err(|e| format!("could not encode TIFF: {}", e))?;
+
+    // Keep the alpha channel only when the image has one.
+    let written = if img.color().has_alpha() {
+        let rgba = img.to_rgba8();
+        encoder.write_image_with_compression::<colortype::RGBA8, _>(
+            rgba.width(),
+            rgba.height(),
+            compression,
+            rgba.as_raw(),
+        )
+    } else {
+        let rgb = img.to_rgb8();
+        encoder.write_image_with_compression::<colortype::RGB8, _>(
+            rgb.width(),
+            rgb.height(),
+            compression,
+            rgb.as_raw(),
+        )
+    };
+    written.map_err(|e| format!("could not encode TIFF: {}", e))?;
+    Ok(cursor.into_inner())
+}
@@ -0,0 +1,317 @@
+use image::RgbaImage;
+
+/// Estimates the background color of an image from its corners: the per-channel median of an
+/// NxN block at each corner, then the per-channel median of the four corner colors.
+/// Larger blocks make the estimate robust to noise and slight gradients.
+pub fn estimate_background(img: &RgbaImage, sample: u32) -> [u8; 3] {
+    let (width, height) = img.dimensions();
+    let (block_w, block_h) = (sample.min(width), sample.min(height));
+    let corners = [
+        (0, 0),
+        (width - block_w, 0),
+        (0, height - block_h),
+        (width - block_w, height - block_h),
+    ];
+
+    // Median color of each corner block.
+    let corner_colors: Vec<[u8; 3]> = corners
+        .iter()
+        .map(|&(left, top)| {
+            let pixels: Vec<[u8; 3]> = (top..top + block_h)
+                .flat_map(|y| (left..left + block_w).map(move |x| (x, y)))
+                .map(|(x, y)| {
+                    let [r, g, b, _] = img.get_pixel(x, y).0;
+                    [r, g, b]
+                })
+                .collect();
+            channel_median(&pixels)
+        })
+        .collect();
+    channel_median(&corner_colors)
+}
+
+/// Per-channel median of a non-empty list of colors; even counts average the middle two.
+fn channel_median(colors: &[[u8; 3]]) -> [u8; 3] {
+    let mut median = [0; 3];
+    for (channel, value) in median.iter_mut().enumerate() {
+        let mut values: Vec<u8> = colors.iter().map(|color| color[channel]).collect();
+        values.sort_unstable();
+        let mid = values.len() / 2;
+        *value = if values.len() % 2 == 1 {
+            values[mid]
+        } else {
+            (values[mid - 1] as u16 + values[mid] as u16).div_ceil(2) as u8
+        };
+    }
+    median
+}
+
+/// Color difference between neighboring pixels that stops the background flood fill.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EdgeThreshold {
+    /// The same threshold for every image.
+    Fixed(u8),
+    /// A threshold derived from each image's own gradients.
+    Auto,
+}
+
+impl EdgeThreshold {
+    /// Parses the `--edge-threshold` value: `auto` or a number from 0 to 255.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        if value == "auto" {
+            return Ok(EdgeThreshold::Auto);
+        }
+        value
+            .parse()
+            .map(EdgeThreshold::Fixed)
+            .map_err(|_| format!("expected auto or 0-255 but got '{}'", value))
+    }
+
+    /// Returns the threshold to use for the image.
+    pub fn for_image(self, img: &RgbaImage) -> u8 {
+        match self {
+            EdgeThreshold::Fixed(threshold) => threshold,
+            EdgeThreshold::Auto => auto_edge_threshold(img),
+        }
+    }
+}
+
+/// Picks the edge threshold that best separates an image's flat regions from its edges:
+/// Otsu's threshold over the histogram of neighbor differences (the largest per-channel
+/// difference to the right and lower neighbor, as in the flood fill's edge test).
+/// Low-contrast images get low thresholds and high-contrast ones higher thresholds.
+fn auto_edge_threshold(img: &RgbaImage) -> u8 {
+    let (width, height) = img.dimensions();
+    let difference = |a: &image::Rgba<u8>, b: &image::Rgba<u8>| {
+        (0..3).map(|c| a[c].abs_diff(b[c])).max().unwrap_or(0)
+    };
+
+    // Histogram of the differences between every pair of neighbors.
+    let mut histogram = [0u64; 256];
+    for (x, y, pixel) in img.enumerate_pixels() {
+        if x + 1 < width {
+            histogram[difference(pixel, img.get_pixel(x + 1, y)) as usize] += 1;
+        }
+        if y + 1 < height {
+            histogram[difference(pixel, img.get_pixel(x, y + 1)) as usize] += 1;
+        }
+    }
+
+    // Otsu: the split with the largest variance between the two classes. Empty bins leave
+    // the variance unchanged, so take the middle of the widest-separating range of splits.
+    let total: u64 = histogram.iter().sum();
+    let sum: f64 = histogram
+        .iter()
+        .enumerate()
+        .map(|(value, &count)| value as f64 * count as f64)
+        .sum();
+    let (mut below, mut sum_below) = (0u64, 0.0);
+    let (mut first, mut last, mut best_variance) = (0usize, 0usize, 0.0);
+    for (value, &count) in histogram.iter().enumerate() {
+        below += count;
+        sum_below += value as f64 * count as f64;
+        let above = total - below;
+        if below == 0 || above == 0 {
+            continue;
+        }
+        let mean_below = sum_below / below as f64;
+        let mean_above = (sum - sum_below) / above as f64;
+        let variance = below as f64 * above as f64 * (mean_below - mean_above).powi(2);
+        if variance > best_variance {
+            (first, last, best_variance) = (value, value, variance);
+        } else if variance == best_variance {
+            last = value;
+        }
+    }
+    ((first + last) / 2) as u8
+}
+
+/// How the distance between a pixel and the background key color is measured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorMetric {
+    /// Plain Euclidean distance between the RGB triples.
+    Rgb,
+    /// Euclidean RGB distance weighted by the "redmean" approximation of perception.
+    WeightedRgb,
+    /// CIEDE2000 color difference computed in CIE L*a*b*.
+    Ciede2000,
+}
+
+impl ColorMetric {
+    /// Parses the `--color-metric` value.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "rgb" => Ok(ColorMetric::Rgb),
+            "weighted-rgb" => Ok(ColorMetric::WeightedRgb),
+            "ciede2000" => Ok(ColorMetric::Ciede2000),
+            _ => Err(format!(
+                "expected rgb, weighted-rgb or ciede2000 but got '{}'",
+                value
+            )),
+        }
+    }
+
+    /// Returns the distance between two sRGB colors under this metric.
+    pub fn distance(self, a: [u8; 3], b: [u8; 3]) -> f64 {
+        match self {
+            ColorMetric::Rgb => {
+                let [dr, dg, db] = channel_deltas(a, b);
+                (dr * dr + dg * dg + db * db).sqrt()
+            }
+            ColorMetric::WeightedRgb => {
+                // Weight red and blue by the mean red level, as in the "redmean" formula.
+                let [dr, dg, db] = channel_deltas(a, b);
+                let red_mean = (a[0] as f64 + b[0] as f64) / 2.0;
+                ((2.0 + red_mean / 256.0) * dr * dr
+                    + 4.0 * dg * dg
+                    + (2.0 + (255.0 - red_mean) / 256.0) * db * db)
+                    .sqrt()
+            }
+            ColorMetric::Ciede2000 => ciede2000(srgb_to_lab(a), srgb_to_lab(b)),
+        }
+    }
+}
+
+/// Per-channel differences of two RGB triples.
+fn channel_deltas(a: [u8; 3], b: [u8; 3]) -> [f64; 3] {
+    [
+        a[0] as f64 - b[0] as f64,
+        a[1] as f64 - b[1] as f64,
+        a[2] as f64 - b[2] as f64,
+    ]
+}
+
+/// Converts an 8-bit sRGB color to CIE L*a*b* under the D65 white point.
+pub fn srgb_to_lab(rgb: [u8; 3]) -> [f64; 3] {
+    // Undo the sRGB transfer curve to get linear light.
+    let linear = rgb.map(|channel| {
+        let encoded = channel as f64 / 255.0;
+        if encoded <= 0.04045 {
+            encoded / 12.92
+        } else {
+            ((encoded + 0.055) / 1.055).powf(2.4)
+        }
+    });
+
+    // Linear sRGB to XYZ, normalized by the D65 reference white.
+    let [r, g, b] = linear;
+    let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+    let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+    let z = (0.0193339 * r + 0.1191920 * g + 0.9503041 * b) / 1.08883;
+
+    // Apply the CIE companding function and combine into L*, a*, b*.
+    let f = |t: f64| {
+        if t > 216.0 / 24389.0 {
+            t.cbrt()
+        } else {
+            (24389.0 / 27.0 * t + 16.0) / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+/// Computes the CIEDE2000 color difference between two L*a*b* colors.
+pub fn ciede2000(lab1: [f64; 3], lab2: [f64; 3]) -> f64 {
+    let [l1, a1, b1] = lab1;
+    let [l2, a2, b2] = lab2;
+
+    // Stretch a* so that neutral colors get the right chroma.
+    let c_mean = ((a1.hypot(b1)) + (a2.hypot(b2))) / 2.0;
+    let g = 0.5 * (1.0 - (c_mean.powi(7) / (c_mean.powi(7) + 25f64.powi(7))).sqrt());
+    let (a1, a2) = ((1.0 + g) * a1, (1.0 + g) * a2);
+
+    // Chroma and hue (in degrees) of both colors.
+    let (c1, c2) = (a1.hypot(b1), a2.hypot(b2));
+    let hue = |b: f64, a: f64| {
+        if a == 0.0 && b == 0.0 {
+            0.0
+        } else {
+            b.atan2(a).to_degrees().rem_euclid(360.0)
+        }
+    };
+    let (h1, h2) = (hue(b1, a1), hue(b2, a2));
+
+    // Differences in lightness, chroma and hue.
+    let delta_l = l2 - l1;
+    let delta_c = c2 - c1;
+    let delta_h = if c1 * c2 == 0.0 {
+        0.0
+    } else if (h2 - h1).abs() <= 180.0 {
+        h2 - h1
+    } else if h2 - h1 > 180.0 {
+        h2 - h1 - 360.0
+    } else {
+        h2 - h1 + 360.0
+    };
+    let delta_big_h = 2.0 * (c1 * c2).sqrt() * (delta_h.to_radians() / 2.0).sin();
+
+    // Means of lightness, chroma and hue.
+    let l_mean = (l1 + l2) / 2.0;
+    let c_mean = (c1 + c2) / 2.0;
+    let h_mean = if c1 * c2 == 0.0 {
+        h1 + h2
+    } else if (h1 - h2).abs() <= 180.0 {
+        (h1 + h2) / 2.0
+    } else if h1 + h2 < 360.0 {
+        (h1 + h2 + 360.0) / 2.0
+    } else {
+        (h1 + h2 - 360.0) / 2.0
+    };
+
+    // Weighting functions and the rotation term for the blue region.
+    let t = 1.0 - 0.17 * (h_mean - 30.0).to_radians().cos()
+        + 0.24 * (2.0 * h_mean).to_radians().cos()
+        + 0.32 * (3.0 * h_mean + 6.0).to_radians().cos()
+        - 0.20 * (4.0 * h_mean - 63.0).to_radians().cos();
+    let delta_theta = 30.0 * (-((h_mean - 275.0) / 25.0).powi(2)).exp();
+    let r_c = 2.0 * (c_mean.powi(7) / (c_mean.powi(7) + 25f64.powi(7))).sqrt();
+    let s_l = 1.0 + 0.015 * (l_mean - 50.0).powi(2) / (20.0 + (l_mean - 50.0).powi(2)).sqrt();
+    let s_c = 1.0 + 0.045 * c_mean;
+    let s_h = 1.0 + 0.015 * c_mean * t;
+    let r_t = -(2.0 * delta_theta).to_radians().sin() * r_c;
+
+    // Combine the weighted differences.
+    let (l_term, c_term, h_term) = (delta_l / s_l, delta_c / s_c, delta_big_h / s_h);
+    (l_term * l_term + c_term * c_term + h_term * h_term + r_t * c_term * h_term).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    #[test]
+    fn larger_corner_samples_ignore_noisy_corner_pixels() {
+        let background = [100, 150, 200];
+        let mut img = RgbaImage::from_pixel(16, 16, Rgba([100, 150, 200, 255]));
+        // Each outermost corner pixel is off, by a different amount.
+        for (i, (x, y)) in [(0, 0), (15, 0), (0, 15), (15, 15)].into_iter().enumerate() {
+            let offset = 20 + 10 * i as u8;
+            img.put_pixel(x, y, Rgba([100 + offset, 150 - offset, 200 + offset, 255]));
+        }
+
+        assert_ne!(estimate_background(&img, 1), background);
+        assert_eq!(estimate_background(&img, 3), background);
+    }
+
+    #[test]
+    fn auto_edge_threshold_follows_the_image_contrast() {
+        // The same square, drawn boldly and faintly on a slightly noisy background.
+        let square = |background: u8, foreground: u8| {
+            RgbaImage::from_fn(32, 32, |x, y| {
+                let noise = ((x * 7 + y * 13) % 3) as u8;
+                let level = if (8..24).contains(&x) && (8..24).contains(&y) {
+                    foreground
+                } else {
+                    background
+                };
+                Rgba([level - noise, level - noise, level - noise, 255])
+            })
+        };
+        let high = EdgeThreshold::Auto.for_image(&square(250, 20));
+        let low = EdgeThreshold::Auto.for_image(&square(250, 235));
+        assert!(high > low, "{} vs {}", high, low);
+        assert_eq!(EdgeThreshold::Fixed(30).for_image(&square(250, 20)), 30);
+    }
+}
@@ -0,0 +1,96 @@
+use crate::error::RicoError;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Name of the per-directory configuration file.
+pub const CONFIG_FILE_NAME: &str = ".rico.toml";
+
+/// Settings read from a `.rico.toml`; every key is optional and mirrors the CLI flag of the same name.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub format: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub filter: Option<String>,
+    pub gamma: Option<f64>,
+    pub embed_srgb: Option<bool>,
+}
+
+impl Config {
+    /// Overlays `other` on top of this config; keys set in `other` win.
+    fn merge(&mut self, other: &Config) {
+        let other = other.clone();
+        self.format = other.format.or(self.format.take());
+        self.width = other.width.or(self.width);
+        self.height = other.height.or(self.height);
+        self.filter = other.filter.or(self.filter.take());
+        self.gamma = other.gamma.or(self.gamma);
+        self.embed_srgb = other.embed_srgb.or(self.embed_srgb);
+    }
+}
+
+/// Every `.rico.toml` found under a source directory, keyed by the directory holding it.
+#[derive(Debug, Default)]
+pub struct ConfigTree {
+    root: PathBuf,
+    configs: BTreeMap<PathBuf, Config>,
+}
+
+impl ConfigTree {
+    /// Finds and parses all configuration files below `source_dir`,
+    /// without descending into directories named in `exclude_dirs`.
+    pub fn load(source_dir: &Path, exclude_dirs: &[String]) -> Result<Self, RicoError> {
+        let mut configs = BTreeMap::new();
+        let walker = WalkDir::new(source_dir).into_iter().filter_entry(|entry| {
+            entry.depth() == 0
+                || !entry.file_type().is_dir()
+                || !exclude_dirs
+                    .iter()
+                    .any(|name| entry.file_name() == name.as_str())
+        });
+        for entry in walker.filter_map(Result::ok) {
+            let path = entry.path();
+            if entry.file_type().is_file() && entry.file_name() == CONFIG_FILE_NAME {
+                // A broken config is an error rather than something to silently ignore.
+                let config: Config = toml::from_str(&fs::read_to_string(path)?).map_err(|e| {
+                    RicoError::InvalidFile(format!("invalid config {:?}: {}", path, e))
+                })?;
+                let dir = path.parent().unwrap_or(source_dir).to_path_buf();
+                configs.insert(dir, config);
+            }
+        }
+        Ok(ConfigTree {
+            root: source_dir.to_path_buf(),
+            configs,
+        })
+    }
+
+    /// Returns the merged settings for a file: configs from the source root down to
+    /// the file's own directory, with the nearest one winning.
+    pub fn for_file(&self, path: &Path) -> Config {
+        let mut merged = Config::default();
+        if self.configs.is_empty() {
+            return merged;
+        }
+
+        // Collect the directories between the file and the source root, nearest first.
+        let mut dirs: Vec<&Path> = path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(&self.root))
+            .collect();
+
+        // Apply them outermost first so that nearer configs override.
+        dirs.reverse();
+        for dir in dirs {
+            if let Some(config) = self.configs.get(dir) {
+                merged.merge(config);
+            }
+        }
+        merged
+    }
+}
@@ -0,0 +1,244 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Everything that can go wrong while converting, removing backgrounds or trimming,
+/// so callers can tell failure causes apart instead of only printing them.
+#[derive(Debug)]
+pub enum RicoError {
+    /// Reading or writing a file failed.
+    Io(io::Error),
+    /// An input could not be decoded (or read back) as an image.
+    Decode(image::ImageError),
+    /// An image could not be encoded in the target format.
+    Encode(String),
+    /// The requested format cannot be written.
+    UnsupportedFormat(String),
+    /// A file that would be written is already there.
+    OutputExists(PathBuf),
+    /// The source or output directory is missing or not a directory.
+    InvalidDirectory(String),
+    /// Some directories under the source could not be read (with `--fail-on-dir-errors`).
+    UnreadableDirectories { count: usize, dir: PathBuf },
+    /// `--require-matches` found nothing to process.
+    NoMatches(String),
+    /// A `.rico.toml`, `--map`, `--jobs-file` or sequence state file is malformed.
+    InvalidFile(String),
+    /// Safe mode refused to write a path outside the output directory.
+    OutsideOutputDir { path: PathBuf, root: PathBuf },
+    /// `--halt-on-dimension-change` found an output whose dimensions differ from its source's.
+    DimensionChanged {
+        path: PathBuf,
+        from: (u32, u32),
+        to: (u32, u32),
+    },
+    /// `--verify-lossless` found an output that does not decode back to its source's pixels.
+    NotLossless { path: PathBuf, detail: String },
+    /// An output misses `--min-ssim`/`--min-psnr` even at quality 100.
+    QualityGate { path: PathBuf, misses: String },
+    /// An output does not fit into `--target-bytes`, even at the lowest quality (if lossy)
+    /// and at the smallest dimensions tried.
+    OverBudget {
+        path: PathBuf,
+        budget: u64,
+        dimensions: (u32, u32),
+        quality: Option<u8>,
+    },
+    /// `--max-errors` files failed, so the rest of the batch was not started.
+    Aborted { failed: usize, max_errors: usize },
+    /// The output disk filled up, so the rest of the batch was not started.
+    DiskFull { completed: usize, total: usize },
+    /// The animate subcommand found no frames in its source directory.
+    NoFrames(PathBuf),
+    /// A frame of the animate subcommand could not be decoded.
+    FrameDecode {
+        path: PathBuf,
+        error: image::ImageError,
+    },
+    /// A frame of the animate subcommand does not have the size of the first frame.
+    FrameSize {
+        path: PathBuf,
+        size: (u32, u32),
+        expected: (u32, u32),
+    },
+    /// A file to move or copy has no file name to give it in its new place.
+    NoFileName(PathBuf),
+    /// The pixel subcommand was asked for a pixel outside the image.
+    OutOfBounds { point: (u32, u32), size: (u32, u32) },
+    /// The diff subcommand was given images of different sizes.
+    SizeMismatch {
+        reference: (u32, u32),
+        candidate: (u32, u32),
+    },
+    /// The `--jobs` worker pool could not be started.
+    ThreadPool {
+        threads: usize,
+        error: rayon::ThreadPoolBuildError,
+    },
+    /// Any other failure, described by its message.
+    Other(String),
+}
+
+impl fmt::Display for RicoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RicoError::Io(e) => write!(f, "{}", e),
+            RicoError::Decode(e) => write!(f, "{}", e),
+            RicoError::Encode(message) => write!(f, "{}", message),
+            RicoError::UnsupportedFormat(format) => write!(f, "Unsupported format: {}", format),
+            RicoError::OutputExists(path) => write!(f, "{:?} already exists", path),
+            RicoError::InvalidDirectory(message) => write!(f, "{}", message),
+            RicoError::UnreadableDirectories { count, dir } => {
+                write!(f, "{} directory traversal error(s) under {:?}", count, dir)
+            }
+            RicoError::NoMatches(message) => write!(f, "no files matched: {}", message),
+            RicoError::InvalidFile(message) => write!(f, "{}", message),
+            RicoError::OutsideOutputDir { path, root } => write!(
+                f,
+                "refusing to write {:?}: it is outside the output directory {:?}",
+                path, root
+            ),
+            RicoError::DimensionChanged { path, from, to } => write!(
+                f,
+                "{} changed dimensions from {}x{} to {}x{}",
+                path.display(),
+                from.0,
+                from.1,
+                to.0,
+                to.1
+            ),
+            RicoError::NotLossless { path, detail } => {
+                write!(
+                    f,
+                    "{} failed the lossless check: {}",
+                    path.display(),
+                    detail
+                )
+            }
+            RicoError::QualityGate { path, misses } => write!(
+                f,
+                "{} misses the quality gate even at quality 100 ({})",
+                path.display(),
+                misses
+            ),
+            RicoError::OverBudget {
+                path,
+                budget,
+                dimensions,
+                quality,
+            } => {
+                write!(
+                    f,
+                    "{} does not fit into {} bytes at {}x{}",
+                    path.display(),
+                    budget,
+                    dimensions.0,
+                    dimensions.1
+                )?;
+                match quality {
+                    Some(quality) => write!(f, " even at quality {}", quality),
+                    None => Ok(()),
+                }
+            }
+            RicoError::Aborted { failed, max_errors } => write!(
+                f,
+                "aborted after {} failed files (--max-errors {})",
+                failed, max_errors
+            ),
+            RicoError::DiskFull { completed, total } => write!(
+                f,
+                "disk full: aborted after {} of {} files were completed",
+                completed, total
+            ),
+            RicoError::NoFrames(dir) => write!(f, "no frames found in {:?}", dir),
+            RicoError::FrameDecode { path, error } => {
+                write!(f, "could not decode frame {:?}: {}", path, error)
+            }
+            RicoError::FrameSize {
+                path,
+                size,
+                expected,
+            } => write!(
+                f,
+                "frame {:?} is {}x{} but the first frame is {}x{} (use --resize-frames)",
+                path, size.0, size.1, expected.0, expected.1
+            ),
+            RicoError::NoFileName(path) => write!(f, "{:?} has no file name", path),
+            RicoError::OutOfBounds { point, size } => write!(
+                f,
+                "({}, {}) is outside the {}x{} image",
+                point.0, point.1, size.0, size.1
+            ),
+            RicoError::SizeMismatch {
+                reference,
+                candidate,
+            } => write!(
+                f,
+                "the images differ in size ({}x{} vs {}x{})",
+                reference.0, reference.1, candidate.0, candidate.1
+            ),
+            RicoError::ThreadPool { threads, error } => {
+                write!(f, "could not start {} worker threads: {}", threads, error)
+            }
+            RicoError::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for RicoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RicoError::Io(e) => Some(e),
+            RicoError::Decode(e) => Some(e),
+            RicoError::FrameDecode { error, .. } => Some(error),
+            RicoError::ThreadPool { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RicoError {
+    fn from(e: io::Error) -> Self {
+        RicoError::Io(e)
+    }
+}
+
+impl From<image::ImageError> for RicoError {
+    fn from(e: image::ImageError) -> Self {
+        match e {
+            // Keep I/O failures (e.g. a full disk) recognizable as such.
+            image::ImageError::IoError(e) => RicoError::Io(e),
+            e => RicoError::Decode(e),
+        }
+    }
+}
+
+impl From<serde_json::Error> for RicoError {
+    fn from(e: serde_json::Error) -> Self {
+        RicoError::Other(e.to_string())
+    }
+}
+
+impl From<std::path::StripPrefixError> for RicoError {
+    fn from(e: std::path::StripPrefixError) -> Self {
+        RicoError::Other(e.to_string())
+    }
+}
+
+impl From<img_parts::Error> for RicoError {
+    fn from(e: img_parts::Error) -> Self {
+        RicoError::Other(e.to_string())
+    }
+}
+
+impl From<String> for RicoError {
+    fn from(message: String) -> Self {
+        RicoError::Other(message)
+    }
+}
+
+impl From<&str> for RicoError {
+    fn from(message: &str) -> Self {
+        RicoError::Other(message.to_string())
+    }
+}
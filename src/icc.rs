@@ -0,0 +1,156 @@
+use img_parts::{Bytes, DynImage, ImageICC};
+
+/// Number of entries in the sampled sRGB tone reproduction curve.
+const TRC_ENTRIES: usize = 1024;
+
+/// Embeds a standard sRGB ICC profile into an encoded PNG, JPEG or WebP image.
+/// Formats that cannot carry an ICC profile (e.g. BMP) are returned unchanged.
+pub fn embed_srgb_profile(encoded: Vec<u8>) -> Result<Vec<u8>, img_parts::Error> {
+    // Parse the encoded image into its container chunks/segments.
+    let bytes = Bytes::from(encoded);
+    match DynImage::from_bytes(bytes.clone())? {
+        Some(mut image) => {
+            // Replace any existing profile with the sRGB one.
+            image.set_icc_profile(Some(Bytes::from(srgb_profile())));
+            // Re-assemble the container with the new profile chunk.
+            Ok(image.encoder().bytes().to_vec())
+        }
+        // The container does not support ICC profiles, keep the bytes as they are.
+        None => Ok(bytes.to_vec()),
+    }
+}
+
+/// Builds a compact ICC v2 display profile describing the sRGB color space.
+pub fn srgb_profile() -> Vec<u8> {
+    // Tag payloads, in the order they are laid out after the tag table.
+    let description = text_description_tag("sRGB");
+    let copyright = text_tag("No copyright, use freely");
+    let white_point = xyz_tag(0.9642, 1.0, 0.8249);
+    let red = xyz_tag(0.4361, 0.2225, 0.0139);
+    let green = xyz_tag(0.3851, 0.7169, 0.0971);
+    let blue = xyz_tag(0.1431, 0.0606, 0.7141);
+    let trc = curve_tag();
+
+    // The three TRC tags share a single curve payload.
+    let payloads: Vec<(&[u8; 4], &Vec<u8>)> = vec![
+        (b"desc", &description),
+        (b"cprt", &copyright),
+        (b"wtpt", &white_point),
+        (b"rXYZ", &red),
+        (b"gXYZ", &green),
+        (b"bXYZ", &blue),
+        (b"rTRC", &trc),
+    ];
+    let shared: [&[u8; 4]; 2] = [b"gTRC", b"bTRC"];
+
+    // Header (128 bytes) + tag count (4 bytes) + 12 bytes per tag entry.
+    let tag_count = payloads.len() + shared.len();
+    let mut offset = 128 + 4 + 12 * tag_count;
+
+    // Lay out the payloads, recording each tag's offset and size.
+    let mut table = Vec::new();
+    let mut data = Vec::new();
+    for (signature, payload) in &payloads {
+        table.push((**signature, offset, payload.len()));
+        data.extend_from_slice(payload);
+        // Tag data must start on a 4-byte boundary.
+        while data.len() % 4 != 0 {
+            data.push(0);
+        }
+        offset = 128 + 4 + 12 * tag_count + data.len();
+    }
+    // Point the green and blue curves at the red curve's data.
+    let (_, trc_offset, trc_size) = table[table.len() - 1];
+    for signature in shared {
+        table.push((*signature, trc_offset, trc_size));
+    }
+
+    // Assemble the header.
+    let total_size = 128 + 4 + 12 * tag_count + data.len();
+    let mut profile = Vec::with_capacity(total_size);
+    profile.extend_from_slice(&(total_size as u32).to_be_bytes());
+    profile.extend_from_slice(&[0; 4]); // Preferred CMM
+    profile.extend_from_slice(&0x0210_0000u32.to_be_bytes()); // Version 2.1
+    profile.extend_from_slice(b"mntr"); // Display device class
+    profile.extend_from_slice(b"RGB "); // Data color space
+    profile.extend_from_slice(b"XYZ "); // Profile connection space
+    profile.extend_from_slice(&[0; 12]); // Creation date
+    profile.extend_from_slice(b"acsp"); // Profile file signature
+    profile.extend_from_slice(&[0; 4]); // Primary platform
+    profile.extend_from_slice(&[0; 4]); // Flags
+    profile.extend_from_slice(&[0; 4]); // Device manufacturer
+    profile.extend_from_slice(&[0; 4]); // Device model
+    profile.extend_from_slice(&[0; 8]); // Device attributes
+    profile.extend_from_slice(&0u32.to_be_bytes()); // Perceptual rendering intent
+    profile.extend_from_slice(&xyz_number(0.9642, 1.0, 0.8249)); // D50 illuminant
+    profile.extend_from_slice(&[0; 4]); // Profile creator
+    profile.extend_from_slice(&[0; 16]); // Profile ID
+    profile.extend_from_slice(&[0; 28]); // Reserved
+    debug_assert_eq!(profile.len(), 128);
+
+    // Append the tag table followed by the tag data.
+    profile.extend_from_slice(&(tag_count as u32).to_be_bytes());
+    for (signature, offset, size) in table {
+        profile.extend_from_slice(&signature);
+        profile.extend_from_slice(&(offset as u32).to_be_bytes());
+        profile.extend_from_slice(&(size as u32).to_be_bytes());
+    }
+    profile.extend_from_slice(&data);
+    profile
+}
+
+/// Encodes a value as an ICC s15Fixed16Number.
+fn s15_fixed16(value: f64) -> [u8; 4] {
+    ((value * 65536.0).round() as i32).to_be_bytes()
+}
+
+/// Encodes an XYZ triple as three s15Fixed16Numbers.
+fn xyz_number(x: f64, y: f64, z: f64) -> Vec<u8> {
+    [s15_fixed16(x), s15_fixed16(y), s15_fixed16(z)].concat()
+}
+
+/// Builds an `XYZ ` tag holding a single XYZ triple.
+fn xyz_tag(x: f64, y: f64, z: f64) -> Vec<u8> {
+    let mut tag = b"XYZ \0\0\0\0".to_vec();
+    tag.extend_from_slice(&xyz_number(x, y, z));
+    tag
+}
+
+/// Builds a `text` tag holding a null-terminated ASCII string.
+fn text_tag(text: &str) -> Vec<u8> {
+    let mut tag = b"text\0\0\0\0".to_vec();
+    tag.extend_from_slice(text.as_bytes());
+    tag.push(0);
+    tag
+}
+
+/// Builds a v2 `desc` tag with an ASCII description and empty Unicode/ScriptCode parts.
+fn text_description_tag(text: &str) -> Vec<u8> {
+    let mut tag = b"desc\0\0\0\0".to_vec();
+    tag.extend_from_slice(&(text.len() as u32 + 1).to_be_bytes());
+    tag.extend_from_slice(text.as_bytes());
+    tag.push(0);
+    tag.extend_from_slice(&[0; 4]); // Unicode language code
+    tag.extend_from_slice(&[0; 4]); // Unicode character count
+    tag.extend_from_slice(&[0; 2]); // ScriptCode code
+    tag.push(0); // ScriptCode character count
+    tag.extend_from_slice(&[0; 67]); // ScriptCode description
+    tag
+}
+
+/// Builds a `curv` tag sampling the sRGB transfer function.
+fn curve_tag() -> Vec<u8> {
+    let mut tag = b"curv\0\0\0\0".to_vec();
+    tag.extend_from_slice(&(TRC_ENTRIES as u32).to_be_bytes());
+    for i in 0..TRC_ENTRIES {
+        // Map the encoded value to linear light using the sRGB piecewise curve.
+        let encoded = i as f64 / (TRC_ENTRIES - 1) as f64;
+        let linear = if encoded <= 0.04045 {
+            encoded / 12.92
+        } else {
+            ((encoded + 0.055) / 1.055).powf(2.4)
+        };
+        tag.extend_from_slice(&((linear * 65535.0).round() as u16).to_be_bytes());
+    }
+    tag
+}
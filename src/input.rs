@@ -0,0 +1,55 @@
+use memmap2::Mmap;
+use std::fs::{self, File};
+use std::io;
+use std::ops::Deref;
+use std::path::Path;
+
+/// Inputs smaller than this are always read into memory: mapping them saves nothing.
+pub const MMAP_MIN_BYTES: u64 = 1024 * 1024;
+
+/// The contents of an input file, either read into memory or memory-mapped.
+#[derive(Debug)]
+pub enum InputBytes {
+    /// Read into a buffer of its own.
+    Buffered(Vec<u8>),
+    /// Mapped from the file, so the OS pages it in on demand instead of it being copied.
+    Mapped(Mmap),
+}
+
+impl InputBytes {
+    /// Takes the contents as an owned buffer, copying them out of a mapping.
+    pub fn into_vec(self) -> Vec<u8> {
+        match self {
+            InputBytes::Buffered(bytes) => bytes,
+            InputBytes::Mapped(map) => map.to_vec(),
+        }
+    }
+}
+
+impl Deref for InputBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            InputBytes::Buffered(bytes) => bytes,
+            InputBytes::Mapped(map) => map,
+        }
+    }
+}
+
+/// Reads an input file, memory-mapping it when `mmap` is set and the file is at least
+/// `MMAP_MIN_BYTES` long. Falls back to a buffered read for small files or when mapping fails.
+pub fn read(path: &Path, mmap: bool) -> io::Result<InputBytes> {
+    if mmap {
+        let file = File::open(path)?;
+        if file.metadata()?.len() >= MMAP_MIN_BYTES {
+            // SAFETY: the mapping is read-only and only lives while the file is processed. Like
+            // any mmap it assumes the file is not truncated meanwhile; `--mmap` is opt-in for
+            // that reason.
+            if let Ok(map) = unsafe { Mmap::map(&file) } {
+                return Ok(InputBytes::Mapped(map));
+            }
+        }
+    }
+    fs::read(path).map(InputBytes::Buffered)
+}
@@ -0,0 +1,50 @@
+use crossbeam_channel::Sender;
+use std::fmt;
+
+/// A unit of I/O work queued for the pool.
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed set of threads for blocking file I/O (reading inputs, writing outputs), kept apart
+/// from the compute pool so many decode/encode workers cannot oversubscribe the disk.
+/// Workers hand jobs over a channel and wait for the result; cheap to clone.
+#[derive(Clone)]
+pub struct IoPool {
+    sender: Sender<Job>,
+}
+
+impl fmt::Debug for IoPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IoPool").finish_non_exhaustive()
+    }
+}
+
+impl IoPool {
+    /// Starts `threads` I/O threads; they exit once every clone of the pool is dropped.
+    pub fn new(threads: usize) -> Self {
+        let (sender, receiver) = crossbeam_channel::unbounded::<Job>();
+        for _ in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            std::thread::spawn(move || {
+                for job in receiver {
+                    job();
+                }
+            });
+        }
+        IoPool { sender }
+    }
+
+    /// Runs `job` on one of the I/O threads and waits for its result.
+    pub fn run<T, F>(&self, job: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (result_sender, result_receiver) = crossbeam_channel::bounded(1);
+        self.sender
+            .send(Box::new(move || {
+                let _ = result_sender.send(job());
+            }))
+            .expect("I/O pool threads stopped");
+        result_receiver.recv().expect("I/O pool job panicked")
+    }
+}
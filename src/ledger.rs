@@ -0,0 +1,90 @@
+use crate::report::Status;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// One line of a `--ledger` file: the status a file ended with in some run.
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    input: PathBuf,
+    status: Status,
+}
+
+/// Per-file progress kept across runs for `--ledger`, as NDJSON lines appended as files finish.
+/// The last line for a file wins, so an interrupted run can be picked up where it stopped.
+#[derive(Debug)]
+pub struct Ledger {
+    path: PathBuf,
+    /// Status of every file as of the start of this run.
+    previous: HashMap<PathBuf, Status>,
+    /// Only process the files that failed before (`--retry-failed`).
+    retry_failed: bool,
+    /// Opened on the first record, so a dry run leaves no file behind.
+    file: Mutex<Option<File>>,
+}
+
+impl Ledger {
+    /// Loads the ledger at `path`; a missing file is an empty ledger.
+    pub fn open(path: &Path, retry_failed: bool) -> Result<Self, String> {
+        let mut previous = HashMap::new();
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                for (index, line) in contents.lines().enumerate() {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let entry: Entry = serde_json::from_str(line)
+                        .map_err(|e| format!("{:?} line {}: {}", path, index + 1, e))?;
+                    previous.insert(entry.input, entry.status);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("could not read {:?}: {}", path, e)),
+        }
+        Ok(Ledger {
+            path: path.to_path_buf(),
+            previous,
+            retry_failed,
+            file: Mutex::new(None),
+        })
+    }
+
+    /// Returns why the file is left out of this run, if the ledger already settled it:
+    /// files that ended ok or skipped are done, and with `--retry-failed` only failed ones run.
+    pub fn skip_reason(&self, input: &Path) -> Option<&'static str> {
+        match (self.previous.get(input), self.retry_failed) {
+            (Some(Status::Failed), _) => None,
+            (Some(Status::Ok | Status::Skipped), _) => Some("already done in the ledger"),
+            (_, true) => Some("did not fail in the ledger"),
+            (_, false) => None,
+        }
+    }
+
+    /// Appends the status a file ended with. Files the ledger skipped keep their old status,
+    /// and dry-run plans are not progress, so neither is recorded.
+    pub fn record(&self, input: &Path, status: Status) -> io::Result<()> {
+        if status == Status::Planned || self.skip_reason(input).is_some() {
+            return Ok(());
+        }
+        let line = serde_json::to_string(&Entry {
+            input: input.to_path_buf(),
+            status,
+        })?;
+        let mut file = self.file.lock().unwrap();
+        if file.is_none() {
+            *file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            );
+        }
+        // One write per line, flushed right away, so an interrupted run loses nothing.
+        let file = file.as_mut().unwrap();
+        file.write_all(format!("{}\n", line).as_bytes())?;
+        file.flush()
+    }
+}
@@ -1,175 +1,2763 @@
-use clap::{Arg, ArgAction, ArgMatches, Command};
-use image::{io::Reader as ImageReader, DynamicImage, ImageFormat, Rgba, RgbaImage};
+mod animation;
+mod archive;
+mod codec;
+mod color;
+mod config;
+mod error;
+mod icc;
+mod input;
+mod io_pool;
+mod ledger;
+mod mapping;
+mod metadata;
+mod pack;
+mod palette;
+mod postprocess;
+mod progress;
+mod quality;
+mod report;
+mod resize;
+mod sequence;
+mod slug;
+mod throttle;
+mod thumbnail;
+mod transform;
+
+use clap::{Arg, ArgAction, ArgGroup, ArgMatches, Command};
+use codec::TiffCompression;
+use color::{ColorMetric, EdgeThreshold};
+use config::{Config, ConfigTree};
+use error::RicoError;
+use image::error::{LimitError, LimitErrorKind};
+use image::io::{Limits, Reader as ImageReader};
+use image::{
+    imageops, Delay, DynamicImage, Frame, GrayImage, ImageDecoder, ImageError, ImageFormat, Rgba,
+    RgbaImage,
+};
+use input::InputBytes;
+use log::{debug, error, info, warn};
+use palette::Palette;
+use progress::{JsonProgress, Progress, ProgressEvent};
 use rayon::prelude::*;
-use std::collections::VecDeque;
+use report::{CropBox, Record, Report, ReportFormat, Status};
+use resize::{AlignMode, Filter, ResizeMode};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::fs;
-use std::io::Read;
-use std::path::{Path, PathBuf};
-use std::sync::Mutex;
-use walkdir::WalkDir;
+use std::io::Cursor;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use throttle::RateLimiter;
+use transform::{AlphaPolarity, Denoise, Gutter, Margins};
+use walkdir::{DirEntry, WalkDir};
+
+/// How the per-file work of a batch is scheduled across threads.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Strategy {
+    /// Rayon `par_iter` over all files, one task per core.
+    #[default]
+    Rayon,
+    /// `--jobs` consumers fed through a bounded channel, capping the images in flight.
+    Pipeline,
+}
+
+/// Settings shared by the convert and remove pipelines, parsed once from the command line.
+#[derive(Clone, Debug, Default)]
+struct Options {
+    /// Embed a standard sRGB ICC profile into PNG/JPEG/WebP outputs.
+    embed_srgb: bool,
+    /// Scheduling strategy for the per-file work.
+    strategy: Strategy,
+    /// Files queued ahead of the workers when using the pipeline strategy.
+    pipeline_bound: usize,
+    /// Frame taken from animated inputs when the target format is a still image.
+    frame: usize,
+    /// Fixed margins cropped off every image before any other processing.
+    trim: Option<Margins>,
+    /// Plan the work without decoding, writing files or creating directories.
+    dry_run: bool,
+    /// Where to write the per-file report, if requested.
+    report: Option<PathBuf>,
+    /// Format of the report file.
+    report_format: ReportFormat,
+    /// Per-file statuses kept across runs, loaded by `load_ledger`.
+    ledger: Option<Arc<ledger::Ledger>>,
+    /// Maximum number of images started per second across all workers.
+    max_rate: Option<f64>,
+    /// Background color used when transparent images are written to a format without alpha.
+    /// `None` means `--flatten` was not given: white is used, with a warning.
+    flatten: Option<Rgba<u8>>,
+    /// Sort outputs into `YYYY/MM/` folders by their EXIF capture date.
+    organize_by_date: bool,
+    /// Gamma correction applied to every channel (`out = in^(1/gamma)`).
+    gamma: Option<f64>,
+    /// Levels each color channel is posterized to.
+    posterize: Option<u16>,
+    /// Keep uppercase output extensions for inputs with uppercase extensions (e.g. `IMG.JPG` -> `IMG.PNG`).
+    preserve_extension_case: bool,
+    /// Re-create outputs that already exist instead of skipping them.
+    overwrite: bool,
+    /// Skip same-format inputs whose existing output is byte-identical to the source.
+    compare_bytes: bool,
+    /// Decode and re-encode same-format inputs even when nothing would change them.
+    force_reencode: bool,
+    /// Inputs carry premultiplied alpha and are converted to straight alpha after decoding.
+    unpremultiply: bool,
+    /// Outputs are written with premultiplied alpha.
+    alpha_premultiply: bool,
+    /// Flip alpha channels that look inverted relative to luminance, given the expected polarity.
+    fix_inverted_alpha: Option<AlphaPolarity>,
+    /// Target width; alone it keeps the aspect ratio, with `height` the image fits within both.
+    width: Option<u32>,
+    /// Target height; alone it keeps the aspect ratio, with `width` the image fits within both.
+    height: Option<u32>,
+    /// How the image is shaped when both `width` and `height` are given.
+    resize_mode: ResizeMode,
+    /// Resampling filter used when resizing.
+    filter: Filter,
+    /// Name outputs `<prefix><number>` in sorted input order instead of after their inputs.
+    sequence_prefix: Option<String>,
+    /// First number of a new sequence; given explicitly, it also overrides the saved state.
+    sequence_start: u64,
+    /// Minimum number of digits of sequence numbers, zero-padded.
+    sequence_pad: usize,
+    /// Noise filter applied before resizing.
+    denoise: Option<Denoise>,
+    /// Reach of the denoise window in pixels.
+    denoise_radius: u32,
+    /// Rotate each image clockwise by this many degrees, growing the canvas.
+    degrees: Option<f64>,
+    /// Center-crop each image to this width:height aspect ratio before resizing.
+    aspect: Option<(u32, u32)>,
+    /// Place the `aspect` and `--resize-mode cover` crops where the most edge detail is.
+    smart_crop: bool,
+    /// Fit each image within this width and height, keeping the aspect ratio.
+    fit_to: Option<(u32, u32)>,
+    /// Color the fitted image is letterboxed with to exactly the `fit_to` size.
+    fill: Option<Rgba<u8>>,
+    /// Pad or crop each image so both sides are multiples of this, after any resize.
+    align: Option<u32>,
+    /// Whether `align` pads or crops.
+    align_mode: AlignMode,
+    /// Ids of the arguments given explicitly on the command line; `.rico.toml` files cannot override them.
+    cli_args: BTreeSet<String>,
+    /// Color quality (0-100) for lossy WebP output; `None` keeps WebP lossless.
+    webp_quality: Option<u8>,
+    /// Alpha channel quality (0-100) for lossy WebP output.
+    webp_alpha_quality: u8,
+    /// Abort the batch once this many files have failed.
+    max_errors: Option<usize>,
+    /// Widths of the responsive variants written per image (`photo-640.webp`); empty for one output.
+    sizes: Vec<u32>,
+    /// Fail the batch when part of the source tree cannot be traversed, instead of only logging it.
+    fail_on_dir_errors: bool,
+    /// Compression used for TIFF outputs.
+    tiff_compression: TiffCompression,
+    /// Names of directories that are never descended into (e.g. `node_modules`, `.git`).
+    exclude_dirs: Vec<String>,
+    /// Split images wider than this width/height ratio into `_l`/`_r` pages.
+    split_spread: Option<f64>,
+    /// Where spreads are split.
+    gutter: Gutter,
+    /// Spread outputs over numbered subdirectories holding at most this many each.
+    max_per_dir: Option<usize>,
+    /// Rename sources whose extension does not match their content before converting.
+    fix_extensions: bool,
+    /// Write images that look like graphics (few colors) losslessly even when the target is lossy.
+    prefer_lossless_for_graphics: bool,
+    /// Embed a generated EXIF thumbnail into JPEG outputs.
+    embed_thumbnail: bool,
+    /// Carry the source's EXIF thumbnail over to JPEG outputs.
+    keep_thumbnail: bool,
+    /// The current source's EXIF thumbnail, set per file with `keep_thumbnail`.
+    source_thumbnail: Option<Arc<Vec<u8>>>,
+    /// Memory-map large inputs instead of reading them into memory.
+    mmap: bool,
+    /// Threads for the decode/encode/transform work; `None` uses rayon's global pool.
+    jobs: Option<usize>,
+    /// Threads each JPEG decode may use internally; 0 leaves it to the decoder.
+    decode_threads: usize,
+    /// Leave files modified more recently than this for a later run, as still being written.
+    wait_stable: Option<Duration>,
+    /// Largest allocation and dimensions any decoder may use, from `--max-alloc-mb` and
+    /// `--max-dimension`.
+    decode_limits: Limits,
+    /// Separate threads that read inputs and write outputs, sized by `--jobs-io`.
+    io_pool: Option<io_pool::IoPool>,
+    /// Stream outputs into this archive (written to stdout) instead of writing files.
+    archive: Option<archive::ArchiveSink>,
+    /// Format written instead when encoding the target format fails.
+    fallback_format: Option<String>,
+    /// Quality (0-100) JPEG outputs are encoded at.
+    jpeg_quality: u8,
+    /// Re-encode lossy outputs at higher quality until their SSIM against the source reaches this.
+    min_ssim: Option<f64>,
+    /// Re-encode lossy outputs at higher quality until their PSNR (dB) against the source reaches this.
+    min_psnr: Option<f64>,
+    /// Byte budget every output is encoded within, lowering the quality of lossy formats first.
+    target_bytes: Option<u64>,
+    /// Downscale outputs that exceed `target_bytes` even at the lowest quality.
+    allow_downscale: bool,
+    /// Unix permission bits set on every written output.
+    chmod: Option<u32>,
+    /// Only process images with (`Some(true)`) or without (`Some(false)`) an alpha channel.
+    only_alpha: Option<bool>,
+    /// Fail the run when discovery leaves no files to process.
+    require_matches: bool,
+    /// Only process inputs whose detected (not extension) format is one of these; empty allows all.
+    only_formats: Vec<ImageFormat>,
+    /// Target formats by lowercase source extension, from `--map-format`.
+    format_map: BTreeMap<String, String>,
+    /// Skip images whose pixels are all within this per-channel tolerance of one color.
+    skip_solid: Option<u8>,
+    /// Directory solid-color inputs are moved into when skipped.
+    solid_dir: Option<PathBuf>,
+    /// Directory failed inputs are copied to, at their path relative to the source.
+    quarantine: Option<PathBuf>,
+    /// Copy the source itself to the output location when converting it fails.
+    copy_on_failure: bool,
+    /// Add a BlurHash placeholder string for every written output to the report.
+    blurhash: bool,
+    /// Decode lossless outputs again and fail unless their pixels equal the source's.
+    verify_lossless: bool,
+    /// Fail outputs whose dimensions differ from their source's, for pure recompressions.
+    halt_on_dimension_change: bool,
+    /// Retry JPEGs the primary decoder rejects with a lenient fallback decoder.
+    fallback_decoder: bool,
+    /// Slugify output stems into lowercase, URL-safe names.
+    slug: bool,
+    /// Recreate every source directory under the output, including empty ones.
+    mirror_dirs: bool,
+    /// Report progress as NDJSON events on stderr instead of log messages.
+    progress_json: bool,
+    /// Resolved output directory every output must stay inside; `None` with `--no-safe-mode`.
+    output_root: Option<PathBuf>,
+    /// Shared palette every image is remapped to, extracted once from `--palette-from`.
+    palette: Option<Arc<Palette>>,
+    /// Dither when remapping to the palette or reducing to black and white.
+    dither: bool,
+    /// Reduce every image to black and white at this gray threshold; PNGs are written 1-bit.
+    bilevel: Option<u8>,
+    /// External command run on every written output.
+    postprocess: Option<Arc<postprocess::PostProcessor>>,
+    /// Write `{output}.txt` with the dimensions, format and size of every output.
+    info_sidecar: bool,
+    /// Write a before/after comparison image next to every processed output.
+    compare_output: bool,
+    /// Add a perceptual hash of every written output to the report.
+    phash: bool,
+    /// Log groups of outputs whose perceptual hashes differ by at most this many bits.
+    group_similar: Option<u32>,
+}
+
+impl Options {
+    /// Builds the shared options from the matches of either subcommand.
+    fn from_matches(matches: &ArgMatches) -> Self {
+        Options {
+            embed_srgb: matches.get_flag("embed-srgb"),
+            strategy: match matches.get_one::<String>("strategy").map(String::as_str) {
+                Some("pipeline") => Strategy::Pipeline,
+                _ => Strategy::Rayon,
+            },
+            pipeline_bound: matches
+                .get_one::<u64>("pipeline-bound")
+                .map_or(2, |&bound| bound as usize),
+            frame: optional_arg::<usize>(matches, "frame").unwrap_or(0),
+            trim: matches.get_one::<Margins>("trim").copied(),
+            dry_run: matches.get_flag("dry-run"),
+            report: matches.get_one::<String>("report").map(PathBuf::from),
+            report_format: match matches
+                .get_one::<String>("report-format")
+                .map(String::as_str)
+            {
+                Some("ndjson") => ReportFormat::Ndjson,
+                Some("csv") => ReportFormat::Csv,
+                _ => ReportFormat::Json,
+            },
+            // Loaded by the subcommands, since a malformed ledger is fatal.
+            ledger: None,
+            max_rate: matches.get_one::<f64>("max-rate").copied(),
+            flatten: optional_arg::<Rgba<u8>>(matches, "flatten"),
+            organize_by_date: matches
+                .get_one::<String>("organize-by")
+                .is_some_and(|by| by == "date"),
+            gamma: matches.get_one::<f64>("gamma").copied(),
+            posterize: matches.get_one::<u16>("posterize").copied(),
+            preserve_extension_case: matches.get_flag("preserve-extension-case"),
+            overwrite: optional_arg::<bool>(matches, "overwrite").unwrap_or(false),
+            compare_bytes: optional_arg::<bool>(matches, "compare-bytes").unwrap_or(false),
+            force_reencode: optional_arg::<bool>(matches, "force-reencode").unwrap_or(false),
+            unpremultiply: matches.get_flag("unpremultiply"),
+            alpha_premultiply: matches.get_flag("alpha-premultiply"),
+            fix_inverted_alpha: matches
+                .get_one::<String>("fix-inverted-alpha")
+                .map(|polarity| match polarity.as_str() {
+                    "dark-opaque" => AlphaPolarity::DarkOpaque,
+                    _ => AlphaPolarity::LightOpaque,
+                }),
+            width: matches.get_one::<u32>("width").copied(),
+            height: matches.get_one::<u32>("height").copied(),
+            sequence_prefix: optional_arg::<String>(matches, "sequence"),
+            sequence_start: optional_arg::<u64>(matches, "start").unwrap_or(1),
+            sequence_pad: optional_arg::<usize>(matches, "pad").unwrap_or(4),
+            denoise: matches
+                .get_one::<String>("denoise")
+                .map(|method| match method.as_str() {
+                    "gaussian" => Denoise::Gaussian,
+                    _ => Denoise::Median,
+                }),
+            denoise_radius: *matches.get_one::<u32>("radius").unwrap_or(&1),
+            aspect: matches.get_one::<(u32, u32)>("aspect").copied(),
+            smart_crop: matches.get_flag("smart-crop"),
+            fit_to: matches.get_one::<(u32, u32)>("fit-to").copied(),
+            fill: matches.get_one::<Rgba<u8>>("fill").copied(),
+            align: matches.get_one::<u32>("align").copied(),
+            align_mode: *matches.get_one::<AlignMode>("align-mode").unwrap(),
+            degrees: matches.get_one::<f64>("degrees").copied(),
+            resize_mode: matches
+                .get_one::<ResizeMode>("resize-mode")
+                .copied()
+                .unwrap_or_default(),
+            filter: matches
+                .get_one::<Filter>("filter")
+                .copied()
+                .unwrap_or_default(),
+            cli_args: matches
+                .ids()
+                .filter(|id| {
+                    matches.value_source(id.as_str())
+                        == Some(clap::parser::ValueSource::CommandLine)
+                })
+                .map(|id| id.to_string())
+                .collect(),
+            webp_quality: optional_arg::<u8>(matches, "webp-quality"),
+            webp_alpha_quality: optional_arg::<u8>(matches, "webp-alpha-quality").unwrap_or(100),
+            max_per_dir: optional_arg::<u64>(matches, "max-per-dir").map(|max| max as usize),
+            fix_extensions: optional_arg::<bool>(matches, "fix-extensions").unwrap_or(false),
+            prefer_lossless_for_graphics: optional_arg::<bool>(
+                matches,
+                "prefer-lossless-for-graphics",
+            )
+            .unwrap_or(false),
+            mmap: matches.get_flag("mmap"),
+            jobs: matches.get_one::<u64>("jobs").map(|&jobs| jobs as usize),
+            decode_limits: {
+                let mut limits = Limits::default();
+                limits.max_alloc = matches
+                    .get_one::<u64>("max-alloc-mb")
+                    .map(|&megabytes| megabytes * 1024 * 1024);
+                limits.max_image_width = matches.get_one::<u32>("max-dimension").copied();
+                limits.max_image_height = limits.max_image_width;
+                limits
+            },
+            decode_threads: matches
+                .get_one::<u64>("decode-threads")
+                .map_or(1, |&threads| threads as usize),
+            wait_stable: matches
+                .get_one::<u64>("wait-stable")
+                .map(|&secs| Duration::from_secs(secs)),
+            io_pool: matches
+                .get_one::<u64>("jobs-io")
+                .map(|&threads| io_pool::IoPool::new(threads as usize)),
+            // Set up by the convert command when streaming to stdout.
+            archive: None,
+            fallback_format: optional_arg::<String>(matches, "fallback-format")
+                .map(|format| format.to_lowercase()),
+            jpeg_quality: optional_arg::<u8>(matches, "jpeg-quality").unwrap_or(75),
+            min_ssim: optional_arg::<f64>(matches, "min-ssim"),
+            min_psnr: optional_arg::<f64>(matches, "min-psnr"),
+            target_bytes: optional_arg::<u64>(matches, "target-bytes"),
+            allow_downscale: optional_arg::<bool>(matches, "allow-downscale").unwrap_or(false),
+            blurhash: matches.get_flag("blurhash"),
+            chmod: matches.get_one::<u32>("chmod").copied(),
+            only_alpha: if matches.get_flag("only-with-alpha") {
+                Some(true)
+            } else if matches.get_flag("only-without-alpha") {
+                Some(false)
+            } else {
+                None
+            },
+            require_matches: matches.get_flag("require-matches"),
+            only_formats: matches
+                .get_one::<Vec<ImageFormat>>("only-format")
+                .cloned()
+                .unwrap_or_default(),
+            format_map: optional_arg::<BTreeMap<String, String>>(matches, "map-format")
+                .unwrap_or_default(),
+            skip_solid: matches.get_one::<u8>("skip-solid").copied(),
+            solid_dir: matches.get_one::<String>("solid-dir").map(PathBuf::from),
+            quarantine: matches.get_one::<String>("quarantine").map(PathBuf::from),
+            copy_on_failure: optional_arg::<bool>(matches, "copy-on-failure").unwrap_or(false),
+            verify_lossless: optional_arg::<bool>(matches, "verify-lossless").unwrap_or(false),
+            embed_thumbnail: optional_arg::<bool>(matches, "embed-thumbnail").unwrap_or(false),
+            keep_thumbnail: optional_arg::<bool>(matches, "keep-thumbnail").unwrap_or(false),
+            // Extracted per file by `convert_image`.
+            source_thumbnail: None,
+            halt_on_dimension_change: optional_arg::<bool>(matches, "halt-on-dimension-change")
+                .unwrap_or(false),
+            fallback_decoder: matches.get_flag("fallback-decoder"),
+            slug: matches.get_flag("slug"),
+            mirror_dirs: matches.get_flag("mirror-dirs"),
+            progress_json: matches.get_flag("progress-json"),
+            // Set once the output directory exists, see `Options::confine_to`.
+            output_root: None,
+            // Decoded by `Options::load_palette`.
+            palette: None,
+            dither: matches.get_flag("dither"),
+            bilevel: matches
+                .get_flag("bilevel")
+                .then(|| *matches.get_one::<u8>("threshold").unwrap()),
+            postprocess: matches
+                .get_one::<postprocess::Template>("postprocess")
+                .map(|template| {
+                    // Without --postprocess-jobs, run as many commands at once as there are cores.
+                    let jobs = matches.get_one::<u64>("postprocess-jobs").map_or_else(
+                        || std::thread::available_parallelism().map_or(1, |n| n.get()),
+                        |&jobs| jobs as usize,
+                    );
+                    Arc::new(postprocess::PostProcessor::new(template.clone(), jobs))
+                }),
+            info_sidecar: matches.get_flag("info-sidecar"),
+            compare_output: matches.get_flag("compare-output"),
+            // Grouping needs the hashes, so it implies --phash.
+            phash: matches.get_flag("phash") || matches.contains_id("group-similar"),
+            group_similar: matches.get_one::<u32>("group-similar").copied(),
+            fail_on_dir_errors: matches.get_flag("fail-on-dir-errors"),
+            split_spread: optional_arg::<f64>(matches, "split-spread"),
+            gutter: match optional_arg::<String>(matches, "gutter").as_deref() {
+                Some("auto") => Gutter::Auto,
+                _ => Gutter::Center,
+            },
+            exclude_dirs: matches
+                .get_many::<String>("exclude-dir")
+                .map(|names| names.cloned().collect())
+                .unwrap_or_default(),
+            tiff_compression: optional_arg::<TiffCompression>(matches, "tiff-compression")
+                .unwrap_or_default(),
+            sizes: optional_arg::<Vec<u32>>(matches, "sizes").unwrap_or_default(),
+            max_errors: matches
+                .get_one::<u64>("max-errors")
+                .map(|&max| max as usize),
+        }
+    }
+
+    /// Returns the target format for a file: its `--map-format` rule, else a `.rico.toml`
+    /// `format` unless `-f` was given. With `--map-format` and no `-f`, files whose extension has
+    /// no rule keep their format.
+    fn configured_format(&self, target_format: &str, config: &Config, input_path: &Path) -> String {
+        let extension = input_path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        if let Some(format) = extension.as_ref().and_then(|e| self.format_map.get(e)) {
+            return format.clone();
+        }
+        let explicit = self.cli_args.contains("format");
+        match (&config.format, extension) {
+            (Some(format), _) if !explicit => format.to_lowercase(),
+            (_, Some(extension)) if !explicit && !self.format_map.is_empty() => extension,
+            _ => target_format.to_string(),
+        }
+    }
+
+    /// Returns these options with the settings of a `.rico.toml` applied,
+    /// except for the ones given explicitly on the command line.
+    fn configured(&self, config: &Config) -> Result<Options, String> {
+        let mut options = self.clone();
+        let from_config = |id: &str| !self.cli_args.contains(id);
+        if from_config("width") && config.width.is_some() {
+            options.width = config.width;
+        }
+        if from_config("height") && config.height.is_some() {
+            options.height = config.height;
+        }
+        if let Some(filter) = config.filter.as_deref().filter(|_| from_config("filter")) {
+            options.filter = Filter::parse(filter)?;
+        }
+        if let Some(gamma) = config.gamma.filter(|_| from_config("gamma")) {
+            options.gamma = Some(parse_gamma(&gamma.to_string())?);
+        }
+        if let Some(embed_srgb) = config.embed_srgb.filter(|_| from_config("embed-srgb")) {
+            options.embed_srgb = embed_srgb;
+        }
+        Ok(options)
+    }
+
+    /// Names of the shared transforms that will be applied to every image, in order.
+    fn transforms(&self) -> Vec<String> {
+        let mut transforms = Vec::new();
+        if self.unpremultiply {
+            transforms.push("unpremultiply".to_string());
+        }
+        if self.fix_inverted_alpha.is_some() {
+            transforms.push("fix-inverted-alpha".to_string());
+        }
+        if self.trim.is_some() {
+            transforms.push("trim".to_string());
+        }
+        if self.denoise.is_some() {
+            transforms.push("denoise".to_string());
+        }
+        if self.degrees.is_some() {
+            transforms.push("rotate".to_string());
+        }
+        if self.aspect.is_some() {
+            transforms.push("aspect".to_string());
+        }
+        if self.width.is_some() || self.height.is_some() {
+            transforms.push("resize".to_string());
+        }
+        if self.fit_to.is_some() {
+            transforms.push("fit".to_string());
+            // Without --fit-to, --fill only colors what --degrees or --align expose.
+            if self.fill.is_some() {
+                transforms.push("letterbox".to_string());
+            }
+        }
+        if self.align.is_some() {
+            transforms.push("align".to_string());
+        }
+        if self.gamma.is_some() {
+            transforms.push("gamma".to_string());
+        }
+        if self.posterize.is_some() {
+            transforms.push("posterize".to_string());
+        }
+        if self.palette.is_some() {
+            transforms.push("palette".to_string());
+        }
+        if self.bilevel.is_some() {
+            transforms.push("bilevel".to_string());
+        }
+        if self.alpha_premultiply {
+            transforms.push("premultiply".to_string());
+        }
+        transforms
+    }
+
+    /// Extracts the `--palette-from` palette once for the whole batch.
+    fn load_palette(&mut self, matches: &ArgMatches) -> Result<(), String> {
+        if let Some(reference) = matches.get_one::<String>("palette-from") {
+            let reference = image::open(reference)
+                .map_err(|e| format!("Error reading palette {:?}: {}", reference, e))?;
+            let palette = Palette::from_image(&reference);
+            info!("Remapping to a {}-color palette", palette.color_count());
+            self.palette = Some(Arc::new(palette));
+        }
+        Ok(())
+    }
+
+    /// Loads the `--ledger` once for the whole batch.
+    fn load_ledger(&mut self, matches: &ArgMatches) -> Result<(), String> {
+        if let Some(path) = matches.get_one::<String>("ledger") {
+            let ledger = ledger::Ledger::open(Path::new(path), matches.get_flag("retry-failed"))
+                .map_err(|e| format!("Error reading ledger: {}", e))?;
+            self.ledger = Some(Arc::new(ledger));
+        }
+        Ok(())
+    }
+
+    /// Confines every output to the output directory, unless `--no-safe-mode` was given.
+    fn confine_to(&mut self, output_dir: &Path, matches: &ArgMatches) {
+        if matches.get_flag("no-safe-mode") {
+            return;
+        }
+        match resolve_path(output_dir) {
+            Ok(root) => self.output_root = Some(root),
+            Err(e) => warn!(
+                "Could not resolve the output directory {:?}: {}",
+                output_dir, e
+            ),
+        }
+    }
+
+    /// Returns true when a same-format output would only re-encode the source's pixels unchanged,
+    /// so the source bytes can be copied instead: no transforms, variants, splits, encoder
+    /// settings or quality checks apply, and `--force-reencode` is not given.
+    fn passes_through(&self) -> bool {
+        !self.force_reencode
+            && self.transforms().is_empty()
+            && !self.embed_srgb
+            && self.sizes.is_empty()
+            && self.split_spread.is_none()
+            && self.webp_quality.is_none()
+            && !self.cli_args.contains("jpeg-quality")
+            && !self.cli_args.contains("tiff-compression")
+            && self.min_ssim.is_none()
+            && self.min_psnr.is_none()
+            && self.target_bytes.is_none()
+            && !self.prefer_lossless_for_graphics
+            && self.skip_solid.is_none()
+            && !self.embed_thumbnail
+    }
+}
+
+/// Applies the shared pre-processing steps (such as border trimming) to a decoded image.
+/// Returns the reason as an error when the image should be skipped instead.
+fn prepare_image(img: DynamicImage, options: &Options) -> Result<DynamicImage, String> {
+    let mut img = img;
+
+    // Bring premultiplied inputs to straight alpha so blending and flattening are correct.
+    if options.unpremultiply && img.color().has_alpha() {
+        img = transform::unpremultiply_alpha(&img);
+    }
+
+    // Flip mask alpha that was exported the wrong way round.
+    if let Some(polarity) = options.fix_inverted_alpha {
+        if let Some(fixed) = transform::fix_inverted_alpha(&img, polarity) {
+            img = fixed;
+        }
+    }
+
+    // Chop the fixed border off before anything else looks at the pixels.
+    if let Some(margins) = options.trim {
+        img = transform::trim_border(&img, margins).ok_or_else(|| {
+            format!(
+                "image is too small to trim ({}x{})",
+                img.width(),
+                img.height()
+            )
+        })?;
+    }
+
+    // Filter out noise at full resolution, before resizing blends it into its neighbors.
+    if let Some(method) = options.denoise {
+        img = transform::denoise(&img, method, options.denoise_radius);
+    }
+
+    // Rotate by an arbitrary angle, painting the exposed corners with the fill color.
+    if let Some(degrees) = options.degrees {
+        img = transform::rotate(&img, degrees, options.fill);
+    }
+
+    // Crop to the gallery's aspect ratio, so the resize below scales the kept area only.
+    if let Some(aspect) = options.aspect {
+        img = transform::crop_to_aspect(&img, aspect, options.smart_crop);
+    }
+
+    // Scale to the requested width and/or height; with both, --resize-mode says how.
+    match (options.width, options.height, options.resize_mode) {
+        (Some(width), Some(height), ResizeMode::Fill) => {
+            if (width, height) != (img.width(), img.height()) {
+                img = resize::resize(&img, width, height, options.filter);
+            }
+        }
+        (Some(width), Some(height), ResizeMode::Cover) => {
+            let (scaled_width, scaled_height) =
+                resize::cover_dimensions((img.width(), img.height()), width, height);
+            if (scaled_width, scaled_height) != (img.width(), img.height()) {
+                img = resize::resize(&img, scaled_width, scaled_height, options.filter);
+            }
+            // Crop the overflow equally from both ends of the longer side, or around the detail.
+            img = transform::crop_to(&img, width, height, options.smart_crop);
+        }
+        (None, None, _) => {}
+        _ => {
+            let (width, height) = resize::target_dimensions(
+                (img.width(), img.height()),
+                options.width,
+                options.height,
+            );
+            if (width, height) != (img.width(), img.height()) {
+                img = resize::resize(&img, width, height, options.filter);
+            }
+        }
+    }
+
+    // Fit within the screen size, letterboxing to exactly that size with a fill color.
+    if let Some((fit_width, fit_height)) = options.fit_to {
+        let (width, height) = resize::target_dimensions(
+            (img.width(), img.height()),
+            Some(fit_width),
+            Some(fit_height),
+        );
+        if (width, height) != (img.width(), img.height()) {
+            img = resize::resize(&img, width, height, options.filter);
+        }
+        if let Some(fill) = options.fill {
+            img = transform::letterbox(&img, fit_width, fit_height, fill);
+        }
+    }
+
+    // Bring both sides to a multiple of --align, padding around the image or cropping its center.
+    if let Some(multiple) = options.align {
+        let (width, height) =
+            resize::aligned_dimensions((img.width(), img.height()), multiple, options.align_mode)
+                .ok_or_else(|| {
+                format!(
+                    "image is too small to crop to a multiple of {} ({}x{})",
+                    multiple,
+                    img.width(),
+                    img.height()
+                )
+            })?;
+        if (width, height) != (img.width(), img.height()) {
+            img = match options.align_mode {
+                AlignMode::Pad => transform::letterbox(
+                    &img,
+                    width,
+                    height,
+                    options.fill.unwrap_or(Rgba([0, 0, 0, 0])),
+                ),
+                AlignMode::Crop => img.crop_imm(
+                    (img.width() - width) / 2,
+                    (img.height() - height) / 2,
+                    width,
+                    height,
+                ),
+            };
+        }
+    }
+
+    // Apply gamma correction through an 8-bit lookup table.
+    if let Some(gamma) = options.gamma {
+        img = transform::apply_gamma(img, gamma);
+    }
+
+    // Band every color channel into a few flat levels.
+    if let Some(levels) = options.posterize {
+        img = transform::posterize(img, levels);
+    }
+
+    // Remap to the shared palette last, so no later step introduces colors outside it.
+    if let Some(palette) = &options.palette {
+        img = palette.remap(&img, options.dither);
+    }
+
+    // Likewise reduce to black and white last.
+    if let Some(threshold) = options.bilevel {
+        img = transform::bilevel(&img, threshold, options.dither);
+    }
+
+    Ok(img)
+}
+
+/// Reads an argument that only some of the subcommands define.
+fn optional_arg<T: Clone + Send + Sync + 'static>(matches: &ArgMatches, id: &str) -> Option<T> {
+    matches.try_get_one::<T>(id).ok().flatten().cloned()
+}
+
+/// Settings controlling the flood-fill background removal of the remove subcommand.
+#[derive(Clone, Debug)]
+struct RemovalOptions {
+    /// Color difference between neighboring pixels that counts as an edge, fixed or per image.
+    edge_threshold: EdgeThreshold,
+    /// Optional (low, high) whiteness range over which removed pixels fade from opaque to transparent.
+    soft_threshold: Option<(u8, u8)>,
+    /// Metric used to match pixels against the white key; `None` keeps the R, G, B > 240 test.
+    color_metric: Option<ColorMetric>,
+    /// Maximum distance from the background color that counts as background: per channel
+    /// with `bg_color` or `corner_sample` alone, otherwise in units of `color_metric`.
+    color_tolerance: f64,
+    /// Background color given with `--bg-color`, replacing the near-white default.
+    bg_color: Option<[u8; 3]>,
+    /// Background image the cutouts are composited onto, decoded once for the whole batch.
+    compose_over: Option<RgbaImage>,
+    /// Position of the cutout's top-left corner on the `compose_over` background.
+    compose_at: (i64, i64),
+    /// With `--auto-bg`, the side of the corner blocks sampled to detect the background color.
+    corner_sample: Option<u32>,
+    /// Also write the cutout's alpha channel as a grayscale `{stem}_mask.png`.
+    emit_mask: bool,
+    /// Guidance mask: white seeds the fill as definite background, black blocks it as foreground.
+    mask: Option<GrayImage>,
+    /// Stretch each cutout's alpha to the full 0-255 range.
+    rescale_alpha: bool,
+    /// Crop each cutout to the bounding box of its non-transparent pixels.
+    autocrop: bool,
+}
+
+/// How processing one file went, as far as scheduling the rest of the batch is concerned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FileStatus {
+    /// The file was written, planned or skipped.
+    Completed,
+    /// The file failed; it counts towards `--max-errors`.
+    Failed,
+    /// The file failed because the output disk is full, so every later file would fail too.
+    DiskFull,
+}
+
+impl FileStatus {
+    /// Classifies the result of processing one file.
+    fn of(result: &Result<Outcome, RicoError>) -> Self {
+        match result {
+            Ok(_) => FileStatus::Completed,
+            Err(e) if is_disk_full(e) => FileStatus::DiskFull,
+            Err(_) => FileStatus::Failed,
+        }
+    }
+}
+
+/// OS error code for "no space left on device" (`ENOSPC`, or `ERROR_DISK_FULL` on Windows).
+#[cfg(unix)]
+const DISK_FULL_OS_ERROR: i32 = 28;
+#[cfg(not(unix))]
+const DISK_FULL_OS_ERROR: i32 = 112;
+
+/// Returns true when the error, or any error it wraps, is an I/O error caused by a full disk.
+fn is_disk_full(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut current = Some(error);
+    while let Some(error) = current {
+        if let Some(io_error) = error.downcast_ref::<std::io::Error>() {
+            if io_error.raw_os_error() == Some(DISK_FULL_OS_ERROR)
+                || io_error.kind() == std::io::ErrorKind::WriteZero
+            {
+                return true;
+            }
+        }
+        current = error.source();
+    }
+    false
+}
+
+/// Runs `task` for every file using the configured scheduling strategy.
+/// Once `--max-errors` failures have been counted, or as soon as a file fails because the
+/// disk is full, the remaining files are not started and an error is returned.
+fn for_each_file<T, F>(files: &[T], options: &Options, task: F) -> Result<(), RicoError>
+where
+    T: Sync,
+    F: Fn(&T) -> FileStatus + Sync,
+{
+    // When throttled, every file waits for a permit from the shared limiter first.
+    let limiter = options.max_rate.map(RateLimiter::new);
+    // Failures are counted across all workers; the flag stops new files once the budget is spent.
+    let failures = AtomicUsize::new(0);
+    let completed = AtomicUsize::new(0);
+    let aborted = AtomicBool::new(false);
+    let disk_full = AtomicBool::new(false);
+    let task = |file: &T| {
+        if aborted.load(Ordering::Relaxed) {
+            return;
+        }
+        if let Some(limiter) = &limiter {
+            limiter.acquire();
+        }
+        match task(file) {
+            FileStatus::Completed => {
+                completed.fetch_add(1, Ordering::Relaxed);
+            }
+            FileStatus::Failed => {
+                let failed = failures.fetch_add(1, Ordering::Relaxed) + 1;
+                if options.max_errors.is_some_and(|max| failed >= max) {
+                    aborted.store(true, Ordering::Relaxed);
+                }
+            }
+            // Every further write would fail the same way, so stop right away.
+            FileStatus::DiskFull => {
+                disk_full.store(true, Ordering::Relaxed);
+                aborted.store(true, Ordering::Relaxed);
+            }
+        }
+    };
+
+    match options.strategy {
+        // Let rayon spread the files over its global thread pool, or one of `--jobs` threads.
+        Strategy::Rayon => match options.jobs {
+            Some(jobs) => rayon::ThreadPoolBuilder::new()
+                .num_threads(jobs)
+                .build()
+                .map_err(|error| RicoError::ThreadPool {
+                    threads: jobs,
+                    error,
+                })?
+                .install(|| files.par_iter().for_each(task)),
+            None => files.par_iter().for_each(task),
+        },
+        Strategy::Pipeline => {
+            // A bounded channel keeps the producer from running ahead of the workers.
+            let (sender, receiver) = crossbeam_channel::bounded::<&T>(options.pipeline_bound);
+            // One consumer per `--jobs` thread (or core), so that many images are decoded at once.
+            let workers = options.jobs.unwrap_or_else(|| {
+                std::thread::available_parallelism().map_or(1, |workers| workers.get())
+            });
+            std::thread::scope(|scope| {
+                for _ in 0..workers.max(1) {
+                    let receiver = receiver.clone();
+                    let task = &task;
+                    scope.spawn(move || {
+                        for file in receiver {
+                            task(file);
+                        }
+                    });
+                }
+                // Feed the files into the channel, then close it so the consumers exit.
+                for file in files {
+                    if aborted.load(Ordering::Relaxed) || sender.send(file).is_err() {
+                        break;
+                    }
+                }
+                drop(sender);
+            });
+        }
+    }
+
+    // Tell the caller the batch was cut short.
+    if disk_full.load(Ordering::Relaxed) {
+        return Err(RicoError::DiskFull {
+            completed: completed.load(Ordering::Relaxed),
+            total: files.len(),
+        });
+    }
+    if aborted.load(Ordering::Relaxed) {
+        return Err(RicoError::Aborted {
+            failed: failures.load(Ordering::Relaxed),
+            max_errors: options.max_errors.unwrap_or_default(),
+        });
+    }
+    Ok(())
+}
+
+/// File extensions that are recognized as images when walking a source directory.
+const IMAGE_EXTENSIONS: [&str; 8] = ["jpg", "jpeg", "png", "gif", "bmp", "webp", "tiff", "tif"];
+
+/// Returns true when the path has one of the recognized image extensions.
+fn has_image_extension(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| {
+        IMAGE_EXTENSIONS.contains(&ext.to_string_lossy().to_lowercase().as_str())
+    })
+}
+
+/// Returns an `Outcome::Skipped` when the path is not a regular file (e.g. a directory named `foo.png`).
+fn skip_if_not_file(input_path: &Path) -> Option<Outcome> {
+    if input_path.is_file() {
+        None
+    } else {
+        info!("Not a file, skipping: {:?}", input_path);
+        Some(Outcome::Skipped("not a file".into()))
+    }
+}
+
+/// Returns an `Outcome::Skipped` for files the `--ledger` settled in an earlier run.
+fn skip_by_ledger(input_path: &Path, options: &Options) -> Option<Outcome> {
+    let reason = options.ledger.as_ref()?.skip_reason(input_path)?;
+    info!("Skipping {:?}: {}", input_path, reason);
+    Some(Outcome::Skipped(reason.into()))
+}
+
+/// Skip reason of files `--wait-stable` leaves for a later run; these are not recorded in the ledger.
+const STILL_BEING_WRITTEN: &str = "still being written";
+
+/// Returns an `Outcome::Skipped` for files modified within the last `--wait-stable` seconds,
+/// such as uploads still in progress; appending to a file changes its size and mtime alike.
+fn skip_unstable(input_path: &Path, options: &Options) -> Option<Outcome> {
+    let wait = options.wait_stable?;
+    let modified = fs::metadata(input_path).and_then(|m| m.modified()).ok()?;
+    // A modification time in the future (e.g. clock skew on a share) counts as just modified.
+    let age = modified.elapsed().unwrap_or_default();
+    if age >= wait {
+        return None;
+    }
+    info!(
+        "Skipping {:?}: modified {:.1}s ago, {}",
+        input_path,
+        age.as_secs_f64(),
+        STILL_BEING_WRITTEN
+    );
+    Some(Outcome::Skipped(STILL_BEING_WRITTEN.into()))
+}
+
+/// Runs the checks every subcommand makes before touching an input: never open directories or
+/// other non-files as images, leave files an earlier run settled, and leave files still being
+/// written for a later run.
+fn skip_before_reading(input_path: &Path, options: &Options) -> Option<Outcome> {
+    skip_if_not_file(input_path)
+        .or_else(|| skip_by_ledger(input_path, options))
+        .or_else(|| skip_unstable(input_path, options))
+}
+
+/// Returns an `Outcome::Skipped` when `--only-with-alpha`/`--only-without-alpha` rule the image out.
+/// Only the header is read to learn the color type; unreadable images are left to the decoder.
+fn skip_by_alpha(input_path: &Path, options: &Options) -> Option<Outcome> {
+    let wanted = options.only_alpha?;
+    let has_alpha = probe_has_alpha(input_path)?;
+    if has_alpha == wanted {
+        return None;
+    }
+    let reason = if has_alpha {
+        "has an alpha channel"
+    } else {
+        "has no alpha channel"
+    };
+    info!("Skipping {:?}: {}", input_path, reason);
+    Some(Outcome::Skipped(reason.into()))
+}
+
+/// Skips inputs whose detected format is not allowed by `--only-format`, whatever their extension.
+fn skip_by_format(
+    input_path: &Path,
+    format: Option<ImageFormat>,
+    options: &Options,
+) -> Option<Outcome> {
+    if options.only_formats.is_empty()
+        || format.is_some_and(|format| options.only_formats.contains(&format))
+    {
+        return None;
+    }
+    let reason = match format {
+        Some(format) => format!(
+            "detected format {} is not in --only-format",
+            format.extensions_str()[0]
+        ),
+        None => "format could not be detected".to_string(),
+    };
+    info!("Skipping {:?}: {}", input_path, reason);
+    Some(Outcome::Skipped(reason))
+}
+
+/// Skips images that are a single solid color (within the `--skip-solid` tolerance),
+/// moving the input into `--solid-dir` when one is given.
+fn skip_solid(input_path: &Path, img: &DynamicImage, options: &Options) -> Option<Outcome> {
+    let tolerance = options.skip_solid?;
+    if !transform::is_solid(img, tolerance) {
+        return None;
+    }
+    info!("Skipping {:?}: solid color", input_path);
+    if let Some(dir) = &options.solid_dir {
+        if let Err(e) = quarantine_solid(input_path, dir, options) {
+            warn!("Could not move {:?} to {:?}: {}", input_path, dir, e);
+        }
+    }
+    Some(Outcome::Skipped("solid color".into()))
+}
+
+/// With `--quarantine`, copies a failed (or undecodable) input to its path relative to the
+/// source under the quarantine directory, for later inspection. The input stays where it is.
+fn quarantine_failed(input_path: &Path, source_dir: &Path, options: &Options) {
+    let Some(dir) = &options.quarantine else {
+        return;
+    };
+    let relative_path = match input_path.strip_prefix(source_dir) {
+        Ok(relative_path) => relative_path,
+        Err(_) => Path::new(input_path.file_name().unwrap_or_default()),
+    };
+    let target = dir.join(relative_path);
+    let copied = ensure_within(&target, dir, options).and_then(|()| {
+        match target.parent() {
+            Some(parent) => fs::create_dir_all(parent),
+            None => Ok(()),
+        }
+        .and_then(|()| fs::copy(input_path, &target))
+        .map_err(RicoError::from)
+    });
+    match copied {
+        Ok(_) => info!("Quarantined {:?} -> {:?}", input_path, target),
+        Err(e) => warn!("Could not quarantine {:?} to {:?}: {}", input_path, dir, e),
+    }
+}
+
+/// With `--copy-on-failure`, copies a source that could not be converted to where its output
+/// would have gone, under its own name and extension, so the output tree stays complete.
+/// The conversion still counts as failed; existing files are never overwritten.
+fn copy_on_failure(
+    input_path: &Path,
+    source_dir: Option<&Path>,
+    output_dir: &Path,
+    error: &RicoError,
+    options: &Options,
+) {
+    if !options.copy_on_failure {
+        return;
+    }
+    let Some(file_name) = input_path.file_name() else {
+        return;
+    };
+    let relative_dir =
+        source_dir.and_then(|source_dir| input_path.parent()?.strip_prefix(source_dir).ok());
+    let target = match relative_dir {
+        Some(relative_dir) => output_dir.join(relative_dir).join(file_name),
+        None => output_dir.join(file_name),
+    };
+    let copied = if let Err(e) = ensure_within_output_root(&target, options) {
+        Err(e)
+    } else if let Some(archive) = &options.archive {
+        let relative_path = target.strip_prefix(STDOUT_OUTPUT).unwrap_or(&target);
+        fs::read(input_path)
+            .map_err(RicoError::from)
+            .and_then(|bytes| {
+                archive
+                    .add(relative_path, bytes, options.chmod.unwrap_or(0o644))
+                    .map_err(RicoError::from)
+            })
+    } else if target.exists() {
+        // This also covers converting in place, where the target is the source itself.
+        Err(RicoError::OutputExists(target.clone()))
+    } else {
+        match target.parent() {
+            Some(parent) => fs::create_dir_all(parent),
+            None => Ok(()),
+        }
+        .and_then(|()| fs::copy(input_path, &target))
+        .map(|_| ())
+        .map_err(RicoError::from)
+    };
+    match copied {
+        Ok(()) => warn!(
+            "Converting {:?} failed ({}); copied the original to {:?} instead",
+            input_path, error, target
+        ),
+        Err(e) => warn!("Could not copy {:?} on failure: {}", input_path, e),
+    }
+}
+
+/// Moves a solid-color input into `dir` under its own file name, never replacing a file there.
+fn quarantine_solid(input_path: &Path, dir: &Path, options: &Options) -> Result<(), RicoError> {
+    let file_name = input_path
+        .file_name()
+        .ok_or_else(|| RicoError::NoFileName(input_path.to_path_buf()))?;
+    let target = dir.join(file_name);
+    if target.exists() {
+        return Err(RicoError::OutputExists(target));
+    }
+    ensure_within(&target, dir, options)?;
+    fs::create_dir_all(dir)?;
+    // Renaming fails across file systems; copy and delete the input there instead.
+    if fs::rename(input_path, &target).is_err() {
+        fs::copy(input_path, &target)?;
+        fs::remove_file(input_path)?;
+    }
+    info!("Moved {:?} -> {:?}", input_path, target);
+    Ok(())
+}
+
+/// Reads just enough of an image to tell whether it has an alpha channel.
+fn probe_has_alpha(input_path: &Path) -> Option<bool> {
+    use image::codecs::{bmp::BmpDecoder, png::PngDecoder, tiff::TiffDecoder, webp::WebPDecoder};
+
+    // Sniff the format from the contents, then hand the reader to that format's decoder.
+    let reader = ImageReader::open(input_path)
+        .ok()?
+        .with_guessed_format()
+        .ok()?;
+    let format = reader.format()?;
+    let reader = reader.into_inner();
+    let color = match format {
+        ImageFormat::Png => PngDecoder::new(reader).ok()?.color_type(),
+        ImageFormat::Bmp => BmpDecoder::new(reader).ok()?.color_type(),
+        ImageFormat::WebP => WebPDecoder::new(reader).ok()?.color_type(),
+        ImageFormat::Tiff => TiffDecoder::new(reader).ok()?.color_type(),
+        // JPEG has no alpha channel; GIF frames always decode to RGBA.
+        ImageFormat::Jpeg => return Some(false),
+        ImageFormat::Gif => return Some(true),
+        _ => return None,
+    };
+    Some(color.has_alpha())
+}
+
+/// Walks the source directory recursively. Entries that cannot be read (e.g. unreadable
+/// subdirectories) are logged rather than dropped, and fail the walk with `--fail-on-dir-errors`.
+fn walk_source(source_dir: &Path, options: &Options) -> Result<Vec<DirEntry>, RicoError> {
+    let mut entries = Vec::new();
+    let mut errors = 0;
+    // Excluded directories are pruned so their contents are never read at all.
+    let walker = WalkDir::new(source_dir)
+        .into_iter()
+        .filter_entry(|entry| !is_excluded_dir(entry, &options.exclude_dirs));
+    for entry in walker {
+        match entry {
+            Ok(entry) => entries.push(entry),
+            Err(e) => {
+                // Tell the user which part of the tree was not searched.
+                warn!("Could not read {:?}: {}", e.path().unwrap_or(source_dir), e);
+                errors += 1;
+            }
+        }
+    }
+
+    // Optionally refuse to continue with an incomplete file list.
+    if errors > 0 && options.fail_on_dir_errors {
+        return Err(RicoError::UnreadableDirectories {
+            count: errors,
+            dir: source_dir.to_path_buf(),
+        });
+    }
+    Ok(entries)
+}
+
+/// Recreates the directory tree of the source under the output directory for `--mirror-dirs`,
+/// so directories without outputs (empty or with only skipped files) exist there too.
+fn mirror_directories(
+    source_dir: &Path,
+    output_dir: &Path,
+    options: &Options,
+) -> Result<(), RicoError> {
+    // An output directory inside the source must not be mirrored into itself.
+    let output_root = fs::canonicalize(output_dir).ok();
+    for entry in walk_source(source_dir, options)? {
+        if entry.depth() == 0 || !entry.file_type().is_dir() {
+            continue;
+        }
+        if output_root.is_some() && fs::canonicalize(entry.path()).ok() == output_root {
+            continue;
+        }
+        let relative_path = entry.path().strip_prefix(source_dir)?;
+        let mirrored = output_dir.join(relative_path);
+        if options.dry_run {
+            info!("Would create directory: {:?}", mirrored);
+        } else {
+            ensure_within_output_root(&mirrored, options)?;
+            fs::create_dir_all(&mirrored)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns true for a directory below the source root whose name is in `exclude_dirs`.
+fn is_excluded_dir(entry: &DirEntry, exclude_dirs: &[String]) -> bool {
+    entry.depth() > 0
+        && entry.file_type().is_dir()
+        && exclude_dirs
+            .iter()
+            .any(|name| entry.file_name() == name.as_str())
+}
 
 /// Collects all image files with allowed extensions from the source directory.
-fn collect_image_files(source_dir: &Path) -> Vec<PathBuf> {
+fn collect_image_files(source_dir: &Path, options: &Options) -> Result<Vec<PathBuf>, RicoError> {
     // Initialize an empty vector to store the paths of image files.
     let mut image_files = Vec::new();
-    // Define a list of allowed image file extensions.
-    let allowed_extensions = ["jpg", "jpeg", "png", "gif", "bmp", "webp", "tiff"];
 
-    // Iterate through the source directory recursively using WalkDir.
-    for entry in WalkDir::new(source_dir).into_iter().filter_map(Result::ok) {
+    // Iterate through the source directory recursively.
+    for entry in walk_source(source_dir, options)? {
         // Get the path of the current entry.
         let path = entry.path();
-        // Check if the current entry is a file.
-        if path.is_file() {
-            // Get the file extension.
-            if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-                // Convert the extension to lowercase and check if it is in the allowed list.
-                if allowed_extensions.contains(&ext.to_lowercase().as_str()) {
-                    // If the extension is allowed, add the file path to the vector.
-                    image_files.push(path.to_path_buf());
-                }
+        // Only entries with an allowed image extension are considered.
+        if has_image_extension(path) {
+            if path.is_file() {
+                // If the entry is a regular file, add the file path to the vector.
+                image_files.push(path.to_path_buf());
+            } else {
+                // Directories named like images (e.g. `foo.png/`) are not inputs.
+                info!("Not a file, skipping: {:?}", path);
             }
         }
     }
 
     // Return the vector of image file paths.
-    image_files
+    Ok(image_files)
+}
+
+/// Error for `--require-matches` runs that found nothing to process, telling an empty source
+/// directory apart from one whose files were all filtered out.
+fn no_matches_in(source_dir: &Path) -> RicoError {
+    let files = WalkDir::new(source_dir)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_file())
+        .count();
+    RicoError::NoMatches(match files {
+        0 => format!("the source directory {:?} contains no files", source_dir),
+        files => format!(
+            "none of the {} files under {:?} passed the filters (file types, --exclude-dir)",
+            files, source_dir
+        ),
+    })
+}
+
+/// Result of handling a single input file in a batch.
+#[derive(Debug)]
+enum Outcome {
+    /// The output was written to the given path.
+    Written(PathBuf),
+    /// Dry run: the output would be written to the given path.
+    Planned(PathBuf),
+    /// The input was skipped for the given reason.
+    Skipped(String),
+}
+
+/// How `convert_image` names an output, when not after its input.
+#[derive(Clone, Copy, Debug)]
+enum OutputName<'a> {
+    /// `<output_dir>/<stem>.<extension>`, for sequence and slugged outputs.
+    Stem(&'a str),
+    /// Exactly this path, from a `--map` row.
+    Path(&'a Path),
 }
 
 /// Converts an image from its current format to a target format (e.g., PNG, JPEG, BMP).
 /// This function will skip unsupported formats and files that cannot be decoded.
+/// With a `source_dir`, the input's directory relative to it is recreated under `output_dir`.
 fn convert_image(
     input_path: &Path,
+    source_dir: Option<&Path>,
     output_dir: &Path,
     target_format: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
+    options: &Options,
+    name: Option<OutputName>,
+) -> Result<Outcome, RicoError> {
+    // Leave non-files, settled files and files still being written alone.
+    if let Some(skipped) = skip_before_reading(input_path, options) {
+        return Ok(skipped);
+    }
+
     // Skip unsupported formats, such as SVG (image::guess_format will return an error for it)
     if let Some(ext) = input_path.extension() {
-        let ext = ext.to_str().unwrap_or("").to_lowercase();
+        let ext = ext.to_string_lossy().to_lowercase();
         if ext == "svg" {
-            println!("Skipping SVG file: {:?}", input_path);
-            return Ok(()); // Skip SVG files, as they're not supported
+            info!("Skipping SVG file: {:?}", input_path);
+            return Ok(Outcome::Skipped("SVG is not supported".into())); // Skip SVG files, as they're not supported
+        }
+    }
+
+    // Determine the format to save the image based on the target_format string.
+    let mut output_format = match output_format_for(target_format) {
+        Some(output_format) => output_format,
+        // If the target format is not supported, return an error.
+        None => return Err(RicoError::UnsupportedFormat(target_format.to_string())),
+    };
+
+    // When organizing by date, outputs go under YYYY/MM/ (or unknown/) subfolders. Only the
+    // EXIF headers are read for that, so outputs that already exist are still skipped before
+    // the input is read in full, saving the I/O for incremental runs.
+    let output_dir = if options.organize_by_date {
+        output_dir.join(metadata::date_folder(input_path))
+    } else {
+        output_dir.to_path_buf()
+    };
+
+    // Outputs mirror the input's place in the source tree, so `a/logo.png` and `b/logo.png`
+    // cannot overwrite each other.
+    let relative_dir =
+        source_dir.and_then(|source_dir| input_path.parent()?.strip_prefix(source_dir).ok());
+    let output_dir = match relative_dir {
+        Some(relative_dir) => output_dir.join(relative_dir),
+        None => output_dir,
+    };
+
+    // Create the output path by changing the file extension to the target format.
+    let extension = output_extension(input_path, target_format, options);
+    let output_path = match name {
+        // Sequence and slugged outputs get their stem from the batch rather than the input.
+        Some(OutputName::Stem(stem)) => Some(output_dir.join(format!("{}.{}", stem, extension))),
+        // Mapped outputs go exactly where the map says.
+        Some(OutputName::Path(path)) => Some(path.to_path_buf()),
+        None => output_path_for(input_path, &output_dir, &extension),
+    };
+    let mut output_path = match output_path {
+        Some(output_path) => output_path,
+        None => {
+            info!("Skipping file (no usable file name): {:?}", input_path);
+            return Ok(Outcome::Skipped("no usable file name".into()));
         }
+    };
+
+    // With --sizes, each width gets its own `-<width>` output next to where the single one would go.
+    let mut sized_paths: Vec<(u32, PathBuf)> = options
+        .sizes
+        .iter()
+        .map(|&width| {
+            (
+                width,
+                suffixed_output_path(&output_path, &format!("-{}", width)),
+            )
+        })
+        .collect();
+
+    // Check if the output file(s) already exist, unless outputs are to be overwritten.
+    // A spread that was split earlier left `_l`/`_r` pages instead of the single output.
+    let split_exists = options.split_spread.is_some()
+        && suffixed_output_path(&output_path, "_l").exists()
+        && suffixed_output_path(&output_path, "_r").exists();
+    let exists = if sized_paths.is_empty() {
+        output_path.exists() || split_exists
+    } else {
+        sized_paths.iter().all(|(_, path)| path.exists())
+    };
+    // With --compare-bytes, an existing output is only kept once it turns out to be
+    // byte-identical to the source; a changed source replaces it.
+    let compare_bytes = options.compare_bytes && sized_paths.is_empty() && output_path.exists();
+    if exists && !options.overwrite && !compare_bytes {
+        info!("Output already exists for {:?}; skipping", input_path);
+        return Ok(Outcome::Skipped("output already exists".into())); // Skip if the file already exists
     }
 
     // Open the input file and read its contents into a buffer.
-    let mut file = std::fs::File::open(input_path)?;
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)?;
+    let buffer = read_input(input_path, options)?;
 
     // Guess the format of the image based on its contents.
     let format = image::guess_format(&buffer)?;
+    if let Some(skipped) = skip_by_format(input_path, Some(format), options) {
+        return Ok(skipped);
+    }
+
+    // If the format is unsupported, skip the file.
+    if !matches!(
+        format,
+        ImageFormat::Png
+            | ImageFormat::Jpeg
+            | ImageFormat::Bmp
+            | ImageFormat::WebP
+            | ImageFormat::Gif
+            | ImageFormat::Tiff
+    ) {
+        info!("Skipping unsupported file format: {:?}", input_path);
+        return Ok(Outcome::Skipped("unsupported file format".into())); // Skip unsupported file formats
+    }
+
+    // Skip images with (or without) an alpha channel when asked to.
+    if let Some(skipped) = skip_by_alpha(input_path, options) {
+        return Ok(skipped);
+    }
+
+    // For same-format copies, an output identical to the source needs no decode/encode cycle.
+    if compare_bytes {
+        if format == output_format
+            && fs::read(&output_path).is_ok_and(|existing| existing == *buffer)
+        {
+            info!("Output is byte-identical for {:?}; skipping", input_path);
+            return Ok(Outcome::Skipped(
+                "output is byte-identical to the source".into(),
+            ));
+        }
+        // Outputs in another format cannot be compared, so they are kept as without the flag.
+        if format != output_format && !options.overwrite {
+            info!("Output already exists for {:?}; skipping", input_path);
+            return Ok(Outcome::Skipped("output already exists".into()));
+        }
+    }
+
+    // In a dry run, stop here and only report what would be written.
+    if options.dry_run {
+        if sized_paths.is_empty() {
+            info!("Would convert: {:?} -> {:?}", input_path, output_path);
+        }
+        for (_, path) in &sized_paths {
+            info!("Would convert: {:?} -> {:?}", input_path, path);
+        }
+        let planned = sized_paths
+            .first()
+            .map_or(output_path, |(_, path)| path.clone());
+        return Ok(Outcome::Planned(planned));
+    }
+
+    // Re-encoding a same-format source without changes would only cost time (and, for lossy
+    // formats, quality), so its bytes are copied as they are.
+    if format == output_format && options.passes_through() {
+        write_output(buffer.into_vec(), &output_path, options)?;
+        info!(
+            "Copied (already {:?}): {:?} -> {:?}",
+            format, input_path, output_path
+        );
+        return Ok(Outcome::Written(output_path));
+    }
+
+    // Animated WebP sources are decoded frame by frame so their timing survives.
+    let animation = if format == ImageFormat::WebP && animation::is_animated_webp(&buffer) {
+        check_decode_limits(&buffer, &options.decode_limits)?;
+        Some(animation::decode_webp_frames(&buffer)?)
+    } else {
+        None
+    };
+
+    let img = match &animation {
+        // For still targets, use the frame selected with --frame.
+        Some(animation) => match animation.frames.get(options.frame) {
+            Some(frame) => DynamicImage::ImageRgba8(frame.buffer().clone()),
+            None => {
+                info!(
+                    "Skipping animation without frame {}: {:?}",
+                    options.frame, input_path
+                );
+                return Ok(Outcome::Skipped(format!(
+                    "animation has no frame {}",
+                    options.frame
+                )));
+            }
+        },
+        // Decode the buffer that was already read, falling back to the lenient decoder if allowed.
+        None => decode_input(input_path, &buffer, options)?,
+    };
+
+    if let Some(skipped) = skip_solid(input_path, &img, options) {
+        return Ok(skipped);
+    }
+    let original = options.compare_output.then(|| img.clone());
+
+    // Run the shared pre-processing steps, skipping images they reject.
+    let img = match prepare_image(img, options) {
+        Ok(img) => img,
+        Err(reason) => {
+            info!("Skipping {:?}: {}", input_path, reason);
+            return Ok(Outcome::Skipped(reason));
+        }
+    };
+    // What the output must measure: the source's size after any transform asked for.
+    let expected_dimensions = (img.width(), img.height());
+
+    // Animated sources keep all frames, their timing and loop count when the target can animate.
+    if let Some(animation) = animation {
+        if matches!(output_format, ImageFormat::Gif | ImageFormat::WebP) {
+            // Every frame goes through the same pre-processing as a still image.
+            let frames = match prepare_frames(animation.frames, options) {
+                Ok(frames) => frames,
+                Err(reason) => {
+                    info!("Skipping {:?}: {}", input_path, reason);
+                    return Ok(Outcome::Skipped(reason));
+                }
+            };
+            let frame_count = frames.len();
+            let output_path = save_animation(
+                frames,
+                animation.loop_count,
+                &output_path,
+                output_format,
+                options,
+            )?;
+            check_dimensions(expected_dimensions, &output_path, options)?;
+            if let Some(original) = &original {
+                write_comparison(original, &img, &output_path, options)?;
+            }
+            info!(
+                "Converted animation ({} frames): {:?} -> {:?}",
+                frame_count, input_path, output_path
+            );
+            return Ok(Outcome::Written(output_path));
+        }
+    }
+
+    // With --keep-thumbnail, the source's EXIF thumbnail goes along to the JPEG outputs.
+    let thumbnail_options;
+    let options = match options
+        .keep_thumbnail
+        .then(|| thumbnail::extract(&buffer))
+        .flatten()
+    {
+        Some(kept) => {
+            thumbnail_options = Options {
+                source_thumbnail: Some(Arc::new(kept)),
+                ..options.clone()
+            };
+            &thumbnail_options
+        }
+        None => options,
+    };
+
+    // Graphics keep their flat colors and hard edges in a lossless format: PNG instead of
+    // JPEG, lossless instead of lossy WebP.
+    let graphic_options;
+    let options = if options.prefer_lossless_for_graphics
+        && lossy_quality(output_format, options).is_some()
+        && transform::is_graphic(&img)
+    {
+        if output_format == ImageFormat::Jpeg {
+            output_format = ImageFormat::Png;
+            let extension = output_extension(input_path, "png", options);
+            output_path.set_extension(&extension);
+            for (_, path) in &mut sized_paths {
+                path.set_extension(&extension);
+            }
+        }
+        info!(
+            "{:?} looks like a graphic; writing it losslessly to {:?}",
+            input_path, output_path
+        );
+        graphic_options = Options {
+            webp_quality: None,
+            ..options.clone()
+        };
+        &graphic_options
+    } else {
+        options
+    };
+
+    // JPEG cannot store alpha, so composite transparent images over a solid color
+    // instead of leaving the result up to the encoder.
+    let img = if output_format == ImageFormat::Jpeg && transform::has_transparency(&img) {
+        let background = options.flatten.unwrap_or_else(|| {
+            warn!(
+                "{:?} has transparency that {} cannot store; compositing over white (use --flatten to choose the color)",
+                input_path, target_format
+            );
+            Rgba([255, 255, 255, 255])
+        });
+        transform::flatten(&img, background)
+    } else {
+        img
+    };
+
+    // Double-page spreads are written as separate left and right pages.
+    if let Some(ratio) = options.split_spread {
+        let spread = (img.width() as f64 > img.height() as f64 * ratio)
+            .then(|| transform::split_spread(&img, options.gutter))
+            .flatten();
+        if let Some((left, right)) = spread {
+            let left_path = suffixed_output_path(&output_path, "_l");
+            let right_path = suffixed_output_path(&output_path, "_r");
+            let left_path = save_image(&left, &left_path, output_format, options)?;
+            let right_path = save_image(&right, &right_path, output_format, options)?;
+            info!(
+                "Split spread: {:?} -> {:?}, {:?}",
+                input_path, left_path, right_path
+            );
+            return Ok(Outcome::Written(left_path));
+        }
+    }
+
+    // Write one resized variant per requested width, reusing the single decode.
+    if !sized_paths.is_empty() {
+        let mut written = Vec::new();
+        for (width, path) in &sized_paths {
+            let (width, height) =
+                resize::target_dimensions((img.width(), img.height()), Some(*width), None);
+            let variant = resize::resize(&img, width, height, options.filter);
+            let path = save_image(&variant, path, output_format, options)?;
+            info!("Converted: {:?} -> {:?}", input_path, path);
+            written.push(path);
+        }
+        return Ok(Outcome::Written(written.swap_remove(0)));
+    }
+
+    // Save the image in the specified format.
+    let output_path = save_image(&img, &output_path, output_format, options)?;
+    check_dimensions(expected_dimensions, &output_path, options)?;
+    if let Some(original) = &original {
+        write_comparison(original, &img, &output_path, options)?;
+    }
+    // Log a message indicating the successful conversion and the input/output paths.
+    info!("Converted: {:?} -> {:?}", input_path, output_path);
+    Ok(Outcome::Written(output_path))
+}
+
+/// With `--halt-on-dimension-change`, reads the dimensions of the written output back and
+/// fails (removing the output) unless they equal the prepared image's, catching encoders and
+/// later steps that change the size in what should be a pure recompression.
+fn check_dimensions(
+    expected_dimensions: (u32, u32),
+    output_path: &Path,
+    options: &Options,
+) -> Result<(), RicoError> {
+    if !options.halt_on_dimension_change {
+        return Ok(());
+    }
+    let output_dimensions = image::image_dimensions(output_path)?;
+    if output_dimensions != expected_dimensions {
+        let _ = fs::remove_file(output_path);
+        return Err(RicoError::DimensionChanged {
+            path: output_path.to_path_buf(),
+            from: expected_dimensions,
+            to: output_dimensions,
+        });
+    }
+    Ok(())
+}
+
+/// With `--compare-output`, writes the source and the processed image side by side to
+/// `{stem}_compare.png` next to the output, whatever the output's format.
+fn write_comparison(
+    original: &DynamicImage,
+    processed: &DynamicImage,
+    output_path: &Path,
+    options: &Options,
+) -> Result<(), RicoError> {
+    if !options.compare_output {
+        return Ok(());
+    }
+    let comparison = DynamicImage::ImageRgba8(transform::side_by_side(original, processed));
+    let comparison_path = suffixed_output_path(output_path, "_compare").with_extension("png");
+    save_auxiliary_png(&comparison, &comparison_path, options)?;
+    info!("Wrote comparison: {:?}", comparison_path);
+    Ok(())
+}
+
+/// Inserts a suffix before the extension of an output path (`photo.webp` -> `photo-640.webp`).
+fn suffixed_output_path(output_path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = output_path.file_stem().unwrap_or_default().to_os_string();
+    file_name.push(suffix);
+    if let Some(extension) = output_path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    output_path.with_file_name(file_name)
+}
+
+/// Builds `<output_dir>/<stem>.<extension>` for an input file.
+/// The stem is kept as an `OsStr` so non-ASCII and non-UTF-8 names survive unchanged,
+/// and dots inside the stem (`photo.v2.png`) are preserved. Returns `None` when the
+/// input has no usable stem (e.g. a path ending in `..`).
+fn output_path_for(input_path: &Path, output_dir: &Path, extension: &str) -> Option<PathBuf> {
+    // Take the file name without its final extension.
+    let stem = input_path.file_stem()?;
+
+    // Append the new extension without re-parsing the stem.
+    let mut file_name = stem.to_os_string();
+    file_name.push(".");
+    file_name.push(extension);
+    Some(output_dir.join(file_name))
+}
+
+/// Returns the extension for an output file: lowercase, unless `--preserve-extension-case`
+/// is set and the input's own extension is all uppercase.
+fn output_extension(input_path: &Path, extension: &str, options: &Options) -> String {
+    // Only an input extension with letters and no lowercase ones counts as uppercase.
+    let uppercase_input = input_path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            ext.chars().any(|c| c.is_ascii_alphabetic()) && !ext.chars().any(|c| c.is_lowercase())
+        });
+    if options.preserve_extension_case && uppercase_input {
+        extension.to_uppercase()
+    } else {
+        extension.to_lowercase()
+    }
+}
+
+/// Traverses the source directory, processes all image files, and converts them to the specified format.
+/// Returns the report of the batch, which decides the `--summary-exit-codes` status.
+fn process_images(
+    source_dir: &Path,
+    output_dir: &Path,
+    target_format: &str,
+    options: &Options,
+    progress: Progress,
+) -> Result<Report, RicoError> {
+    // Time the whole run, traversal included, for the summary.
+    let started = Instant::now();
+
+    // Files already in the target format are only picked up when they go to a different directory,
+    // so they can never overwrite themselves.
+    let same_dir = fs::canonicalize(source_dir).ok() == fs::canonicalize(output_dir).ok();
+
+    // Give mislabeled sources the extension of their actual format before anything else looks at them.
+    if options.fix_extensions {
+        fix_source_extensions(source_dir, options)?;
+    }
+
+    // Lay out the full directory tree first, so it exists even where nothing gets written.
+    if options.mirror_dirs && options.archive.is_none() {
+        mirror_directories(source_dir, output_dir, options)?;
+    }
+
+    // Per-directory `.rico.toml` files override the CLI defaults for the files below them.
+    let configs = ConfigTree::load(source_dir, &options.exclude_dirs)?;
+
+    // Mutex is used to safely share the file list among threads.
+    // Initialize a Mutex-protected vector to store the paths of files to be processed.
+    let files_to_process: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+    // Traverse the source directory recursively, logging unreadable directories.
+    walk_source(source_dir, options)?
+        .into_iter()
+        .for_each(|entry| {
+            // Get the path of the current entry.
+            let path = entry.path();
+
+            // Check if the current entry is a file (other than a config file).
+            if path.is_file() && entry.file_name() != config::CONFIG_FILE_NAME {
+                // Get the file extension.
+                if let Some(ext) = path.extension() {
+                    // Convert the extension to lowercase.
+                    let ext = ext.to_string_lossy().to_lowercase();
+                    // The target format may come from a `.rico.toml` above the file.
+                    let target_format =
+                        options.configured_format(target_format, &configs.for_file(path), path);
+
+                    // Skip unsupported file formats like SVG.
+                    if ext == "svg" {
+                        info!("Skipping SVG file: {:?}", path);
+                    } else if ext != target_format || !same_dir {
+                        // Lock the mutex to safely access the shared file list.
+                        let mut files = files_to_process.lock().unwrap();
+                        // Add the file path to the list of files to be processed.
+                        files.push(path.to_path_buf());
+                        // Log that a supported image file was found.
+                        info!("Found supported image file: {:?}", path);
+                    }
+                }
+            } else if path.is_dir() && has_image_extension(path) {
+                // Directories named like images (e.g. `foo.png/`) are not inputs.
+                info!("Not a file, skipping: {:?}", path);
+            }
+        });
+
+    // Retrieve the list of files to process by unlocking the mutex and extracting the vector.
+    let mut files = files_to_process.into_inner().unwrap();
+
+    // Sequences are numbered in sorted input order, continuing after the last saved number
+    // unless --start is given.
+    let (mut sequence_state, sequence_first) = match &options.sequence_prefix {
+        Some(prefix) => {
+            files.sort();
+            let state = sequence::SequenceState::load(output_dir)?;
+            let first = match state.last(prefix) {
+                Some(last) if !options.cli_args.contains("start") => last + 1,
+                _ => options.sequence_start,
+            };
+            (state, Some(first))
+        }
+        None => (sequence::SequenceState::default(), None),
+    };
+    let last_written = AtomicU64::new(0);
+
+    // Slugged stems are assigned up front so colliding names can be numbered deterministically.
+    let slug_stems = if options.slug {
+        slug::stems(&files)
+    } else {
+        HashMap::new()
+    };
+
+    // Shards are filled in sorted input order too.
+    if options.max_per_dir.is_some() {
+        files.sort();
+    }
+
+    // If no files were found to process, log a message and exit.
+    if files.is_empty() {
+        if options.require_matches {
+            return Err(no_matches_in(source_dir));
+        }
+        info!("No files found to convert!");
+    }
+
+    // Process the image files in parallel using the configured strategy.
+    let report = Report::default();
+    let processed = for_each_file(&files, options, |file| {
+        notify(
+            progress,
+            ProgressEvent::Started {
+                input: file.clone(),
+            },
+        );
+        // Resolve the settings for this file from the `.rico.toml` files above it.
+        let config = configs.for_file(file);
+        let target_format = options.configured_format(target_format, &config, file);
+        let file_options = options.configured(&config);
+        let transforms = file_options
+            .as_ref()
+            .map(Options::transforms)
+            .unwrap_or_default();
+
+        // Attempt to convert the image file and let the observer know how it went.
+        let result = match &file_options {
+            Ok(file_options) => {
+                let number = sequence_first
+                    .map(|first| first + files.binary_search(file).unwrap_or_default() as u64);
+                let stem = match (&options.sequence_prefix, number) {
+                    (Some(prefix), Some(number)) => {
+                        Some(sequence::stem(prefix, number, options.sequence_pad))
+                    }
+                    _ => slug_stems.get(file).cloned(),
+                };
+                // With --max-per-dir, outputs go to 000/, 001/, ... holding at most N each,
+                // instead of mirroring the source tree.
+                let shard_dir = options.max_per_dir.map(|max| {
+                    let index = files.binary_search(file).unwrap_or_default();
+                    output_dir.join(format!("{:03}", index / max))
+                });
+                let result = convert_image(
+                    file,
+                    shard_dir.is_none().then_some(source_dir),
+                    shard_dir.as_deref().unwrap_or(output_dir),
+                    &target_format,
+                    file_options,
+                    stem.as_deref().map(OutputName::Stem),
+                );
+                if let Err(e) = &result {
+                    copy_on_failure(
+                        file,
+                        shard_dir.is_none().then_some(source_dir),
+                        shard_dir.as_deref().unwrap_or(output_dir),
+                        e,
+                        options,
+                    );
+                }
+                // Remember the highest number actually written, for the next run.
+                if let (Ok(Outcome::Written(_)), Some(number)) = (&result, number) {
+                    last_written.fetch_max(number, Ordering::Relaxed);
+                }
+                result
+            }
+            Err(e) => Err(RicoError::InvalidFile(format!(
+                "invalid config for {:?}: {}",
+                file, e
+            ))),
+        };
+        if result.is_err() {
+            quarantine_failed(file, source_dir, options);
+        }
+        notify(progress, outcome_event(file, &result));
+        report.push(outcome_record(
+            file,
+            &target_format,
+            &transforms,
+            &result,
+            options,
+        ));
+        FileStatus::of(&result)
+    });
+
+    // Save where the sequence stopped so the next run continues after it.
+    if let Some(prefix) = &options.sequence_prefix {
+        let last = last_written.into_inner();
+        if last > 0 {
+            sequence_state.save(output_dir, prefix, last)?;
+        }
+    }
+
+    log_summary(&report, started, options);
+
+    // Write the machine-readable report if one was requested, even for an aborted batch.
+    if let Some(report_path) = &options.report {
+        report.write(report_path, options.report_format)?;
+    }
+    processed?;
+
+    // Return the report to indicate successful completion.
+    Ok(report)
+}
+
+/// Converts exactly the work items of a `--map` or `--jobs-file`, skipping discovery and output
+/// naming. Each item's format is its own, else the output extension's, else `--format`.
+/// Returns the report of the batch, which decides the `--summary-exit-codes` status.
+fn process_map(
+    entries: &[mapping::MapEntry],
+    target_format: &str,
+    options: &Options,
+    progress: Progress,
+) -> Result<Report, RicoError> {
+    // Time the whole run for the summary.
+    let started = Instant::now();
+    if entries.is_empty() {
+        if options.require_matches {
+            return Err(RicoError::NoMatches("the work list is empty".into()));
+        }
+        info!("No files found to convert!");
+    }
+
+    let report = Report::default();
+    let processed = for_each_file(entries, options, |entry| {
+        notify(
+            progress,
+            ProgressEvent::Started {
+                input: entry.input.clone(),
+            },
+        );
+        let target_format = entry
+            .format
+            .clone()
+            .or_else(|| {
+                entry
+                    .output
+                    .extension()
+                    .map(|extension| extension.to_string_lossy().to_lowercase())
+            })
+            .unwrap_or_else(|| target_format.to_string());
+        // Job items carry their own transforms, applied like a `.rico.toml`.
+        let file_options = options.configured(&entry.config);
+        let transforms = file_options
+            .as_ref()
+            .map(Options::transforms)
+            .unwrap_or_default();
+        let result = match &file_options {
+            Ok(file_options) => convert_image(
+                &entry.input,
+                None,
+                entry.output.parent().unwrap_or(Path::new("")),
+                &target_format,
+                file_options,
+                Some(OutputName::Path(&entry.output)),
+            ),
+            Err(e) => Err(RicoError::InvalidFile(format!(
+                "invalid transforms for {:?}: {}",
+                entry.input, e
+            ))),
+        };
+        notify(progress, outcome_event(&entry.input, &result));
+        report.push(outcome_record(
+            &entry.input,
+            &target_format,
+            &transforms,
+            &result,
+            options,
+        ));
+        FileStatus::of(&result)
+    });
+
+    log_summary(&report, started, options);
+
+    // Write the machine-readable report if one was requested, even for an aborted batch.
+    if let Some(report_path) = &options.report {
+        report.write(report_path, options.report_format)?;
+    }
+    processed?;
+    Ok(report)
+}
+
+/// Renames source images whose extension does not match their content (e.g. a PNG saved as
+/// `.jpg`) to the extension of the detected format, never overwriting an existing file.
+fn fix_source_extensions(source_dir: &Path, options: &Options) -> Result<(), RicoError> {
+    for entry in walk_source(source_dir, options)? {
+        let path = entry.path();
+        if !path.is_file() || !has_image_extension(path) {
+            continue;
+        }
+
+        // Compare the extension with the format sniffed from the file's first bytes.
+        let Ok(Some(format)) = ImageReader::open(path)
+            .and_then(|reader| reader.with_guessed_format())
+            .map(|reader| reader.format())
+        else {
+            continue;
+        };
+        let extension = path
+            .extension()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_lowercase();
+        if format.extensions_str().contains(&extension.as_str()) {
+            continue;
+        }
+        let Some(&correct) = format.extensions_str().first() else {
+            continue;
+        };
+
+        // Pick a free name, adding -1, -2, ... when the plain one is taken.
+        let mut renamed = path.with_extension(correct);
+        let mut counter = 1;
+        while renamed.exists() {
+            renamed = suffixed_output_path(&path.with_extension(correct), &format!("-{}", counter));
+            counter += 1;
+        }
+
+        if options.dry_run {
+            info!("Would rename mislabeled {:?} -> {:?}", path, renamed);
+        } else {
+            ensure_within(&renamed, source_dir, options)?;
+            fs::rename(path, &renamed)?;
+            info!("Renamed mislabeled {:?} -> {:?}", path, renamed);
+        }
+    }
+    Ok(())
+}
+
+/// Encodes the image in the given format and writes it to the output path,
+/// applying output-level options such as ICC profile embedding.
+/// Returns the path actually written, which differs when `--fallback-format` stepped in.
+fn save_image(
+    img: &DynamicImage,
+    output_path: &Path,
+    format: ImageFormat,
+    options: &Options,
+) -> Result<PathBuf, RicoError> {
+    // Switch to premultiplied alpha right before encoding, if requested.
+    let premultiplied;
+    let img = if options.alpha_premultiply && img.color().has_alpha() {
+        premultiplied = transform::premultiply_alpha(img);
+        &premultiplied
+    } else {
+        img
+    };
+
+    // Encode, switching to the fallback format (and its extension) if the encoder fails.
+    let fallback = options
+        .fallback_format
+        .as_deref()
+        .and_then(|extension| Some((extension, output_format_for(extension)?)));
+    let (bytes, output_path, format) = match encode_output(img, output_path, format, options) {
+        Ok(bytes) => (bytes, output_path.to_path_buf(), format),
+        Err(e) => match fallback {
+            Some((extension, fallback)) if fallback != format => {
+                let fallback_path = output_path.with_extension(extension);
+                warn!(
+                    "Could not encode {:?} ({}); writing {} instead: {:?}",
+                    output_path, e, extension, fallback_path
+                );
+                let bytes = encode_output(img, &fallback_path, fallback, options)?;
+                (bytes, fallback_path, fallback)
+            }
+            _ => return Err(e),
+        },
+    };
+
+    // JPEG outputs can carry an EXIF thumbnail: the source's, or a freshly generated one.
+    let thumbnail = match &options.source_thumbnail {
+        Some(kept) => Some(kept.to_vec()),
+        None if options.embed_thumbnail => Some(thumbnail::generate(img)?),
+        None => None,
+    };
+    let bytes = match thumbnail.filter(|_| format == ImageFormat::Jpeg) {
+        Some(thumbnail) => match thumbnail::embed(&bytes, &thumbnail) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                warn!("Could not embed a thumbnail in {:?}: {}", output_path, e);
+                bytes
+            }
+        },
+        None => bytes,
+    };
+
+    // Hand the encoded bytes over to be written.
+    write_output(bytes, &output_path, options)?;
+    Ok(output_path)
+}
+
+/// Writes an image that accompanies an output, such as a `--compare-output` image or an
+/// `--emit-mask` mask, as a plain PNG in the image's own color type. The palette, bilevel,
+/// premultiply, target size and fallback of the output do not apply, and neither do
+/// `--embed-srgb`, `--postprocess` or `--info-sidecar`; safe mode, `--archive` and `--chmod` do.
+fn save_auxiliary_png(
+    img: &DynamicImage,
+    output_path: &Path,
+    options: &Options,
+) -> Result<(), RicoError> {
+    let mut buffer = Cursor::new(Vec::new());
+    img.write_to(&mut buffer, ImageFormat::Png)
+        .map_err(|e| RicoError::Encode(e.to_string()))?;
+    let plain_options = Options {
+        embed_srgb: false,
+        postprocess: None,
+        info_sidecar: false,
+        ..options.clone()
+    };
+    write_output(buffer.into_inner(), output_path, &plain_options)
+}
+
+/// Runs every frame of an animation through `prepare_image`, in parallel. Fails if a frame is
+/// rejected or the frames no longer share one size, which an animation needs.
+fn prepare_frames(frames: Vec<Frame>, options: &Options) -> Result<Vec<Frame>, String> {
+    let frames = frames
+        .into_par_iter()
+        .map(|frame| {
+            let (left, top, delay) = (frame.left(), frame.top(), frame.delay());
+            let img = prepare_image(DynamicImage::ImageRgba8(frame.into_buffer()), options)?;
+            Ok(Frame::from_parts(img.to_rgba8(), left, top, delay))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let dimensions = frames.first().map(|frame| frame.buffer().dimensions());
+    if frames
+        .iter()
+        .any(|frame| Some(frame.buffer().dimensions()) != dimensions)
+    {
+        return Err("animation frames differ in size after pre-processing".into());
+    }
+    Ok(frames)
+}
+
+/// Encodes prepared frames as an animated GIF or WebP at `output_path`, with the parts of
+/// `save_image` that apply to animations: premultiplied alpha, and the fallback format,
+/// which gets the first frame as a still image when the animation cannot be encoded.
+fn save_animation(
+    frames: Vec<Frame>,
+    loop_count: u16,
+    output_path: &Path,
+    format: ImageFormat,
+    options: &Options,
+) -> Result<PathBuf, RicoError> {
+    // Switch to premultiplied alpha right before encoding, if requested.
+    let frames: Vec<Frame> = if options.alpha_premultiply {
+        frames
+            .into_iter()
+            .map(|frame| {
+                let (left, top, delay) = (frame.left(), frame.top(), frame.delay());
+                let img = DynamicImage::ImageRgba8(frame.into_buffer());
+                Frame::from_parts(
+                    transform::premultiply_alpha(&img).to_rgba8(),
+                    left,
+                    top,
+                    delay,
+                )
+            })
+            .collect()
+    } else {
+        frames
+    };
+
+    // Keep the first frame for the fallback, since the GIF encoder consumes the frames.
+    let fallback = options
+        .fallback_format
+        .as_deref()
+        .and_then(|extension| Some((extension, output_format_for(extension)?)))
+        .filter(|&(_, fallback)| fallback != format);
+    let first_frame = fallback
+        .and(frames.first())
+        .map(|frame| DynamicImage::ImageRgba8(frame.buffer().clone()));
+
+    let encoded = match format {
+        ImageFormat::Gif => animation::encode_gif(frames, loop_count).map_err(RicoError::from),
+        _ => animation::encode_webp(&frames, loop_count).map_err(RicoError::Encode),
+    };
+    let (bytes, output_path) = match (encoded, fallback.zip(first_frame)) {
+        (Ok(bytes), _) => (bytes, output_path.to_path_buf()),
+        (Err(e), Some(((extension, fallback), first_frame))) => {
+            let fallback_path = output_path.with_extension(extension);
+            warn!(
+                "Could not encode {:?} ({}); writing its first frame as {} instead: {:?}",
+                output_path, e, extension, fallback_path
+            );
+            let bytes = encode_output(&first_frame, &fallback_path, fallback, options)?;
+            (bytes, fallback_path)
+        }
+        (Err(e), None) => return Err(e),
+    };
+    write_output(bytes, &output_path, options)?;
+    Ok(output_path)
+}
+
+/// Encodes the image for `output_path`, applying the quality gate and the lossless check.
+fn encode_output(
+    img: &DynamicImage,
+    output_path: &Path,
+    format: ImageFormat,
+    options: &Options,
+) -> Result<Vec<u8>, RicoError> {
+    // Encode, re-encoding lossy outputs at higher quality while they miss the quality gate,
+    // or at lower quality (and size) until they fit the byte budget.
+    let (bytes, img) = match (options.target_bytes, lossy_quality(format, options)) {
+        (Some(budget), quality) => {
+            encode_within_budget(img, output_path, format, options, quality, budget)?
+        }
+        (None, Some(quality)) if options.min_ssim.is_some() || options.min_psnr.is_some() => (
+            encode_with_quality_gate(img, output_path, format, options, quality)?,
+            Cow::Borrowed(img),
+        ),
+        (None, quality) => (
+            encode_image(img, format, options, quality)?,
+            Cow::Borrowed(img),
+        ),
+    };
+
+    // Make sure lossless outputs decode back to exactly the pixels that were encoded.
+    if options.verify_lossless && is_lossless(format, options) {
+        verify_round_trip(&img, &bytes, format, output_path)?;
+    }
+    Ok(bytes)
+}
+
+/// Lowest quality tried when fitting a lossy output into `--target-bytes`.
+const MIN_BUDGET_QUALITY: u8 = 1;
+
+/// Share of its width and height an image keeps per `--allow-downscale` step.
+const DOWNSCALE_STEP: f64 = 0.9;
+
+/// Encodes an output within `budget` bytes: lossy formats at the highest quality (up to the
+/// configured one) that fits, then, with `--allow-downscale`, at ever smaller dimensions.
+/// Returns the bytes together with the image they encode, which may have been downscaled.
+fn encode_within_budget<'a>(
+    img: &'a DynamicImage,
+    output_path: &Path,
+    format: ImageFormat,
+    options: &Options,
+    quality: Option<u8>,
+    budget: u64,
+) -> Result<(Vec<u8>, Cow<'a, DynamicImage>), RicoError> {
+    let mut scaled = Cow::Borrowed(img);
+    loop {
+        let fitted = match quality {
+            Some(quality) => highest_quality_within(&scaled, format, options, quality, budget)?
+                .map(|(quality, bytes)| (Some(quality), bytes)),
+            None => {
+                let bytes = encode_image(&scaled, format, options, None)?;
+                (bytes.len() as u64 <= budget).then_some((None, bytes))
+            }
+        };
+        if let Some((quality, bytes)) = fitted {
+            let quality = quality.map_or(String::new(), |q| format!(" at quality {}", q));
+            info!(
+                "Fitted {:?} into {} bytes: {}x{}{}",
+                output_path,
+                bytes.len(),
+                scaled.width(),
+                scaled.height(),
+                quality
+            );
+            return Ok((bytes, scaled));
+        }
+
+        // Out of options: report the smallest attempt that still missed the budget.
+        if !options.allow_downscale || (scaled.width() <= 1 && scaled.height() <= 1) {
+            return Err(RicoError::OverBudget {
+                path: output_path.to_path_buf(),
+                budget,
+                dimensions: (scaled.width(), scaled.height()),
+                quality: quality.map(|_| MIN_BUDGET_QUALITY),
+            });
+        }
+
+        // Shrink and try again.
+        let width = ((scaled.width() as f64 * DOWNSCALE_STEP) as u32).max(1);
+        let height = ((scaled.height() as f64 * DOWNSCALE_STEP) as u32).max(1);
+        debug!("Downscaling {:?} to {}x{}", output_path, width, height);
+        scaled = Cow::Owned(resize::resize(&scaled, width, height, options.filter));
+    }
+}
+
+/// Encoded bytes together with the quality they were encoded at.
+type QualityEncoding = (u8, Vec<u8>);
+
+/// Binary-searches the highest quality from `MIN_BUDGET_QUALITY` to `max_quality` whose
+/// encoding fits into `budget` bytes, returning it with the encoded bytes.
+fn highest_quality_within(
+    img: &DynamicImage,
+    format: ImageFormat,
+    options: &Options,
+    max_quality: u8,
+    budget: u64,
+) -> Result<Option<QualityEncoding>, RicoError> {
+    let (mut low, mut high) = (MIN_BUDGET_QUALITY, max_quality.max(MIN_BUDGET_QUALITY));
+    let mut best = None;
+    while low <= high {
+        let quality = low + (high - low) / 2;
+        let bytes = encode_image(img, format, options, Some(quality))?;
+        if bytes.len() as u64 <= budget {
+            best = Some((quality, bytes));
+            low = quality + 1;
+        } else if quality == MIN_BUDGET_QUALITY {
+            break;
+        } else {
+            high = quality - 1;
+        }
+    }
+    Ok(best)
+}
+
+/// Maps a target format name (as given to `-f`) to the image format it is encoded in.
+fn output_format_for(target_format: &str) -> Option<ImageFormat> {
+    match target_format {
+        "png" => Some(ImageFormat::Png),
+        "jpg" | "jpeg" => Some(ImageFormat::Jpeg),
+        "bmp" => Some(ImageFormat::Bmp),
+        "webp" => Some(ImageFormat::WebP),
+        "gif" => Some(ImageFormat::Gif),
+        "tif" | "tiff" => Some(ImageFormat::Tiff),
+        _ => None,
+    }
+}
+
+/// Returns the starting quality when `format` is encoded lossily, or `None` for lossless formats.
+fn lossy_quality(format: ImageFormat, options: &Options) -> Option<u8> {
+    match format {
+        ImageFormat::Jpeg => Some(options.jpeg_quality),
+        ImageFormat::WebP => options.webp_quality,
+        _ => None,
+    }
+}
+
+/// Returns whether `format` stores pixels exactly (GIF is excluded since it quantizes to a palette).
+fn is_lossless(format: ImageFormat, options: &Options) -> bool {
+    match format {
+        ImageFormat::Png | ImageFormat::Bmp | ImageFormat::Tiff => true,
+        ImageFormat::WebP => options.webp_quality.is_none(),
+        _ => false,
+    }
+}
+
+/// Decodes encoded output bytes and fails unless their pixels equal the source's.
+fn verify_round_trip(
+    img: &DynamicImage,
+    bytes: &[u8],
+    format: ImageFormat,
+    output_path: &Path,
+) -> Result<(), RicoError> {
+    // Compare at 16 bits per channel so both 8- and 16-bit images are compared exactly.
+    let decoded = image::load_from_memory_with_format(bytes, format)?;
+    let (expected, actual) = (img.to_rgba16(), decoded.to_rgba16());
+    if expected.dimensions() != actual.dimensions() {
+        return Err(RicoError::NotLossless {
+            path: output_path.to_path_buf(),
+            detail: format!(
+                "decoded as {}x{} instead of {}x{}",
+                actual.width(),
+                actual.height(),
+                expected.width(),
+                expected.height()
+            ),
+        });
+    }
+
+    // Report the first differing pixel and how many differ in total.
+    let mut mismatches = expected
+        .enumerate_pixels()
+        .zip(actual.pixels())
+        .filter(|((_, _, a), b)| a != b);
+    if let Some(((x, y, a), b)) = mismatches.next() {
+        return Err(RicoError::NotLossless {
+            path: output_path.to_path_buf(),
+            detail: format!(
+                "{} pixels differ, first at ({}, {}): {:?} != {:?}",
+                mismatches.count() + 1,
+                x,
+                y,
+                a.0,
+                b.0
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// Encodes an image into `format`, using `quality` for lossy formats.
+fn encode_image(
+    img: &DynamicImage,
+    format: ImageFormat,
+    options: &Options,
+    quality: Option<u8>,
+) -> Result<Vec<u8>, RicoError> {
+    let encoded = match (format, quality) {
+        // Lossy WebP goes through libwebp, which supports a separate alpha quality.
+        (ImageFormat::WebP, Some(quality)) => {
+            codec::encode_lossy_webp(img, quality, options.webp_alpha_quality)
+        }
+        // With a shared palette, PNGs are written indexed with the png crate.
+        (ImageFormat::Png, _) if options.palette.is_some() => {
+            let palette = options.palette.as_ref().unwrap();
+            palette.encode_indexed_png(&img.to_rgba8())
+        }
+        // Black-and-white PNGs are written at 1 bit per pixel.
+        (ImageFormat::Png, _) if options.bilevel.is_some() => {
+            codec::encode_bilevel_png(&img.to_luma8())
+        }
+        // TIFF is written with the tiff crate directly so the compression can be chosen.
+        (ImageFormat::Tiff, _) => codec::encode_tiff(img, options.tiff_compression),
+        // Everything else is encoded into an in-memory buffer by the image crate.
+        _ => {
+            let mut buffer = Cursor::new(Vec::new());
+            let written = match (format, quality) {
+                (ImageFormat::Jpeg, Some(quality)) => {
+                    img.write_to(&mut buffer, image::ImageOutputFormat::Jpeg(quality))
+                }
+                _ => img.write_to(&mut buffer, format),
+            };
+            written
+                .map(|()| buffer.into_inner())
+                .map_err(|e| e.to_string())
+        }
+    };
+    encoded.map_err(RicoError::Encode)
+}
+
+/// Encodes a lossy output, decoding it again to check SSIM/PSNR against the source, and
+/// raises the quality until `--min-ssim`/`--min-psnr` are met. Fails if quality 100 still misses.
+fn encode_with_quality_gate(
+    img: &DynamicImage,
+    output_path: &Path,
+    format: ImageFormat,
+    options: &Options,
+    mut quality: u8,
+) -> Result<Vec<u8>, RicoError> {
+    loop {
+        // Encode at the current quality and decode the result again.
+        let bytes = encode_image(img, format, options, Some(quality))?;
+        let decoded = image::load_from_memory_with_format(&bytes, format)?;
+
+        // Collect every threshold the output falls short of.
+        let mut misses = Vec::new();
+        if let Some(min_ssim) = options.min_ssim {
+            let ssim = quality::ssim(img, &decoded);
+            if ssim < min_ssim {
+                misses.push(format!("SSIM {:.4} < {}", ssim, min_ssim));
+            }
+        }
+        if let Some(min_psnr) = options.min_psnr {
+            let psnr = quality::psnr(img, &decoded);
+            if psnr < min_psnr {
+                misses.push(format!("PSNR {:.2} dB < {} dB", psnr, min_psnr));
+            }
+        }
+        if misses.is_empty() {
+            return Ok(bytes);
+        }
+
+        // Give up once the highest quality is still not good enough.
+        if quality >= 100 {
+            return Err(RicoError::QualityGate {
+                path: output_path.to_path_buf(),
+                misses: misses.join(", "),
+            });
+        }
+        let next = quality.saturating_add(10).min(100);
+        info!(
+            "{} at quality {} misses the quality gate ({}), re-encoding at {}",
+            output_path.display(),
+            quality,
+            misses.join(", "),
+            next
+        );
+        quality = next;
+    }
+}
+
+/// Reads an input file, on the I/O pool if there is one.
+/// With `--mmap`, large inputs are memory-mapped instead of copied into memory.
+fn read_input(input_path: &Path, options: &Options) -> std::io::Result<InputBytes> {
+    let mmap = options.mmap;
+    match &options.io_pool {
+        Some(pool) => {
+            let input_path = input_path.to_path_buf();
+            pool.run(move || input::read(&input_path, mmap))
+        }
+        None => input::read(input_path, mmap),
+    }
+}
+
+/// Decodes an input buffer, with JPEG decoders limited to `--decode-threads` threads.
+fn decode_input(
+    input_path: &Path,
+    bytes: &[u8],
+    options: &Options,
+) -> image::ImageResult<DynamicImage> {
+    // Only the JPEG decoder threads internally; other formats decode on the calling thread.
+    let threads = match image::guess_format(bytes) {
+        Ok(ImageFormat::Jpeg) => options.decode_threads,
+        _ => 0,
+    };
+    let result = codec::with_decode_threads(threads, || decode_bytes(input_path, bytes, options));
+    if let Err(ImageError::Limits(e)) = &result {
+        warn!(
+            "Rejected {:?}: decoding it would exceed the decode limits (--max-alloc-mb, --max-dimension): {}",
+            input_path, e
+        );
+    }
+    result
+}
+
+/// Rejects inputs whose header promises more than `--max-dimension` or, decoded as 8-bit RGBA,
+/// more than `--max-alloc-mb`, before any decoder allocates for them. Small files can claim
+/// enormous dimensions (decompression bombs). Unreadable headers are left to the decoder.
+fn check_decode_limits(bytes: &[u8], limits: &Limits) -> image::ImageResult<()> {
+    let dimensions = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_dimensions().ok());
+    let Some((width, height)) = dimensions else {
+        return Ok(());
+    };
+    let too_wide = limits.max_image_width.is_some_and(|max| width > max);
+    let too_tall = limits.max_image_height.is_some_and(|max| height > max);
+    if too_wide || too_tall {
+        return Err(ImageError::Limits(LimitError::from_kind(
+            LimitErrorKind::DimensionError,
+        )));
+    }
+    if limits
+        .max_alloc
+        .is_some_and(|max| width as u64 * height as u64 * 4 > max)
+    {
+        return Err(ImageError::Limits(LimitError::from_kind(
+            LimitErrorKind::InsufficientMemory,
+        )));
+    }
+    Ok(())
+}
+
+/// Decodes an input buffer, retrying JPEGs with the lenient decoder when
+/// `--fallback-decoder` is set and the primary decoder fails.
+fn decode_bytes(
+    input_path: &Path,
+    bytes: &[u8],
+    options: &Options,
+) -> image::ImageResult<DynamicImage> {
+    // Every decoder below is guarded by the header check; the primary one also enforces the
+    // limits itself while decoding.
+    check_decode_limits(bytes, &options.decode_limits)?;
+
+    // Plain CMYK JPEGs would come out inverted (reds turning cyan) from the primary decoder.
+    if codec::is_unmarked_cmyk_jpeg(bytes) {
+        match codec::decode_unmarked_cmyk_jpeg(bytes) {
+            Ok(img) => {
+                info!("Corrected the CMYK inversion of {:?}", input_path);
+                return Ok(img);
+            }
+            Err(e) => warn!(
+                "Could not correct the CMYK colors of {:?}: {}",
+                input_path, e
+            ),
+        }
+    }
+    let mut reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+    reader.limits(options.decode_limits.clone());
+    let error = match reader.decode() {
+        Ok(img) => return Ok(img),
+        Err(error) => error,
+    };
+    if !options.fallback_decoder || image::guess_format(bytes).ok() != Some(ImageFormat::Jpeg) {
+        return Err(error);
+    }
+
+    // Keep the primary error if the fallback cannot recover the image either.
+    match codec::decode_jpeg_lenient(bytes) {
+        Ok(img) => {
+            warn!(
+                "Decoded {:?} with the fallback JPEG decoder ({})",
+                input_path, error
+            );
+            Ok(img)
+        }
+        Err(_) => Err(error),
+    }
+}
 
-    // If the format is unsupported, skip the file.
-    if !matches!(
-        format,
-        ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Bmp
-    ) {
-        println!("Skipping unsupported file format: {:?}", input_path);
-        return Ok(()); // Skip unsupported file formats
+/// Writes already-encoded image bytes to the output path, applying output-level
+/// options such as ICC profile embedding.
+fn write_output(
+    mut bytes: Vec<u8>,
+    output_path: &Path,
+    options: &Options,
+) -> Result<(), RicoError> {
+    // Embed the sRGB profile if requested (no-op for containers without ICC support).
+    if options.embed_srgb {
+        bytes = icc::embed_srgb_profile(bytes)?;
     }
 
-    // Try opening and decoding the image file.
-    let img_result = ImageReader::open(input_path);
+    // The sidecar goes next to the output, so checking the output covers both.
+    ensure_within_output_root(output_path, options)?;
 
-    // If the image cannot be decoded, skip the file.
-    let img = match img_result {
-        Ok(reader) => reader.decode(),
-        Err(_) => {
-            println!("Skipping file (could not decode): {:?}", input_path);
-            return Ok(());
+    // When streaming an archive, the output goes into it under its path relative to `-`.
+    if let Some(archive) = &options.archive {
+        let relative_path = output_path
+            .strip_prefix(STDOUT_OUTPUT)
+            .unwrap_or(output_path);
+        if options.info_sidecar {
+            archive.add(
+                &sidecar_path(relative_path),
+                sidecar_info(&bytes)?.into_bytes(),
+                options.chmod.unwrap_or(0o644),
+            )?;
         }
-    };
+        archive.add(relative_path, bytes, options.chmod.unwrap_or(0o644))?;
+        return Ok(());
+    }
 
-    // Unwrap the result of image decoding.
-    let img = img?;
+    // Create parent directories for the output file if they don't exist.
+    if let Some(parent) = output_path.parent() {
+        if !parent.exists() {
+            fs::create_dir_all(parent)?;
+        }
+    }
 
-    // Create the output path by changing the file extension to the target format.
-    let mut output_path = output_dir.to_path_buf();
-    output_path.push(input_path.file_stem().unwrap());
-    output_path.set_extension(target_format);
+    // Write the encoded bytes to the output path, on the I/O pool if there is one.
+    let written = match &options.io_pool {
+        Some(pool) => {
+            let path = output_path.to_path_buf();
+            pool.run(move || fs::write(path, bytes))
+        }
+        None => fs::write(output_path, bytes),
+    };
+    // Don't leave a truncated file behind when the write fails part-way (e.g. a full disk).
+    if let Err(e) = written {
+        let _ = fs::remove_file(output_path);
+        return Err(e.into());
+    }
 
-    // Check if the output file already exists.
-    if output_path.exists() {
-        println!("Output already exists for {:?}; skipping", input_path);
-        return Ok(()); // Skip if the file already exists
+    // Apply the requested permissions to the written file.
+    if let Some(mode) = options.chmod {
+        set_output_mode(output_path, mode)?;
     }
 
-    // Determine the format to save the image based on the target_format string.
-    let format = match target_format {
-        "png" => ImageFormat::Png,
-        "jpg" | "jpeg" => ImageFormat::Jpeg,
-        "bmp" => ImageFormat::Bmp,
-        "webp" => ImageFormat::WebP,
-        // If the target format is not supported, return an error.
-        _ => return Err(format!("Unsupported format: {}", target_format).into()),
-    };
+    // Hand the finished output to the external post-processor; its failures don't fail the file.
+    if let Some(postprocess) = &options.postprocess {
+        if let Err(e) = postprocess.run(output_path) {
+            warn!("Post-processing {:?} failed: {}", output_path, e);
+        }
+    }
 
-    // Save the image in the specified format.
-    img.save_with_format(output_path.clone(), format)?;
-    // Print a message indicating the successful conversion and the input/output paths.
-    println!("Converted: {:?} -> {:?}", input_path, output_path);
+    // Describe the final file, after any post-processing, in its sidecar.
+    if options.info_sidecar {
+        let info = sidecar_info(&fs::read(output_path)?)?;
+        fs::write(sidecar_path(output_path), info)?;
+    }
     Ok(())
 }
 
-/// Traverses the source directory, processes all image files, and converts them to the specified format.
-fn process_images(
-    source_dir: &Path,
-    output_dir: &Path,
-    target_format: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // Mutex is used to safely share the file list among threads.
-    // Initialize a Mutex-protected vector to store the paths of files to be processed.
-    let files_to_process: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+/// Returns the `--info-sidecar` path of an output: its full file name plus `.txt`.
+fn sidecar_path(output_path: &Path) -> PathBuf {
+    let mut path = output_path.as_os_str().to_os_string();
+    path.push(".txt");
+    PathBuf::from(path)
+}
 
-    // Traverse the source directory recursively using WalkDir.
-    WalkDir::new(source_dir)
-        .into_iter()
-        .filter_map(Result::ok) // Ignore errors from unreadable directories.
-        .for_each(|entry| {
-            // Get the path of the current entry.
-            let path = entry.path();
+/// Describes an encoded output as `WIDTHxHEIGHT FORMAT bytes`, e.g. `640x480 PNG 10324`.
+fn sidecar_info(bytes: &[u8]) -> Result<String, RicoError> {
+    let reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+    let format = reader
+        .format()
+        .ok_or_else(|| RicoError::Encode("could not detect the format of the output".into()))?;
+    let (width, height) = reader.into_dimensions()?;
+    Ok(format!(
+        "{}x{} {} {}\n",
+        width,
+        height,
+        format!("{:?}", format).to_uppercase(),
+        bytes.len()
+    ))
+}
 
-            // Check if the current entry is a file.
-            if path.is_file() {
-                // Get the file extension.
-                if let Some(ext) = path.extension() {
-                    // Convert the extension to lowercase.
-                    let ext = ext.to_str().unwrap_or("").to_lowercase();
+/// In safe mode, refuses to write `path` unless it stays within the output directory,
+/// e.g. against `..` in a --sequence prefix or a symlinked subdirectory.
+fn ensure_within_output_root(path: &Path, options: &Options) -> Result<(), RicoError> {
+    match &options.output_root {
+        Some(root) => ensure_within(path, root, options),
+        None => Ok(()),
+    }
+}
 
-                    // Skip unsupported file formats like SVG.
-                    if ext == "svg" {
-                        println!("Skipping SVG file: {:?}", path);
-                    } else if ext != target_format {
-                        // Lock the mutex to safely access the shared file list.
-                        let mut files = files_to_process.lock().unwrap();
-                        // Add the file path to the list of files to be processed.
-                        files.push(path.to_path_buf());
-                        // Log that a supported image file was found.
-                        println!("Found supported image file: {:?}", path);
-                    }
-                }
-            }
-        });
+/// In safe mode, refuses to write `path` unless it stays within `root` once `..` and symlinks
+/// are resolved. Archive entries may not contain `..` at all, since unpacking the archive
+/// would put them outside its target (tar-slip). Every file system or archive write of a
+/// batch is checked here first.
+fn ensure_within(path: &Path, root: &Path, options: &Options) -> Result<(), RicoError> {
+    if options.output_root.is_none() {
+        return Ok(());
+    }
+    let inside = match path.strip_prefix(STDOUT_OUTPUT) {
+        Ok(entry) if options.archive.is_some() => entry
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir)),
+        _ => resolve_path(path)?.starts_with(resolve_path(root)?),
+    };
+    if inside {
+        Ok(())
+    } else {
+        Err(RicoError::OutsideOutputDir {
+            path: path.to_path_buf(),
+            root: root.to_path_buf(),
+        })
+    }
+}
 
-    // Retrieve the list of files to process by unlocking the mutex and extracting the vector.
-    let files = files_to_process.into_inner().unwrap();
+/// Resolves a path that may not exist yet to an absolute one: its deepest existing ancestor
+/// is canonicalized (following symlinks and `..`), then the rest is applied lexically.
+fn resolve_path(path: &Path) -> std::io::Result<PathBuf> {
+    let components: Vec<Component> = path.components().collect();
 
-    // If no files were found to process, print a message and exit.
-    if files.is_empty() {
-        println!("No files found to convert!");
+    // Find the longest prefix of the path that exists.
+    let mut existing = components.len();
+    while existing > 0 && !components[..existing].iter().collect::<PathBuf>().exists() {
+        existing -= 1;
     }
+    let mut resolved = if existing == 0 {
+        fs::canonicalize(".")?
+    } else {
+        fs::canonicalize(components[..existing].iter().collect::<PathBuf>())?
+    };
 
-    // Process the image files in parallel using rayon.
-    files.par_iter().for_each(|file| {
-        // Attempt to convert the image file.
-        if let Err(e) = convert_image(file, output_dir, target_format) {
-            // If an error occurs during conversion, log the error to stderr.
-            eprintln!("Failed to process {:?}: {}", file, e);
+    // The missing part cannot contain symlinks, so `.` and `..` are resolved by hand.
+    for component in &components[existing..] {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            other => resolved.push(other),
         }
-    });
+    }
+    Ok(resolved)
+}
+
+/// `--output` value that streams the outputs to stdout as an archive instead of writing files.
+const STDOUT_OUTPUT: &str = "-";
+
+/// Sets the Unix permission bits of a written output for `--chmod`.
+#[cfg(unix)]
+fn set_output_mode(output_path: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(output_path, fs::Permissions::from_mode(mode))
+}
 
-    // Return Ok to indicate successful completion.
+/// File modes are a Unix concept, so `--chmod` only warns (once) elsewhere.
+#[cfg(not(unix))]
+fn set_output_mode(_output_path: &Path, _mode: u32) -> std::io::Result<()> {
+    static WARNED: std::sync::Once = std::sync::Once::new();
+    WARNED.call_once(|| warn!("--chmod is only supported on Unix; ignoring it"));
     Ok(())
 }
 
@@ -187,10 +2775,26 @@ fn is_edge(p1: Rgba<u8>, p2: Rgba<u8>, edge_threshold: u8) -> bool {
     // which is considered an edge. This edge is used as a stopping point.
     diff_r > edge_threshold || diff_g > edge_threshold || diff_b > edge_threshold
 }
+/// Guidance mask values at or above this mark definite background.
+const MASK_BACKGROUND: u8 = 192;
+/// Guidance mask values at or below this mark definite foreground.
+const MASK_FOREGROUND: u8 = 63;
+
 /// Removes only the outer near-white background, stopping at edges.
-fn remove_background(img: &DynamicImage, edge_threshold: u8) -> RgbaImage {
+fn remove_background(img: &DynamicImage, removal: &RemovalOptions) -> RgbaImage {
     // Convert the input image to Rgba8 format for pixel-level manipulation.
+    // Grayscale inputs expand to equal R, G and B (so the whiteness tests apply unchanged),
+    // opaque ones gain alpha 255 and LumaA ones keep their own alpha.
     let img = img.to_rgba8();
+    // An empty image has nothing to remove and no border to start the fill from.
+    if img.width() == 0 || img.height() == 0 {
+        return img;
+    }
+    // Resolve the edge threshold used to stop the flood fill (with `auto`, from this image).
+    let edge_threshold = removal.edge_threshold.for_image(&img);
+    if removal.edge_threshold == EdgeThreshold::Auto {
+        debug!("Using edge threshold {}", edge_threshold);
+    }
     // Get the dimensions of the image.
     let (width, height) = img.dimensions();
     // Create a clone of the input image to store the output.
@@ -212,6 +2816,34 @@ fn remove_background(img: &DynamicImage, edge_threshold: u8) -> RgbaImage {
         queue.push_back((width - 1, y));
     }
 
+    // A guidance mask is stretched to the image, and its white pixels seed the fill as well.
+    let resized_mask;
+    let mask = match &removal.mask {
+        Some(mask) if mask.dimensions() != (width, height) => {
+            resized_mask = imageops::resize(mask, width, height, imageops::FilterType::Nearest);
+            Some(&resized_mask)
+        }
+        mask => mask.as_ref(),
+    };
+    if let Some(mask) = mask {
+        for (x, y, value) in mask.enumerate_pixels() {
+            if value[0] >= MASK_BACKGROUND {
+                queue.push_back((x, y));
+            }
+        }
+    }
+
+    // With --bg-color the fill keys on that color, with --auto-bg on the color estimated
+    // from the corners, and otherwise on white.
+    let key = removal.bg_color.or_else(|| {
+        removal
+            .corner_sample
+            .map(|sample| color::estimate_background(&img, sample))
+    });
+    if let Some(key) = key {
+        debug!("Detected background color {:?}", key);
+    }
+
     // Perform BFS to remove the background.
     while let Some((x, y)) = queue.pop_front() {
         // Skip pixels that are out of bounds or already visited.
@@ -221,37 +2853,73 @@ fn remove_background(img: &DynamicImage, edge_threshold: u8) -> RgbaImage {
         // Mark the current pixel as visited.
         visited[y as usize][x as usize] = true;
 
+        // Black mask pixels are hard foreground: the fill never enters them.
+        let mask_value = mask.map(|mask| mask.get_pixel(x, y)[0]);
+        if mask_value.is_some_and(|value| value <= MASK_FOREGROUND) {
+            continue;
+        }
+        // White mask pixels are background no matter their color or nearby edges.
+        let definite_background = mask_value.is_some_and(|value| value >= MASK_BACKGROUND);
+
         // Get the RGBA values of the current pixel.
         let pixel = img.get_pixel(x, y);
-        let [r, g, b, _] = pixel.0;
+        let [r, g, b, a] = pixel.0;
+        // The whiteness of a pixel is its darkest channel.
+        let whiteness = r.min(g).min(b);
+
+        // In soft mode the fill admits everything from the low end of the ramp,
+        // with a color metric everything close enough to the key color (white unless detected),
+        // otherwise only near-white pixels (R, G, B > 240), or with --bg-color or --auto-bg
+        // pixels whose channels are all within the tolerance of that color, are part of the background.
+        let is_background = match (removal.soft_threshold, removal.color_metric) {
+            (Some((low, _)), _) => whiteness >= low,
+            (None, Some(metric)) => {
+                metric.distance([r, g, b], key.unwrap_or([255, 255, 255]))
+                    <= removal.color_tolerance
+            }
+            (None, None) => match key {
+                Some(key) => [r, g, b].iter().zip(key).all(|(&channel, key)| {
+                    f64::from(channel.abs_diff(key)) <= removal.color_tolerance
+                }),
+                None => whiteness > 240,
+            },
+        };
 
-        // If the pixel is near-white (R, G, B > 240) and not an edge, continue flood-fill.
-        if r > 240 && g > 240 && b > 240 {
+        // If the pixel is background and not an edge, continue flood-fill.
+        if is_background || definite_background {
             // Flag to indicate if the pixel is surrounded by edges.
             let mut is_surrounded_by_edges = false;
 
             // Check neighboring pixels for strong edges.
             // If any neighboring pixel has a significant color difference (edge), set the flag.
-            if x > 0 && is_edge(*pixel, img.get_pixel(x - 1, y).clone(), edge_threshold) {
+            if x > 0 && is_edge(*pixel, *img.get_pixel(x - 1, y), edge_threshold) {
                 is_surrounded_by_edges = true;
             }
-            if x + 1 < width && is_edge(*pixel, img.get_pixel(x + 1, y).clone(), edge_threshold) {
+            if x + 1 < width && is_edge(*pixel, *img.get_pixel(x + 1, y), edge_threshold) {
                 is_surrounded_by_edges = true;
             }
-            if y > 0 && is_edge(*pixel, img.get_pixel(x, y - 1).clone(), edge_threshold) {
+            if y > 0 && is_edge(*pixel, *img.get_pixel(x, y - 1), edge_threshold) {
                 is_surrounded_by_edges = true;
             }
-            if y + 1 < height && is_edge(*pixel, img.get_pixel(x, y + 1).clone(), edge_threshold) {
+            if y + 1 < height && is_edge(*pixel, *img.get_pixel(x, y + 1), edge_threshold) {
                 is_surrounded_by_edges = true;
             }
 
             // If an edge is nearby, stop removing the background at this pixel.
-            if is_surrounded_by_edges {
+            if is_surrounded_by_edges && !definite_background {
                 continue;
             }
 
-            // Make the background pixel transparent.
-            output.put_pixel(x, y, Rgba([0, 0, 0, 0]));
+            match removal.soft_threshold.filter(|_| !definite_background) {
+                // Scale alpha linearly from opaque at `low` to transparent at `high`.
+                Some((low, high)) => {
+                    let opacity = soft_alpha(whiteness, low, high);
+                    let alpha = (a as u16 * opacity as u16 / 255) as u8;
+                    output.put_pixel(x, y, Rgba([r, g, b, alpha]));
+                }
+                // Make the background pixel transparent.
+                None => output.put_pixel(x, y, Rgba([0, 0, 0, 0])),
+            }
 
             // Add neighboring pixels to the queue for further processing.
             if x > 0 {
@@ -273,87 +2941,793 @@ fn remove_background(img: &DynamicImage, edge_threshold: u8) -> RgbaImage {
     output
 }
 
-/// Removes the background from images in the specified source directory and saves the results to the output directory.
-fn remove_bg_from_images(
+/// Computes the opacity (255 = opaque) of a background pixel for the soft threshold ramp.
+fn soft_alpha(whiteness: u8, low: u8, high: u8) -> u8 {
+    if whiteness >= high {
+        // At or beyond the high end the pixel is fully removed.
+        0
+    } else if whiteness <= low {
+        // At or below the low end the pixel stays fully opaque.
+        255
+    } else {
+        // In between, fade linearly with the pixel's whiteness.
+        let span = (high - low) as u32;
+        (255 * (high - whiteness) as u32 / span) as u8
+    }
+}
+
+/// Removes the background from a single image and saves the result as PNG,
+/// mirroring its path relative to the source directory under the output directory.
+fn remove_bg_from_image(
+    input_path: &Path,
+    source_dir: &Path,
+    output_dir: &Path,
+    removal: &RemovalOptions,
+    options: &Options,
+    stem: Option<&str>,
+) -> Result<(Outcome, Option<CropBox>), RicoError> {
+    // Leave non-files, settled files and files still being written alone.
+    if let Some(skipped) = skip_before_reading(input_path, options) {
+        return Ok((skipped, None));
+    }
+
+    // Skip images with (or without) an alpha channel when asked to.
+    if let Some(skipped) = skip_by_alpha(input_path, options) {
+        return Ok((skipped, None));
+    }
+
+    // Cutouts are always PNG, so they can keep their transparency.
+    let output_path =
+        mirrored_output_path(input_path, source_dir, output_dir, "png", options, stem)?;
+
+    // In a dry run, stop here and only report what would be written.
+    if options.dry_run {
+        info!("Would process: {:?} -> {:?}", input_path, output_path);
+        return Ok((Outcome::Planned(output_path), None));
+    }
+
+    // Attempt to read the image file, skipping it when its detected format is not wanted.
+    let bytes = read_input(input_path, options);
+    if let Ok(bytes) = &bytes {
+        if let Some(skipped) = skip_by_format(input_path, image::guess_format(bytes).ok(), options)
+        {
+            return Ok((skipped, None));
+        }
+    }
+
+    // Decode the image that was read.
+    let img_result = bytes
+        .and_then(|bytes| decode_input(input_path, &bytes, options).map_err(std::io::Error::other));
+
+    // Handle the result of image decoding.
+    let img = match img_result {
+        // If decoding was successful, use the decoded image.
+        Ok(img) => img,
+        // If decoding failed, log a message and skip the file.
+        Err(_) => {
+            info!("Skipping file (could not decode): {:?}", input_path);
+            quarantine_failed(input_path, source_dir, options);
+            return Ok((Outcome::Skipped("could not decode".into()), None));
+        }
+    };
+
+    if let Some(skipped) = skip_solid(input_path, &img, options) {
+        return Ok((skipped, None));
+    }
+
+    // Run the shared pre-processing steps, skipping images they reject.
+    let img = match prepare_image(img, options) {
+        Ok(img) => img,
+        Err(reason) => {
+            info!("Skipping {:?}: {}", input_path, reason);
+            return Ok((Outcome::Skipped(reason), None));
+        }
+    };
+
+    // Remove the background from the image using the provided removal settings.
+    let mut processed_img = remove_background(&img, removal);
+
+    // Stretch a washed-out alpha range (e.g. from --background-soft-threshold) to full contrast.
+    if removal.rescale_alpha {
+        transform::rescale_alpha(&mut processed_img);
+    }
+
+    // Crop to the kept pixels, remembering where the crop sat in the full cutout.
+    let mut crop = None;
+    if removal.autocrop {
+        if let Some((x, y, width, height)) = transform::opaque_bounds(&processed_img) {
+            crop = Some(CropBox {
+                x,
+                y,
+                orig_w: processed_img.width(),
+                orig_h: processed_img.height(),
+            });
+            processed_img = imageops::crop_imm(&processed_img, x, y, width, height).to_image();
+        }
+    }
+
+    // Keep the raw alpha mask of the cutout, before any compositing, if it is to be written too.
+    let mask = removal
+        .emit_mask
+        .then(|| transform::alpha_mask(&processed_img));
+
+    // Optionally place the cutout onto a copy of the shared background image.
+    if let Some(background) = &removal.compose_over {
+        let mut composed = background.clone();
+        let (x, y) = removal.compose_at;
+        imageops::overlay(&mut composed, &processed_img, x, y);
+        processed_img = composed;
+    }
+
+    // Create parent directories for the output file if they don't exist.
+    ensure_within_output_root(&output_path, options)?;
+    if let Some(parent) = output_path.parent() {
+        if !parent.exists() {
+            // If parent directory does not exist, create it and all necessary parent directories.
+            fs::create_dir_all(parent).unwrap_or_else(|e| {
+                error!("Failed to create output subdirectory: {}", e);
+            });
+        }
+    }
+
+    // Save the processed image to the output path.
+    let processed_img = DynamicImage::ImageRgba8(processed_img);
+    let output_path = save_image(&processed_img, &output_path, ImageFormat::Png, options)?;
+    // If saving is successful, log a message indicating the input and output paths.
+    info!("Processed: {:?} -> {:?}", input_path, output_path);
+    write_comparison(&img, &processed_img, &output_path, options)?;
+
+    // Write the mask next to the cutout as `{stem}_mask.png`.
+    if let Some(mask) = mask {
+        let mask_path = suffixed_output_path(&output_path, "_mask");
+        save_auxiliary_png(&DynamicImage::ImageLuma8(mask), &mask_path, options)?;
+        info!("Wrote mask: {:?}", mask_path);
+    }
+    Ok((Outcome::Written(output_path), crop))
+}
+
+/// Builds the output path of an input at the same relative path under the output directory,
+/// with the given extension and, when slugging, the slugged stem.
+fn mirrored_output_path(
+    input_path: &Path,
+    source_dir: &Path,
+    output_dir: &Path,
+    extension: &str,
+    options: &Options,
+    stem: Option<&str>,
+) -> std::io::Result<PathBuf> {
+    // Get the relative path of the input file from the source directory.
+    let relative_path = input_path.strip_prefix(source_dir).unwrap();
+
+    // When organizing by date, outputs go under YYYY/MM/ (or unknown/) subfolders.
+    let output_dir = if options.organize_by_date {
+        output_dir.join(metadata::date_folder(input_path))
+    } else {
+        output_dir.to_path_buf()
+    };
+
+    // Construct the full output path by joining the output directory and the relative path.
+    let mut output_path = output_dir.join(relative_path);
+    let extension = output_extension(input_path, extension, options);
+    match stem {
+        // Slugged outputs keep their directory but take the slugged stem.
+        Some(stem) => output_path.set_file_name(format!("{}.{}", stem, extension)),
+        None => {
+            output_path.set_extension(extension);
+        }
+    }
+    Ok(output_path)
+}
+
+/// Removes the background from images in the specified source directory and saves the results to the output directory.
+/// Returns the report of the batch, which decides the `--summary-exit-codes` status.
+fn remove_bg_from_images(
+    source_dir: &Path,
+    output_dir: &Path,
+    removal: &RemovalOptions,
+    options: &Options,
+    progress: Progress,
+) -> Result<Report, RicoError> {
+    // Time the whole run, traversal included, for the summary.
+    let started = Instant::now();
+
+    // Check if the source directory exists and is a directory.
+    if !source_dir.exists() || !source_dir.is_dir() {
+        // If not, return an error.
+        return Err(RicoError::InvalidDirectory(
+            "Source directory does not exist or is not a directory".into(),
+        ));
+    }
+
+    // Lay out the full directory tree first, so it exists even where nothing gets written.
+    if options.mirror_dirs {
+        mirror_directories(source_dir, output_dir, options)?;
+    }
+
+    // Collect all image files from the source directory.
+    let files = collect_image_files(source_dir, options)?;
+    // Check if any files were found.
+    if files.is_empty() {
+        if options.require_matches {
+            return Err(no_matches_in(source_dir));
+        }
+        // If no images were found, log a message and return Ok.
+        info!("No images found in the source directory.");
+        return Ok(Report::default());
+    }
+
+    // Slugged stems are assigned up front so colliding names can be numbered deterministically.
+    let slug_stems = if options.slug {
+        slug::stems(&files)
+    } else {
+        HashMap::new()
+    };
+
+    // Background removal comes after the shared pre-processing steps.
+    let mut transforms = options.transforms();
+    transforms.push("remove-background".to_string());
+    if removal.mask.is_some() {
+        transforms.push("mask".to_string());
+    }
+    if removal.rescale_alpha {
+        transforms.push("rescale-alpha".to_string());
+    }
+    if removal.autocrop {
+        transforms.push("autocrop".to_string());
+    }
+    if removal.compose_over.is_some() {
+        transforms.push("compose-over".to_string());
+    }
+
+    // Process each image file in parallel using the configured strategy.
+    let report = Report::default();
+    let processed = for_each_file(&files, options, |input_path| {
+        notify(
+            progress,
+            ProgressEvent::Started {
+                input: input_path.clone(),
+            },
+        );
+        let result = remove_bg_from_image(
+            input_path,
+            source_dir,
+            output_dir,
+            removal,
+            options,
+            slug_stems.get(input_path).map(String::as_str),
+        );
+        // The --autocrop box travels beside the outcome into the report.
+        let (result, crop) = match result {
+            Ok((outcome, crop)) => (Ok(outcome), crop),
+            Err(e) => (Err(e), None),
+        };
+        if result.is_err() {
+            quarantine_failed(input_path, source_dir, options);
+        }
+        notify(progress, outcome_event(input_path, &result));
+        let mut record = outcome_record(input_path, "png", &transforms, &result, options);
+        record.crop = crop;
+        report.push(record);
+        FileStatus::of(&result)
+    });
+
+    log_summary(&report, started, options);
+
+    // Write the machine-readable report if one was requested, even for an aborted batch.
+    if let Some(report_path) = &options.report {
+        report.write(report_path, options.report_format)?;
+    }
+    processed?;
+
+    // Return the report to indicate successful completion.
+    Ok(report)
+}
+
+/// Returns the extension and format a trimmed image is written with: those of the input,
+/// or PNG when its format cannot be written.
+fn trimmed_format(input_path: &Path) -> (String, ImageFormat) {
+    let extension = input_path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    match output_format_for(&extension) {
+        Some(format) => (extension, format),
+        None => ("png".to_string(), ImageFormat::Png),
+    }
+}
+
+/// Crops an image to its content by trimming the uniform margins around it, keeping the
+/// image's own format where it can be written (PNG otherwise).
+fn trim_image(
+    input_path: &Path,
+    source_dir: &Path,
+    output_dir: &Path,
+    tolerance: u8,
+    options: &Options,
+    stem: Option<&str>,
+) -> Result<Outcome, RicoError> {
+    // Leave non-files, settled files and files still being written alone.
+    if let Some(skipped) = skip_before_reading(input_path, options) {
+        return Ok(skipped);
+    }
+
+    // Skip images with (or without) an alpha channel when asked to.
+    if let Some(skipped) = skip_by_alpha(input_path, options) {
+        return Ok(skipped);
+    }
+
+    let (extension, format) = trimmed_format(input_path);
+    let output_path = mirrored_output_path(
+        input_path, source_dir, output_dir, &extension, options, stem,
+    )?;
+
+    // In a dry run, stop here and only report what would be written.
+    if options.dry_run {
+        info!("Would process: {:?} -> {:?}", input_path, output_path);
+        return Ok(Outcome::Planned(output_path));
+    }
+
+    // Read the image, skipping it when its detected format is not wanted or it cannot be decoded.
+    let bytes = read_input(input_path, options)?;
+    if let Some(skipped) = skip_by_format(input_path, image::guess_format(&bytes).ok(), options) {
+        return Ok(skipped);
+    }
+    let img = match decode_input(input_path, &bytes, options) {
+        Ok(img) => img,
+        Err(_) => {
+            info!("Skipping file (could not decode): {:?}", input_path);
+            quarantine_failed(input_path, source_dir, options);
+            return Ok(Outcome::Skipped("could not decode".into()));
+        }
+    };
+
+    if let Some(skipped) = skip_solid(input_path, &img, options) {
+        return Ok(skipped);
+    }
+
+    let original = options.compare_output.then(|| img.clone());
+
+    // Run the shared pre-processing steps, then crop to the content.
+    let trimmed = prepare_image(img, options).and_then(|img| {
+        transform::trim_to_content(&img, tolerance)
+            .ok_or_else(|| "image has no content besides its border color".to_string())
+    });
+    let img = match trimmed {
+        Ok(img) => img,
+        Err(reason) => {
+            info!("Skipping {:?}: {}", input_path, reason);
+            return Ok(Outcome::Skipped(reason));
+        }
+    };
+
+    let output_path = save_image(&img, &output_path, format, options)?;
+    info!(
+        "Trimmed to {}x{}: {:?} -> {:?}",
+        img.width(),
+        img.height(),
+        input_path,
+        output_path
+    );
+    if let Some(original) = &original {
+        write_comparison(original, &img, &output_path, options)?;
+    }
+    Ok(Outcome::Written(output_path))
+}
+
+/// Trims the images in the source directory to their content and saves them to the output directory.
+/// Returns the report of the batch, which decides the `--summary-exit-codes` status.
+fn trim_images(
+    source_dir: &Path,
+    output_dir: &Path,
+    tolerance: u8,
+    options: &Options,
+    progress: Progress,
+) -> Result<Report, RicoError> {
+    // Time the whole run, traversal included, for the summary.
+    let started = Instant::now();
+
+    // Lay out the full directory tree first, so it exists even where nothing gets written.
+    if options.mirror_dirs {
+        mirror_directories(source_dir, output_dir, options)?;
+    }
+
+    let files = collect_image_files(source_dir, options)?;
+    if files.is_empty() {
+        if options.require_matches {
+            return Err(no_matches_in(source_dir));
+        }
+        info!("No images found in the source directory.");
+        return Ok(Report::default());
+    }
+
+    // Slugged stems are assigned up front so colliding names can be numbered deterministically.
+    let slug_stems = if options.slug {
+        slug::stems(&files)
+    } else {
+        HashMap::new()
+    };
+
+    // Trimming to the content comes after the shared pre-processing steps.
+    let mut transforms = options.transforms();
+    transforms.push("trim-to-content".to_string());
+
+    let report = Report::default();
+    let processed = for_each_file(&files, options, |input_path| {
+        notify(
+            progress,
+            ProgressEvent::Started {
+                input: input_path.clone(),
+            },
+        );
+        let result = trim_image(
+            input_path,
+            source_dir,
+            output_dir,
+            tolerance,
+            options,
+            slug_stems.get(input_path).map(String::as_str),
+        );
+        if result.is_err() {
+            quarantine_failed(input_path, source_dir, options);
+        }
+        notify(progress, outcome_event(input_path, &result));
+        report.push(outcome_record(
+            input_path,
+            &trimmed_format(input_path).0,
+            &transforms,
+            &result,
+            options,
+        ));
+        FileStatus::of(&result)
+    });
+
+    log_summary(&report, started, options);
+
+    // Write the machine-readable report if one was requested, even for an aborted batch.
+    if let Some(report_path) = &options.report {
+        report.write(report_path, options.report_format)?;
+    }
+    processed?;
+    Ok(report)
+}
+
+/// Log target of the end-of-run summary, which stays visible with `--summary-only`.
+const SUMMARY_TARGET: &str = "summary";
+
+/// Logs the end-of-run summary of a batch.
+fn log_summary(report: &Report, started: Instant, options: &Options) {
+    for line in report.summary(started.elapsed()) {
+        info!(target: SUMMARY_TARGET, "{}", line);
+    }
+
+    // Flag outputs claimed by several inputs, which would silently overwrite each other.
+    for (output, inputs) in report.collisions() {
+        let names: Vec<String> = inputs
+            .iter()
+            .map(|input| input.display().to_string())
+            .collect();
+        warn!(
+            target: SUMMARY_TARGET,
+            "Output collision: {} inputs map to {}: {}",
+            inputs.len(),
+            output.display(),
+            names.join(", ")
+        );
+    }
+
+    // With --group-similar, list the clusters of near-duplicate outputs after the counts.
+    if let Some(max_distance) = options.group_similar {
+        let groups = report.similar_groups(max_distance);
+        info!(
+            target: SUMMARY_TARGET,
+            "Similar outputs (pHash distance <= {}): {} groups",
+            max_distance,
+            groups.len()
+        );
+        for group in groups {
+            let names: Vec<String> = group
+                .iter()
+                .map(|output| output.display().to_string())
+                .collect();
+            info!(target: SUMMARY_TARGET, "  {}", names.join(", "));
+        }
+    }
+}
+
+/// Turns the outcome of processing one file into a report record.
+fn outcome_record(
+    input_path: &Path,
+    format: &str,
+    transforms: &[String],
+    result: &Result<Outcome, RicoError>,
+    options: &Options,
+) -> Record {
+    // Split the outcome into its status, output path and reason.
+    let (status, output, reason) = match result {
+        Ok(Outcome::Written(path)) => (Status::Ok, Some(path.clone()), None),
+        Ok(Outcome::Planned(path)) => (Status::Planned, Some(path.clone()), None),
+        Ok(Outcome::Skipped(reason)) => (Status::Skipped, None, Some(reason.clone())),
+        Err(e) => (Status::Failed, None, Some(e.to_string())),
+    };
+    // Keep the --ledger up to date as files finish, so an interrupted run can resume.
+    // Files still being written are not settled, so the next run looks at them again.
+    let deferred = matches!(result, Ok(Outcome::Skipped(reason)) if reason == STILL_BEING_WRITTEN);
+    if let Some(ledger) = options.ledger.as_ref().filter(|_| !deferred) {
+        if let Err(e) = ledger.record(input_path, status) {
+            warn!("Could not update the ledger for {:?}: {}", input_path, e);
+        }
+    }
+    // Compute the placeholder and perceptual hashes of written outputs, if requested.
+    let (blurhash, phash) = match (&output, status) {
+        (Some(output), Status::Ok) if options.blurhash || options.phash => {
+            output_hashes(output, options)
+        }
+        _ => (None, None),
+    };
+    Record {
+        input: input_path.to_path_buf(),
+        output,
+        format: format.to_string(),
+        transforms: transforms.to_vec(),
+        status,
+        reason,
+        blurhash,
+        phash,
+        crop: None,
+    }
+}
+
+/// Decodes a written output and computes the requested BlurHash and perceptual hash
+/// (as 16 hex digits), logging any failure.
+fn output_hashes(output_path: &Path, options: &Options) -> (Option<String>, Option<String>) {
+    let img = match image::open(output_path) {
+        Ok(img) => img,
+        Err(e) => {
+            warn!("Could not hash {:?}: {}", output_path, e);
+            return (None, None);
+        }
+    };
+    let blurhash = options
+        .blurhash
+        .then(|| codec::blurhash(&img))
+        .and_then(|hash| match hash {
+            Ok(hash) => Some(hash),
+            Err(e) => {
+                warn!("Could not compute a BlurHash for {:?}: {}", output_path, e);
+                None
+            }
+        });
+    let phash = options
+        .phash
+        .then(|| format!("{:016x}", quality::phash(&img)));
+    (blurhash, phash)
+}
+
+/// Turns the outcome of processing one file into its final progress event.
+fn outcome_event(input_path: &Path, result: &Result<Outcome, RicoError>) -> ProgressEvent {
+    let input = input_path.to_path_buf();
+    match result {
+        Ok(Outcome::Written(output)) => ProgressEvent::Finished {
+            input,
+            output: output.clone(),
+        },
+        Ok(Outcome::Planned(output)) => ProgressEvent::Planned {
+            input,
+            output: output.clone(),
+        },
+        Ok(Outcome::Skipped(reason)) => ProgressEvent::Skipped {
+            input,
+            reason: reason.clone(),
+        },
+        Err(e) => ProgressEvent::Failed {
+            input,
+            error: e.to_string(),
+        },
+    }
+}
+
+/// Passes the event to the progress callback, if one was given.
+fn notify(progress: Progress, event: ProgressEvent) {
+    if let Some(callback) = progress {
+        callback(event);
+    }
+}
+
+/// Picks the CLI's progress observer: NDJSON events with `--progress-json`, otherwise
+/// [`print_progress`].
+fn progress_observer<'a>(
+    options: &Options,
+    json: &'a JsonProgress,
+) -> Box<dyn Fn(ProgressEvent) + Sync + 'a> {
+    if options.progress_json {
+        Box::new(|event| json.observe(event))
+    } else {
+        Box::new(print_progress)
+    }
+}
+
+/// Logs per-file failures as errors; the CLI's progress observer.
+fn print_progress(event: ProgressEvent) {
+    if let ProgressEvent::Failed { input, error } = event {
+        error!("Failed to process {:?}: {}", input, error);
+    }
+}
+
+/// Packs every image in the source directory into one sprite sheet PNG plus a JSON atlas.
+fn pack_images(
     source_dir: &Path,
     output_dir: &Path,
-    edge_threshold: u8,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // Check if the source directory exists and is a directory.
-    if !source_dir.exists() || !source_dir.is_dir() {
-        // If not, return an error.
-        return Err("Source directory does not exist or is not a directory".into());
-    }
-
+    name: &str,
+    max_width: u32,
+    padding: u32,
+) -> Result<(), RicoError> {
     // Collect all image files from the source directory.
-    let files = collect_image_files(source_dir);
-    // Check if any files were found.
+    let files = collect_image_files(source_dir, &Options::default())?;
     if files.is_empty() {
-        // If no images were found, print a message and return Ok.
-        println!("No images found in the source directory.");
+        info!("No images found in the source directory.");
         return Ok(());
     }
 
-    // Process each image file in parallel.
-    files.par_iter().for_each(|input_path| {
-        // Attempt to open and decode the image file.
-        let img_result = ImageReader::open(input_path)
-            .and_then(|reader| {
-                reader
-                    .decode()
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
-            })
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e));
-
-        // Handle the result of image decoding.
-        let img = match img_result {
-            // If decoding was successful, use the decoded image.
-            Ok(img) => img,
-            // If decoding failed, print a message and skip the file.
-            Err(_) => {
-                println!("Skipping file (could not decode): {:?}", input_path);
-                return;
+    // Decode the sprites in parallel, keyed by their path relative to the source directory.
+    let sprites: Vec<(String, DynamicImage)> = files
+        .par_iter()
+        .filter_map(|input_path| {
+            // Never try to open directories or other non-files as images.
+            if skip_if_not_file(input_path).is_some() {
+                return None;
             }
-        };
+            let img = ImageReader::open(input_path)
+                .map_err(image::ImageError::IoError)
+                .and_then(|reader| reader.decode());
+            match img {
+                Ok(img) => {
+                    let relative_path = input_path.strip_prefix(source_dir).unwrap_or(input_path);
+                    Some((relative_path.to_string_lossy().replace('\\', "/"), img))
+                }
+                Err(_) => {
+                    info!("Skipping file (could not decode): {:?}", input_path);
+                    None
+                }
+            }
+        })
+        .collect();
 
-        // Remove the background from the image using the provided edge threshold.
-        let processed_img = remove_background(&img, edge_threshold);
+    // Lay the sprites out on the sheet.
+    let sheet = pack::pack(sprites, max_width, padding)?;
 
-        // Get the relative path of the input file from the source directory.
-        let relative_path = input_path.strip_prefix(source_dir).unwrap();
+    // Save the sheet image next to its atlas.
+    let image_path = output_dir.join(format!("{}.png", name));
+    let atlas_path = output_dir.join(format!("{}.json", name));
+    let sheet_image = DynamicImage::ImageRgba8(sheet.image);
+    save_image(
+        &sheet_image,
+        &image_path,
+        ImageFormat::Png,
+        &Options::default(),
+    )?;
+    fs::write(&atlas_path, serde_json::to_string_pretty(&sheet.atlas)?)?;
 
-        // Construct the full output path by joining the output directory and the relative path.
-        let mut output_path = output_dir.join(relative_path);
-        // Ensure the output format is PNG by setting the file extension.
-        output_path.set_extension("png");
+    info!(
+        "Packed {} images into {:?} ({}x{}) with atlas {:?}",
+        sheet.atlas.len(),
+        image_path,
+        sheet_image.width(),
+        sheet_image.height(),
+        atlas_path
+    );
+    Ok(())
+}
 
-        // Create parent directories for the output file if they don't exist.
-        if let Some(parent) = output_path.parent() {
-            if !parent.exists() {
-                // If parent directory does not exist, create it and all necessary parent directories.
-                fs::create_dir_all(parent).unwrap_or_else(|e| {
-                    eprintln!("Failed to create output subdirectory: {}", e);
-                });
-            }
-        }
+/// Encodes the images in the source directory, in path order, as the frames of one animated
+/// GIF or WebP (chosen by the output extension), each shown for `delay_ms`. Frames must all
+/// have the size of the first one, unless `resize_frames` stretches them to it.
+fn animate_frames(
+    source_dir: &Path,
+    output_path: &Path,
+    delay_ms: u32,
+    loop_count: u16,
+    resize_frames: bool,
+) -> Result<(), RicoError> {
+    let format = output_path
+        .extension()
+        .and_then(|ext| output_format_for(&ext.to_string_lossy().to_lowercase()))
+        .filter(|format| matches!(format, ImageFormat::Gif | ImageFormat::WebP))
+        .ok_or_else(|| {
+            RicoError::UnsupportedFormat(format!(
+                "{:?} (the output must be a .gif or .webp file)",
+                output_path
+            ))
+        })?;
+
+    // Frames play in the order of their names, so number them with leading zeros.
+    let mut files = collect_image_files(source_dir, &Options::default())?;
+    files.sort();
+    if files.is_empty() {
+        return Err(RicoError::NoFrames(source_dir.to_path_buf()));
+    }
+
+    // Decode the frames in parallel, keeping their order.
+    let images = files
+        .par_iter()
+        .map(|input_path| {
+            ImageReader::open(input_path)
+                .map_err(image::ImageError::IoError)
+                .and_then(|reader| reader.with_guessed_format()?.decode())
+                .map_err(|error| RicoError::FrameDecode {
+                    path: input_path.clone(),
+                    error,
+                })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
 
-        // Save the processed image to the output path.
-        if let Err(e) = processed_img.save(&output_path) {
-            // If saving fails, print an error message to stderr.
-            eprintln!("Failed to save {:?}: {}", output_path, e);
+    // Every frame must fit the canvas of the first.
+    let (width, height) = (images[0].width(), images[0].height());
+    let delay = Delay::from_numer_denom_ms(delay_ms, 1);
+    let mut frames = Vec::with_capacity(images.len());
+    for (input_path, img) in files.iter().zip(images) {
+        let img = if (img.width(), img.height()) == (width, height) {
+            img
+        } else if resize_frames {
+            resize::resize(&img, width, height, Filter::default())
         } else {
-            // If saving is successful, print a message indicating the input and output paths.
-            println!("Processed: {:?} -> {:?}", input_path, output_path);
-        }
-    });
+            return Err(RicoError::FrameSize {
+                path: input_path.clone(),
+                size: (img.width(), img.height()),
+                expected: (width, height),
+            });
+        };
+        frames.push(Frame::from_parts(img.to_rgba8(), 0, 0, delay));
+    }
+
+    let frame_count = frames.len();
+    let bytes = match format {
+        ImageFormat::Gif => animation::encode_gif(frames, loop_count)?,
+        _ => animation::encode_webp(&frames, loop_count)?,
+    };
+    if let Some(parent) = output_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(output_path, bytes)?;
 
-    // Return Ok to indicate successful completion.
+    info!(
+        "Animated {} frames ({}x{}, {} ms each) into {:?}",
+        frame_count, width, height, delay_ms, output_path
+    );
     Ok(())
 }
 
 fn main() {
     let matches = parse_args();
 
+    // Send log messages to stderr at info level, unless RUST_LOG asks for something else.
+    // Stdout is left for data such as the output of the pixel command.
+    // With --summary-only, per-file messages are hidden and only the end-of-run summary remains.
+    let summary_only = matches
+        .subcommand()
+        .and_then(|(_, sub_matches)| optional_arg::<bool>(sub_matches, "summary-only"))
+        .unwrap_or(false);
+    // With --progress-json, stderr carries the JSON events, so only errors are logged beside them.
+    let progress_json = matches
+        .subcommand()
+        .and_then(|(_, sub_matches)| optional_arg::<bool>(sub_matches, "progress-json"))
+        .unwrap_or(false);
+    // With --summary-exit-codes, the exit status tells scripts how the run went.
+    let summary_exit_codes = matches
+        .subcommand()
+        .and_then(|(_, sub_matches)| optional_arg::<bool>(sub_matches, "summary-exit-codes"))
+        .unwrap_or(false);
+    let default_filter = if progress_json {
+        "error".to_string()
+    } else if summary_only {
+        format!("warn,{}=info", SUMMARY_TARGET)
+    } else {
+        "info".to_string()
+    };
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default_filter))
+        .format_timestamp(None)
+        .format_target(false)
+        .init();
+
     // Handle "remove" command
     if let Some(remove_matches) = matches.subcommand_matches("remove") {
         // Check if the "background" flag was provided in the "remove" subcommand.
@@ -370,25 +3744,92 @@ fn main() {
 
         // Get the edge threshold value from the "edge-threshold" argument.
         // If "edge-threshold" is not provided, default to 30.
-        let edge_threshold: u8 = *remove_matches
-            .get_one::<u8>("edge-threshold")
-            .unwrap_or(&30);
+        let edge_threshold = *remove_matches
+            .get_one::<EdgeThreshold>("edge-threshold")
+            .unwrap_or(&EdgeThreshold::Fixed(30));
+
+        // Bundle the flood-fill settings, including the optional soft threshold ramp.
+        let mut removal = RemovalOptions {
+            edge_threshold,
+            soft_threshold: remove_matches
+                .get_one::<(u8, u8)>("background-soft-threshold")
+                .copied(),
+            color_metric: remove_matches
+                .get_one::<ColorMetric>("color-metric")
+                .copied(),
+            color_tolerance: *remove_matches.get_one::<f64>("color-tolerance").unwrap(),
+            bg_color: remove_matches
+                .get_one::<Rgba<u8>>("bg-color")
+                .map(|color| [color[0], color[1], color[2]]),
+            corner_sample: remove_matches
+                .get_flag("auto-bg")
+                .then(|| *remove_matches.get_one::<u32>("corner-sample").unwrap()),
+            compose_over: None,
+            compose_at: *remove_matches.get_one::<(i64, i64)>("at").unwrap(),
+            mask: None,
+            emit_mask: remove_matches.get_flag("emit-mask"),
+            rescale_alpha: remove_matches.get_flag("rescale-alpha"),
+            autocrop: remove_matches.get_flag("autocrop"),
+        };
+
+        // Decode the --compose-over background once, up front.
+        if let Some(background) = remove_matches.get_one::<String>("compose-over") {
+            match image::open(background) {
+                Ok(background) => removal.compose_over = Some(background.to_rgba8()),
+                Err(e) => {
+                    error!("Error reading background {:?}: {}", background, e);
+                    exit_fatal(summary_exit_codes);
+                }
+            }
+        }
+
+        // Decode the --mask guidance image once, up front.
+        if let Some(mask) = remove_matches.get_one::<String>("mask") {
+            match image::open(mask) {
+                Ok(mask) => removal.mask = Some(mask.to_luma8()),
+                Err(e) => {
+                    error!("Error reading mask {:?}: {}", mask, e);
+                    exit_fatal(summary_exit_codes);
+                }
+            }
+        }
+
+        // Collect the settings shared with the convert subcommand.
+        let mut options = Options::from_matches(remove_matches);
+        if let Err(e) = options
+            .load_palette(remove_matches)
+            .and_then(|()| options.load_ledger(remove_matches))
+        {
+            error!("{}", e);
+            exit_fatal(summary_exit_codes);
+        }
 
         // Validate that the source directory exists and the output directory can be created.
         // This ensures that the program can proceed with the file operations.
-        validate_directories(source_dir, output_dir);
+        validate_directories(source_dir, output_dir, options.dry_run, summary_exit_codes);
+        options.confine_to(output_dir, remove_matches);
+
+        // Report progress as log messages, or as JSON events for a wrapping process.
+        let json_progress = JsonProgress::default();
+        let observer = progress_observer(&options, &json_progress);
 
         // If the "background" flag is set, proceed with background removal.
         if remove_bg {
             // Attempt to remove the background from images in the source directory and save them to the output directory.
             // The edge threshold is used to determine the sensitivity of the background removal algorithm.
-            if let Err(e) = remove_bg_from_images(source_dir, output_dir, edge_threshold) {
-                // If an error occurs during background removal, print the error message to stderr.
-                eprintln!("Error removing background: {}", e);
+            let result =
+                remove_bg_from_images(source_dir, output_dir, &removal, &options, Some(&*observer));
+            if let Err(e) = &result {
+                // If an error occurs during background removal, log the error message.
+                error!("Error removing background: {}", e);
             } else {
-                // If background removal is successful, print a success message to stdout.
-                println!("Background removal completed.");
+                // If background removal is successful, log a success message.
+                info!("Background removal completed.");
             }
+            if options.progress_json {
+                json_progress.finish();
+            }
+            exit_with_outcome(&result, summary_exit_codes);
         }
         // Return from the function after handling the "remove" subcommand.
         // This ensures that no further subcommands are processed.
@@ -397,34 +3838,367 @@ fn main() {
 
     // Handle "convert" command
     if let Some(convert_matches) = matches.subcommand_matches("convert") {
+        // Get the target image format from the "format" argument.
+        // Unwrap is used because "format" is a required argument.
+        // The format is matched case-insensitively, so `-f JPG` behaves like `-f jpg`.
+        let target_format = convert_matches
+            .get_one::<String>("format")
+            .unwrap()
+            .to_lowercase();
+
+        // Collect the settings shared with the remove subcommand.
+        let mut options = Options::from_matches(convert_matches);
+        if let Err(e) = options
+            .load_palette(convert_matches)
+            .and_then(|()| options.load_ledger(convert_matches))
+        {
+            error!("{}", e);
+            exit_fatal(summary_exit_codes);
+        }
+
+        // With --map or --jobs-file, exactly the listed work items are converted.
+        let work_list = match (
+            convert_matches.get_one::<String>("map"),
+            convert_matches.get_one::<String>("jobs-file"),
+        ) {
+            (Some(map_path), _) => Some((map_path, mapping::load(Path::new(map_path)))),
+            (_, Some(jobs_path)) => Some((jobs_path, mapping::load_jobs(Path::new(jobs_path)))),
+            (None, None) => None,
+        };
+        if let Some((list_path, entries)) = work_list {
+            let entries = match entries {
+                Ok(entries) => entries,
+                Err(e) => {
+                    error!("Error reading {:?}: {}", list_path, e);
+                    exit_fatal(summary_exit_codes);
+                }
+            };
+            let json_progress = JsonProgress::default();
+            let observer = progress_observer(&options, &json_progress);
+            let result = process_map(&entries, &target_format, &options, Some(&*observer));
+            if let Err(e) = &result {
+                error!("Error processing images: {}", e);
+            } else {
+                info!("Image processing completed.");
+            }
+            if options.progress_json {
+                json_progress.finish();
+            }
+            exit_with_outcome(&result, summary_exit_codes);
+            return;
+        }
+
         // Get the source directory path from the "source" argument.
-        // Unwrap is used because "source" is a required argument.
+        // Unwrap is used because "source" is required unless --map or --jobs-file is given.
         let source_dir = Path::new(convert_matches.get_one::<String>("source").unwrap());
 
         // Determine the output directory path.
         // The output directory can be specified via an argument, or it defaults to a related directory.
         let output_dir = get_output_dir(convert_matches, source_dir);
 
-        // Get the target image format from the "format" argument.
-        // Unwrap is used because "format" is a required argument.
-        let target_format = convert_matches.get_one::<String>("format").unwrap();
+        // `--output -` streams a tar archive to stdout, written by its own thread.
+        let archiving = output_dir == Path::new(STDOUT_OUTPUT);
+        if archiving != convert_matches.contains_id("archive") {
+            error!("--output - and --archive tar must be used together");
+            exit_fatal(summary_exit_codes);
+        }
+        let archive_writer = if archiving && !options.dry_run {
+            let (sink, writer) = archive::stream_tar(std::io::stdout());
+            options.archive = Some(sink);
+            Some(writer)
+        } else {
+            None
+        };
 
         // Validate that the source directory exists and the output directory can be created.
         // This function ensures that the program can proceed with the file operations.
-        validate_directories(source_dir, output_dir);
+        validate_directories(
+            source_dir,
+            output_dir,
+            options.dry_run || archiving,
+            summary_exit_codes,
+        );
+        options.confine_to(output_dir, convert_matches);
+
+        // Report progress as log messages, or as JSON events for a wrapping process.
+        let json_progress = JsonProgress::default();
+        let observer = progress_observer(&options, &json_progress);
 
         // Attempt to process images in the source directory by converting them to the target format and saving them to the output directory.
-        if let Err(e) = process_images(source_dir, output_dir, target_format) {
-            // If an error occurs during image processing, print the error message to stderr.
-            eprintln!("Error processing images: {}", e);
+        let result = process_images(
+            source_dir,
+            output_dir,
+            &target_format,
+            &options,
+            Some(&*observer),
+        );
+        if let Err(e) = &result {
+            // If an error occurs during image processing, log the error message.
+            error!("Error processing images: {}", e);
         } else {
-            // If image processing is successful, print a success message to stdout.
-            println!("Image processing completed.");
+            // If image processing is successful, log a success message.
+            info!("Image processing completed.");
+        }
+        if options.progress_json {
+            json_progress.finish();
+        }
+
+        // Close the archive and wait for its last entries to be written.
+        if let Some(writer) = archive_writer {
+            drop(options);
+            match writer.join() {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    error!("Error writing archive: {}", e);
+                    exit_fatal(summary_exit_codes);
+                }
+                Err(_) => {
+                    error!("Error writing archive: the writer thread panicked");
+                    exit_fatal(summary_exit_codes);
+                }
+            }
         }
+        exit_with_outcome(&result, summary_exit_codes);
         // Return from the function after handling the "convert" subcommand.
         // This ensures that no further subcommands are processed.
         return;
     }
+
+    // Handle "trim" command
+    if let Some(trim_matches) = matches.subcommand_matches("trim") {
+        let source_dir = Path::new(trim_matches.get_one::<String>("source").unwrap());
+        let output_dir = get_output_dir(trim_matches, source_dir);
+        let tolerance = *trim_matches.get_one::<u8>("tolerance").unwrap();
+
+        // Collect the settings shared with the convert and remove subcommands.
+        let mut options = Options::from_matches(trim_matches);
+        if let Err(e) = options
+            .load_palette(trim_matches)
+            .and_then(|()| options.load_ledger(trim_matches))
+        {
+            error!("{}", e);
+            exit_fatal(summary_exit_codes);
+        }
+
+        // Validate that the source directory exists and the output directory can be created.
+        validate_directories(source_dir, output_dir, options.dry_run, summary_exit_codes);
+        options.confine_to(output_dir, trim_matches);
+
+        // Report progress as log messages, or as JSON events for a wrapping process.
+        let json_progress = JsonProgress::default();
+        let observer = progress_observer(&options, &json_progress);
+
+        let result = trim_images(
+            source_dir,
+            output_dir,
+            tolerance,
+            &options,
+            Some(&*observer),
+        );
+        if let Err(e) = &result {
+            error!("Error trimming images: {}", e);
+        } else {
+            info!("Trimming completed.");
+        }
+        if options.progress_json {
+            json_progress.finish();
+        }
+        exit_with_outcome(&result, summary_exit_codes);
+        return;
+    }
+
+    // Handle "pixel" command
+    if let Some(pixel_matches) = matches.subcommand_matches("pixel") {
+        // Read the file and the coordinate to inspect.
+        let file = Path::new(pixel_matches.get_one::<String>("file").unwrap());
+        let x = *pixel_matches.get_one::<u32>("x").unwrap();
+        let y = *pixel_matches.get_one::<u32>("y").unwrap();
+        let neighborhood = pixel_matches.get_flag("neighborhood");
+
+        // Print the pixel, or log the failure and exit non-zero.
+        if let Err(e) = print_pixel(file, x, y, neighborhood) {
+            error!("Error reading pixel: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Handle "diff" command
+    if let Some(diff_matches) = matches.subcommand_matches("diff") {
+        // Read the two images and the alpha tolerance.
+        let reference = Path::new(diff_matches.get_one::<String>("reference").unwrap());
+        let candidate = Path::new(diff_matches.get_one::<String>("candidate").unwrap());
+        let alpha_threshold = *diff_matches.get_one::<u8>("alpha-threshold").unwrap();
+
+        // Print the comparison, or log the failure and exit non-zero.
+        if let Err(e) = print_diff(reference, candidate, alpha_threshold) {
+            error!("Error comparing images: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Handle "animate" command
+    if let Some(animate_matches) = matches.subcommand_matches("animate") {
+        let source_dir = Path::new(animate_matches.get_one::<String>("source").unwrap());
+        let output_path = Path::new(animate_matches.get_one::<String>("output").unwrap());
+
+        // --fps is turned into the delay of each frame.
+        let delay_ms = match animate_matches.get_one::<f64>("fps") {
+            Some(fps) => (1000.0 / fps).round() as u32,
+            None => *animate_matches.get_one::<u32>("delay").unwrap(),
+        };
+        let loop_count = *animate_matches.get_one::<u16>("loop").unwrap();
+        let resize_frames = animate_matches.get_flag("resize-frames");
+
+        // Encode the animation, or log the failure and exit non-zero.
+        if let Err(e) = animate_frames(source_dir, output_path, delay_ms, loop_count, resize_frames)
+        {
+            error!("Error animating frames: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Handle "pack" command
+    if let Some(pack_matches) = matches.subcommand_matches("pack") {
+        // Get the source directory path from the "source" argument.
+        let source_dir = Path::new(pack_matches.get_one::<String>("source").unwrap());
+
+        // Determine the output directory path, defaulting to the source directory.
+        let output_dir = get_output_dir(pack_matches, source_dir);
+
+        // Read the sheet name and the packing constraints.
+        let name = pack_matches.get_one::<String>("name").unwrap();
+        let max_width = *pack_matches.get_one::<u32>("max-width").unwrap();
+        let padding = *pack_matches.get_one::<u32>("padding").unwrap();
+
+        // Validate that the source directory exists and the output directory can be created.
+        validate_directories(source_dir, output_dir, false, false);
+
+        // Pack the images, or log the failure and exit non-zero.
+        if let Err(e) = pack_images(source_dir, output_dir, name, max_width, padding) {
+            error!("Error packing images: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Exit status of `--summary-exit-codes` when no files matched.
+const EXIT_NO_MATCHES: i32 = 2;
+/// Exit status of `--summary-exit-codes` when some files failed.
+const EXIT_SOME_FAILED: i32 = 3;
+/// Exit status of `--summary-exit-codes` on a fatal error or an aborted batch.
+const EXIT_FATAL: i32 = 4;
+
+/// Exits with the status of a finished batch. With `--summary-exit-codes`: 0 when every file
+/// was processed, 2 when no files matched, 3 when some failed and 4 when the batch could not
+/// run or was aborted. Without it, only a `--require-matches` run that found nothing and a
+/// batch cut short by `--max-errors` or a full disk exit non-zero (1).
+fn exit_with_outcome(result: &Result<Report, RicoError>, summary_exit_codes: bool) {
+    let code = match result {
+        Err(RicoError::NoMatches(_)) if summary_exit_codes => EXIT_NO_MATCHES,
+        Err(RicoError::NoMatches(_)) => 1,
+        // An aborted or incomplete batch must never pass for a successful one.
+        Err(
+            RicoError::Aborted { .. }
+            | RicoError::DiskFull { .. }
+            | RicoError::UnreadableDirectories { .. },
+        ) if !summary_exit_codes => 1,
+        _ if !summary_exit_codes => return,
+        Err(_) => EXIT_FATAL,
+        Ok(report) if report.is_empty() => EXIT_NO_MATCHES,
+        Ok(report) if report.count(Status::Failed) > 0 => EXIT_SOME_FAILED,
+        Ok(_) => return,
+    };
+    std::process::exit(code);
+}
+
+/// Exits after an error that stops the run before any file is processed: with the fatal
+/// status of `--summary-exit-codes`, or 1 without it.
+fn exit_fatal(summary_exit_codes: bool) -> ! {
+    std::process::exit(if summary_exit_codes { EXIT_FATAL } else { 1 });
+}
+
+/// Decodes the image and prints the RGBA value at (x, y), optionally with its 3x3 neighborhood.
+fn print_pixel(file: &Path, x: u32, y: u32, neighborhood: bool) -> Result<(), RicoError> {
+    // Decode the image into RGBA so every format prints the same way.
+    let img = ImageReader::open(file)?
+        .with_guessed_format()?
+        .decode()?
+        .to_rgba8();
+    let (width, height) = img.dimensions();
+    if x >= width || y >= height {
+        return Err(RicoError::OutOfBounds {
+            point: (x, y),
+            size: (width, height),
+        });
+    }
+
+    // Print the requested pixel.
+    println!("({}, {}): {:?}", x, y, img.get_pixel(x, y));
+
+    // Print the surrounding pixels row by row, clipped to the image bounds.
+    if neighborhood {
+        for ny in y.saturating_sub(1)..=(y + 1).min(height - 1) {
+            for nx in x.saturating_sub(1)..=(x + 1).min(width - 1) {
+                println!("  ({}, {}): {:?}", nx, ny, img.get_pixel(nx, ny));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Decodes two versions of an image and prints how they differ: PSNR, SSIM and alpha statistics.
+fn print_diff(reference: &Path, candidate: &Path, alpha_threshold: u8) -> Result<(), RicoError> {
+    // Decode both images; they can only be compared pixel by pixel at the same size.
+    let reference = ImageReader::open(reference)?
+        .with_guessed_format()?
+        .decode()?;
+    let candidate = ImageReader::open(candidate)?
+        .with_guessed_format()?
+        .decode()?;
+    let sizes = (
+        (reference.width(), reference.height()),
+        (candidate.width(), candidate.height()),
+    );
+    if sizes.0 != sizes.1 {
+        return Err(RicoError::SizeMismatch {
+            reference: sizes.0,
+            candidate: sizes.1,
+        });
+    }
+
+    // Print the overall similarity.
+    println!("PSNR: {:.2} dB", quality::psnr(&reference, &candidate));
+    println!("SSIM: {:.4}", quality::ssim(&reference, &candidate));
+
+    // Print how much of the alpha channel changed, and where.
+    let alpha = quality::alpha_diff(
+        &reference.to_rgba8(),
+        &candidate.to_rgba8(),
+        alpha_threshold,
+    );
+    println!(
+        "Alpha: {:.2}% of pixels differ by more than {} ({} of {})",
+        alpha.percent(),
+        alpha_threshold,
+        alpha.differing,
+        alpha.total
+    );
+    match alpha.bounds {
+        Some((left, top, right, bottom)) => println!(
+            "Alpha difference bounds: ({}, {})-({}, {}), {}x{}",
+            left,
+            top,
+            right,
+            bottom,
+            right - left + 1,
+            bottom - top + 1
+        ),
+        None => println!("Alpha difference bounds: none"),
+    }
+    Ok(())
 }
 
 /// Retrieves the output directory, defaulting to the source directory if not specified
@@ -439,30 +4213,775 @@ fn get_output_dir<'a>(matches: &'a ArgMatches, source_dir: &'a Path) -> &'a Path
 }
 
 /// Ensures that the source directory exists and the output directory is created if needed
-fn validate_directories(source_dir: &Path, output_dir: &Path) {
+fn validate_directories(
+    source_dir: &Path,
+    output_dir: &Path,
+    dry_run: bool,
+    summary_exit_codes: bool,
+) {
     // Check if the source directory exists and is a directory.
     if !source_dir.exists() || !source_dir.is_dir() {
-        // If the source directory does not exist or is not a directory, print an error message to stderr.
-        eprintln!("Source directory does not exist or is not a directory");
+        // If the source directory does not exist or is not a directory, log an error message.
+        error!("Source directory does not exist or is not a directory");
         // Exit the program with an error code.
-        std::process::exit(1);
+        exit_fatal(summary_exit_codes);
     }
 
-    // Check if the output directory exists.
-    if !output_dir.exists() {
+    // Check if the output directory exists (a dry run never touches the disk).
+    if !dry_run && !output_dir.exists() {
         // If the output directory does not exist, create it and all necessary parent directories.
         // If the creation fails, panic with an error message.
         fs::create_dir_all(output_dir).expect("Failed to create output directory");
     }
 }
 
+/// Parses a `LOW,HIGH` whiteness range for the soft background threshold.
+fn parse_soft_threshold(value: &str) -> Result<(u8, u8), String> {
+    // Split the value into its two bounds.
+    let (low, high) = value
+        .split_once(',')
+        .ok_or_else(|| format!("expected LOW,HIGH but got '{}'", value))?;
+    // Parse each bound as an 8-bit channel value.
+    let low: u8 = low
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid low bound '{}'", low))?;
+    let high: u8 = high
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid high bound '{}'", high))?;
+    // The ramp needs a non-empty range to interpolate over.
+    if low >= high {
+        return Err(format!(
+            "low bound {} must be below high bound {}",
+            low, high
+        ));
+    }
+    Ok((low, high))
+}
+
+/// Parses `N` (all sides) or `TOP,RIGHT,BOTTOM,LEFT` pixel margins for trimming.
+fn parse_margins(value: &str) -> Result<Margins, String> {
+    // Parse every comma-separated part as a pixel count.
+    let parts = value
+        .split(',')
+        .map(|part| {
+            part.trim()
+                .parse::<u32>()
+                .map_err(|_| format!("invalid margin '{}'", part))
+        })
+        .collect::<Result<Vec<u32>, String>>()?;
+
+    // Accept either one margin for every side or one per side.
+    match parts[..] {
+        [all] => Ok(Margins {
+            top: all,
+            right: all,
+            bottom: all,
+            left: all,
+        }),
+        [top, right, bottom, left] => Ok(Margins {
+            top,
+            right,
+            bottom,
+            left,
+        }),
+        _ => Err(format!(
+            "expected N or TOP,RIGHT,BOTTOM,LEFT but got '{}'",
+            value
+        )),
+    }
+}
+
+/// Parses a `#RRGGBB` (or `RRGGBB`) hex string into an opaque color.
+fn parse_hex_color(value: &str) -> Result<Rgba<u8>, String> {
+    // Allow the leading '#' to be omitted.
+    let hex = value.trim().trim_start_matches('#');
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("expected a color like #RRGGBB but got '{}'", value));
+    }
+    // Decode each pair of hex digits into a channel.
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+    Ok(Rgba([channel(0), channel(2), channel(4), 255]))
+}
+
+/// Parses a non-negative color distance for `--color-tolerance`.
+fn parse_tolerance(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(tolerance) if tolerance >= 0.0 && tolerance.is_finite() => Ok(tolerance),
+        _ => Err(format!(
+            "tolerance must be a non-negative number but got '{}'",
+            value
+        )),
+    }
+}
+
+/// Parses a `WxH` size such as `1920x1080` for `--fit-to`.
+fn parse_dimensions(value: &str) -> Result<(u32, u32), String> {
+    let parsed = value.split_once(['x', 'X']).and_then(|(width, height)| {
+        match (width.trim().parse::<u32>(), height.trim().parse::<u32>()) {
+            (Ok(width), Ok(height)) if width > 0 && height > 0 => Some((width, height)),
+            _ => None,
+        }
+    });
+    parsed.ok_or_else(|| format!("expected a size like 1920x1080 but got '{}'", value))
+}
+
+/// Parses a finite rotation angle in degrees for `--degrees`; negative angles turn counterclockwise.
+fn parse_degrees(value: &str) -> Result<f64, String> {
+    match value.trim().parse::<f64>() {
+        Ok(degrees) if degrees.is_finite() => Ok(degrees),
+        _ => Err(format!("expected an angle in degrees but got '{}'", value)),
+    }
+}
+
+/// Parses a `W:H` aspect ratio such as `16:9` for `--aspect`.
+fn parse_aspect(value: &str) -> Result<(u32, u32), String> {
+    let parsed = value.split_once(':').and_then(|(width, height)| {
+        match (width.trim().parse::<u32>(), height.trim().parse::<u32>()) {
+            (Ok(width), Ok(height)) if width > 0 && height > 0 => Some((width, height)),
+            _ => None,
+        }
+    });
+    parsed.ok_or_else(|| format!("expected a ratio like 16:9 but got '{}'", value))
+}
+
+/// Parses a byte count such as `150000`, `200K` or `2M` (binary multiples) for `--target-bytes`.
+fn parse_byte_size(value: &str) -> Result<u64, String> {
+    let trimmed = value.trim();
+    let (digits, multiplier) = match trimmed.char_indices().last() {
+        Some((index, 'k' | 'K')) => (&trimmed[..index], 1024),
+        Some((index, 'm' | 'M')) => (&trimmed[..index], 1024 * 1024),
+        _ => (trimmed, 1),
+    };
+    match digits.trim().parse::<u64>() {
+        Ok(count) if count > 0 => count
+            .checked_mul(multiplier)
+            .ok_or_else(|| format!("byte size '{}' is too large", value)),
+        _ => Err(format!(
+            "expected a byte size like 200K but got '{}'",
+            value
+        )),
+    }
+}
+
+/// Parses an octal file mode such as `644` or `0600` for `--chmod`.
+fn parse_mode(value: &str) -> Result<u32, String> {
+    match u32::from_str_radix(value, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!(
+            "expected an octal mode like 644 but got '{}'",
+            value
+        )),
+    }
+}
+
+/// Parses an SSIM threshold between 0 and 1 for `--min-ssim`.
+fn parse_min_ssim(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(ssim) if (0.0..=1.0).contains(&ssim) => Ok(ssim),
+        _ => Err(format!(
+            "SSIM must be a number between 0 and 1 but got '{}'",
+            value
+        )),
+    }
+}
+
+/// Parses a positive PSNR threshold in decibels for `--min-psnr`.
+fn parse_min_psnr(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(psnr) if psnr > 0.0 && psnr.is_finite() => Ok(psnr),
+        _ => Err(format!(
+            "PSNR must be a positive number of decibels but got '{}'",
+            value
+        )),
+    }
+}
+
+/// Parses a comma-separated list of output widths for `--sizes`, e.g. `320,640,1280`.
+fn parse_sizes(value: &str) -> Result<Vec<u32>, String> {
+    value
+        .split(',')
+        .map(|part| match part.trim().parse::<u32>() {
+            Ok(width) if width > 0 => Ok(width),
+            _ => Err(format!(
+                "expected positive widths like 320,640 but got '{}'",
+                value
+            )),
+        })
+        .collect()
+}
+
+/// Parses a comma-separated list of formats such as `jpeg,png` for `--only-format`.
+fn parse_formats(value: &str) -> Result<Vec<ImageFormat>, String> {
+    value
+        .split(',')
+        .map(|part| {
+            output_format_for(&part.trim().to_lowercase()).ok_or_else(|| {
+                format!(
+                    "expected formats like jpeg,png (png, jpeg, bmp, webp, gif, tiff) but got '{}'",
+                    value
+                )
+            })
+        })
+        .collect()
+}
+
+/// Parses `--map-format` rules such as `png=webp,bmp=png` into target formats by source extension.
+fn parse_format_map(value: &str) -> Result<BTreeMap<String, String>, String> {
+    value
+        .split(',')
+        .map(|rule| {
+            let (from, to) = rule
+                .split_once('=')
+                .map(|(from, to)| (from.trim().trim_start_matches('.'), to.trim()))
+                .filter(|(from, to)| !from.is_empty() && !to.is_empty())
+                .ok_or_else(|| {
+                    format!("expected rules like png=webp,bmp=png but got '{}'", value)
+                })?;
+            let to = to.to_lowercase();
+            if output_format_for(&to).is_none() {
+                return Err(format!(
+                    "cannot convert to '{}' (expected png, jpg, bmp, webp, gif or tiff)",
+                    to
+                ));
+            }
+            Ok((from.to_lowercase(), to))
+        })
+        .collect()
+}
+
+/// Parses an `X,Y` pixel position for `--at`; negative values place the image partly outside.
+fn parse_position(value: &str) -> Result<(i64, i64), String> {
+    let parts: Vec<&str> = value.split(',').collect();
+    match parts.as_slice() {
+        [x, y] => match (x.trim().parse::<i64>(), y.trim().parse::<i64>()) {
+            (Ok(x), Ok(y)) => Ok((x, y)),
+            _ => Err(format!(
+                "expected a position like 10,20 but got '{}'",
+                value
+            )),
+        },
+        _ => Err(format!(
+            "expected a position like 10,20 but got '{}'",
+            value
+        )),
+    }
+}
+
+/// Parses a positive width/height ratio for `--split-spread`.
+fn parse_positive_ratio(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(ratio) if ratio > 0.0 && ratio.is_finite() => Ok(ratio),
+        _ => Err(format!(
+            "ratio must be a positive number but got '{}'",
+            value
+        )),
+    }
+}
+
+/// Parses a positive gamma value for `--gamma`.
+fn parse_gamma(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(gamma) if gamma > 0.0 && gamma.is_finite() => Ok(gamma),
+        _ => Err(format!("gamma must be greater than 0 but got '{}'", value)),
+    }
+}
+
+/// Parses a positive images-per-second rate for `--max-rate`.
+fn parse_rate(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(format!(
+            "rate must be a positive number but got '{}'",
+            value
+        )),
+    }
+}
+
+/// Adds the arguments shared by the convert and remove subcommands.
+fn shared_args(command: Command) -> Command {
+    command
+        .arg(
+            Arg::new("embed-srgb")
+                .long("embed-srgb")
+                .action(ArgAction::SetTrue)
+                .help("Embed a standard sRGB ICC profile into PNG/JPEG/WebP outputs"),
+        )
+        .arg(
+            Arg::new("trim")
+                .long("trim")
+                .value_name("N|TOP,RIGHT,BOTTOM,LEFT")
+                .value_parser(parse_margins)
+                .help("Crop a fixed border off every image before processing"),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .long("dry-run")
+                .action(ArgAction::SetTrue)
+                .help("List the planned operations without decoding or writing any files"),
+        )
+        .arg(
+            Arg::new("report")
+                .long("report")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(String))
+                .help("Write a report with one record per input file"),
+        )
+        .arg(
+            Arg::new("ledger")
+                .long("ledger")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(String))
+                .help("Record every file's status in FILE (NDJSON) and skip files it already records as ok or skipped"),
+        )
+        .arg(
+            Arg::new("wait-stable")
+                .long("wait-stable")
+                .value_name("SECS")
+                .value_parser(clap::value_parser!(u64))
+                .help("Skip files modified within the last SECS seconds, e.g. uploads still being written"),
+        )
+        .arg(
+            Arg::new("retry-failed")
+                .long("retry-failed")
+                .action(ArgAction::SetTrue)
+                .requires("ledger")
+                .help("Only process the files the --ledger records as failed"),
+        )
+        .arg(
+            Arg::new("report-format")
+                .long("report-format")
+                .value_parser(["json", "ndjson", "csv"])
+                .default_value("json")
+                .help("Format of the --report file: a JSON array, one JSON object per line, or CSV"),
+        )
+        .arg(
+            Arg::new("no-safe-mode")
+                .long("no-safe-mode")
+                .action(ArgAction::SetTrue)
+                .help("Allow outputs outside the output directory (by default such writes are refused)"),
+        )
+        .arg(
+            Arg::new("mirror-dirs")
+                .long("mirror-dirs")
+                .action(ArgAction::SetTrue)
+                .help("Recreate every source directory under the output, including empty ones"),
+        )
+        .arg(
+            Arg::new("slug")
+                .long("slug")
+                .action(ArgAction::SetTrue)
+                .help("Slugify output file names: lowercase, spaces to hyphens, other unsafe characters removed"),
+        )
+        .arg(
+            Arg::new("fallback-decoder")
+                .long("fallback-decoder")
+                .action(ArgAction::SetTrue)
+                .help("Retry JPEGs that fail to decode with a lenient decoder before giving up"),
+        )
+        .arg(
+            Arg::new("progress-json")
+                .long("progress-json")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("summary-only")
+                .help("Report progress as one JSON object per line (NDJSON) on stderr, for wrapping processes"),
+        )
+        .arg(
+            Arg::new("summary-only")
+                .long("summary-only")
+                .action(ArgAction::SetTrue)
+                .help("Hide the per-file messages and only print warnings, errors and the end-of-run summary"),
+        )
+        .arg(
+            Arg::new("summary-exit-codes")
+                .long("summary-exit-codes")
+                .action(ArgAction::SetTrue)
+                .help("Exit with 0 when every file succeeded, 2 when no files matched, 3 when some files failed and 4 on a fatal error or abort"),
+        )
+        .arg(
+            Arg::new("chmod")
+                .long("chmod")
+                .value_name("MODE")
+                .value_parser(parse_mode)
+                .help("Set the permissions of every written output to the octal MODE, e.g. 644 (Unix only)"),
+        )
+        .arg(
+            Arg::new("only-with-alpha")
+                .long("only-with-alpha")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("only-without-alpha")
+                .help("Only process images that have an alpha channel"),
+        )
+        .arg(
+            Arg::new("only-without-alpha")
+                .long("only-without-alpha")
+                .action(ArgAction::SetTrue)
+                .help("Only process images that have no alpha channel"),
+        )
+        .arg(
+            Arg::new("require-matches")
+                .long("require-matches")
+                .action(ArgAction::SetTrue)
+                .help("Fail with a non-zero exit code when no files are left to process after filtering"),
+        )
+        .arg(
+            Arg::new("only-format")
+                .long("only-format")
+                .value_name("FORMATS")
+                .value_parser(parse_formats)
+                .help("Only process inputs whose contents are in one of these formats (e.g. jpeg,png), whatever their extension"),
+        )
+        .arg(
+            Arg::new("skip-solid")
+                .long("skip-solid")
+                .value_name("TOLERANCE")
+                .num_args(0..=1)
+                .default_missing_value("0")
+                .value_parser(clap::value_parser!(u8))
+                .help("Skip images whose pixels are all one color, within TOLERANCE per channel (default: 0, identical)"),
+        )
+        .arg(
+            Arg::new("solid-dir")
+                .long("solid-dir")
+                .value_name("DIR")
+                .requires("skip-solid")
+                .help("Move inputs skipped by --skip-solid into DIR"),
+        )
+        .arg(
+            Arg::new("quarantine")
+                .long("quarantine")
+                .value_name("DIR")
+                .value_parser(clap::value_parser!(String))
+                .help("Copy inputs that fail or cannot be decoded to DIR, at their path relative to the source, for later inspection"),
+        )
+        .arg(
+            Arg::new("blurhash")
+                .long("blurhash")
+                .action(ArgAction::SetTrue)
+                .requires("report")
+                .help("Add a BlurHash placeholder string for every written output to the --report"),
+        )
+        .arg(
+            Arg::new("phash")
+                .long("phash")
+                .action(ArgAction::SetTrue)
+                .requires("report")
+                .help("Add a 64-bit perceptual hash (pHash) of every written output to the --report"),
+        )
+        .arg(
+            Arg::new("group-similar")
+                .long("group-similar")
+                .value_name("DIST")
+                .value_parser(clap::value_parser!(u32).range(0..=64))
+                .help("After the run, list groups of outputs whose pHashes differ by at most DIST bits"),
+        )
+        .arg(
+            Arg::new("palette-from")
+                .long("palette-from")
+                .value_name("IMAGE")
+                .value_parser(clap::value_parser!(String))
+                .help("Remap every image to the palette of this reference image (at most 256 colors); PNGs are written indexed"),
+        )
+        .arg(
+            Arg::new("dither")
+                .long("dither")
+                .action(ArgAction::SetTrue)
+                .requires("ditherable")
+                .help("Dither (Floyd-Steinberg) when remapping to the --palette-from palette or reducing to --bilevel black and white"),
+        )
+        .arg(
+            Arg::new("bilevel")
+                .long("bilevel")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("palette-from")
+                .help("Reduce every image to pure black and white (e.g. for thermal printers and e-ink); PNGs are written 1-bit"),
+        )
+        .arg(
+            Arg::new("threshold")
+                .long("threshold")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u8))
+                .default_value("128")
+                .requires("bilevel")
+                .help("Gray level (0-255) from which --bilevel pixels become white (default: 128)"),
+        )
+        .group(
+            ArgGroup::new("ditherable")
+                .args(["palette-from", "bilevel"])
+                .multiple(true),
+        )
+        .arg(
+            Arg::new("width")
+                .long("width")
+                .value_name("PX")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .help("Resize to this width (keeping the aspect ratio unless --height is also given)"),
+        )
+        .arg(
+            Arg::new("height")
+                .long("height")
+                .value_name("PX")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .help("Resize to this height (keeping the aspect ratio unless --width is also given)"),
+        )
+        .arg(
+            Arg::new("resize-mode")
+                .long("resize-mode")
+                .value_name("MODE")
+                .value_parser(ResizeMode::parse)
+                .help("How --width and --height together shape the image: contain (fit within, default), cover (fill and crop the overflow) or fill (stretch)"),
+        )
+        .arg(
+            Arg::new("denoise")
+                .long("denoise")
+                .value_name("FILTER")
+                .value_parser(["median", "gaussian"])
+                .help("Remove noise before resizing: median (salt-and-pepper) or gaussian"),
+        )
+        .arg(
+            Arg::new("radius")
+                .long("radius")
+                .value_name("PX")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .default_value("1")
+                .requires("denoise")
+                .help("Reach of the --denoise window in pixels (default: 1, a 3x3 window)"),
+        )
+        .arg(
+            Arg::new("degrees")
+                .long("degrees")
+                .value_name("F")
+                .value_parser(parse_degrees)
+                .allow_negative_numbers(true)
+                .help("Rotate every image clockwise by F degrees (any angle), growing the canvas; exposed corners are transparent unless --fill is given"),
+        )
+        .arg(
+            Arg::new("aspect")
+                .long("aspect")
+                .value_name("W:H")
+                .value_parser(parse_aspect)
+                .help("Center-crop every image to the largest area with this aspect ratio (e.g. 1:1 or 16:9)"),
+        )
+        .arg(
+            Arg::new("smart-crop")
+                .long("smart-crop")
+                .action(ArgAction::SetTrue)
+                .help("Place the --aspect and --resize-mode cover crops on the window with the most edge detail instead of the center"),
+        )
+        .arg(
+            Arg::new("fit-to")
+                .long("fit-to")
+                .value_name("WxH")
+                .value_parser(parse_dimensions)
+                .conflicts_with_all(["width", "height"])
+                .help("Resize to fit within WxH (e.g. 1920x1080), keeping the aspect ratio"),
+        )
+        .arg(
+            Arg::new("fill")
+                .long("fill")
+                .value_name("#RRGGBB")
+                .value_parser(parse_hex_color)
+                .requires("fillable")
+                .help("Letterbox images fitted with --fit-to to exactly WxH, and paint the corners exposed by --degrees and the padding added by --align, with this color"),
+        )
+        .group(
+            ArgGroup::new("fillable")
+                .args(["fit-to", "degrees", "align"])
+                .multiple(true),
+        )
+        .arg(
+            Arg::new("align")
+                .long("align")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .help("After any resize, pad or crop every image so its width and height are multiples of N (e.g. 16 for video encoders)"),
+        )
+        .arg(
+            Arg::new("align-mode")
+                .long("align-mode")
+                .value_name("MODE")
+                .value_parser(AlignMode::parse)
+                .default_value("pad")
+                .requires("align")
+                .help("How --align reaches a multiple: pad (grow, centered, transparent or --fill) or crop (shrink around the center) (default: pad)"),
+        )
+        .arg(
+            Arg::new("filter")
+                .long("filter")
+                .value_name("FILTER")
+                .value_parser(Filter::parse)
+                .default_value("lanczos3")
+                .help("Resampling filter: nearest, triangle, catmull-rom, gaussian or lanczos3"),
+        )
+        .arg(
+            Arg::new("gamma")
+                .long("gamma")
+                .value_name("G")
+                .value_parser(parse_gamma)
+                .help("Apply gamma correction (out = in^(1/G)) to every channel; G must be > 0"),
+        )
+        .arg(
+            Arg::new("posterize")
+                .long("posterize")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u16).range(2..=256))
+                .help("Reduce every color channel to N evenly spaced levels (2-256) for a banded poster effect"),
+        )
+        .arg(
+            Arg::new("unpremultiply")
+                .long("unpremultiply")
+                .action(ArgAction::SetTrue)
+                .help("Treat input alpha as premultiplied and convert it to straight alpha before compositing"),
+        )
+        .arg(
+            Arg::new("alpha-premultiply")
+                .long("alpha-premultiply")
+                .action(ArgAction::SetTrue)
+                .help("Write outputs with premultiplied alpha instead of straight alpha"),
+        )
+        .arg(
+            Arg::new("fix-inverted-alpha")
+                .long("fix-inverted-alpha")
+                .value_name("POLARITY")
+                .num_args(0..=1)
+                .default_missing_value("light-opaque")
+                .value_parser(["light-opaque", "dark-opaque"])
+                .help("Flip alpha that looks inverted relative to luminance (default polarity: light-opaque)"),
+        )
+        .arg(
+            Arg::new("preserve-extension-case")
+                .long("preserve-extension-case")
+                .action(ArgAction::SetTrue)
+                .help("Keep uppercase output extensions for inputs with uppercase extensions"),
+        )
+        .arg(
+            Arg::new("organize-by")
+                .long("organize-by")
+                .value_parser(["date"])
+                .help("Sort outputs into YYYY/MM/ folders by EXIF capture date (unknown/ when missing)"),
+        )
+        .arg(
+            Arg::new("max-rate")
+                .long("max-rate")
+                .value_name("N")
+                .value_parser(parse_rate)
+                .help("Throttle processing to roughly N images per second"),
+        )
+        .arg(
+            Arg::new("exclude-dir")
+                .long("exclude-dir")
+                .value_name("NAME")
+                .action(ArgAction::Append)
+                .value_parser(clap::value_parser!(String))
+                .help("Skip directories with this name entirely (repeatable), e.g. node_modules"),
+        )
+        .arg(
+            Arg::new("fail-on-dir-errors")
+                .long("fail-on-dir-errors")
+                .action(ArgAction::SetTrue)
+                .help("Fail instead of only logging when part of the source tree cannot be read"),
+        )
+        .arg(
+            Arg::new("max-errors")
+                .long("max-errors")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Abort the batch once N files have failed"),
+        )
+        .arg(
+            Arg::new("strategy")
+                .long("strategy")
+                .value_parser(["rayon", "pipeline"])
+                .default_value("rayon")
+                .help("Parallel strategy: rayon par_iter or a bounded-channel pipeline"),
+        )
+        .arg(
+            Arg::new("pipeline-bound")
+                .long("pipeline-bound")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .default_value("2")
+                .help("Number of files queued ahead of the --jobs workers with --strategy pipeline (default: 2)"),
+        )
+        .arg(
+            Arg::new("jobs")
+                .long("jobs")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Number of threads that decode, transform and encode images (default: one per core)"),
+        )
+        .arg(
+            Arg::new("max-alloc-mb")
+                .long("max-alloc-mb")
+                .value_name("MB")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .default_value("512")
+                .help("Reject inputs that would need more than MB megabytes to decode, e.g. decompression bombs (default: 512)"),
+        )
+        .arg(
+            Arg::new("max-dimension")
+                .long("max-dimension")
+                .value_name("PX")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .help("Reject inputs wider or taller than PX pixels before decoding them"),
+        )
+        .arg(
+            Arg::new("decode-threads")
+                .long("decode-threads")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("1")
+                .help("Threads each JPEG decode may use internally; 0 lets the decoder use one per core (default: 1)"),
+        )
+        .arg(
+            Arg::new("mmap")
+                .long("mmap")
+                .action(ArgAction::SetTrue)
+                .help("Memory-map inputs of 1 MiB or more instead of reading them into memory"),
+        )
+        .arg(
+            Arg::new("jobs-io")
+                .long("jobs-io")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Read inputs and write outputs on a separate pool of N threads"),
+        )
+        .arg(
+            Arg::new("postprocess")
+                .long("postprocess")
+                .value_name("COMMAND")
+                .value_parser(postprocess::Template::parse)
+                .help("Run COMMAND on every written output, with {output} replaced by its path (e.g. \"oxipng -o 4 {output}\")"),
+        )
+        .arg(
+            Arg::new("postprocess-jobs")
+                .long("postprocess-jobs")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .requires("postprocess")
+                .help("Run at most N --postprocess commands at once (default: one per core)"),
+        )
+        .arg(
+            Arg::new("compare-output")
+                .long("compare-output")
+                .action(ArgAction::SetTrue)
+                .help("Also write the source and the result side by side to {stem}_compare.png for review"),
+        )
+        .arg(
+            Arg::new("info-sidecar")
+                .long("info-sidecar")
+                .action(ArgAction::SetTrue)
+                .help("Write {output}.txt next to every output with its 'WIDTHxHEIGHT FORMAT bytes'"),
+        )
+}
+
 fn parse_args() -> ArgMatches {
     Command::new("RICO - Rust Image Converter")
         .version("1.0")
         .author("Rana Jahanzaib <work@withrana.com>")
         .about("RICO is a Rust-powered CLI tool for rapid, parallel image conversion.")
         .subcommand(
-            Command::new("remove")
+            shared_args(Command::new("remove")
                 .about("Remove background from images")
                 .arg(
                     Arg::new("background")
@@ -490,22 +5009,129 @@ fn parse_args() -> ArgMatches {
                     Arg::new("edge-threshold")
                         .short('e')
                         .long("edge-threshold")
-                        .value_parser(clap::value_parser!(u8))
+                        .value_parser(EdgeThreshold::parse)
                         .default_value("30")
-                        .help("Set the edge detection threshold (default: 30)"),
+                        .help("Set the edge detection threshold, or 'auto' to derive it from each image's gradients (default: 30)"),
+                )
+                .arg(
+                    Arg::new("background-soft-threshold")
+                        .long("background-soft-threshold")
+                        .value_name("LOW,HIGH")
+                        .value_parser(parse_soft_threshold)
+                        .help("Fade removed pixels from opaque at whiteness LOW to transparent at HIGH (e.g. 200,250)"),
+                )
+                .arg(
+                    Arg::new("color-metric")
+                        .long("color-metric")
+                        .value_name("METRIC")
+                        .value_parser(ColorMetric::parse)
+                        .conflicts_with("background-soft-threshold")
+                        .help("Match the white background by distance: rgb, weighted-rgb or ciede2000"),
+                )
+                .arg(
+                    Arg::new("bg-color")
+                        .long("bg-color")
+                        .value_name("#RRGGBB")
+                        .value_parser(parse_hex_color)
+                        .conflicts_with_all(["background-soft-threshold", "auto-bg"])
+                        .help("Remove this background color instead of near-white, within --color-tolerance per channel"),
+                )
+                .arg(
+                    Arg::new("auto-bg")
+                        .long("auto-bg")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("background-soft-threshold")
+                        .help("Detect the background color from the image corners instead of assuming white"),
+                )
+                .arg(
+                    Arg::new("corner-sample")
+                        .long("corner-sample")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(u32).range(1..))
+                        .default_value("1")
+                        .requires("auto-bg")
+                        .help("Take the median of an NxN block at each corner when detecting the background (default: 1)"),
+                )
+                .arg(
+                    Arg::new("color-tolerance")
+                        .long("color-tolerance")
+                        .value_name("DISTANCE")
+                        .value_parser(parse_tolerance)
+                        .default_value("10")
+                        .help("Maximum distance from the background color that counts as background: per channel with --bg-color, else in --color-metric units"),
+                )
+                .arg(
+                    Arg::new("mask")
+                        .long("mask")
+                        .value_name("IMAGE")
+                        .value_parser(clap::value_parser!(String))
+                        .help("Guide the removal with a mask: white marks definite background, black definite foreground"),
+                )
+                .arg(
+                    Arg::new("emit-mask")
+                        .long("emit-mask")
+                        .action(ArgAction::SetTrue)
+                        .help("Also write each cutout's alpha as a grayscale {stem}_mask.png (255 = kept, 0 = removed)"),
+                )
+                .arg(
+                    Arg::new("rescale-alpha")
+                        .long("rescale-alpha")
+                        .action(ArgAction::SetTrue)
+                        .help("Stretch each cutout's alpha linearly from its own min-max to the full 0-255 range for crisper edges"),
+                )
+                .arg(
+                    Arg::new("autocrop")
+                        .long("autocrop")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("compose-over")
+                        .help("Crop each cutout to the bounding box of its non-transparent pixels, recording the box in the --report"),
+                )
+                .arg(
+                    Arg::new("compose-over")
+                        .long("compose-over")
+                        .value_name("IMAGE")
+                        .value_parser(clap::value_parser!(String))
+                        .help("Composite each cutout onto a copy of this background image"),
+                )
+                .arg(
+                    Arg::new("at")
+                        .long("at")
+                        .value_name("X,Y")
+                        .value_parser(parse_position)
+                        .allow_hyphen_values(true)
+                        .default_value("0,0")
+                        .requires("compose-over")
+                        .help("Position of the cutout on the --compose-over background (default: 0,0)"),
                 ),
+            ),
         )
         .subcommand(
-            Command::new("convert")
+            shared_args(Command::new("convert")
                 .about("Convert images to different formats")
                 .arg(
                     Arg::new("source")
                         .short('s')
                         .long("source")
                         .value_parser(clap::value_parser!(String))
-                        .required(true)
+                        .required_unless_present_any(["map", "jobs-file"])
                         .help("Source directory for input images"),
                 )
+                .arg(
+                    Arg::new("map")
+                        .long("map")
+                        .value_name("CSV")
+                        .value_parser(clap::value_parser!(String))
+                        .conflicts_with_all(["source", "output", "sequence", "slug", "max-per-dir", "organize-by", "fix-extensions", "mirror-dirs"])
+                        .help("Convert exactly the input,output[,format] pairs listed in this CSV file instead of a source directory"),
+                )
+                .arg(
+                    Arg::new("jobs-file")
+                        .long("jobs-file")
+                        .value_name("JSONL")
+                        .value_parser(clap::value_parser!(String))
+                        .conflicts_with_all(["map", "source", "output", "sequence", "slug", "max-per-dir", "organize-by", "fix-extensions", "mirror-dirs"])
+                        .help("Convert exactly the work items in this file, one {\"input\", \"output\", \"format\", \"transforms\"} JSON object per line, instead of a source directory"),
+                )
                 .arg(
                     Arg::new("output")
                         .short('o')
@@ -519,8 +5145,599 @@ fn parse_args() -> ArgMatches {
                         .long("format")
                         .value_parser(clap::value_parser!(String))
                         .default_value("png")
-                        .help("Target format for conversion (e.g., png, jpg, bmp, webp)"),
+                        .help("Target format for conversion (e.g., png, jpg, bmp, webp, gif, tiff)"),
+                )
+                .arg(
+                    Arg::new("map-format")
+                        .long("map-format")
+                        .value_name("RULES")
+                        .value_parser(parse_format_map)
+                        .help("Choose the target format per source extension, e.g. png=webp,bmp=png; other extensions keep their format unless -f is given"),
+                )
+                .arg(
+                    Arg::new("flatten")
+                        .long("flatten")
+                        .value_name("COLOR")
+                        .num_args(0..=1)
+                        .default_missing_value("#ffffff")
+                        .value_parser(parse_hex_color)
+                        .help("Composite transparent images over COLOR (default: white) when the target format has no alpha"),
+                )
+                .arg(
+                    Arg::new("frame")
+                        .long("frame")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("0")
+                        .help("Frame to keep when converting an animated WebP to a still format (default: 0)"),
+                )
+                .arg(
+                    Arg::new("sizes")
+                        .long("sizes")
+                        .value_name("W1,W2,...")
+                        .value_parser(parse_sizes)
+                        .help("Write one output per width, suffixed with the width (photo-640.webp)"),
+                )
+                .arg(
+                    Arg::new("split-spread")
+                        .long("split-spread")
+                        .value_name("RATIO")
+                        .num_args(0..=1)
+                        .default_missing_value("1.2")
+                        .value_parser(parse_positive_ratio)
+                        .conflicts_with("sizes")
+                        .help("Split images wider than RATIO x their height (default: 1.2) into _l/_r pages"),
+                )
+                .arg(
+                    Arg::new("gutter")
+                        .long("gutter")
+                        .value_parser(["center", "auto"])
+                        .default_value("center")
+                        .requires("split-spread")
+                        .help("Split spreads down the middle, or at the darkest column near it (auto)"),
+                )
+                .arg(
+                    Arg::new("tiff-compression")
+                        .long("tiff-compression")
+                        .value_name("METHOD")
+                        .value_parser(TiffCompression::parse)
+                        .help("Compression for TIFF outputs: none, lzw, deflate or packbits (default: none)"),
+                )
+                .arg(
+                    Arg::new("webp-quality")
+                        .long("webp-quality")
+                        .value_name("Q")
+                        .value_parser(clap::value_parser!(u8).range(0..=100))
+                        .help("Encode WebP outputs lossily with color quality Q (0-100) instead of losslessly"),
+                )
+                .arg(
+                    Arg::new("webp-alpha-quality")
+                        .long("webp-alpha-quality")
+                        .value_name("Q")
+                        .value_parser(clap::value_parser!(u8).range(0..=100))
+                        .requires("webp-quality")
+                        .help("Alpha channel quality (0-100) for lossy WebP outputs (default: 100)"),
+                )
+                .arg(
+                    Arg::new("max-per-dir")
+                        .long("max-per-dir")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .help("Spread outputs over numbered subdirectories (000/, 001/, ...) of at most N files, in sorted input order"),
+                )
+                .arg(
+                    Arg::new("fix-extensions")
+                        .long("fix-extensions")
+                        .action(ArgAction::SetTrue)
+                        .help("Rename sources whose extension does not match their content (e.g. a PNG named .jpg) before converting"),
+                )
+                .arg(
+                    Arg::new("copy-on-failure")
+                        .long("copy-on-failure")
+                        .action(ArgAction::SetTrue)
+                        .help("Copy inputs that fail to convert to the output directory unchanged, keeping their extension"),
+                )
+                .arg(
+                    Arg::new("archive")
+                        .long("archive")
+                        .value_name("FORMAT")
+                        .value_parser(["tar"])
+                        .conflicts_with("sequence")
+                        .help("With --output -, stream all outputs to stdout as a tar archive"),
+                )
+                .arg(
+                    Arg::new("prefer-lossless-for-graphics")
+                        .long("prefer-lossless-for-graphics")
+                        .action(ArgAction::SetTrue)
+                        .help("Write graphics (at most 256 colors) as PNG instead of JPEG and as lossless instead of lossy WebP"),
+                )
+                .arg(
+                    Arg::new("fallback-format")
+                        .long("fallback-format")
+                        .value_name("FORMAT")
+                        .value_parser(["png", "jpg", "jpeg", "bmp", "webp", "gif", "tif", "tiff"])
+                        .ignore_case(true)
+                        .help("Write this format instead when encoding the target format fails"),
+                )
+                .arg(
+                    Arg::new("sequence")
+                        .long("sequence")
+                        .value_name("PREFIX")
+                        .value_parser(clap::value_parser!(String))
+                        .help("Name outputs PREFIX0001, PREFIX0002, ... in sorted input order, continuing across runs"),
+                )
+                .arg(
+                    Arg::new("start")
+                        .long("start")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(u64))
+                        .requires("sequence")
+                        .help("Number of the first --sequence output (default: 1, or one after the last run's)"),
+                )
+                .arg(
+                    Arg::new("pad")
+                        .long("pad")
+                        .value_name("W")
+                        .value_parser(clap::value_parser!(usize))
+                        .requires("sequence")
+                        .help("Zero-pad --sequence numbers to W digits (default: 4)"),
+                )
+                .arg(
+                    Arg::new("jpeg-quality")
+                        .long("jpeg-quality")
+                        .value_name("Q")
+                        .value_parser(clap::value_parser!(u8).range(0..=100))
+                        .help("Quality (0-100) for JPEG outputs (default: 75)"),
+                )
+                .arg(
+                    Arg::new("min-ssim")
+                        .long("min-ssim")
+                        .value_name("SSIM")
+                        .value_parser(parse_min_ssim)
+                        .help("Re-encode lossy JPEG/WebP outputs at higher quality until their SSIM against the source is at least SSIM (0-1)"),
+                )
+                .arg(
+                    Arg::new("min-psnr")
+                        .long("min-psnr")
+                        .value_name("DB")
+                        .value_parser(parse_min_psnr)
+                        .help("Re-encode lossy JPEG/WebP outputs at higher quality until their PSNR against the source is at least DB decibels"),
+                )
+                .arg(
+                    Arg::new("target-bytes")
+                        .long("target-bytes")
+                        .value_name("N")
+                        .value_parser(parse_byte_size)
+                        .conflicts_with_all(["min-ssim", "min-psnr"])
+                        .help("Encode every output within N bytes (e.g. 150000 or 200K), lowering the quality of JPEG/WebP outputs as needed"),
+                )
+                .arg(
+                    Arg::new("allow-downscale")
+                        .long("allow-downscale")
+                        .action(ArgAction::SetTrue)
+                        .requires("target-bytes")
+                        .help("Shrink outputs in 10% steps when even the lowest quality misses --target-bytes"),
+                )
+                .arg(
+                    Arg::new("embed-thumbnail")
+                        .long("embed-thumbnail")
+                        .action(ArgAction::SetTrue)
+                        .help("Embed a small (at most 160x160) thumbnail in the EXIF data of JPEG outputs, for file managers"),
+                )
+                .arg(
+                    Arg::new("keep-thumbnail")
+                        .long("keep-thumbnail")
+                        .action(ArgAction::SetTrue)
+                        .help("Copy the source's embedded EXIF thumbnail, when it has one, into JPEG outputs"),
+                )
+                .arg(
+                    Arg::new("verify-lossless")
+                        .long("verify-lossless")
+                        .action(ArgAction::SetTrue)
+                        .help("Decode PNG, BMP, TIFF and lossless WebP outputs again and fail unless their pixels equal the source's"),
+                )
+                .arg(
+                    Arg::new("halt-on-dimension-change")
+                        .long("halt-on-dimension-change")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["width", "height", "fit-to", "trim", "aspect", "degrees", "align", "sizes", "split-spread", "allow-downscale", "archive"])
+                        .help("Fail outputs whose dimensions differ from their source's (for recompressions without resizing)"),
+                )
+                .arg(
+                    Arg::new("overwrite")
+                        .long("overwrite")
+                        .action(ArgAction::SetTrue)
+                        .help("Re-create outputs that already exist instead of skipping them"),
+                )
+                .arg(
+                    Arg::new("compare-bytes")
+                        .long("compare-bytes")
+                        .action(ArgAction::SetTrue)
+                        .help("Skip same-format inputs whose existing output is byte-identical to the source, replacing outputs that differ"),
+                )
+                .arg(
+                    Arg::new("force-reencode")
+                        .long("force-reencode")
+                        .action(ArgAction::SetTrue)
+                        .help("Decode and re-encode same-format inputs even when no transform or encoder setting applies, instead of copying their bytes"),
+                ),
+            ),
+        )
+        .subcommand(
+            shared_args(Command::new("trim")
+                .about("Crop images to their content by trimming uniform margins")
+                .arg(
+                    Arg::new("source")
+                        .short('s')
+                        .long("source")
+                        .value_parser(clap::value_parser!(String))
+                        .required(true)
+                        .help("Source directory for input images"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_parser(clap::value_parser!(String))
+                        .help("Output directory for trimmed images (optional, defaults to source directory)"),
+                )
+                .arg(
+                    Arg::new("tolerance")
+                        .long("tolerance")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(u8))
+                        .default_value("10")
+                        .help("Largest per-channel difference (0-255) from the border color that still counts as margin (default: 10)"),
+                ),
+            ),
+        )
+        .subcommand(
+            Command::new("animate")
+                .about("Merge a directory of frames into an animated GIF or WebP")
+                .arg(
+                    Arg::new("source")
+                        .short('s')
+                        .long("source")
+                        .value_parser(clap::value_parser!(String))
+                        .required(true)
+                        .help("Directory of frames, played in the order of their names"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("FILE")
+                        .value_parser(clap::value_parser!(String))
+                        .required(true)
+                        .help("Animation to write; its extension (.gif or .webp) picks the format"),
+                )
+                .arg(
+                    Arg::new("delay")
+                        .long("delay")
+                        .value_name("MS")
+                        .value_parser(clap::value_parser!(u32))
+                        .default_value("100")
+                        .help("How long each frame is shown, in milliseconds (default: 100)"),
+                )
+                .arg(
+                    Arg::new("fps")
+                        .long("fps")
+                        .value_name("F")
+                        .value_parser(parse_rate)
+                        .conflicts_with("delay")
+                        .help("Frames per second, instead of --delay"),
+                )
+                .arg(
+                    Arg::new("loop")
+                        .long("loop")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(u16))
+                        .default_value("0")
+                        .help("Loop count of the animation; 0 loops forever (default: 0)"),
+                )
+                .arg(
+                    Arg::new("resize-frames")
+                        .long("resize-frames")
+                        .action(ArgAction::SetTrue)
+                        .help("Stretch frames to the size of the first one instead of failing on a mismatch"),
+                ),
+        )
+        .subcommand(
+            Command::new("pack")
+                .about("Pack images into a single sprite sheet with a JSON atlas")
+                .arg(
+                    Arg::new("source")
+                        .short('s')
+                        .long("source")
+                        .value_parser(clap::value_parser!(String))
+                        .required(true)
+                        .help("Source directory for input images"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_parser(clap::value_parser!(String))
+                        .help("Output directory for the sprite sheet and atlas (optional, defaults to source directory)"),
+                )
+                .arg(
+                    Arg::new("name")
+                        .short('n')
+                        .long("name")
+                        .value_parser(clap::value_parser!(String))
+                        .default_value("spritesheet")
+                        .help("File name (without extension) for the sheet PNG and atlas JSON"),
+                )
+                .arg(
+                    Arg::new("max-width")
+                        .long("max-width")
+                        .value_parser(clap::value_parser!(u32).range(1..))
+                        .default_value("2048")
+                        .help("Maximum width of the sprite sheet in pixels (default: 2048)"),
+                )
+                .arg(
+                    Arg::new("padding")
+                        .long("padding")
+                        .value_parser(clap::value_parser!(u32))
+                        .default_value("0")
+                        .help("Padding in pixels between packed images (default: 0)"),
+                ),
+        )
+        .subcommand(
+            Command::new("pixel")
+                .about("Print the RGBA value of one pixel, to help pick removal thresholds")
+                .arg(
+                    Arg::new("file")
+                        .value_parser(clap::value_parser!(String))
+                        .required(true)
+                        .help("Image file to inspect"),
+                )
+                .arg(
+                    Arg::new("x")
+                        .long("x")
+                        .value_parser(clap::value_parser!(u32))
+                        .required(true)
+                        .help("Column of the pixel, counted from the left edge"),
+                )
+                .arg(
+                    Arg::new("y")
+                        .long("y")
+                        .value_parser(clap::value_parser!(u32))
+                        .required(true)
+                        .help("Row of the pixel, counted from the top edge"),
+                )
+                .arg(
+                    Arg::new("neighborhood")
+                        .long("neighborhood")
+                        .action(ArgAction::SetTrue)
+                        .help("Also print the 3x3 neighborhood around the pixel"),
+                ),
+        )
+        .subcommand(
+            Command::new("diff")
+                .about("Compare two versions of an image, e.g. cutouts before and after a change")
+                .arg(
+                    Arg::new("reference")
+                        .value_parser(clap::value_parser!(String))
+                        .required(true)
+                        .help("Image to compare against"),
+                )
+                .arg(
+                    Arg::new("candidate")
+                        .value_parser(clap::value_parser!(String))
+                        .required(true)
+                        .help("Image to compare, with the same dimensions"),
+                )
+                .arg(
+                    Arg::new("alpha-threshold")
+                        .long("alpha-threshold")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(u8))
+                        .default_value("0")
+                        .help("Count pixels whose alpha differs by more than N (default: 0)"),
                 ),
         )
         .get_matches()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// A fresh, empty directory for one test under the system temp directory.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rico-unit-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn progress_callback_sees_one_finished_event_per_input() {
+        let dir = temp_dir("progress");
+        let (source_dir, output_dir) = (dir.join("src"), dir.join("out"));
+        fs::create_dir_all(&source_dir).unwrap();
+        for i in 0..3 {
+            RgbaImage::from_pixel(4, 4, Rgba([i, 0, 0, 255]))
+                .save(source_dir.join(format!("{}.png", i)))
+                .unwrap();
+        }
+
+        let events = Mutex::new(Vec::new());
+        let collect = |event: ProgressEvent| events.lock().unwrap().push(event);
+        let report = process_images(
+            &source_dir,
+            &output_dir,
+            "webp",
+            &Options::default(),
+            Some(&collect),
+        )
+        .unwrap();
+        assert_eq!(report.count(Status::Ok), 3);
+
+        let mut finished: Vec<PathBuf> = events
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .filter_map(|event| match event {
+                ProgressEvent::Finished { input, .. } => Some(input),
+                _ => None,
+            })
+            .collect();
+        finished.sort();
+        let inputs: Vec<PathBuf> = (0..3)
+            .map(|i| source_dir.join(format!("{}.png", i)))
+            .collect();
+        assert_eq!(finished, inputs);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn letterbox_is_only_reported_with_fit_to() {
+        let fill = Some(Rgba([0, 0, 0, 255]));
+        let rotated = Options {
+            degrees: Some(10.0),
+            fill,
+            ..Options::default()
+        };
+        assert_eq!(rotated.transforms(), ["rotate"]);
+        let fitted = Options {
+            fit_to: Some((100, 100)),
+            fill,
+            ..Options::default()
+        };
+        assert_eq!(fitted.transforms(), ["fit", "letterbox"]);
+    }
+
+    fn default_removal() -> RemovalOptions {
+        RemovalOptions {
+            edge_threshold: EdgeThreshold::Fixed(30),
+            soft_threshold: None,
+            color_metric: None,
+            color_tolerance: 10.0,
+            bg_color: None,
+            compose_over: None,
+            compose_at: (0, 0),
+            corner_sample: None,
+            emit_mask: false,
+            mask: None,
+            rescale_alpha: false,
+            autocrop: false,
+        }
+    }
+
+    #[test]
+    fn remove_background_handles_degenerate_sizes() {
+        let removal = default_removal();
+        for (width, height) in [(0, 0), (0, 3), (3, 0)] {
+            let img = DynamicImage::ImageRgba8(RgbaImage::new(width, height));
+            assert_eq!(
+                remove_background(&img, &removal).dimensions(),
+                (width, height)
+            );
+        }
+        for (width, height) in [(1, 1), (1, 4), (4, 1)] {
+            let white = RgbaImage::from_pixel(width, height, Rgba([255, 255, 255, 255]));
+            let cutout = remove_background(&DynamicImage::ImageRgba8(white), &removal);
+            assert_eq!(cutout.dimensions(), (width, height));
+            assert!(cutout.pixels().all(|pixel| pixel[3] == 0));
+        }
+    }
+
+    #[test]
+    fn soft_threshold_fades_alpha_between_low_and_high() {
+        // A ramp from 200 at the left edge to 255 from x = 55 on.
+        let ramp = RgbaImage::from_fn(64, 4, |x, _| {
+            let value = (200 + x).min(255) as u8;
+            Rgba([value, value, value, 255])
+        });
+        let removal = RemovalOptions {
+            soft_threshold: Some((200, 250)),
+            ..default_removal()
+        };
+        let cutout = remove_background(&DynamicImage::ImageRgba8(ramp), &removal);
+        assert_eq!(cutout.get_pixel(0, 1)[3], 255);
+        assert_eq!(cutout.get_pixel(25, 1)[3], 127);
+        assert_eq!(cutout.get_pixel(50, 1)[3], 0);
+        assert!((1..50).all(|x| (1..255).contains(&cutout.get_pixel(x, 1)[3])));
+    }
+
+    #[test]
+    fn halt_on_dimension_change_fails_resized_outputs() {
+        let dir = temp_dir("dimension-change");
+        let input = dir.join("photo.png");
+        RgbaImage::from_fn(12, 8, |x, y| Rgba([x as u8 * 20, y as u8 * 30, 0, 255]))
+            .save(&input)
+            .unwrap();
+        let options = Options {
+            halt_on_dimension_change: true,
+            ..Options::default()
+        };
+
+        // A plain recompression keeps the size and passes.
+        let outcome = convert_image(&input, None, &dir.join("out"), "webp", &options, None);
+        let Ok(Outcome::Written(output_path)) = outcome else {
+            panic!("recompression failed: {:?}", outcome.err());
+        };
+        assert_eq!(image::image_dimensions(&output_path).unwrap(), (12, 8));
+
+        // An output that came out resized is reported and removed.
+        RgbaImage::new(6, 4).save(&output_path).unwrap();
+        let error = check_dimensions((12, 8), &output_path, &options).unwrap_err();
+        assert!(matches!(
+            error,
+            RicoError::DimensionChanged {
+                from: (12, 8),
+                to: (6, 4),
+                ..
+            }
+        ));
+        assert!(!output_path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pipeline_runs_as_many_workers_as_jobs() {
+        let options = Options {
+            strategy: Strategy::Pipeline,
+            pipeline_bound: 64,
+            jobs: Some(2),
+            ..Options::default()
+        };
+        let files: Vec<u32> = (0..20).collect();
+        let active = AtomicUsize::new(0);
+        let most_active = AtomicUsize::new(0);
+        let processed = AtomicUsize::new(0);
+        for_each_file(&files, &options, |_| {
+            let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+            most_active.fetch_max(now, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(5));
+            active.fetch_sub(1, Ordering::SeqCst);
+            processed.fetch_add(1, Ordering::SeqCst);
+            FileStatus::Completed
+        })
+        .unwrap();
+        assert_eq!(processed.into_inner(), files.len());
+        assert!(most_active.into_inner() <= 2);
+    }
+
+    #[test]
+    fn verify_round_trip_passes_exact_outputs_and_fails_quantized_ones() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(8, 8, |x, y| {
+            Rgba([(x * 31) as u8, (y * 29) as u8, 7, 200])
+        }));
+        let png = |img: &DynamicImage| {
+            let mut bytes = Cursor::new(Vec::new());
+            img.write_to(&mut bytes, ImageFormat::Png).unwrap();
+            bytes.into_inner()
+        };
+        let output_path = Path::new("out/a.png");
+        assert!(verify_round_trip(&img, &png(&img), ImageFormat::Png, output_path).is_ok());
+
+        // An output that went through quantization on the way no longer matches its source.
+        let quantized = transform::posterize(img.clone(), 4);
+        let error =
+            verify_round_trip(&img, &png(&quantized), ImageFormat::Png, output_path).unwrap_err();
+        assert!(matches!(error, RicoError::NotLossless { .. }), "{}", error);
+    }
+}
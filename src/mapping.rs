@@ -0,0 +1,146 @@
+use crate::config::Config;
+use crate::error::RicoError;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// One work item of a `--map` or `--jobs-file`: convert `input` to exactly `output`.
+#[derive(Clone, Debug)]
+pub struct MapEntry {
+    /// Image to convert.
+    pub input: PathBuf,
+    /// Exact path the output is written to.
+    pub output: PathBuf,
+    /// Target format of this row; `None` takes it from the output extension, then `--format`.
+    pub format: Option<String>,
+    /// Settings of this item, with the keys of a `.rico.toml`; empty for `--map` rows.
+    pub config: Config,
+}
+
+/// One line of a `--jobs-file`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Job {
+    input: PathBuf,
+    output: PathBuf,
+    #[serde(default)]
+    format: Option<String>,
+    #[serde(default)]
+    transforms: Config,
+}
+
+/// Loads a `--map` CSV file of `input,output[,format]` rows. Blank lines are ignored and
+/// fields may be quoted (`"a, b.png"`, with `""` for a quote). Every input must exist;
+/// inputs that are not files (e.g. a directory named `foo.png`) are skipped when converting.
+pub fn load(path: &Path) -> Result<Vec<MapEntry>, RicoError> {
+    let contents = fs::read_to_string(path)?;
+    let mut entries = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let number = index + 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields = split_row(line)
+            .map_err(|e| RicoError::InvalidFile(format!("line {}: {}", number, e)))?;
+        let (input, output, format) = match fields.as_slice() {
+            [input, output] => (input, output, None),
+            [input, output, format] => (input, output, Some(format)),
+            _ => {
+                return Err(RicoError::InvalidFile(format!(
+                    "line {}: expected input,output[,format] but got {} fields",
+                    number,
+                    fields.len()
+                )))
+            }
+        };
+        if input.is_empty() || output.is_empty() {
+            return Err(RicoError::InvalidFile(format!(
+                "line {}: input and output cannot be empty",
+                number
+            )));
+        }
+        let input = PathBuf::from(input);
+        if !input.exists() {
+            return Err(RicoError::InvalidFile(format!(
+                "line {}: input {:?} does not exist",
+                number, input
+            )));
+        }
+        entries.push(MapEntry {
+            input,
+            output: PathBuf::from(output),
+            format: format
+                .filter(|format| !format.is_empty())
+                .map(|format| format.to_lowercase()),
+            config: Config::default(),
+        });
+    }
+    Ok(entries)
+}
+
+/// Loads a `--jobs-file` with one JSON object per line:
+/// `{"input": ..., "output": ..., "format": ..., "transforms": {...}}`, where `format` and
+/// `transforms` (with the keys of a `.rico.toml`) are optional. Blank lines are ignored and
+/// every input must exist, as with [`load`].
+pub fn load_jobs(path: &Path) -> Result<Vec<MapEntry>, RicoError> {
+    let contents = fs::read_to_string(path)?;
+    let mut entries = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let number = index + 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut job: Job = serde_json::from_str(line)
+            .map_err(|e| RicoError::InvalidFile(format!("line {}: {}", number, e)))?;
+        if job.input.as_os_str().is_empty() || job.output.as_os_str().is_empty() {
+            return Err(RicoError::InvalidFile(format!(
+                "line {}: input and output cannot be empty",
+                number
+            )));
+        }
+        if !job.input.exists() {
+            return Err(RicoError::InvalidFile(format!(
+                "line {}: input {:?} does not exist",
+                number, job.input
+            )));
+        }
+        // The format may be given at the top level or among the transforms.
+        let format = job.format.take().or(job.transforms.format.take());
+        entries.push(MapEntry {
+            input: job.input,
+            output: job.output,
+            format: format.map(|format| format.to_lowercase()),
+            config: job.transforms,
+        });
+    }
+    Ok(entries)
+}
+
+/// Splits one CSV line into its trimmed fields, unquoting quoted ones.
+fn split_row(line: &str) -> Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            // A doubled quote inside a quoted field is a literal quote.
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (false, '"') if field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+            }
+            (false, ',') => fields.push(std::mem::take(&mut field).trim().to_string()),
+            (_, c) => field.push(c),
+        }
+    }
+    if quoted {
+        return Err("unterminated quoted field".into());
+    }
+    fields.push(field.trim().to_string());
+    Ok(fields)
+}
@@ -0,0 +1,36 @@
+use exif::{DateTime, In, Reader, Tag, Value};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek};
+use std::path::{Path, PathBuf};
+
+/// Reads the EXIF capture date (year, month) from an encoded image, if it has one.
+/// `DateTimeOriginal` is preferred, falling back to the generic `DateTime` tag.
+pub fn capture_date<R: BufRead + Seek>(reader: &mut R) -> Option<(u16, u8)> {
+    // Parse the EXIF block out of the image container; the pixel data is never decoded.
+    let exif = Reader::new().read_from_container(reader).ok()?;
+
+    // Use the first date tag that is present and well-formed.
+    [Tag::DateTimeOriginal, Tag::DateTime]
+        .iter()
+        .filter_map(|tag| exif.get_field(*tag, In::PRIMARY))
+        .find_map(|field| match &field.value {
+            Value::Ascii(values) => values
+                .first()
+                .and_then(|ascii| DateTime::from_ascii(ascii).ok())
+                .filter(|date| (1..=12).contains(&date.month))
+                .map(|date| (date.year, date.month)),
+            _ => None,
+        })
+}
+
+/// Returns the `YYYY/MM` folder for an image file's capture date, or `unknown` without one.
+/// Only the container headers are read, so this stays cheap for large inputs.
+pub fn date_folder(path: &Path) -> PathBuf {
+    let date = File::open(path)
+        .ok()
+        .and_then(|file| capture_date(&mut BufReader::new(file)));
+    match date {
+        Some((year, month)) => PathBuf::from(format!("{:04}", year)).join(format!("{:02}", month)),
+        None => PathBuf::from("unknown"),
+    }
+}
@@ -0,0 +1,75 @@
+use image::{imageops, DynamicImage, RgbaImage};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Position and size of one source image inside the sprite sheet.
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+/// A packed sprite sheet together with the atlas describing where each sprite lives.
+pub struct SpriteSheet {
+    pub image: RgbaImage,
+    pub atlas: BTreeMap<String, Rect>,
+}
+
+/// Packs the named images into a single sheet using shelf bin-packing.
+/// Images are placed tallest-first on rows ("shelves") no wider than `max_width`.
+pub fn pack(
+    sprites: Vec<(String, DynamicImage)>,
+    max_width: u32,
+    padding: u32,
+) -> Result<SpriteSheet, String> {
+    // Sort by height (tallest first) so each shelf wastes as little space as possible.
+    let mut sprites = sprites;
+    sprites.sort_by(|(a_name, a), (b_name, b)| {
+        b.height().cmp(&a.height()).then_with(|| a_name.cmp(b_name))
+    });
+
+    // Place every sprite, opening a new shelf when the current one is full.
+    let mut atlas = BTreeMap::new();
+    let (mut cursor_x, mut shelf_y, mut shelf_height) = (0u32, 0u32, 0u32);
+    let mut sheet_width = 0u32;
+    for (name, sprite) in &sprites {
+        let (w, h) = (sprite.width(), sprite.height());
+        // A sprite wider than the sheet can never fit.
+        if w > max_width {
+            return Err(format!(
+                "{} is {}px wide, which exceeds the maximum sheet width of {}px",
+                name, w, max_width
+            ));
+        }
+        // Move down to a new shelf if the sprite does not fit on this one.
+        if cursor_x > 0 && cursor_x + w > max_width {
+            shelf_y += shelf_height + padding;
+            cursor_x = 0;
+            shelf_height = 0;
+        }
+        atlas.insert(
+            name.clone(),
+            Rect {
+                x: cursor_x,
+                y: shelf_y,
+                w,
+                h,
+            },
+        );
+        sheet_width = sheet_width.max(cursor_x + w);
+        shelf_height = shelf_height.max(h);
+        cursor_x += w + padding;
+    }
+    let sheet_height = shelf_y + shelf_height;
+
+    // Draw each sprite onto a transparent sheet at its packed position.
+    let mut image = RgbaImage::new(sheet_width.max(1), sheet_height.max(1));
+    for (name, sprite) in &sprites {
+        let rect = atlas[name];
+        imageops::replace(&mut image, &sprite.to_rgba8(), rect.x as i64, rect.y as i64);
+    }
+
+    Ok(SpriteSheet { image, atlas })
+}
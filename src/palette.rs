@@ -0,0 +1,126 @@
+use image::imageops::{self, ColorMap};
+use image::{DynamicImage, Rgba, RgbaImage};
+use std::collections::BTreeSet;
+
+/// Largest number of colors a palette (and an indexed PNG) can hold.
+const MAX_COLORS: usize = 256;
+
+/// NeuQuant sampling factor: 1 looks at every pixel, 30 is fastest; 10 is the usual trade-off.
+const NEUQUANT_SAMPLE_FACTOR: i32 = 10;
+
+/// A fixed set of RGBA colors that images are remapped to with `--palette-from`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Palette {
+    colors: Vec<[u8; 4]>,
+}
+
+impl Palette {
+    /// Extracts the palette of a reference image: its own colors when it has at most 256,
+    /// otherwise 256 colors quantized with NeuQuant.
+    pub fn from_image(reference: &DynamicImage) -> Self {
+        let rgba = reference.to_rgba8();
+        let unique: BTreeSet<[u8; 4]> = rgba.pixels().map(|pixel| pixel.0).collect();
+        let colors = if unique.len() <= MAX_COLORS {
+            unique.into_iter().collect()
+        } else {
+            color_quant::NeuQuant::new(NEUQUANT_SAMPLE_FACTOR, MAX_COLORS, rgba.as_raw())
+                .color_map_rgba()
+                .chunks_exact(4)
+                .map(|color| [color[0], color[1], color[2], color[3]])
+                .collect()
+        };
+        Palette { colors }
+    }
+
+    /// Number of colors in the palette.
+    pub fn color_count(&self) -> usize {
+        self.colors.len()
+    }
+
+    /// Index of the palette color closest to `color` (squared distance over RGBA).
+    fn nearest(&self, color: [u8; 4]) -> usize {
+        let distance = |candidate: &[u8; 4]| -> u32 {
+            candidate
+                .iter()
+                .zip(color)
+                .map(|(&a, b)| (a as i32 - b as i32).pow(2) as u32)
+                .sum()
+        };
+        self.colors
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, candidate)| distance(candidate))
+            .map_or(0, |(index, _)| index)
+    }
+
+    /// Replaces every pixel with its closest palette color, optionally spreading the error
+    /// over the neighbors with Floyd-Steinberg dithering. Opaque images stay without alpha.
+    pub fn remap(&self, img: &DynamicImage, dither: bool) -> DynamicImage {
+        let mut rgba = img.to_rgba8();
+        if dither {
+            imageops::dither(&mut rgba, self);
+        } else {
+            rgba.pixels_mut().for_each(|pixel| self.map_color(pixel));
+        }
+        if img.color().has_alpha() {
+            DynamicImage::ImageRgba8(rgba)
+        } else {
+            DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(rgba).to_rgb8())
+        }
+    }
+
+    /// Encodes the image as an 8-bit indexed PNG with this palette (and a tRNS chunk for
+    /// its alpha), mapping any pixel that is not a palette color to the closest one.
+    pub fn encode_indexed_png(&self, img: &RgbaImage) -> Result<Vec<u8>, String> {
+        let indices: Vec<u8> = img
+            .pixels()
+            .map(|pixel| self.nearest(pixel.0) as u8)
+            .collect();
+        let rgb: Vec<u8> = self
+            .colors
+            .iter()
+            .flat_map(|c| [c[0], c[1], c[2]])
+            .collect();
+        let alpha: Vec<u8> = self.colors.iter().map(|c| c[3]).collect();
+
+        let mut bytes = Vec::new();
+        let mut encoder = png::Encoder::new(&mut bytes, img.width(), img.height());
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_palette(rgb);
+        // Fully opaque palettes need no transparency chunk.
+        if alpha.iter().any(|&a| a < 255) {
+            encoder.set_trns(alpha);
+        }
+        let mut writer = encoder
+            .write_header()
+            .map_err(|e| format!("could not write indexed PNG: {}", e))?;
+        writer
+            .write_image_data(&indices)
+            .map_err(|e| format!("could not write indexed PNG: {}", e))?;
+        writer
+            .finish()
+            .map_err(|e| format!("could not write indexed PNG: {}", e))?;
+        Ok(bytes)
+    }
+}
+
+impl ColorMap for Palette {
+    type Color = Rgba<u8>;
+
+    fn index_of(&self, color: &Rgba<u8>) -> usize {
+        self.nearest(color.0)
+    }
+
+    fn lookup(&self, index: usize) -> Option<Rgba<u8>> {
+        self.colors.get(index).copied().map(Rgba)
+    }
+
+    fn has_lookup(&self) -> bool {
+        true
+    }
+
+    fn map_color(&self, color: &mut Rgba<u8>) {
+        *color = Rgba(self.colors[self.nearest(color.0)]);
+    }
+}
@@ -0,0 +1,107 @@
+use std::path::Path;
+use std::process::Command;
+use std::sync::{Condvar, Mutex};
+
+/// Placeholder in a `--postprocess` template that is replaced with the output path.
+pub const OUTPUT_PLACEHOLDER: &str = "{output}";
+
+/// A `--postprocess` command template, split into its program and arguments.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template {
+    words: Vec<String>,
+}
+
+impl Template {
+    /// Parses a template such as `oxipng -o 4 {output}`. Words are split on whitespace and may
+    /// be quoted with `'` or `"`; there is no shell, so the output path always stays a single
+    /// argument whatever characters it contains.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut words = Vec::new();
+        let mut word: Option<String> = None;
+        let mut quote = None;
+        for c in value.chars() {
+            match (quote, c) {
+                (Some(open), c) if c == open => quote = None,
+                (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+                (None, '\'' | '"') => {
+                    quote = Some(c);
+                    word.get_or_insert_with(String::new);
+                }
+                (None, c) if c.is_whitespace() => words.extend(word.take()),
+                (None, c) => word.get_or_insert_with(String::new).push(c),
+            }
+        }
+        if quote.is_some() {
+            return Err(format!("unbalanced quote in '{}'", value));
+        }
+        words.extend(word);
+
+        // The command must name a program and say where the output goes.
+        if words.is_empty() {
+            return Err("the command is empty".into());
+        }
+        if words[0].contains(OUTPUT_PLACEHOLDER) {
+            return Err(format!("{} cannot be the program", OUTPUT_PLACEHOLDER));
+        }
+        if !words.iter().any(|word| word.contains(OUTPUT_PLACEHOLDER)) {
+            return Err(format!(
+                "the command must contain {} where the output path goes",
+                OUTPUT_PLACEHOLDER
+            ));
+        }
+        Ok(Template { words })
+    }
+}
+
+/// Runs the `--postprocess` command on written outputs, at most `jobs` at a time.
+#[derive(Debug)]
+pub struct PostProcessor {
+    template: Template,
+    jobs: usize,
+    running: Mutex<usize>,
+    finished: Condvar,
+}
+
+impl PostProcessor {
+    /// Creates a post-processor running at most `jobs` commands at once.
+    pub fn new(template: Template, jobs: usize) -> Self {
+        PostProcessor {
+            template,
+            jobs: jobs.max(1),
+            running: Mutex::new(0),
+            finished: Condvar::new(),
+        }
+    }
+
+    /// Runs the command for one output, waiting for a free slot first.
+    /// Returns an error describing why the command could not run or did not succeed.
+    pub fn run(&self, output_path: &Path) -> Result<(), String> {
+        // Wait for one of the `jobs` slots.
+        {
+            let mut running = self.running.lock().unwrap();
+            while *running >= self.jobs {
+                running = self.finished.wait(running).unwrap();
+            }
+            *running += 1;
+        }
+
+        let output = output_path.to_string_lossy();
+        let mut words = self
+            .template
+            .words
+            .iter()
+            .map(|word| word.replace(OUTPUT_PLACEHOLDER, &output));
+        let program = words.next().unwrap_or_default();
+        let status = Command::new(&program).args(words).status();
+
+        // Free the slot before reporting.
+        *self.running.lock().unwrap() -= 1;
+        self.finished.notify_one();
+
+        match status {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(format!("{} exited with {}", program, status)),
+            Err(e) => Err(format!("could not run {}: {}", program, e)),
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Per-file progress notification emitted by the batch functions.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum ProgressEvent {
+    /// Work on the input has begun.
+    Started { input: PathBuf },
+    /// The output was planned but not written (dry run).
+    Planned { input: PathBuf, output: PathBuf },
+    /// The output was written.
+    Finished { input: PathBuf, output: PathBuf },
+    /// The input was deliberately not processed.
+    Skipped { input: PathBuf, reason: String },
+    /// Processing the input failed.
+    Failed { input: PathBuf, error: String },
+}
+
+/// Optional callback observing the progress of a batch; called from worker threads.
+/// The CLI passes one that logs each event, or writes it as JSON with `--progress-json`;
+/// rico is a binary only, so there is no outside caller to pass another.
+pub type Progress<'a> = Option<&'a (dyn Fn(ProgressEvent) + Sync)>;
+
+/// Number of files that reached each final state so far.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+struct Counts {
+    ok: usize,
+    planned: usize,
+    skipped: usize,
+    failed: usize,
+}
+
+/// Progress observer for `--progress-json`: writes every event as one JSON object per line
+/// (NDJSON) to stderr, for wrapping processes such as GUIs to parse.
+#[derive(Debug, Default)]
+pub struct JsonProgress {
+    counts: Mutex<Counts>,
+}
+
+impl JsonProgress {
+    /// Writes one event line; events that finish a file also carry the running counts.
+    pub fn observe(&self, event: ProgressEvent) {
+        let Ok(mut line) = serde_json::to_value(&event) else {
+            return;
+        };
+        if !matches!(event, ProgressEvent::Started { .. }) {
+            // Count under the lock, so every line carries a consistent snapshot.
+            let mut counts = self.counts.lock().unwrap();
+            match event {
+                ProgressEvent::Finished { .. } => counts.ok += 1,
+                ProgressEvent::Planned { .. } => counts.planned += 1,
+                ProgressEvent::Skipped { .. } => counts.skipped += 1,
+                ProgressEvent::Failed { .. } => counts.failed += 1,
+                ProgressEvent::Started { .. } => {}
+            }
+            line["completed"] =
+                (counts.ok + counts.planned + counts.skipped + counts.failed).into();
+        }
+        eprintln!("{}", line);
+    }
+
+    /// Writes the final `summary` event with the totals of the batch.
+    pub fn finish(&self) {
+        let counts = *self.counts.lock().unwrap();
+        let mut line = serde_json::json!({ "event": "summary" });
+        if let Ok(serde_json::Value::Object(totals)) = serde_json::to_value(counts) {
+            line.as_object_mut().unwrap().extend(totals);
+        }
+        eprintln!("{}", line);
+    }
+}
@@ -0,0 +1,185 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, GrayImage, RgbaImage};
+use std::f64::consts::PI;
+
+/// Side of the square windows SSIM is computed over.
+const SSIM_WINDOW: u32 = 8;
+/// Step between neighboring SSIM windows.
+const SSIM_STRIDE: u32 = 4;
+
+/// Peak signal-to-noise ratio (in dB) between two equally sized images over their RGB channels.
+/// Identical images give infinity.
+pub fn psnr(reference: &DynamicImage, candidate: &DynamicImage) -> f64 {
+    let (a, b) = (reference.to_rgb8(), candidate.to_rgb8());
+    let squared_error: f64 = a
+        .as_raw()
+        .iter()
+        .zip(b.as_raw())
+        .map(|(&x, &y)| (x as f64 - y as f64).powi(2))
+        .sum();
+    let mse = squared_error / a.as_raw().len().max(1) as f64;
+    if mse == 0.0 {
+        f64::INFINITY
+    } else {
+        10.0 * (255.0 * 255.0 / mse).log10()
+    }
+}
+
+/// Mean structural similarity (SSIM) between two equally sized images, computed on luma
+/// over overlapping windows. 1.0 means identical.
+pub fn ssim(reference: &DynamicImage, candidate: &DynamicImage) -> f64 {
+    let (a, b) = (reference.to_luma8(), candidate.to_luma8());
+    let (width, height) = a.dimensions();
+    let window_w = SSIM_WINDOW.min(width);
+    let window_h = SSIM_WINDOW.min(height);
+
+    // Average the SSIM of every window position.
+    let (mut total, mut count) = (0.0, 0u32);
+    let mut y = 0;
+    while y + window_h <= height {
+        let mut x = 0;
+        while x + window_w <= width {
+            total += window_ssim(&a, &b, x, y, window_w, window_h);
+            count += 1;
+            x += SSIM_STRIDE;
+        }
+        y += SSIM_STRIDE;
+    }
+    if count == 0 {
+        1.0
+    } else {
+        total / count as f64
+    }
+}
+
+/// SSIM of one window, using the standard stabilizing constants for 8-bit data.
+fn window_ssim(a: &GrayImage, b: &GrayImage, x: u32, y: u32, w: u32, h: u32) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    // Accumulate the first and second moments of both windows.
+    let n = (w * h) as f64;
+    let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+    for wy in y..y + h {
+        for wx in x..x + w {
+            let pa = a.get_pixel(wx, wy)[0] as f64;
+            let pb = b.get_pixel(wx, wy)[0] as f64;
+            sum_a += pa;
+            sum_b += pb;
+            sum_aa += pa * pa;
+            sum_bb += pb * pb;
+            sum_ab += pa * pb;
+        }
+    }
+    let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+    let var_a = sum_aa / n - mean_a * mean_a;
+    let var_b = sum_bb / n - mean_b * mean_b;
+    let covariance = sum_ab / n - mean_a * mean_b;
+
+    ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+        / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2))
+}
+
+/// How the alpha channels of two equally sized images differ.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AlphaDiff {
+    /// Pixels whose alpha differs by more than the threshold.
+    pub differing: u64,
+    /// Total number of pixels compared.
+    pub total: u64,
+    /// Inclusive bounding box `(left, top, right, bottom)` of the differing pixels.
+    pub bounds: Option<(u32, u32, u32, u32)>,
+}
+
+impl AlphaDiff {
+    /// Share of differing pixels, in percent.
+    pub fn percent(&self) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            self.differing as f64 * 100.0 / self.total as f64
+        }
+    }
+}
+
+/// Compares the alpha channels of two equally sized images, counting the pixels whose
+/// alpha differs by more than `threshold` and the bounding box they span.
+pub fn alpha_diff(reference: &RgbaImage, candidate: &RgbaImage, threshold: u8) -> AlphaDiff {
+    let mut diff = AlphaDiff {
+        differing: 0,
+        total: reference.width() as u64 * reference.height() as u64,
+        bounds: None,
+    };
+    for (x, y, pixel) in reference.enumerate_pixels() {
+        if pixel[3].abs_diff(candidate.get_pixel(x, y)[3]) <= threshold {
+            continue;
+        }
+        diff.differing += 1;
+        // Grow the bounding box to include this pixel.
+        diff.bounds = Some(match diff.bounds {
+            Some((left, top, right, bottom)) => {
+                (left.min(x), top.min(y), right.max(x), bottom.max(y))
+            }
+            None => (x, y, x, y),
+        });
+    }
+    diff
+}
+
+/// Side of the grayscale thumbnail the perceptual hash is computed from.
+const PHASH_SAMPLE_SIZE: u32 = 32;
+/// Side of the block of lowest DCT frequencies that make up the hash.
+const PHASH_BLOCK: usize = 8;
+
+/// 64-bit perceptual hash (pHash) of an image: one bit per low DCT frequency of a
+/// 32x32 grayscale thumbnail, set when the coefficient is above the median. Visually
+/// similar images get hashes with a small Hamming distance.
+pub fn phash(img: &DynamicImage) -> u64 {
+    let size = PHASH_SAMPLE_SIZE as usize;
+    let sample = img
+        .resize_exact(PHASH_SAMPLE_SIZE, PHASH_SAMPLE_SIZE, FilterType::Triangle)
+        .to_luma8();
+
+    // Cosine basis of the lowest frequencies, shared by both passes of the separable DCT.
+    let basis: Vec<Vec<f64>> = (0..PHASH_BLOCK)
+        .map(|u| {
+            (0..size)
+                .map(|x| ((2 * x + 1) as f64 * u as f64 * PI / (2 * size) as f64).cos())
+                .collect()
+        })
+        .collect();
+
+    // Transform the rows, then the columns, keeping only the low frequencies.
+    let rows: Vec<[f64; PHASH_BLOCK]> = sample
+        .rows()
+        .map(|row| {
+            let pixels: Vec<f64> = row.map(|pixel| pixel[0] as f64).collect();
+            let mut coefficients = [0.0; PHASH_BLOCK];
+            for (u, coefficient) in coefficients.iter_mut().enumerate() {
+                *coefficient = pixels.iter().zip(&basis[u]).map(|(p, c)| p * c).sum();
+            }
+            coefficients
+        })
+        .collect();
+    let mut coefficients = Vec::with_capacity(PHASH_BLOCK * PHASH_BLOCK);
+    for column_basis in &basis {
+        for u in 0..PHASH_BLOCK {
+            coefficients.push(
+                rows.iter()
+                    .zip(column_basis)
+                    .map(|(row, c)| row[u] * c)
+                    .sum(),
+            );
+        }
+    }
+
+    // The DC term only encodes overall brightness, so it is left out of the median.
+    let mut ac: Vec<f64> = coefficients[1..].to_vec();
+    ac.sort_by(|a, b| a.total_cmp(b));
+    let median = ac[ac.len() / 2];
+    coefficients
+        .iter()
+        .enumerate()
+        .filter(|&(_, &coefficient)| coefficient > median)
+        .fold(0, |hash, (bit, _)| hash | 1 << bit)
+}
@@ -0,0 +1,259 @@
+use crate::error::RicoError;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Final state of one input file in a batch run.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// The output would be written (dry run).
+    Planned,
+    /// The output was written.
+    Ok,
+    /// The input was deliberately not processed.
+    Skipped,
+    /// Processing the input failed.
+    Failed,
+}
+
+impl Status {
+    /// Lowercase name of the status, as used in every report format.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::Planned => "planned",
+            Status::Ok => "ok",
+            Status::Skipped => "skipped",
+            Status::Failed => "failed",
+        }
+    }
+}
+
+/// File format of the machine-readable report.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// A single pretty-printed JSON array.
+    #[default]
+    Json,
+    /// One JSON object per line, suitable for streaming.
+    Ndjson,
+    /// Comma-separated values with a header row.
+    Csv,
+}
+
+/// Where an `--autocrop`ped cutout came from: the offset of the crop in the uncropped cutout
+/// and that cutout's size, so consumers can put the crop back in place.
+#[derive(Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+pub struct CropBox {
+    pub x: u32,
+    pub y: u32,
+    pub orig_w: u32,
+    pub orig_h: u32,
+}
+
+/// One per-file entry of the machine-readable report.
+#[derive(Clone, Debug, Serialize)]
+pub struct Record {
+    pub input: PathBuf,
+    pub output: Option<PathBuf>,
+    pub format: String,
+    pub transforms: Vec<String>,
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blurhash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crop: Option<CropBox>,
+}
+
+/// Thread-safe collector for the per-file records of a batch run.
+#[derive(Default)]
+pub struct Report {
+    records: Mutex<Vec<Record>>,
+}
+
+impl Report {
+    /// Adds a record; safe to call from rayon workers.
+    pub fn push(&self, record: Record) {
+        self.records.lock().unwrap().push(record);
+    }
+
+    /// Returns the records collected so far, ordered by input path.
+    pub fn records(&self) -> Vec<Record> {
+        let mut records = self.records.lock().unwrap().clone();
+        records.sort_by(|a, b| a.input.cmp(&b.input));
+        records
+    }
+
+    /// Returns true when no file was recorded.
+    pub fn is_empty(&self) -> bool {
+        self.records.lock().unwrap().is_empty()
+    }
+
+    /// Returns how many files ended with the given status.
+    pub fn count(&self, status: Status) -> usize {
+        self.records
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|record| record.status == status)
+            .count()
+    }
+
+    /// Summarizes the run in a few lines: counts per status, then how often each skip reason occurred.
+    pub fn summary(&self, elapsed: Duration) -> Vec<String> {
+        let records = self.records();
+        let count = |status: Status| records.iter().filter(|r| r.status == status).count();
+        let mut lines = vec![format!(
+            "Summary: {} files in {:.2}s: {} ok, {} planned, {} skipped, {} failed",
+            records.len(),
+            elapsed.as_secs_f64(),
+            count(Status::Ok),
+            count(Status::Planned),
+            count(Status::Skipped),
+            count(Status::Failed)
+        )];
+
+        // Group the skipped files by reason, most frequent first.
+        let mut reasons: BTreeMap<&str, usize> = BTreeMap::new();
+        for record in records.iter().filter(|r| r.status == Status::Skipped) {
+            *reasons
+                .entry(record.reason.as_deref().unwrap_or("unknown"))
+                .or_default() += 1;
+        }
+        let mut reasons: Vec<(&str, usize)> = reasons.into_iter().collect();
+        reasons.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+        for (reason, count) in reasons {
+            lines.push(format!("  skipped ({}): {}", reason, count));
+        }
+        lines
+    }
+
+    /// Returns every output path that more than one input was written or planned to, with
+    /// those inputs, so a dry run can reveal files that would overwrite each other.
+    pub fn collisions(&self) -> Vec<(PathBuf, Vec<PathBuf>)> {
+        let mut inputs: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+        for record in self.records() {
+            if let (Some(output), Status::Ok | Status::Planned) = (record.output, record.status) {
+                inputs.entry(output).or_default().push(record.input);
+            }
+        }
+        inputs
+            .into_iter()
+            .filter(|(_, inputs)| inputs.len() > 1)
+            .collect()
+    }
+
+    /// Groups the outputs whose perceptual hashes are at most `max_distance` bits apart,
+    /// transitively; outputs without a similar one are left out.
+    pub fn similar_groups(&self, max_distance: u32) -> Vec<Vec<PathBuf>> {
+        let hashed: Vec<(PathBuf, u64)> = self
+            .records()
+            .into_iter()
+            .filter_map(|record| {
+                let hash = u64::from_str_radix(record.phash.as_deref()?, 16).ok()?;
+                Some((record.output?, hash))
+            })
+            .collect();
+
+        // Union-find over all pairs that are close enough.
+        let mut parents: Vec<usize> = (0..hashed.len()).collect();
+        fn root(parents: &mut [usize], mut i: usize) -> usize {
+            while parents[i] != i {
+                parents[i] = parents[parents[i]];
+                i = parents[i];
+            }
+            i
+        }
+        for i in 0..hashed.len() {
+            for j in i + 1..hashed.len() {
+                // The Hamming distance is the number of differing bits.
+                if (hashed[i].1 ^ hashed[j].1).count_ones() <= max_distance {
+                    let (a, b) = (root(&mut parents, i), root(&mut parents, j));
+                    parents[b] = a;
+                }
+            }
+        }
+
+        // Collect the members of every group with more than one output, in input order.
+        let mut groups: BTreeMap<usize, Vec<PathBuf>> = BTreeMap::new();
+        for (i, (output, _)) in hashed.iter().enumerate() {
+            let group = root(&mut parents, i);
+            groups.entry(group).or_default().push(output.clone());
+        }
+        groups
+            .into_values()
+            .filter(|group| group.len() > 1)
+            .collect()
+    }
+
+    /// Writes the records to `path` in the given format.
+    pub fn write(&self, path: &Path, format: ReportFormat) -> Result<(), RicoError> {
+        let records = self.records();
+        let contents = match format {
+            ReportFormat::Json => serde_json::to_string_pretty(&records)?,
+            ReportFormat::Ndjson => {
+                // One compact object per line, each terminated by a newline.
+                let mut lines = String::new();
+                for record in &records {
+                    lines.push_str(&serde_json::to_string(record)?);
+                    lines.push('\n');
+                }
+                lines
+            }
+            ReportFormat::Csv => {
+                // Header row, then one row per record; transforms are joined with ';'.
+                let mut rows = String::from(
+                    "input,output,format,transforms,status,reason,blurhash,phash,crop_x,crop_y,orig_w,orig_h\n",
+                );
+                for record in &records {
+                    let fields = [
+                        record.input.to_string_lossy().into_owned(),
+                        record
+                            .output
+                            .as_ref()
+                            .map(|output| output.to_string_lossy().into_owned())
+                            .unwrap_or_default(),
+                        record.format.clone(),
+                        record.transforms.join(";"),
+                        record.status.as_str().to_string(),
+                        record.reason.clone().unwrap_or_default(),
+                        record.blurhash.clone().unwrap_or_default(),
+                        record.phash.clone().unwrap_or_default(),
+                    ];
+                    // The --autocrop box is left empty for uncropped records.
+                    let crop: [String; 4] = match record.crop {
+                        Some(crop) => [crop.x, crop.y, crop.orig_w, crop.orig_h]
+                            .map(|value| value.to_string()),
+                        None => Default::default(),
+                    };
+                    let fields: Vec<String> = fields
+                        .iter()
+                        .chain(&crop)
+                        .map(|field| csv_field(field))
+                        .collect();
+                    rows.push_str(&fields.join(","));
+                    rows.push('\n');
+                }
+                rows
+            }
+        };
+        fs::write(path, contents)?;
+        Ok(())
+    }
+}
+
+/// Quotes a CSV field when it contains a separator, quote or line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
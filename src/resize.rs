@@ -0,0 +1,209 @@
+use image::{imageops::FilterType, DynamicImage};
+
+/// Resampling filter used by `--filter`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Filter {
+    /// Nearest-neighbor sampling.
+    Nearest,
+    /// Linear (bilinear) filtering.
+    Triangle,
+    /// Cubic Catmull-Rom filtering.
+    CatmullRom,
+    /// Gaussian filtering.
+    Gaussian,
+    /// Lanczos filtering with a window of 3.
+    #[default]
+    Lanczos3,
+}
+
+impl Filter {
+    /// Parses the `--filter` value.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "nearest" => Ok(Filter::Nearest),
+            "triangle" => Ok(Filter::Triangle),
+            "catmull-rom" => Ok(Filter::CatmullRom),
+            "gaussian" => Ok(Filter::Gaussian),
+            "lanczos3" => Ok(Filter::Lanczos3),
+            _ => Err(format!(
+                "expected nearest, triangle, catmull-rom, gaussian or lanczos3 but got '{}'",
+                value
+            )),
+        }
+    }
+
+    /// The matching `image` crate filter.
+    fn image_filter(self) -> FilterType {
+        match self {
+            Filter::Nearest => FilterType::Nearest,
+            Filter::Triangle => FilterType::Triangle,
+            Filter::CatmullRom => FilterType::CatmullRom,
+            Filter::Gaussian => FilterType::Gaussian,
+            Filter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+
+    /// The matching `fast_image_resize` algorithm.
+    #[cfg(feature = "fir")]
+    fn fir_algorithm(self) -> fast_image_resize::ResizeAlg {
+        use fast_image_resize::{FilterType as FirFilter, ResizeAlg};
+        match self {
+            Filter::Nearest => ResizeAlg::Nearest,
+            Filter::Triangle => ResizeAlg::Convolution(FirFilter::Bilinear),
+            Filter::CatmullRom => ResizeAlg::Convolution(FirFilter::CatmullRom),
+            Filter::Gaussian => ResizeAlg::Convolution(FirFilter::Gaussian),
+            Filter::Lanczos3 => ResizeAlg::Convolution(FirFilter::Lanczos3),
+        }
+    }
+}
+
+/// How `--width` and `--height` together shape the output, chosen with `--resize-mode`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResizeMode {
+    /// Fit within WxH keeping the aspect ratio; one side may come out smaller.
+    #[default]
+    Contain,
+    /// Fill WxH keeping the aspect ratio, cropping the overflow around the center.
+    Cover,
+    /// Stretch to exactly WxH, ignoring the aspect ratio.
+    Fill,
+}
+
+impl ResizeMode {
+    /// Parses the `--resize-mode` value.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "contain" => Ok(ResizeMode::Contain),
+            "cover" => Ok(ResizeMode::Cover),
+            "fill" => Ok(ResizeMode::Fill),
+            _ => Err(format!(
+                "expected contain, cover or fill but got '{}'",
+                value
+            )),
+        }
+    }
+}
+
+/// How `--align` reaches a multiple of N, chosen with `--align-mode`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AlignMode {
+    /// Grow each side to the next multiple, padding around the image.
+    #[default]
+    Pad,
+    /// Shrink each side to the previous multiple, cropping around the center.
+    Crop,
+}
+
+impl AlignMode {
+    /// Parses the `--align-mode` value.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "pad" => Ok(AlignMode::Pad),
+            "crop" => Ok(AlignMode::Crop),
+            _ => Err(format!("expected pad or crop but got '{}'", value)),
+        }
+    }
+}
+
+/// Works out the size `--align` brings an image to: both sides a multiple of `multiple`.
+/// Returns `None` when cropping would leave nothing of a side shorter than `multiple`.
+pub fn aligned_dimensions(
+    (width, height): (u32, u32),
+    multiple: u32,
+    mode: AlignMode,
+) -> Option<(u32, u32)> {
+    let align = |side: u32| match mode {
+        AlignMode::Pad => side.checked_next_multiple_of(multiple),
+        AlignMode::Crop => Some(side / multiple * multiple).filter(|&side| side > 0),
+    };
+    Some((align(width)?, align(height)?))
+}
+
+/// Works out the size an image is scaled to before the `--resize-mode cover` crop: the
+/// smallest size keeping the aspect ratio that covers `target_width` x `target_height`.
+pub fn cover_dimensions(
+    (width, height): (u32, u32),
+    target_width: u32,
+    target_height: u32,
+) -> (u32, u32) {
+    let scale = (target_width as f64 / width as f64).max(target_height as f64 / height as f64);
+    // Rounding must never leave a side short of the target, or the crop could not fill it.
+    (
+        ((width as f64 * scale).round() as u32).max(target_width),
+        ((height as f64 * scale).round() as u32).max(target_height),
+    )
+}
+
+/// Works out the output size for `--width`/`--height`.
+/// With one side given the other follows the aspect ratio; with both the image fits within them.
+pub fn target_dimensions(
+    (width, height): (u32, u32),
+    target_width: Option<u32>,
+    target_height: Option<u32>,
+) -> (u32, u32) {
+    // Scale factor that keeps the aspect ratio while honoring the given sides.
+    let scale = match (target_width, target_height) {
+        (Some(w), Some(h)) => (w as f64 / width as f64).min(h as f64 / height as f64),
+        (Some(w), None) => w as f64 / width as f64,
+        (None, Some(h)) => h as f64 / height as f64,
+        (None, None) => return (width, height),
+    };
+    let scaled = |side: u32| ((side as f64 * scale).round() as u32).max(1);
+    (scaled(width), scaled(height))
+}
+
+/// Resizes the image to exactly `width` x `height` with the given filter.
+#[cfg(not(feature = "fir"))]
+pub fn resize(img: &DynamicImage, width: u32, height: u32, filter: Filter) -> DynamicImage {
+    img.resize_exact(width, height, filter.image_filter())
+}
+
+/// Resizes the image to exactly `width` x `height` with the given filter, using SIMD
+/// through `fast_image_resize` and falling back to the `image` crate if it reports an error.
+#[cfg(feature = "fir")]
+pub fn resize(img: &DynamicImage, width: u32, height: u32, filter: Filter) -> DynamicImage {
+    use fast_image_resize::{images::Image, PixelType, ResizeOptions, Resizer};
+
+    // Wrap the RGBA pixels in FIR's image type.
+    let rgba = img.to_rgba8();
+    let (src_width, src_height) = rgba.dimensions();
+    let resized = Image::from_vec_u8(src_width, src_height, rgba.into_raw(), PixelType::U8x4)
+        .ok()
+        .and_then(|src| {
+            // Resize into a new buffer; FIR handles the alpha multiplication itself.
+            let mut dst = Image::new(width, height, PixelType::U8x4);
+            let options = ResizeOptions::new().resize_alg(filter.fir_algorithm());
+            Resizer::new().resize(&src, &mut dst, &options).ok()?;
+            image::RgbaImage::from_raw(width, height, dst.into_vec())
+        });
+
+    match resized {
+        Some(resized) => DynamicImage::ImageRgba8(resized),
+        None => img.resize_exact(width, height, filter.image_filter()),
+    }
+}
+
+#[cfg(all(test, feature = "fir"))]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    #[test]
+    fn fir_resize_matches_the_image_crate() {
+        // A smooth, opaque gradient, so both resizers agree up to rounding.
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(64, 48, |x, y| {
+            Rgba([(x * 4) as u8, (y * 5) as u8, ((x + y) * 2) as u8, 255])
+        }));
+
+        for filter in [Filter::Triangle, Filter::CatmullRom, Filter::Lanczos3] {
+            let fir = resize(&img, 20, 15, filter).to_rgba8();
+            let reference = img.resize_exact(20, 15, filter.image_filter()).to_rgba8();
+            assert_eq!(fir.dimensions(), (20, 15));
+            for (a, b) in fir.pixels().zip(reference.pixels()) {
+                for (&a, &b) in a.0.iter().zip(&b.0) {
+                    assert!(a.abs_diff(b) <= 6, "{:?}: {:?} vs {:?}", filter, a, b);
+                }
+            }
+        }
+    }
+}
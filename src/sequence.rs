@@ -0,0 +1,48 @@
+use crate::error::RicoError;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// Name of the file in the output directory that remembers the last number of each sequence.
+pub const STATE_FILE_NAME: &str = ".rico-sequence.json";
+
+/// Last number used per sequence prefix, persisted so later runs continue where earlier ones stopped.
+#[derive(Debug, Default)]
+pub struct SequenceState {
+    last: BTreeMap<String, u64>,
+}
+
+impl SequenceState {
+    /// Loads the state from the output directory; a missing file means no sequence has started yet.
+    pub fn load(output_dir: &Path) -> Result<Self, RicoError> {
+        let path = output_dir.join(STATE_FILE_NAME);
+        if !path.exists() {
+            return Ok(SequenceState::default());
+        }
+        let last = serde_json::from_str(&fs::read_to_string(&path)?).map_err(|e| {
+            RicoError::InvalidFile(format!("invalid sequence state {:?}: {}", path, e))
+        })?;
+        Ok(SequenceState { last })
+    }
+
+    /// Returns the last number used for the prefix, if any.
+    pub fn last(&self, prefix: &str) -> Option<u64> {
+        self.last.get(prefix).copied()
+    }
+
+    /// Records the last number used for the prefix and saves the state to the output directory.
+    pub fn save(&mut self, output_dir: &Path, prefix: &str, last: u64) -> Result<(), RicoError> {
+        self.last.insert(prefix.to_string(), last);
+        fs::create_dir_all(output_dir)?;
+        fs::write(
+            output_dir.join(STATE_FILE_NAME),
+            serde_json::to_string_pretty(&self.last)?,
+        )?;
+        Ok(())
+    }
+}
+
+/// Builds the file stem of a sequence output, e.g. `frame_0007` for prefix `frame_`, number 7 and pad 4.
+pub fn stem(prefix: &str, number: u64, pad: usize) -> String {
+    format!("{}{:0width$}", prefix, number, width = pad)
+}
@@ -0,0 +1,88 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Stem used when nothing URL-safe is left of a file name.
+const EMPTY_SLUG: &str = "image";
+
+/// Turns a file stem into a URL-safe slug: lowercase ASCII letters, digits, `.` and `_`,
+/// with every other run of characters collapsed into a single `-`.
+pub fn slugify(stem: &str) -> String {
+    let mut slug = String::with_capacity(stem.len());
+    let mut pending_hyphen = false;
+    for c in stem.chars() {
+        if c.is_ascii_alphanumeric() || c == '.' || c == '_' {
+            // Separators are only written between kept characters, never at either end.
+            if pending_hyphen && !slug.is_empty() {
+                slug.push('-');
+            }
+            pending_hyphen = false;
+            slug.push(c.to_ascii_lowercase());
+        } else if c.is_whitespace() || c == '-' {
+            pending_hyphen = true;
+        }
+        // Anything else, such as brackets or non-ASCII letters, is dropped.
+    }
+
+    // A stem of only dots would name a hidden file or a parent directory.
+    if slug.trim_matches('.').is_empty() {
+        EMPTY_SLUG.to_string()
+    } else {
+        slug
+    }
+}
+
+/// Assigns every file a slugged output stem, numbering stems that collide within a
+/// directory (`my-photo`, `my-photo-2`, ...) in sorted input order.
+pub fn stems(files: &[PathBuf]) -> HashMap<PathBuf, String> {
+    let mut sorted: Vec<&PathBuf> = files.iter().collect();
+    sorted.sort();
+
+    let mut used: HashMap<&Path, HashSet<String>> = HashMap::new();
+    let mut stems = HashMap::with_capacity(files.len());
+    for file in sorted {
+        let base = slugify(&file.file_stem().unwrap_or_default().to_string_lossy());
+        let taken = used
+            .entry(file.parent().unwrap_or(Path::new("")))
+            .or_default();
+        let mut stem = base.clone();
+        let mut number = 2;
+        while taken.contains(&stem) {
+            stem = format!("{}-{}", base, number);
+            number += 1;
+        }
+        taken.insert(stem.clone());
+        stems.insert(file.clone(), stem);
+    }
+    stems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slugify_drops_emoji_and_non_ascii_letters() {
+        assert_eq!(slugify("🎉 Party Café"), "party-caf");
+        assert_eq!(slugify("My Photo (1)"), "my-photo-1");
+        assert_eq!(slugify("🎉🎉"), EMPTY_SLUG);
+        assert_eq!(slugify(".."), EMPTY_SLUG);
+        assert_eq!(slugify("写真"), EMPTY_SLUG);
+    }
+
+    #[test]
+    fn stems_number_names_that_slug_alike() {
+        let files = [
+            "dir/🎉.png",
+            "dir/写真.png",
+            "dir/Photo.png",
+            "dir/photo.jpg",
+        ]
+        .map(PathBuf::from);
+        let stems = stems(&files);
+        assert_eq!(stems[Path::new("dir/Photo.png")], "photo");
+        assert_eq!(stems[Path::new("dir/photo.jpg")], "photo-2");
+        // Sorted by path, the CJK name comes before the emoji.
+        assert_eq!(stems[Path::new("dir/写真.png")], "image");
+        assert_eq!(stems[Path::new("dir/🎉.png")], "image-2");
+    }
+}
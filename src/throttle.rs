@@ -0,0 +1,59 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Token-bucket style limiter shared across worker threads, handing out
+/// at most `rate` permits per second (with a burst of one).
+pub struct RateLimiter {
+    /// Time between two consecutive permits.
+    interval: Duration,
+    /// Earliest instant at which the next permit may be handed out.
+    next_slot: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    /// Creates a limiter allowing `rate` permits per second.
+    pub fn new(rate: f64) -> Self {
+        RateLimiter {
+            interval: Duration::from_secs_f64(1.0 / rate),
+            next_slot: Mutex::new(None),
+        }
+    }
+
+    /// Blocks the calling thread until it may proceed.
+    pub fn acquire(&self) {
+        // Reserve the next free slot while holding the lock...
+        let wait = {
+            let mut next_slot = self.next_slot.lock().unwrap();
+            let now = Instant::now();
+            let slot = next_slot.map_or(now, |slot| slot.max(now));
+            *next_slot = Some(slot + self.interval);
+            slot - now
+        };
+        // ...then sleep outside of it so other workers can queue up behind us.
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permits_are_spaced_across_threads() {
+        let limiter = RateLimiter::new(20.0);
+        let started = Instant::now();
+        // Six permits at 20 per second: the first is immediate, the other five 50 ms apart.
+        thread::scope(|scope| {
+            for _ in 0..3 {
+                scope.spawn(|| {
+                    limiter.acquire();
+                    limiter.acquire();
+                });
+            }
+        });
+        assert!(started.elapsed() >= Duration::from_millis(250));
+    }
+}
@@ -0,0 +1,108 @@
+use exif::experimental::Writer;
+use exif::{Field, In, Reader, Tag, Value};
+use image::{DynamicImage, ImageOutputFormat};
+use img_parts::jpeg::{markers, Jpeg, JpegSegment};
+use img_parts::Bytes;
+use std::io::Cursor;
+
+/// Longest side of generated thumbnails; 160x120 is the classic EXIF thumbnail size.
+pub const THUMBNAIL_SIZE: u32 = 160;
+
+/// JPEG quality of generated thumbnails.
+const THUMBNAIL_QUALITY: u8 = 75;
+
+/// Identifier that starts the EXIF APP1 segment of a JPEG.
+const EXIF_PREFIX: &[u8] = b"Exif\0\0";
+
+/// Largest EXIF block that fits in a single APP1 segment (64 KiB minus its length and prefix).
+const MAX_EXIF_BYTES: usize = 65533 - EXIF_PREFIX.len();
+
+/// Encodes a JPEG thumbnail of the image that fits within `THUMBNAIL_SIZE` on both sides.
+pub fn generate(img: &DynamicImage) -> Result<Vec<u8>, String> {
+    let thumbnail =
+        DynamicImage::ImageRgb8(img.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE).to_rgb8());
+    let mut bytes = Cursor::new(Vec::new());
+    thumbnail
+        .write_to(&mut bytes, ImageOutputFormat::Jpeg(THUMBNAIL_QUALITY))
+        .map_err(|e| format!("could not encode thumbnail: {}", e))?;
+    Ok(bytes.into_inner())
+}
+
+/// Returns the JPEG thumbnail embedded in an image's EXIF data, if it has one.
+pub fn extract(bytes: &[u8]) -> Option<Vec<u8>> {
+    let exif = Reader::new()
+        .read_from_container(&mut Cursor::new(bytes))
+        .ok()?;
+    // The thumbnail's offset (from the start of the EXIF data) and length live in IFD1.
+    let offset = exif
+        .get_field(Tag::JPEGInterchangeFormat, In::THUMBNAIL)?
+        .value
+        .get_uint(0)? as usize;
+    let length = exif
+        .get_field(Tag::JPEGInterchangeFormatLength, In::THUMBNAIL)?
+        .value
+        .get_uint(0)? as usize;
+    exif.buf()
+        .get(offset..offset.checked_add(length)?)
+        .map(<[u8]>::to_vec)
+}
+
+/// Embeds `thumbnail` (a JPEG) into the EXIF data of an encoded JPEG, replacing any EXIF
+/// data it had, so file managers can show it without decoding the full image.
+pub fn embed(jpeg: &[u8], thumbnail: &[u8]) -> Result<Vec<u8>, String> {
+    // IFD0 needs at least one entry; the resolution tags are the ones EXIF requires there.
+    let resolution = |tag| Field {
+        tag,
+        ifd_num: In::PRIMARY,
+        value: Value::Rational(vec![(72, 1).into()]),
+    };
+    let fields = [
+        resolution(Tag::XResolution),
+        resolution(Tag::YResolution),
+        Field {
+            tag: Tag::ResolutionUnit,
+            ifd_num: In::PRIMARY,
+            value: Value::Short(vec![2]),
+        },
+        // Compression 6 marks the IFD1 thumbnail as JPEG.
+        Field {
+            tag: Tag::Compression,
+            ifd_num: In::THUMBNAIL,
+            value: Value::Short(vec![6]),
+        },
+    ];
+    let mut writer = Writer::new();
+    for field in &fields {
+        writer.push_field(field);
+    }
+    writer.set_jpeg(thumbnail, In::THUMBNAIL);
+    let mut exif = Cursor::new(Vec::new());
+    writer
+        .write(&mut exif, false)
+        .map_err(|e| format!("could not write EXIF thumbnail: {}", e))?;
+    let exif = exif.into_inner();
+    if exif.len() > MAX_EXIF_BYTES {
+        return Err(format!(
+            "thumbnail of {} bytes does not fit in the EXIF segment",
+            thumbnail.len()
+        ));
+    }
+
+    // Put the APP1 segment first, after the JFIF header if there is one.
+    let mut image = Jpeg::from_bytes(Bytes::copy_from_slice(jpeg))
+        .map_err(|e| format!("could not parse the JPEG output: {}", e))?;
+    let segments = image.segments_mut();
+    segments.retain(|segment| {
+        segment.marker() != markers::APP1 || !segment.contents().starts_with(EXIF_PREFIX)
+    });
+    let position = usize::from(
+        segments
+            .first()
+            .is_some_and(|s| s.marker() == markers::APP0),
+    );
+    segments.insert(
+        position,
+        JpegSegment::new_with_contents(markers::APP1, Bytes::from([EXIF_PREFIX, &exif].concat())),
+    );
+    Ok(image.encoder().bytes().to_vec())
+}
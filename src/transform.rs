@@ -0,0 +1,829 @@
+use image::{
+    imageops, DynamicImage, GenericImageView, GrayImage, Luma, Rgb, RgbImage, Rgba, RgbaImage,
+};
+use rayon::prelude::*;
+
+/// Fixed margins (in pixels) to cut from each side of an image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Margins {
+    pub top: u32,
+    pub right: u32,
+    pub bottom: u32,
+    pub left: u32,
+}
+
+/// Crops the margins off all sides of the image.
+/// Returns `None` when the image is too small to leave any pixels behind.
+pub fn trim_border(img: &DynamicImage, margins: Margins) -> Option<DynamicImage> {
+    // Work out how much of each axis is being removed.
+    let horizontal = margins.left.checked_add(margins.right)?;
+    let vertical = margins.top.checked_add(margins.bottom)?;
+
+    // Skip images that would be cropped to nothing.
+    if horizontal >= img.width() || vertical >= img.height() {
+        return None;
+    }
+
+    // Cut the remaining rectangle out of the image.
+    Some(img.crop_imm(
+        margins.left,
+        margins.top,
+        img.width() - horizontal,
+        img.height() - vertical,
+    ))
+}
+
+/// Crops away the uniform margins around the content of the image. The border color is the
+/// corner color most of the other corners agree with; rows and columns are trimmed from each
+/// edge while all of their pixels are within `tolerance` of it on every channel.
+/// Returns `None` when the whole image matches the border color.
+pub fn trim_to_content(img: &DynamicImage, tolerance: u8) -> Option<DynamicImage> {
+    let rgba = img.to_rgba8();
+    let (width, height) = rgba.dimensions();
+    if width == 0 || height == 0 {
+        return None;
+    }
+    let matches = |a: &Rgba<u8>, b: &Rgba<u8>| {
+        a.0.iter()
+            .zip(b.0)
+            .all(|(&channel, reference)| channel.abs_diff(reference) <= tolerance)
+    };
+
+    // Vote on the border color, so a single corner touched by the content does not decide it.
+    let corners = [
+        *rgba.get_pixel(0, 0),
+        *rgba.get_pixel(width - 1, 0),
+        *rgba.get_pixel(0, height - 1),
+        *rgba.get_pixel(width - 1, height - 1),
+    ];
+    let border = *corners.iter().max_by_key(|corner| {
+        corners
+            .iter()
+            .filter(|other| matches(corner, other))
+            .count()
+    })?;
+
+    // Shrink the box from each side while its outermost row or column is all border.
+    let row_is_border = |y: u32, left: u32, right: u32| {
+        (left..right).all(|x| matches(rgba.get_pixel(x, y), &border))
+    };
+    let column_is_border = |x: u32, top: u32, bottom: u32| {
+        (top..bottom).all(|y| matches(rgba.get_pixel(x, y), &border))
+    };
+    let (mut top, mut bottom) = (0, height);
+    while top < bottom && row_is_border(top, 0, width) {
+        top += 1;
+    }
+    if top == bottom {
+        return None;
+    }
+    while row_is_border(bottom - 1, 0, width) {
+        bottom -= 1;
+    }
+    let (mut left, mut right) = (0, width);
+    while column_is_border(left, top, bottom) {
+        left += 1;
+    }
+    while column_is_border(right - 1, top, bottom) {
+        right -= 1;
+    }
+    Some(img.crop_imm(left, top, right - left, bottom - top))
+}
+
+/// Crops the image to the largest rectangle with the `width`:`height` aspect ratio, around the
+/// center or, with `smart`, where the most detail is (see `crop_to`).
+pub fn crop_to_aspect(
+    img: &DynamicImage,
+    (width, height): (u32, u32),
+    smart: bool,
+) -> DynamicImage {
+    let (img_width, img_height) = (img.width() as u64, img.height() as u64);
+
+    // Too wide images lose columns, too tall ones lose rows; never crop to nothing.
+    let (crop_width, crop_height) = if img_width * height as u64 > img_height * width as u64 {
+        (
+            (img_height * width as u64 / height as u64).max(1),
+            img_height,
+        )
+    } else {
+        (img_width, (img_width * height as u64 / width as u64).max(1))
+    };
+    crop_to(img, crop_width as u32, crop_height as u32, smart)
+}
+
+/// Crops the image to `width` x `height` (no larger than the image) around its center or,
+/// with `smart`, at the window keeping the most edge energy (Sobel gradient magnitude), so an
+/// off-center subject survives. Windows with equal energy, as in flat images, prefer the center.
+pub fn crop_to(img: &DynamicImage, width: u32, height: u32, smart: bool) -> DynamicImage {
+    let (img_width, img_height) = (img.width(), img.height());
+    let center = ((img_width - width) / 2, (img_height - height) / 2);
+    if !smart || (width, height) == (img_width, img_height) {
+        return img.crop_imm(center.0, center.1, width, height);
+    }
+
+    // Summed-area table of the edge energy, so every window sums in constant time.
+    let energy = imageproc::gradients::sobel_gradients(&img.to_luma8());
+    let stride = img_width as usize + 1;
+    let mut sums = vec![0u64; stride * (img_height as usize + 1)];
+    for (x, y, pixel) in energy.enumerate_pixels() {
+        let (x, y) = (x as usize, y as usize);
+        sums[(y + 1) * stride + x + 1] =
+            pixel[0] as u64 + sums[y * stride + x + 1] + sums[(y + 1) * stride + x]
+                - sums[y * stride + x];
+    }
+    let window_energy = |x: u32, y: u32| {
+        let (left, top) = (x as usize, y as usize);
+        let (right, bottom) = (left + width as usize, top + height as usize);
+        sums[bottom * stride + right] + sums[top * stride + left]
+            - sums[top * stride + right]
+            - sums[bottom * stride + left]
+    };
+
+    let (x, y) = (0..=img_width - width)
+        .flat_map(|x| (0..=img_height - height).map(move |y| (x, y)))
+        .max_by_key(|&(x, y)| {
+            let distance = x.abs_diff(center.0) + y.abs_diff(center.1);
+            (window_energy(x, y), std::cmp::Reverse(distance))
+        })
+        .unwrap_or(center);
+    img.crop_imm(x, y, width, height)
+}
+
+/// Rotates the image clockwise by any angle in degrees, growing the canvas to hold all of it.
+/// Exposed corners are painted `fill`, or left transparent without one. Quarter turns are
+/// exact; other angles are resampled bilinearly, row by row in parallel.
+pub fn rotate(img: &DynamicImage, degrees: f64, fill: Option<Rgba<u8>>) -> DynamicImage {
+    let degrees = degrees.rem_euclid(360.0);
+    if degrees == 0.0 {
+        return img.clone();
+    } else if degrees == 90.0 {
+        return img.rotate90();
+    } else if degrees == 180.0 {
+        return img.rotate180();
+    } else if degrees == 270.0 {
+        return img.rotate270();
+    }
+
+    let source = img.to_rgba8();
+    let (width, height) = (source.width() as f64, source.height() as f64);
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    // The bounding box of the rotated rectangle; the epsilon keeps float noise from adding a pixel.
+    let canvas_width = (width * cos.abs() + height * sin.abs() - 1e-6)
+        .ceil()
+        .max(1.0) as u32;
+    let canvas_height = (width * sin.abs() + height * cos.abs() - 1e-6)
+        .ceil()
+        .max(1.0) as u32;
+    let background = fill.unwrap_or(Rgba([0, 0, 0, 0]));
+
+    let mut output = RgbaImage::new(canvas_width, canvas_height);
+    output
+        .par_chunks_mut(canvas_width as usize * 4)
+        .enumerate()
+        .for_each(|(y, row)| {
+            let dy = y as f64 + 0.5 - canvas_height as f64 / 2.0;
+            for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                let dx = x as f64 + 0.5 - canvas_width as f64 / 2.0;
+                // Rotate the output pixel center back into the source, counterclockwise.
+                let source_x = dx * cos + dy * sin + width / 2.0 - 0.5;
+                let source_y = -dx * sin + dy * cos + height / 2.0 - 0.5;
+                pixel.copy_from_slice(&sample_bilinear(&source, source_x, source_y, background).0);
+            }
+        });
+
+    // Opaque images rotated onto an opaque fill need no alpha channel.
+    if img.color().has_alpha() || fill.is_none() {
+        DynamicImage::ImageRgba8(output)
+    } else {
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(output).to_rgb8())
+    }
+}
+
+/// Samples the image at a fractional position, blending the four nearest pixels; positions
+/// outside the image read `background`. Colors are blended weighted by their alpha so
+/// transparent neighbors do not darken the edges.
+fn sample_bilinear(img: &RgbaImage, x: f64, y: f64, background: Rgba<u8>) -> Rgba<u8> {
+    let (left, top) = (x.floor(), y.floor());
+    let (fx, fy) = (x - left, y - top);
+    let pixel_at = |px: f64, py: f64| -> Rgba<u8> {
+        if px < 0.0 || py < 0.0 || px >= img.width() as f64 || py >= img.height() as f64 {
+            background
+        } else {
+            *img.get_pixel(px as u32, py as u32)
+        }
+    };
+
+    let mut color = [0.0f64; 3];
+    let mut alpha = 0.0f64;
+    for (px, py, weight) in [
+        (left, top, (1.0 - fx) * (1.0 - fy)),
+        (left + 1.0, top, fx * (1.0 - fy)),
+        (left, top + 1.0, (1.0 - fx) * fy),
+        (left + 1.0, top + 1.0, fx * fy),
+    ] {
+        let [r, g, b, a] = pixel_at(px, py).0;
+        let weighted_alpha = weight * a as f64;
+        for (sum, channel) in color.iter_mut().zip([r, g, b]) {
+            *sum += weighted_alpha * channel as f64;
+        }
+        alpha += weighted_alpha;
+    }
+    if alpha <= 0.0 {
+        return Rgba([0, 0, 0, 0]);
+    }
+    let channel = |sum: f64| (sum / alpha).round().clamp(0.0, 255.0) as u8;
+    Rgba([
+        channel(color[0]),
+        channel(color[1]),
+        channel(color[2]),
+        alpha.round().clamp(0.0, 255.0) as u8,
+    ])
+}
+
+/// Returns true when the image has an alpha channel with at least one non-opaque pixel.
+pub fn has_transparency(img: &DynamicImage) -> bool {
+    // Images without an alpha channel are always opaque.
+    if !img.color().has_alpha() {
+        return false;
+    }
+    // Stop at the first pixel that is not fully opaque.
+    img.to_rgba8().pixels().any(|pixel| pixel[3] < 255)
+}
+
+/// Returns true when every pixel is within `tolerance` (per channel) of the first one,
+/// i.e. the image is a single solid color such as an accidentally blank export.
+pub fn is_solid(img: &DynamicImage, tolerance: u8) -> bool {
+    let mut pixels = img.pixels().map(|(_, _, pixel)| pixel.0);
+    let Some(first) = pixels.next() else {
+        return true;
+    };
+    // Stop at the first pixel that differs from the first one.
+    pixels.all(|pixel| {
+        pixel
+            .iter()
+            .zip(first)
+            .all(|(&channel, reference)| channel.abs_diff(reference) <= tolerance)
+    })
+}
+
+/// Most distinct colors an image may have to count as a graphic (logo, line art, text).
+const GRAPHIC_MAX_COLORS: usize = 256;
+
+/// Returns true when the image looks like a graphic rather than a photo: it uses only a
+/// handful of distinct colors, so it has flat areas and hard edges that lossy codecs smear.
+pub fn is_graphic(img: &DynamicImage) -> bool {
+    let mut colors = std::collections::HashSet::new();
+    for pixel in img.to_rgba8().pixels() {
+        // Stop counting as soon as there are too many colors.
+        if colors.insert(pixel.0) && colors.len() > GRAPHIC_MAX_COLORS {
+            return false;
+        }
+    }
+    true
+}
+
+/// Composites the image over a solid background color, dropping the alpha channel.
+pub fn flatten(img: &DynamicImage, background: Rgba<u8>) -> DynamicImage {
+    let rgba = img.to_rgba8();
+    let flattened = RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        // Blend each channel: src * alpha + background * (1 - alpha).
+        let blend = |src: u8, dst: u8| -> u8 {
+            ((src as u32 * a as u32 + dst as u32 * (255 - a as u32) + 127) / 255) as u8
+        };
+        Rgb([
+            blend(r, background[0]),
+            blend(g, background[1]),
+            blend(b, background[2]),
+        ])
+    });
+    DynamicImage::ImageRgb8(flattened)
+}
+
+/// Extracts the alpha channel as a grayscale mask (255 = opaque, 0 = transparent).
+pub fn alpha_mask(img: &RgbaImage) -> GrayImage {
+    GrayImage::from_fn(img.width(), img.height(), |x, y| {
+        Luma([img.get_pixel(x, y)[3]])
+    })
+}
+
+/// Stretches the alpha channel linearly so its lowest value becomes 0 and its highest 255,
+/// crisping up cutouts whose alpha only spans a narrow range. Uniform alpha is left alone.
+pub fn rescale_alpha(img: &mut RgbaImage) {
+    let (min, max) = img.pixels().fold((u8::MAX, u8::MIN), |(min, max), pixel| {
+        (min.min(pixel[3]), max.max(pixel[3]))
+    });
+    if min >= max {
+        return;
+    }
+    let range = (max - min) as u32;
+    for pixel in img.pixels_mut() {
+        pixel[3] = (((pixel[3] - min) as u32 * 255 + range / 2) / range) as u8;
+    }
+}
+
+/// Maps gray levels below the threshold to black and the rest to white.
+struct Threshold(u8);
+
+impl imageops::ColorMap for Threshold {
+    type Color = Luma<u8>;
+
+    fn index_of(&self, color: &Luma<u8>) -> usize {
+        usize::from(color[0] >= self.0)
+    }
+
+    fn lookup(&self, index: usize) -> Option<Luma<u8>> {
+        match index {
+            0 => Some(Luma([0])),
+            1 => Some(Luma([255])),
+            _ => None,
+        }
+    }
+
+    fn has_lookup(&self) -> bool {
+        true
+    }
+
+    fn map_color(&self, color: &mut Luma<u8>) {
+        color[0] = if color[0] >= self.0 { 255 } else { 0 };
+    }
+}
+
+/// Converts the image to pure black and white: gray levels from `threshold` up become white,
+/// the rest black, optionally Floyd-Steinberg dithered. Transparent areas count as white paper.
+pub fn bilevel(img: &DynamicImage, threshold: u8, dither: bool) -> DynamicImage {
+    let mut gray = if img.color().has_alpha() {
+        flatten(img, Rgba([255, 255, 255, 255])).to_luma8()
+    } else {
+        img.to_luma8()
+    };
+    let map = Threshold(threshold);
+    if dither {
+        imageops::dither(&mut gray, &map);
+    } else {
+        gray.pixels_mut()
+            .for_each(|pixel| imageops::ColorMap::map_color(&map, pixel));
+    }
+    DynamicImage::ImageLuma8(gray)
+}
+
+/// Returns the bounding box `(x, y, width, height)` of the pixels that are not fully
+/// transparent, or `None` when there are none.
+pub fn opaque_bounds(img: &RgbaImage) -> Option<(u32, u32, u32, u32)> {
+    let mut bounds: Option<(u32, u32, u32, u32)> = None;
+    for (x, y, pixel) in img.enumerate_pixels() {
+        if pixel[3] == 0 {
+            continue;
+        }
+        // Grow the inclusive (left, top, right, bottom) box to include this pixel.
+        bounds = Some(match bounds {
+            None => (x, y, x, y),
+            Some((left, top, right, bottom)) => {
+                (left.min(x), top.min(y), right.max(x), bottom.max(y))
+            }
+        });
+    }
+    bounds.map(|(left, top, right, bottom)| (left, top, right - left + 1, bottom - top + 1))
+}
+
+/// Centers the image on a `width` x `height` canvas of the fill color (letterboxing or pillarboxing).
+pub fn letterbox(img: &DynamicImage, width: u32, height: u32, fill: Rgba<u8>) -> DynamicImage {
+    let mut canvas = RgbaImage::from_pixel(width, height, fill);
+    let x = (width as i64 - img.width() as i64) / 2;
+    let y = (height as i64 - img.height() as i64) / 2;
+    imageops::overlay(&mut canvas, &img.to_rgba8(), x, y);
+    // Keep opaque images without an alpha channel.
+    if img.color().has_alpha() {
+        DynamicImage::ImageRgba8(canvas)
+    } else {
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).to_rgb8())
+    }
+}
+
+/// Builds the 8-bit lookup table for gamma correction, `out = in^(1/gamma)`.
+pub fn gamma_lut(gamma: f64) -> [u8; 256] {
+    let mut lut = [0u8; 256];
+    for (value, entry) in lut.iter_mut().enumerate() {
+        let normalized = value as f64 / 255.0;
+        *entry = (normalized.powf(1.0 / gamma) * 255.0).round() as u8;
+    }
+    lut
+}
+
+/// Applies gamma correction to the color channels of the image, leaving alpha untouched.
+pub fn apply_gamma(img: DynamicImage, gamma: f64) -> DynamicImage {
+    apply_lut(img, &gamma_lut(gamma))
+}
+
+/// Builds the lookup table that snaps every 8-bit value to the nearest of `levels`
+/// evenly spaced levels (0 and 255 always among them), for posterizing.
+pub fn posterize_lut(levels: u16) -> [u8; 256] {
+    let steps = (levels.clamp(2, 256) - 1) as f64;
+    let mut lut = [0u8; 256];
+    for (value, entry) in lut.iter_mut().enumerate() {
+        let level = (value as f64 * steps / 255.0).round();
+        *entry = (level * 255.0 / steps).round() as u8;
+    }
+    lut
+}
+
+/// Posterizes the color channels of the image to `levels` levels each, leaving alpha untouched.
+pub fn posterize(img: DynamicImage, levels: u16) -> DynamicImage {
+    apply_lut(img, &posterize_lut(levels))
+}
+
+/// Maps the color channels of the image through an 8-bit lookup table, leaving alpha untouched.
+fn apply_lut(img: DynamicImage, lut: &[u8; 256]) -> DynamicImage {
+    // Map every color sample through the table, skipping the alpha channel if present.
+    let map = |samples: &mut [u8], channels: usize, has_alpha: bool| {
+        for (i, sample) in samples.iter_mut().enumerate() {
+            if !(has_alpha && i % channels == channels - 1) {
+                *sample = lut[*sample as usize];
+            }
+        }
+    };
+    match img {
+        DynamicImage::ImageLuma8(mut buffer) => {
+            map(&mut buffer, 1, false);
+            DynamicImage::ImageLuma8(buffer)
+        }
+        DynamicImage::ImageLumaA8(mut buffer) => {
+            map(&mut buffer, 2, true);
+            DynamicImage::ImageLumaA8(buffer)
+        }
+        DynamicImage::ImageRgb8(mut buffer) => {
+            map(&mut buffer, 3, false);
+            DynamicImage::ImageRgb8(buffer)
+        }
+        // Other depths are brought down to 8-bit RGBA so the table applies.
+        other => {
+            let mut buffer = other.to_rgba8();
+            map(&mut buffer, 4, true);
+            DynamicImage::ImageRgba8(buffer)
+        }
+    }
+}
+
+/// Multiplies every color channel by its alpha, producing premultiplied-alpha pixels.
+pub fn premultiply_alpha(img: &DynamicImage) -> DynamicImage {
+    let mut rgba = img.to_rgba8();
+    for pixel in rgba.pixels_mut() {
+        let a = pixel[3] as u32;
+        // Round c * a / 255 to the nearest value.
+        for channel in 0..3 {
+            pixel[channel] = ((pixel[channel] as u32 * a + 127) / 255) as u8;
+        }
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
+/// Divides every color channel by its alpha, turning premultiplied pixels into straight alpha.
+pub fn unpremultiply_alpha(img: &DynamicImage) -> DynamicImage {
+    let mut rgba = img.to_rgba8();
+    for pixel in rgba.pixels_mut() {
+        let a = pixel[3] as u32;
+        // Fully transparent pixels carry no color at all.
+        for channel in 0..3 {
+            pixel[channel] = (pixel[channel] as u32 * 255 + a / 2)
+                .checked_div(a)
+                .map_or(0, |value| value.min(255) as u8);
+        }
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
+/// Which pixels of a mask are expected to be opaque, used to spot inverted alpha.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlphaPolarity {
+    /// Light pixels should be opaque and dark pixels transparent (white-on-alpha masks).
+    LightOpaque,
+    /// Dark pixels should be opaque and light pixels transparent.
+    DarkOpaque,
+}
+
+/// Correlation below which the alpha channel is considered inverted.
+const INVERTED_ALPHA_CORRELATION: f64 = -0.5;
+
+/// Flips the alpha channel when it runs against the expected polarity relative to luminance.
+/// Returns `None` when the image has no alpha or its alpha already looks correct.
+pub fn fix_inverted_alpha(img: &DynamicImage, polarity: AlphaPolarity) -> Option<DynamicImage> {
+    if !img.color().has_alpha() {
+        return None;
+    }
+    let mut rgba = img.to_rgba8();
+
+    // Pearson correlation between each pixel's luminance and its alpha.
+    let n = (rgba.width() as f64) * (rgba.height() as f64);
+    let (mut sum_l, mut sum_a, mut sum_ll, mut sum_aa, mut sum_la) = (0.0, 0.0, 0.0, 0.0, 0.0);
+    for pixel in rgba.pixels() {
+        let [r, g, b, a] = pixel.0;
+        let l = 0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64;
+        let a = a as f64;
+        sum_l += l;
+        sum_a += a;
+        sum_ll += l * l;
+        sum_aa += a * a;
+        sum_la += l * a;
+    }
+    let covariance = sum_la - sum_l * sum_a / n;
+    let spread = ((sum_ll - sum_l * sum_l / n) * (sum_aa - sum_a * sum_a / n)).sqrt();
+    // A flat luminance or alpha channel carries no evidence either way.
+    if spread <= f64::EPSILON {
+        return None;
+    }
+    let correlation = covariance / spread;
+
+    // Dark-opaque masks are expected to correlate negatively, so flip the sign for them.
+    let correlation = match polarity {
+        AlphaPolarity::LightOpaque => correlation,
+        AlphaPolarity::DarkOpaque => -correlation,
+    };
+    if correlation >= INVERTED_ALPHA_CORRELATION {
+        return None;
+    }
+
+    // Invert the alpha channel.
+    for pixel in rgba.pixels_mut() {
+        pixel[3] = 255 - pixel[3];
+    }
+    Some(DynamicImage::ImageRgba8(rgba))
+}
+
+/// How the split point of a double-page spread is chosen.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Gutter {
+    /// Split exactly down the middle.
+    #[default]
+    Center,
+    /// Split at the darkest column near the middle (the shadow of the book's spine).
+    Auto,
+}
+
+/// Fraction of the width, on each side of the center, searched for the gutter.
+const GUTTER_SEARCH: f64 = 0.1;
+
+/// Splits a two-page spread into its left and right pages, or returns `None` if the image
+/// is too narrow to give each page a column.
+pub fn split_spread(img: &DynamicImage, gutter: Gutter) -> Option<(DynamicImage, DynamicImage)> {
+    let (width, height) = (img.width(), img.height());
+    let split = match gutter {
+        Gutter::Center if width >= 2 => width / 2,
+        Gutter::Center => return None,
+        Gutter::Auto => find_gutter(img)?,
+    };
+    Some((
+        img.crop_imm(0, 0, split, height),
+        img.crop_imm(split, 0, width - split, height),
+    ))
+}
+
+/// Width in pixels of the divider between the halves of a `--compare-output` image.
+const COMPARE_DIVIDER: u32 = 4;
+
+/// Places `before` and `after` side by side with a thin gray divider between them, for
+/// reviewing what processing did. The shorter image is scaled to the height of the taller one.
+pub fn side_by_side(before: &DynamicImage, after: &DynamicImage) -> RgbaImage {
+    let height = before.height().max(after.height());
+    let scaled = |img: &DynamicImage| {
+        if img.height() == height {
+            return img.to_rgba8();
+        }
+        let width =
+            ((img.width() as f64 * height as f64 / img.height() as f64).round() as u32).max(1);
+        imageops::resize(
+            &img.to_rgba8(),
+            width,
+            height,
+            imageops::FilterType::Triangle,
+        )
+    };
+    let (before, after) = (scaled(before), scaled(after));
+
+    // Transparent canvas, so cutouts stay see-through on both sides of the divider.
+    let mut canvas = RgbaImage::new(before.width() + COMPARE_DIVIDER + after.width(), height);
+    imageops::replace(&mut canvas, &before, 0, 0);
+    for x in before.width()..before.width() + COMPARE_DIVIDER {
+        for y in 0..height {
+            canvas.put_pixel(x, y, Rgba([128, 128, 128, 255]));
+        }
+    }
+    imageops::replace(
+        &mut canvas,
+        &after,
+        (before.width() + COMPARE_DIVIDER) as i64,
+        0,
+    );
+    canvas
+}
+
+/// Finds the column with the lowest mean brightness within the central band of the image,
+/// or `None` for images narrower than 2 pixels, which have no column to split at.
+fn find_gutter(img: &DynamicImage) -> Option<u32> {
+    let luma = img.to_luma8();
+    let (width, height) = luma.dimensions();
+    if width < 2 {
+        return None;
+    }
+    let band = ((width as f64 * GUTTER_SEARCH) as u32).max(1);
+    let (start, end) = (
+        (width / 2).saturating_sub(band).max(1),
+        (width / 2 + band).min(width - 1),
+    );
+
+    // Sum each column's brightness and keep the darkest one (the first on ties).
+    (start..=end)
+        .min_by_key(|&x| {
+            (0..height)
+                .map(|y| luma.get_pixel(x, y)[0] as u64)
+                .sum::<u64>()
+        })
+        .or(Some(width / 2))
+}
+
+/// Noise filter applied by `--denoise`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Denoise {
+    /// Per-channel median of the surrounding window; removes salt-and-pepper noise.
+    Median,
+    /// Gaussian blur; smooths gaussian (sensor) noise.
+    Gaussian,
+}
+
+/// Removes noise from the image with the given filter; `radius` is the window reach in pixels.
+pub fn denoise(img: &DynamicImage, method: Denoise, radius: u32) -> DynamicImage {
+    match method {
+        Denoise::Median => DynamicImage::ImageRgba8(median_filter(&img.to_rgba8(), radius)),
+        // A sigma of half the radius keeps nearly all of the kernel's weight within the radius.
+        Denoise::Gaussian => img.blur(radius as f32 / 2.0),
+    }
+}
+
+/// Replaces every channel of every pixel with its median over the (2r+1)x(2r+1) window around
+/// it, clamped at the image borders. Rows are filtered in parallel.
+fn median_filter(img: &RgbaImage, radius: u32) -> RgbaImage {
+    let (width, height) = img.dimensions();
+    let mut output = RgbaImage::new(width, height);
+    if width == 0 || height == 0 {
+        return output;
+    }
+    output
+        .par_chunks_mut(width as usize * 4)
+        .enumerate()
+        .for_each(|(y, row)| {
+            let y = y as u32;
+            let (top, bottom) = (y.saturating_sub(radius), (y + radius).min(height - 1));
+            let mut window = Vec::new();
+            for x in 0..width {
+                let (left, right) = (x.saturating_sub(radius), (x + radius).min(width - 1));
+                for channel in 0..4 {
+                    // Collect the channel over the window and pick its middle value.
+                    window.clear();
+                    for wy in top..=bottom {
+                        for wx in left..=right {
+                            window.push(img.get_pixel(wx, wy)[channel]);
+                        }
+                    }
+                    let mid = window.len() / 2;
+                    row[x as usize * 4 + channel] = *window.select_nth_unstable(mid).1;
+                }
+            }
+        });
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trim_border_crops_the_margins() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::new(30, 30));
+        let margins = Margins {
+            top: 5,
+            right: 5,
+            bottom: 5,
+            left: 5,
+        };
+        let trimmed = trim_border(&img, margins).unwrap();
+        assert_eq!(trimmed.dimensions(), (20, 20));
+    }
+
+    #[test]
+    fn split_spread_needs_at_least_two_columns() {
+        for width in [0, 1] {
+            let img = DynamicImage::ImageRgba8(RgbaImage::new(width, 3));
+            assert!(find_gutter(&img).is_none());
+            assert!(split_spread(&img, Gutter::Auto).is_none());
+            assert!(split_spread(&img, Gutter::Center).is_none());
+        }
+        let img = DynamicImage::ImageRgba8(RgbaImage::new(2, 3));
+        let (left, right) = split_spread(&img, Gutter::Auto).unwrap();
+        assert_eq!((left.width(), right.width()), (1, 1));
+    }
+
+    #[test]
+    fn trim_border_skips_images_too_small_to_trim() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::new(10, 30));
+        let margins = Margins {
+            top: 0,
+            right: 5,
+            bottom: 0,
+            left: 5,
+        };
+        assert!(trim_border(&img, margins).is_none());
+    }
+
+    #[test]
+    fn gamma_lightens_mid_gray_and_round_trips() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 4, Rgba([128, 128, 128, 100])));
+        let lightened = apply_gamma(img, 2.2).to_rgba8();
+        // (128 / 255)^(1 / 2.2) * 255 = 186.1; alpha is left alone.
+        assert_eq!(lightened.get_pixel(0, 0).0, [186, 186, 186, 100]);
+
+        let restored = apply_gamma(DynamicImage::ImageRgba8(lightened), 1.0 / 2.2).to_rgba8();
+        for &value in &restored.get_pixel(0, 0).0[..3] {
+            assert!(value.abs_diff(128) <= 1, "{}", value);
+        }
+        assert_eq!(restored.get_pixel(0, 0).0[3], 100);
+    }
+
+    #[test]
+    fn inverted_alpha_is_flipped_only_when_it_runs_against_luminance() {
+        // A white-on-alpha mask exported the wrong way round: the white shape is transparent.
+        let inverted = RgbaImage::from_fn(8, 8, |x, _| {
+            if x < 4 {
+                Rgba([255, 255, 255, 0])
+            } else {
+                Rgba([0, 0, 0, 255])
+            }
+        });
+        let fixed = fix_inverted_alpha(
+            &DynamicImage::ImageRgba8(inverted.clone()),
+            AlphaPolarity::LightOpaque,
+        )
+        .unwrap()
+        .to_rgba8();
+        assert_eq!(fixed.get_pixel(0, 0).0, [255, 255, 255, 255]);
+        assert_eq!(fixed.get_pixel(7, 0).0, [0, 0, 0, 0]);
+
+        // The fixed mask already looks right, and the inverted one is right for dark-opaque masks.
+        assert!(
+            fix_inverted_alpha(&DynamicImage::ImageRgba8(fixed), AlphaPolarity::LightOpaque)
+                .is_none()
+        );
+        assert!(fix_inverted_alpha(
+            &DynamicImage::ImageRgba8(inverted),
+            AlphaPolarity::DarkOpaque
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn median_denoise_removes_salt_and_pepper_outliers() {
+        // Mid-gray with every seventh pixel turned fully black or white.
+        let noisy = RgbaImage::from_fn(32, 32, |x, y| match (x + y * 32) % 7 {
+            0 => Rgba([0, 0, 0, 255]),
+            3 => Rgba([255, 255, 255, 255]),
+            _ => Rgba([128, 128, 128, 255]),
+        });
+        let outliers = |img: &RgbaImage| {
+            img.pixels()
+                .filter(|pixel| pixel[0] == 0 || pixel[0] == 255)
+                .count()
+        };
+
+        let denoised =
+            denoise(&DynamicImage::ImageRgba8(noisy.clone()), Denoise::Median, 1).to_rgba8();
+        assert!(outliers(&noisy) > 250);
+        assert_eq!(outliers(&denoised), 0);
+    }
+
+    #[test]
+    fn rotate_by_any_angle_grows_the_canvas_and_fills_the_corners() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(40, 20, Rgba([255, 0, 0, 255])));
+        let fill = Rgba([0, 255, 0, 255]);
+
+        let rotated = rotate(&img, 45.0, Some(fill)).to_rgba8();
+        let (width, height) = rotated.dimensions();
+        // (40 + 20) / sqrt(2) = 42.4 on both sides.
+        assert!(
+            (42..=44).contains(&width) && (42..=44).contains(&height),
+            "{}x{}",
+            width,
+            height
+        );
+        for (x, y) in [
+            (0, 0),
+            (width - 1, 0),
+            (0, height - 1),
+            (width - 1, height - 1),
+        ] {
+            assert_eq!(*rotated.get_pixel(x, y), fill);
+        }
+        assert_eq!(rotated.get_pixel(width / 2, height / 2).0, [255, 0, 0, 255]);
+
+        // Without a fill color, the exposed corners are transparent.
+        let transparent = rotate(&img, 45.0, None).to_rgba8();
+        assert_eq!(transparent.get_pixel(0, 0)[3], 0);
+    }
+}
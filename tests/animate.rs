@@ -0,0 +1,52 @@
+mod common;
+
+use common::{rico_on, stderr, TempDir};
+use image::codecs::gif::GifDecoder;
+use image::AnimationDecoder;
+use std::fs::{self, File};
+use std::io::BufReader;
+
+#[test]
+fn three_pngs_become_a_three_frame_gif() {
+    let dir = TempDir::new("animate");
+    for i in 1..=3 {
+        common::write_image(
+            &dir.join(format!("frames/frame_{:04}.png", i)),
+            6,
+            4,
+            |x, y| common::pattern(x + i, y),
+        );
+    }
+
+    let output = rico_on(
+        "animate",
+        &dir.join("frames"),
+        &dir.join("out.gif"),
+        &["--delay", "50"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let decoder =
+        GifDecoder::new(BufReader::new(File::open(dir.join("out.gif")).unwrap())).unwrap();
+    let frames = decoder.into_frames().collect_frames().unwrap();
+    assert_eq!(frames.len(), 3);
+    for frame in &frames {
+        assert_eq!(frame.buffer().dimensions(), (6, 4));
+        assert_eq!(frame.delay().numer_denom_ms(), (50, 1));
+    }
+}
+
+#[test]
+fn undecodable_frames_exit_non_zero() {
+    let dir = TempDir::new("animate-broken");
+    common::write_image(&dir.join("frames/frame_0001.png"), 6, 4, common::pattern);
+    fs::write(dir.join("frames/frame_0002.png"), b"not an image").unwrap();
+
+    let output = rico_on("animate", &dir.join("frames"), &dir.join("out.gif"), &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("could not decode frame"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(!dir.join("out.gif").exists());
+}
@@ -0,0 +1,103 @@
+//! Helpers shared by the integration tests, which run the `rico` binary on generated images.
+#![allow(dead_code)]
+
+use image::{Rgba, RgbaImage};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// A fresh, empty directory for one test, removed again when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates `rico-<name>-<pid>` under the system temp directory; names must be unique per test.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("rico-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Runs `rico` with the arguments, returning its exit status and output.
+pub fn rico<I, S>(args: I) -> Output
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    Command::new(env!("CARGO_BIN_EXE_rico"))
+        .args(args)
+        .env("RUST_LOG", "info")
+        .output()
+        .unwrap()
+}
+
+/// Runs a `rico` subcommand on the source directory with `-s`, writing to the output with `-o`.
+pub fn rico_on(subcommand: &str, source: &Path, output: &Path, args: &[&str]) -> Output {
+    let mut all_args = vec![
+        OsStr::new(subcommand),
+        OsStr::new("-s"),
+        source.as_os_str(),
+        OsStr::new("-o"),
+        output.as_os_str(),
+    ];
+    all_args.extend(args.iter().map(OsStr::new));
+    rico(all_args)
+}
+
+/// Returns everything `rico` logged, for asserting on messages.
+pub fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+/// Writes a `width`x`height` image with the pixels from `pixel`, in the format of the extension,
+/// creating parent directories as needed.
+pub fn write_image(path: &Path, width: u32, height: u32, pixel: impl Fn(u32, u32) -> Rgba<u8>) {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).unwrap();
+    }
+    RgbaImage::from_fn(width, height, pixel).save(path).unwrap();
+}
+
+/// A colorful pattern that no encoder can shrink to nothing and no trim mistakes for a border.
+pub fn pattern(x: u32, y: u32) -> Rgba<u8> {
+    Rgba([
+        (x * 37 % 256) as u8,
+        (y * 53 % 256) as u8,
+        ((x + y) * 11 % 256) as u8,
+        255,
+    ])
+}
+
+/// Lists the files under `dir`, relative to it and sorted, to compare whole output trees.
+pub fn files_in(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        for entry in fs::read_dir(&current).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                pending.push(path);
+            } else {
+                files.push(path.strip_prefix(dir).unwrap().to_path_buf());
+            }
+        }
+    }
+    files.sort();
+    files
+}
@@ -0,0 +1,2601 @@
+mod common;
+
+use common::{rico_on, stderr, TempDir};
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::{CompressionType, FilterType as PngFilter, PngEncoder};
+use image::{AnimationDecoder, ColorType, ImageEncoder, Rgba, RgbaImage};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Writes an animated WebP of `frames` solid-color 20x20 frames, 100 ms each, playing `loops` times.
+fn write_animated_webp(path: &Path, frames: u8, loops: i32) {
+    let mut config = webp::WebPConfig::new().unwrap();
+    config.lossless = 1;
+    let images: Vec<RgbaImage> = (0..frames)
+        .map(|i| RgbaImage::from_pixel(20, 20, Rgba([i * 60, 255 - i * 60, 0, 255])))
+        .collect();
+    let mut encoder = webp::AnimEncoder::new(20, 20, &config);
+    encoder.set_loop_count(loops);
+    for (i, img) in images.iter().enumerate() {
+        encoder.add_frame(webp::AnimFrame::from_rgba(
+            img.as_raw(),
+            20,
+            20,
+            i as i32 * 100,
+        ));
+    }
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, &*encoder.encode()).unwrap();
+}
+
+#[test]
+fn animated_webp_keeps_its_frames_as_gif() {
+    let dir = TempDir::new("animated-gif");
+    write_animated_webp(&dir.join("src/spin.webp"), 3, 0);
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "gif", "--width", "10"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    // All frames survive, each resized like a still image would be.
+    let gif = GifDecoder::new(File::open(dir.join("out/spin.gif")).unwrap()).unwrap();
+    let frames = gif.into_frames().collect_frames().unwrap();
+    assert_eq!(frames.len(), 3);
+    for frame in &frames {
+        assert_eq!(frame.buffer().dimensions(), (10, 10));
+    }
+}
+
+#[test]
+fn animated_webp_keeps_its_loop_count() {
+    let dir = TempDir::new("animated-loops");
+    write_animated_webp(&dir.join("src/spin.webp"), 2, 3);
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp", "--force-reencode"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let bytes = fs::read(dir.join("out/spin.webp")).unwrap();
+    let animation = webp::AnimDecoder::new(&bytes).decode().unwrap();
+    assert_eq!(animation.len(), 2);
+    assert_eq!(animation.loop_count, 3);
+}
+
+/// Returns the ICC profile embedded in an encoded image, if any.
+fn icc_profile(path: &Path) -> Option<Vec<u8>> {
+    use img_parts::{Bytes, DynImage, ImageICC};
+    let image = DynImage::from_bytes(Bytes::from(fs::read(path).unwrap())).unwrap()?;
+    image.icc_profile().map(|profile| profile.to_vec())
+}
+
+#[test]
+fn embed_srgb_adds_an_icc_profile() {
+    let dir = TempDir::new("embed-srgb");
+    common::write_image(&dir.join("src/photo.png"), 64, 64, common::pattern);
+
+    for format in ["png", "jpg", "webp"] {
+        let plain = dir.join(format!("plain-{}", format));
+        let embedded = dir.join(format!("embedded-{}", format));
+        let output = rico_on("convert", &dir.join("src"), &plain, &["-f", format]);
+        assert!(output.status.success(), "{}", stderr(&output));
+        let output = rico_on(
+            "convert",
+            &dir.join("src"),
+            &embedded,
+            &["-f", format, "--embed-srgb"],
+        );
+        assert!(output.status.success(), "{}", stderr(&output));
+
+        let name = format!("photo.{}", format);
+        assert_eq!(icc_profile(&plain.join(&name)), None, "{}", format);
+        let profile = icc_profile(&embedded.join(&name)).expect(format);
+        // An ICC profile declares its own size first and has the `acsp` signature at 36.
+        assert_eq!(
+            u32::from_be_bytes(profile[..4].try_into().unwrap()) as usize,
+            profile.len()
+        );
+        assert_eq!(&profile[36..40], b"acsp");
+    }
+}
+
+#[test]
+fn trim_crops_a_fixed_border() {
+    let dir = TempDir::new("trim-border");
+    common::write_image(&dir.join("src/scan.png"), 30, 30, common::pattern);
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "png", "--trim", "5"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        image::image_dimensions(dir.join("out/scan.png")).unwrap(),
+        (20, 20)
+    );
+}
+
+#[test]
+fn compare_bytes_skips_identical_outputs_and_replaces_changed_ones() {
+    let dir = TempDir::new("compare-bytes");
+    let source = dir.join("src/logo.png");
+    common::write_image(&source, 8, 8, common::pattern);
+    let args = ["-f", "png", "--compare-bytes"];
+
+    let output = rico_on("convert", &dir.join("src"), &dir.join("out"), &args);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        fs::read(dir.join("out/logo.png")).unwrap(),
+        fs::read(&source).unwrap()
+    );
+
+    // The unchanged copy is recognized by its bytes.
+    let output = rico_on("convert", &dir.join("src"), &dir.join("out"), &args);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("Output is byte-identical"));
+
+    // A changed source replaces its output even without --overwrite.
+    common::write_image(&source, 8, 8, |_, _| Rgba([1, 2, 3, 255]));
+    let output = rico_on("convert", &dir.join("src"), &dir.join("out"), &args);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        fs::read(dir.join("out/logo.png")).unwrap(),
+        fs::read(&source).unwrap()
+    );
+}
+
+#[test]
+fn max_errors_aborts_the_batch_early() {
+    let dir = TempDir::new("max-errors");
+    fs::create_dir_all(dir.join("src")).unwrap();
+    for i in 0..5 {
+        fs::write(dir.join(format!("src/broken{}.png", i)), b"not an image").unwrap();
+    }
+
+    // One worker makes the point of the abort deterministic.
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp", "--max-errors", "2", "--jobs", "1"],
+    );
+    let log = stderr(&output);
+    assert!(!output.status.success(), "{}", log);
+    assert!(log.contains("aborted after 2 failed files"), "{}", log);
+    assert_eq!(log.matches("Failed to process").count(), 2, "{}", log);
+}
+
+/// Writing to `/dev/full` fails with "no space left on device", like a full output disk.
+#[cfg(target_os = "linux")]
+#[test]
+fn full_disk_aborts_the_batch() {
+    let dir = TempDir::new("disk-full");
+    fs::create_dir_all(dir.join("out")).unwrap();
+    for name in ["a", "b", "c"] {
+        common::write_image(
+            &dir.join(format!("src/{}.png", name)),
+            8,
+            8,
+            common::pattern,
+        );
+        std::os::unix::fs::symlink("/dev/full", dir.join(format!("out/{}.webp", name))).unwrap();
+    }
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp", "--overwrite", "--no-safe-mode", "--jobs", "1"],
+    );
+    let log = stderr(&output);
+    assert!(!output.status.success(), "{}", log);
+    assert!(
+        log.contains("disk full: aborted after 0 of 3 files were completed"),
+        "{}",
+        log
+    );
+    assert_eq!(log.matches("Failed to process").count(), 1, "{}", log);
+}
+
+#[test]
+fn safe_mode_refuses_writes_outside_the_output_directory() {
+    let dir = TempDir::new("safe-mode");
+    common::write_image(&dir.join("src/a.png"), 8, 8, common::pattern);
+
+    // A `../` in the sequence prefix points outside the output directory.
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "png", "--sequence", "../escaped_"],
+    );
+    let log = stderr(&output);
+    assert!(log.contains("is outside the output directory"), "{}", log);
+    assert!(!dir.join("escaped_0001.png").exists());
+
+    // Without safe mode the same prefix is taken at its word.
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "png", "--sequence", "../escaped_", "--no-safe-mode"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(dir.join("escaped_0001.png").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn safe_mode_refuses_copies_through_a_symlinked_output_subdirectory() {
+    let dir = TempDir::new("safe-mode-copy");
+    fs::create_dir_all(dir.join("src/sub")).unwrap();
+    fs::write(dir.join("src/sub/broken.png"), b"not an image").unwrap();
+    fs::create_dir_all(dir.join("out")).unwrap();
+    fs::create_dir_all(dir.join("elsewhere")).unwrap();
+    std::os::unix::fs::symlink(dir.join("elsewhere"), dir.join("out/sub")).unwrap();
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp", "--copy-on-failure"],
+    );
+    let log = stderr(&output);
+    assert!(log.contains("is outside the output directory"), "{}", log);
+    assert!(!dir.join("elsewhere/broken.png").exists());
+}
+
+#[test]
+fn pipeline_strategy_converts_every_file() {
+    let dir = TempDir::new("pipeline");
+    for i in 0..6 {
+        common::write_image(&dir.join(format!("src/{}.png", i)), 8, 8, common::pattern);
+    }
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &[
+            "-f",
+            "webp",
+            "--strategy",
+            "pipeline",
+            "--pipeline-bound",
+            "1",
+            "--jobs",
+            "2",
+        ],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let expected: Vec<PathBuf> = (0..6).map(|i| format!("{}.webp", i).into()).collect();
+    assert_eq!(common::files_in(&dir.join("out")), expected);
+}
+
+#[test]
+fn retry_failed_reprocesses_only_the_failed_file() {
+    let dir = TempDir::new("retry-failed");
+    let ledger = dir.join("photos.ledger");
+    let ledger_arg = ledger.to_str().unwrap();
+    for name in ["a", "c"] {
+        common::write_image(
+            &dir.join(format!("src/{}.png", name)),
+            8,
+            8,
+            common::pattern,
+        );
+    }
+    fs::write(dir.join("src/b.png"), b"not an image").unwrap();
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp", "--ledger", ledger_arg],
+    );
+    assert!(
+        fs::read_to_string(&ledger).unwrap().contains("failed"),
+        "{}",
+        stderr(&output)
+    );
+
+    // With b repaired and the earlier outputs gone, only b may come back.
+    common::write_image(&dir.join("src/b.png"), 8, 8, common::pattern);
+    fs::remove_dir_all(dir.join("out")).unwrap();
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp", "--ledger", ledger_arg, "--retry-failed"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(common::files_in(&dir.join("out")), [Path::new("b.webp")]);
+}
+
+#[test]
+fn sequence_numbering_continues_across_runs() {
+    let dir = TempDir::new("sequence");
+    for name in ["c", "a", "b"] {
+        common::write_image(
+            &dir.join(format!("src/{}.png", name)),
+            4,
+            4,
+            common::pattern,
+        );
+    }
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "png", "--sequence", "frame_"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        common::files_in(&dir.join("out")),
+        [
+            ".rico-sequence.json",
+            "frame_0001.png",
+            "frame_0002.png",
+            "frame_0003.png"
+        ]
+        .map(Path::new)
+    );
+
+    // The next run picks up where the last one stopped.
+    fs::remove_dir_all(dir.join("src")).unwrap();
+    common::write_image(&dir.join("src/d.png"), 4, 4, common::pattern);
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "png", "--sequence", "frame_"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        dir.join("out/frame_0004.png").is_file(),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn same_format_conversions_copy_the_source_bytes() {
+    let dir = TempDir::new("passthrough");
+    let source = dir.join("src/photo.png");
+    fs::create_dir_all(dir.join("src")).unwrap();
+    // Encoded unlike rico would, so a decode and re-encode cannot give the same bytes.
+    let img = RgbaImage::from_fn(16, 16, common::pattern);
+    PngEncoder::new_with_quality(
+        File::create(&source).unwrap(),
+        CompressionType::Fast,
+        PngFilter::NoFilter,
+    )
+    .write_image(&img, 16, 16, ColorType::Rgba8)
+    .unwrap();
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "png"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        fs::read(dir.join("out/photo.png")).unwrap(),
+        fs::read(&source).unwrap()
+    );
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("reencoded"),
+        &["-f", "png", "--force-reencode"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_ne!(
+        fs::read(dir.join("reencoded/photo.png")).unwrap(),
+        fs::read(&source).unwrap()
+    );
+}
+
+#[test]
+fn nested_directories_keep_same_named_files_apart() {
+    let dir = TempDir::new("nested");
+    common::write_image(&dir.join("src/a/logo.png"), 6, 4, common::pattern);
+    common::write_image(&dir.join("src/b/logo.png"), 4, 6, common::pattern);
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        image::image_dimensions(dir.join("out/a/logo.webp")).unwrap(),
+        (6, 4)
+    );
+    assert_eq!(
+        image::image_dimensions(dir.join("out/b/logo.webp")).unwrap(),
+        (4, 6)
+    );
+}
+
+#[test]
+fn webp_and_tiff_inputs_convert_to_png() {
+    let dir = TempDir::new("webp-tiff-input");
+    common::write_image(&dir.join("src/web.webp"), 5, 3, common::pattern);
+    common::write_image(&dir.join("src/scan.tiff"), 3, 5, common::pattern);
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "png"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    for (name, source) in [("web.png", "src/web.webp"), ("scan.png", "src/scan.tiff")] {
+        let converted = image::open(dir.join("out").join(name)).unwrap().to_rgba8();
+        assert_eq!(converted, image::open(dir.join(source)).unwrap().to_rgba8());
+    }
+}
+
+/// A horizontal gray gradient from black to white.
+fn gradient(x: u32, _: u32) -> Rgba<u8> {
+    let value = (x * 255 / 63) as u8;
+    Rgba([value, value, value, 255])
+}
+
+/// The distinct RGBA colors of a decoded image.
+fn colors_of(path: &Path) -> HashSet<[u8; 4]> {
+    let img = image::open(path).unwrap().to_rgba8();
+    img.pixels().map(|pixel| pixel.0).collect()
+}
+
+#[test]
+fn palette_from_remaps_the_output_but_not_the_comparison() {
+    let dir = TempDir::new("palette-from");
+    common::write_image(&dir.join("src/gradient.png"), 64, 8, gradient);
+    let palette = [
+        [0, 0, 0, 255],
+        [255, 255, 255, 255],
+        [200, 0, 0, 255],
+        [0, 0, 200, 255],
+    ];
+    let reference = dir.join("reference.png");
+    common::write_image(&reference, 2, 2, |x, y| Rgba(palette[(y * 2 + x) as usize]));
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &[
+            "-f",
+            "png",
+            "--palette-from",
+            reference.to_str().unwrap(),
+            "--dither",
+            "--compare-output",
+        ],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let colors = colors_of(&dir.join("out/gradient.png"));
+    assert!(
+        colors.iter().all(|color| palette.contains(color)),
+        "{:?}",
+        colors
+    );
+    // The comparison shows the source as it is, gradient and all.
+    assert!(colors_of(&dir.join("out/gradient_compare.png")).len() > palette.len());
+}
+
+#[test]
+fn unreadable_palette_reference_exits_non_zero() {
+    let dir = TempDir::new("palette-from-missing");
+    common::write_image(&dir.join("src/a.png"), 4, 4, common::pattern);
+    let reference = dir.join("nope.png");
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp", "--palette-from", reference.to_str().unwrap()],
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("Error reading palette"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(!dir.join("out/a.webp").exists());
+}
+
+#[test]
+fn bilevel_writes_one_bit_pngs_but_keeps_the_comparison() {
+    let dir = TempDir::new("bilevel");
+    common::write_image(&dir.join("src/gradient.png"), 64, 8, gradient);
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "png", "--bilevel", "--dither", "--compare-output"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let bytes = fs::read(dir.join("out/gradient.png")).unwrap();
+    // The bit depth is the first byte after the width and height in the IHDR chunk.
+    assert_eq!(bytes[24], 1);
+    let values: HashSet<u8> = image::load_from_memory(&bytes)
+        .unwrap()
+        .to_luma8()
+        .pixels()
+        .map(|pixel| pixel[0])
+        .collect();
+    assert_eq!(values, HashSet::from([0, 255]));
+    assert!(colors_of(&dir.join("out/gradient_compare.png")).len() > 2);
+}
+
+#[test]
+fn existing_outputs_are_skipped_without_reading_the_input() {
+    let dir = TempDir::new("existing-outputs");
+    fs::create_dir_all(dir.join("src")).unwrap();
+    fs::create_dir_all(dir.join("out")).unwrap();
+    // Inputs that would fail to decode, had they been read.
+    for i in 0..5 {
+        fs::write(dir.join(format!("src/broken{}.png", i)), b"not an image").unwrap();
+        fs::write(dir.join(format!("out/broken{}.webp", i)), b"earlier output").unwrap();
+    }
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp", "--summary-exit-codes"],
+    );
+    let log = stderr(&output);
+    assert!(output.status.success(), "{}", log);
+    assert_eq!(
+        log.matches("Output already exists for").count(),
+        5,
+        "{}",
+        log
+    );
+    assert!(!log.contains("Failed to process"), "{}", log);
+}
+
+#[test]
+fn malformed_ledger_exits_non_zero() {
+    let dir = TempDir::new("ledger-malformed");
+    common::write_image(&dir.join("src/a.png"), 4, 4, common::pattern);
+    let ledger = dir.join("photos.ledger");
+    fs::write(&ledger, "not json\n").unwrap();
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp", "--ledger", ledger.to_str().unwrap()],
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("Error reading ledger"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(!dir.join("out/a.webp").exists());
+}
+
+#[test]
+fn fit_to_letterboxes_to_the_exact_size() {
+    let dir = TempDir::new("fit-to");
+    common::write_image(&dir.join("src/wide.png"), 200, 100, |_, _| {
+        Rgba([255, 255, 255, 255])
+    });
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "png", "--fit-to", "100x100", "--fill", "#000000"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let fitted = image::open(dir.join("out/wide.png")).unwrap().to_rgba8();
+    assert_eq!(fitted.dimensions(), (100, 100));
+    // The 100x50 image sits in the middle, between black bars at the top and bottom.
+    assert_eq!(fitted.get_pixel(50, 10).0, [0, 0, 0, 255]);
+    assert_eq!(fitted.get_pixel(50, 50).0, [255, 255, 255, 255]);
+    assert_eq!(fitted.get_pixel(50, 90).0, [0, 0, 0, 255]);
+}
+
+#[test]
+fn pipeline_bound_of_zero_is_rejected() {
+    let dir = TempDir::new("pipeline-bound-zero");
+    common::write_image(&dir.join("src/a.png"), 4, 4, common::pattern);
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &[
+            "-f",
+            "webp",
+            "--strategy",
+            "pipeline",
+            "--pipeline-bound",
+            "0",
+        ],
+    );
+    assert_eq!(output.status.code(), Some(2));
+    assert!(
+        stderr(&output).contains("--pipeline-bound"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(!dir.join("out").exists());
+}
+
+#[test]
+fn dry_run_reports_the_plan_without_writing() {
+    let dir = TempDir::new("dry-run");
+    for name in ["a", "b", "c"] {
+        common::write_image(
+            &dir.join(format!("src/{}.png", name)),
+            4,
+            4,
+            common::pattern,
+        );
+    }
+    let plan = dir.join("plan.json");
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &[
+            "-f",
+            "webp",
+            "--width",
+            "2",
+            "--dry-run",
+            "--report",
+            plan.to_str().unwrap(),
+        ],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(!dir.join("out").exists());
+    let records: Vec<serde_json::Value> =
+        serde_json::from_str(&fs::read_to_string(&plan).unwrap()).unwrap();
+    let mut inputs: Vec<&str> = records
+        .iter()
+        .map(|record| record["input"].as_str().unwrap())
+        .collect();
+    inputs.sort();
+    let expected: Vec<PathBuf> = ["a", "b", "c"]
+        .iter()
+        .map(|name| dir.join(format!("src/{}.png", name)))
+        .collect();
+    assert_eq!(
+        inputs,
+        expected
+            .iter()
+            .map(|path| path.to_str().unwrap())
+            .collect::<Vec<_>>()
+    );
+    for record in &records {
+        assert_eq!(record["status"], "planned");
+        assert_eq!(record["format"], "webp");
+        assert_eq!(record["transforms"], serde_json::json!(["resize"]));
+        assert!(record["output"].as_str().unwrap().ends_with(".webp"));
+    }
+}
+
+#[test]
+fn unicode_and_dotfile_names_keep_their_stems() {
+    let dir = TempDir::new("unicode-names");
+    for name in ["🎉 café.png", ".hidden.png"] {
+        common::write_image(&dir.join("src").join(name), 4, 4, common::pattern);
+    }
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        common::files_in(&dir.join("out")),
+        [".hidden.webp", "🎉 café.webp"].map(Path::new)
+    );
+}
+
+#[test]
+fn max_rate_throttles_the_batch() {
+    let dir = TempDir::new("max-rate");
+    for i in 0..4 {
+        common::write_image(&dir.join(format!("src/{}.png", i)), 4, 4, common::pattern);
+    }
+
+    // Four files at 10 per second take at least the three 100 ms gaps between them.
+    let started = Instant::now();
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp", "--max-rate", "10"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(started.elapsed() >= Duration::from_millis(300));
+    assert_eq!(common::files_in(&dir.join("out")).len(), 4);
+}
+
+#[test]
+fn transparent_pixels_become_white_in_jpeg() {
+    let dir = TempDir::new("jpeg-transparency");
+    // Transparent left half (with a color the encoder would otherwise keep), opaque black right.
+    common::write_image(&dir.join("src/logo.png"), 16, 16, |x, _| {
+        if x < 8 {
+            Rgba([255, 0, 0, 0])
+        } else {
+            Rgba([0, 0, 0, 255])
+        }
+    });
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "jpg"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stderr(&output).contains("compositing over white"),
+        "{}",
+        stderr(&output)
+    );
+    let jpeg = image::open(dir.join("out/logo.jpg")).unwrap().to_rgb8();
+    // JPEG is lossy, so allow a little ringing away from the edge.
+    let [r, g, b] = jpeg.get_pixel(2, 8).0;
+    assert!(r >= 250 && g >= 250 && b >= 250, "{:?}", [r, g, b]);
+    let [r, g, b] = jpeg.get_pixel(13, 8).0;
+    assert!(r <= 5 && g <= 5 && b <= 5, "{:?}", [r, g, b]);
+}
+
+/// Builds an APP1 segment whose EXIF block carries only a `DateTime` tag.
+fn exif_segment(date: &str) -> Vec<u8> {
+    let mut tiff = b"II*\0".to_vec();
+    tiff.extend(8u32.to_le_bytes());
+    // One IFD entry: DateTime (0x0132), ASCII, 20 bytes stored right after the IFD.
+    tiff.extend(1u16.to_le_bytes());
+    tiff.extend(0x0132u16.to_le_bytes());
+    tiff.extend(2u16.to_le_bytes());
+    tiff.extend(20u32.to_le_bytes());
+    tiff.extend(26u32.to_le_bytes());
+    tiff.extend(0u32.to_le_bytes());
+    tiff.extend(date.as_bytes());
+    tiff.push(0);
+
+    let mut segment = vec![0xFF, 0xE1];
+    segment.extend(((2 + 6 + tiff.len()) as u16).to_be_bytes());
+    segment.extend(b"Exif\0\0");
+    segment.extend(tiff);
+    segment
+}
+
+#[test]
+fn organize_by_date_files_outputs_under_the_exif_date() {
+    let dir = TempDir::new("organize-by-date");
+    let mut jpeg = Vec::new();
+    image::DynamicImage::ImageRgba8(RgbaImage::from_fn(8, 8, common::pattern))
+        .to_rgb8()
+        .write_to(
+            &mut std::io::Cursor::new(&mut jpeg),
+            image::ImageFormat::Jpeg,
+        )
+        .unwrap();
+    let segment = exif_segment("2021:07:04 10:00:00");
+    jpeg.splice(2..2, segment.iter().copied());
+    fs::create_dir_all(dir.join("src")).unwrap();
+    fs::write(dir.join("src/photo.jpg"), &jpeg).unwrap();
+    // Only the EXIF headers survive here, so decoding this one would fail.
+    jpeg.truncate(2 + segment.len());
+    fs::write(dir.join("src/done.jpg"), &jpeg).unwrap();
+    fs::create_dir_all(dir.join("out/2021/07")).unwrap();
+    fs::write(dir.join("out/2021/07/done.png"), b"earlier output").unwrap();
+    common::write_image(&dir.join("src/plain.png"), 8, 8, common::pattern);
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "png", "--organize-by", "date"],
+    );
+    let log = stderr(&output);
+    assert!(output.status.success(), "{}", log);
+    assert!(!log.contains("Failed to process"), "{}", log);
+    assert!(log.contains("Output already exists for"), "{}", log);
+    assert_eq!(
+        common::files_in(&dir.join("out")),
+        [
+            PathBuf::from("2021/07/done.png"),
+            PathBuf::from("2021/07/photo.png"),
+            PathBuf::from("unknown/plain.png"),
+        ]
+    );
+    assert_eq!(
+        fs::read(dir.join("out/2021/07/done.png")).unwrap(),
+        b"earlier output"
+    );
+}
+
+#[test]
+fn uppercase_extensions_are_lowercased_unless_preserved() {
+    let dir = TempDir::new("extension-case");
+    fs::create_dir_all(dir.join("src")).unwrap();
+    image::DynamicImage::ImageRgba8(RgbaImage::from_fn(8, 8, common::pattern))
+        .to_rgb8()
+        .save_with_format(dir.join("src/IMG.JPG"), image::ImageFormat::Jpeg)
+        .unwrap();
+
+    for (format, out, extra) in [
+        ("webp", "out-webp", None),
+        // Same-format copies are renamed too.
+        ("jpg", "out-jpg", None),
+        ("jpg", "out-preserved", Some("--preserve-extension-case")),
+    ] {
+        let mut args = vec!["-f", format];
+        args.extend(extra);
+        let output = rico_on("convert", &dir.join("src"), &dir.join(out), &args);
+        assert!(output.status.success(), "{}", stderr(&output));
+    }
+    assert_eq!(
+        common::files_in(&dir.join("out-webp")),
+        [PathBuf::from("IMG.webp")]
+    );
+    assert_eq!(
+        common::files_in(&dir.join("out-jpg")),
+        [PathBuf::from("IMG.jpg")]
+    );
+    assert_eq!(
+        common::files_in(&dir.join("out-preserved")),
+        [PathBuf::from("IMG.JPG")]
+    );
+}
+
+#[test]
+fn directories_named_like_images_are_skipped() {
+    let dir = TempDir::new("directory-named-image");
+    common::write_image(&dir.join("src/a.png"), 8, 8, common::pattern);
+    fs::create_dir_all(dir.join("src/bar.jpg")).unwrap();
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp", "--summary-exit-codes"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        common::files_in(&dir.join("out")),
+        [PathBuf::from("a.webp")]
+    );
+
+    // A work list naming the directory skips it instead of failing to open it.
+    let map = dir.join("map.csv");
+    fs::write(
+        &map,
+        format!(
+            "{},{}\n",
+            dir.join("src/bar.jpg").display(),
+            dir.join("mapped/bar.webp").display()
+        ),
+    )
+    .unwrap();
+    let output = common::rico([
+        "convert",
+        "--map",
+        map.to_str().unwrap(),
+        "-f",
+        "webp",
+        "--summary-exit-codes",
+    ]);
+    let log = stderr(&output);
+    assert!(output.status.success(), "{}", log);
+    assert!(log.contains("Not a file, skipping"), "{}", log);
+    assert!(!log.contains("Failed to process"), "{}", log);
+    assert!(!dir.join("mapped/bar.webp").exists());
+}
+
+#[test]
+fn every_report_format_parses_with_one_record_per_file() {
+    let dir = TempDir::new("report-formats");
+    for name in ["a", "b", "c"] {
+        common::write_image(
+            &dir.join(format!("src/{}.png", name)),
+            8,
+            8,
+            common::pattern,
+        );
+    }
+
+    let mut reports = Vec::new();
+    for format in ["json", "ndjson", "csv"] {
+        let report = dir.join(format!("report.{}", format));
+        let output = rico_on(
+            "convert",
+            &dir.join("src"),
+            &dir.join(format!("out-{}", format)),
+            &[
+                "-f",
+                "webp",
+                "--report",
+                report.to_str().unwrap(),
+                "--report-format",
+                format,
+            ],
+        );
+        assert!(output.status.success(), "{}", stderr(&output));
+        reports.push(fs::read_to_string(report).unwrap());
+    }
+
+    let json: Vec<serde_json::Value> = serde_json::from_str(&reports[0]).unwrap();
+    assert_eq!(json.len(), 3);
+    assert!(json.iter().all(|record| record["status"] == "ok"));
+
+    let ndjson: Vec<serde_json::Value> = reports[1]
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(ndjson.len(), 3);
+
+    let mut rows = reports[2]
+        .lines()
+        .map(|line| line.split(',').collect::<Vec<_>>());
+    let header = rows.next().unwrap();
+    assert_eq!(header[0], "input");
+    let rows: Vec<_> = rows.collect();
+    assert_eq!(rows.len(), 3);
+    assert!(rows.iter().all(|row| row.len() == header.len()));
+}
+
+#[test]
+fn nearest_config_wins_and_the_cli_overrides_all() {
+    let dir = TempDir::new("nested-config");
+    common::write_image(&dir.join("src/cover.tif"), 8, 8, common::pattern);
+    common::write_image(&dir.join("src/thumbs/small.tif"), 8, 8, common::pattern);
+    fs::write(dir.join("src/.rico.toml"), "format = \"png\"\nwidth = 4\n").unwrap();
+    fs::write(dir.join("src/thumbs/.rico.toml"), "format = \"bmp\"\n").unwrap();
+
+    let output = rico_on("convert", &dir.join("src"), &dir.join("out"), &[]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        common::files_in(&dir.join("out")),
+        [
+            PathBuf::from("cover.png"),
+            PathBuf::from("thumbs/small.bmp"),
+        ]
+    );
+    // Keys the nearer config leaves out still come from the root one.
+    let small = image::open(dir.join("out/thumbs/small.bmp")).unwrap();
+    assert_eq!(small.width(), 4);
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("cli"),
+        &["-f", "webp", "--width", "6"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        common::files_in(&dir.join("cli")),
+        [
+            PathBuf::from("cover.webp"),
+            PathBuf::from("thumbs/small.webp"),
+        ]
+    );
+    let small = image::open(dir.join("cli/thumbs/small.webp")).unwrap();
+    assert_eq!(small.width(), 6);
+}
+
+#[test]
+fn deflate_tiffs_are_smaller_than_uncompressed_ones() {
+    let dir = TempDir::new("tiff-compression");
+    common::write_image(&dir.join("src/scan.png"), 64, 64, gradient);
+
+    let mut sizes = Vec::new();
+    for compression in ["none", "deflate"] {
+        let out = dir.join(format!("out-{}", compression));
+        let output = rico_on(
+            "convert",
+            &dir.join("src"),
+            &out,
+            &["-f", "tiff", "--tiff-compression", compression],
+        );
+        assert!(output.status.success(), "{}", stderr(&output));
+        let tiff = out.join("scan.tiff");
+        // Both decode to the same pixels.
+        assert_eq!(
+            image::open(&tiff).unwrap().to_rgba8(),
+            image::open(dir.join("src/scan.png")).unwrap().to_rgba8()
+        );
+        sizes.push(fs::metadata(tiff).unwrap().len());
+    }
+    assert!(sizes[1] < sizes[0], "{:?}", sizes);
+}
+
+#[cfg(unix)]
+#[test]
+fn chmod_sets_the_mode_of_outputs() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = TempDir::new("chmod");
+    common::write_image(&dir.join("src/a.png"), 8, 8, common::pattern);
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp", "--chmod", "600"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let mode = fs::metadata(dir.join("out/a.webp"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+}
+
+#[test]
+fn tar_stream_holds_one_image_per_input() {
+    let dir = TempDir::new("tar-stream");
+    common::write_image(&dir.join("src/a.png"), 8, 8, common::pattern);
+    common::write_image(&dir.join("src/nested/b.png"), 6, 4, common::pattern);
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        Path::new("-"),
+        &["--archive", "tar", "-f", "webp"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let mut archive = tar::Archive::new(output.stdout.as_slice());
+    let mut entries: Vec<(PathBuf, (u32, u32))> = archive
+        .entries()
+        .unwrap()
+        .map(|entry| {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().into_owned();
+            let mut bytes = Vec::new();
+            std::io::Read::read_to_end(&mut entry, &mut bytes).unwrap();
+            let img =
+                image::load_from_memory_with_format(&bytes, image::ImageFormat::WebP).unwrap();
+            (path, (img.width(), img.height()))
+        })
+        .collect();
+    entries.sort();
+    assert_eq!(
+        entries,
+        [
+            (PathBuf::from("a.webp"), (8, 8)),
+            (PathBuf::from("nested/b.webp"), (6, 4)),
+        ]
+    );
+}
+
+#[test]
+fn max_per_dir_shards_outputs_in_sorted_order() {
+    let dir = TempDir::new("max-per-dir");
+    for name in ["e", "c", "a", "d", "b"] {
+        common::write_image(
+            &dir.join(format!("src/{}.png", name)),
+            4,
+            4,
+            common::pattern,
+        );
+    }
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp", "--max-per-dir", "2"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        common::files_in(&dir.join("out")),
+        [
+            "000/a.webp",
+            "000/b.webp",
+            "001/c.webp",
+            "001/d.webp",
+            "002/e.webp"
+        ]
+        .map(PathBuf::from)
+    );
+}
+
+#[test]
+fn corrupt_inputs_are_quarantined_at_their_relative_path() {
+    let dir = TempDir::new("quarantine");
+    common::write_image(&dir.join("src/good.png"), 8, 8, common::pattern);
+    fs::create_dir_all(dir.join("src/scans")).unwrap();
+    fs::write(dir.join("src/scans/broken.png"), b"not an image").unwrap();
+
+    for (subcommand, action) in [("convert", &["-f", "webp"][..]), ("remove", &["-b"])] {
+        let quarantine = dir.join(format!("q-{}", subcommand));
+        let mut args = action.to_vec();
+        args.extend(["--quarantine", quarantine.to_str().unwrap()]);
+        let output = rico_on(
+            subcommand,
+            &dir.join("src"),
+            &dir.join(format!("out-{}", subcommand)),
+            &args,
+        );
+        assert!(output.status.success(), "{}", stderr(&output));
+        assert_eq!(
+            common::files_in(&quarantine),
+            [PathBuf::from("scans/broken.png")]
+        );
+        assert_eq!(
+            fs::read(quarantine.join("scans/broken.png")).unwrap(),
+            b"not an image"
+        );
+    }
+    // Quarantining copies, so the source tree is left as it was.
+    assert!(dir.join("src/scans/broken.png").exists());
+}
+
+#[test]
+fn copy_on_failure_copies_the_original_into_the_output_tree() {
+    let dir = TempDir::new("copy-on-failure");
+    common::write_image(&dir.join("src/good.png"), 8, 8, common::pattern);
+    fs::create_dir_all(dir.join("src/scans")).unwrap();
+    fs::write(dir.join("src/scans/broken.png"), b"not an image").unwrap();
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp", "--copy-on-failure", "--summary-exit-codes"],
+    );
+    let log = stderr(&output);
+    // The copy keeps the tree complete, but the conversion still counts as failed.
+    assert_eq!(output.status.code(), Some(3), "{}", log);
+    assert!(log.contains("copied the original"), "{}", log);
+    assert_eq!(
+        common::files_in(&dir.join("out")),
+        [
+            PathBuf::from("good.webp"),
+            PathBuf::from("scans/broken.png")
+        ]
+    );
+    assert_eq!(
+        fs::read(dir.join("out/scans/broken.png")).unwrap(),
+        b"not an image"
+    );
+}
+
+#[test]
+fn wait_stable_defers_files_still_being_written() {
+    let dir = TempDir::new("wait-stable");
+    let settled = std::time::SystemTime::now() - Duration::from_secs(60);
+    common::write_image(&dir.join("src/done.png"), 8, 8, common::pattern);
+    File::options()
+        .write(true)
+        .open(dir.join("src/done.png"))
+        .unwrap()
+        .set_modified(settled)
+        .unwrap();
+    // An upload in progress: written just now and still growing.
+    common::write_image(&dir.join("src/upload.png"), 8, 8, common::pattern);
+    let mut upload = File::options()
+        .append(true)
+        .open(dir.join("src/upload.png"))
+        .unwrap();
+    std::io::Write::write_all(&mut upload, &[0; 16]).unwrap();
+
+    let run = || {
+        rico_on(
+            "convert",
+            &dir.join("src"),
+            &dir.join("out"),
+            &["-f", "webp", "--wait-stable", "5"],
+        )
+    };
+    let output = run();
+    let log = stderr(&output);
+    assert!(output.status.success(), "{}", log);
+    assert!(log.contains("still being written"), "{}", log);
+    assert_eq!(
+        common::files_in(&dir.join("out")),
+        [PathBuf::from("done.webp")]
+    );
+
+    // Once the upload has settled, the next run picks it up.
+    upload.set_modified(settled).unwrap();
+    let output = run();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        common::files_in(&dir.join("out")),
+        [PathBuf::from("done.webp"), PathBuf::from("upload.webp")]
+    );
+}
+
+#[test]
+fn premultiplied_alpha_is_blended_and_written_with_premultiplied_math() {
+    let dir = TempDir::new("premultiply");
+    // Half-transparent orange, stored premultiplied: straight alpha would be (200, 80, 0).
+    common::write_image(&dir.join("src/logo.png"), 16, 16, |_, _| {
+        Rgba([100, 40, 0, 128])
+    });
+
+    let flatten = |out: &str, extra: &[&str]| {
+        let mut args = vec!["-f", "jpg", "--flatten"];
+        args.extend(extra);
+        let output = rico_on("convert", &dir.join("src"), &dir.join(out), &args);
+        assert!(output.status.success(), "{}", stderr(&output));
+        let jpeg = image::open(dir.join(out).join("logo.jpg"))
+            .unwrap()
+            .to_rgb8();
+        jpeg.get_pixel(8, 8).0
+    };
+    let close = |actual: [u8; 3], expected: [u8; 3]| {
+        assert!(
+            actual
+                .iter()
+                .zip(expected)
+                .all(|(&a, e)| a.abs_diff(e) <= 3),
+            "{:?} vs {:?}",
+            actual,
+            expected
+        );
+    };
+    // Premultiplied over white: c + 255 * (1 - a).
+    close(
+        flatten("premultiplied", &["--unpremultiply"]),
+        [227, 167, 127],
+    );
+    // Read as straight alpha, the color is blended in a second time: c * a + 255 * (1 - a).
+    close(flatten("straight", &[]), [177, 147, 127]);
+
+    // Straight input written premultiplied: c * a, with alpha kept.
+    common::write_image(&dir.join("straight-src/logo.png"), 4, 4, |_, _| {
+        Rgba([200, 80, 0, 128])
+    });
+    let output = rico_on(
+        "convert",
+        &dir.join("straight-src"),
+        &dir.join("out"),
+        &["-f", "webp", "--alpha-premultiply"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let webp = image::open(dir.join("out/logo.webp")).unwrap().to_rgba8();
+    assert_eq!(webp.get_pixel(0, 0).0, [100, 40, 0, 128]);
+}
+
+#[test]
+fn webp_alpha_quality_keeps_alpha_more_faithful_than_color() {
+    let dir = TempDir::new("webp-alpha-quality");
+    // A busy cutout with a soft alpha ramp that never reaches full transparency.
+    let cutout = |x: u32, y: u32| {
+        let [r, g, b, _] = common::pattern(x, y).0;
+        Rgba([r, g, b, (64 + x * 6) as u8])
+    };
+    common::write_image(&dir.join("src/cutout.png"), 32, 32, cutout);
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &[
+            "-f",
+            "webp",
+            "--webp-quality",
+            "50",
+            "--webp-alpha-quality",
+            "100",
+        ],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let webp = image::open(dir.join("out/cutout.webp")).unwrap().to_rgba8();
+
+    // Mean absolute error of the color channels and of alpha, against the source.
+    let (mut color_error, mut alpha_error) = (0u64, 0u64);
+    for (x, y, pixel) in webp.enumerate_pixels() {
+        let source = cutout(x, y).0;
+        for channel in 0..3 {
+            color_error += u64::from(pixel[channel].abs_diff(source[channel]));
+        }
+        alpha_error += u64::from(pixel[3].abs_diff(source[3]));
+    }
+    let pixels = u64::from(webp.width() * webp.height());
+    let (color_error, alpha_error) = (color_error / (3 * pixels), alpha_error / pixels);
+    assert!(alpha_error <= 1, "{}", alpha_error);
+    assert!(
+        color_error > alpha_error,
+        "{} vs {}",
+        color_error,
+        alpha_error
+    );
+}
+
+#[test]
+fn sizes_emit_one_output_per_width() {
+    let dir = TempDir::new("sizes");
+    common::write_image(&dir.join("src/photo.png"), 64, 32, common::pattern);
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp", "--sizes", "8,16,32"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        common::files_in(&dir.join("out")),
+        ["photo-16.webp", "photo-32.webp", "photo-8.webp"].map(PathBuf::from)
+    );
+    for width in [8, 16, 32] {
+        let img = image::open(dir.join(format!("out/photo-{}.webp", width))).unwrap();
+        // The aspect ratio is kept.
+        assert_eq!((img.width(), img.height()), (width, width / 2));
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn unreadable_directories_are_logged_not_ignored() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = TempDir::new("dir-errors");
+    common::write_image(&dir.join("src/a.png"), 4, 4, common::pattern);
+    common::write_image(&dir.join("src/locked/b.png"), 4, 4, common::pattern);
+    let locked = dir.join("src/locked");
+    fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+    // Root reads the directory regardless, so there is nothing to test there.
+    if fs::read_dir(&locked).is_ok() {
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+        return;
+    }
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp"],
+    );
+    let strict = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("strict"),
+        &["-f", "webp", "--fail-on-dir-errors"],
+    );
+    fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let log = stderr(&output);
+    assert!(output.status.success(), "{}", log);
+    assert!(log.contains("Could not read"), "{}", log);
+    assert!(log.contains("locked"), "{}", log);
+    assert_eq!(
+        common::files_in(&dir.join("out")),
+        [PathBuf::from("a.webp")]
+    );
+    assert_eq!(strict.status.code(), Some(1), "{}", stderr(&strict));
+}
+
+#[test]
+fn excluded_directories_are_never_collected() {
+    let dir = TempDir::new("exclude-dir");
+    common::write_image(&dir.join("src/a.png"), 4, 4, common::pattern);
+    common::write_image(&dir.join("src/node_modules/b.png"), 4, 4, common::pattern);
+    common::write_image(&dir.join("src/nested/.git/c.png"), 4, 4, common::pattern);
+    common::write_image(&dir.join("src/nested/d.png"), 4, 4, common::pattern);
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &[
+            "-f",
+            "webp",
+            "--exclude-dir",
+            "node_modules",
+            "--exclude-dir",
+            ".git",
+        ],
+    );
+    let log = stderr(&output);
+    assert!(output.status.success(), "{}", log);
+    assert_eq!(
+        common::files_in(&dir.join("out")),
+        [PathBuf::from("a.webp"), PathBuf::from("nested/d.webp")]
+    );
+    // Pruned subtrees are not even listed.
+    assert!(!log.contains("node_modules"), "{}", log);
+    assert!(!log.contains(".git"), "{}", log);
+}
+
+#[test]
+fn split_spread_writes_two_half_width_pages() {
+    let dir = TempDir::new("split-spread");
+    // A red left page and a blue right page.
+    common::write_image(&dir.join("src/spread.png"), 40, 16, |x, _| {
+        if x < 20 {
+            Rgba([255, 0, 0, 255])
+        } else {
+            Rgba([0, 0, 255, 255])
+        }
+    });
+    // Portrait pages are not spreads.
+    common::write_image(&dir.join("src/page.png"), 16, 20, common::pattern);
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "png", "--split-spread"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        common::files_in(&dir.join("out")),
+        ["page.png", "spread_l.png", "spread_r.png"].map(PathBuf::from)
+    );
+    let left = image::open(dir.join("out/spread_l.png"))
+        .unwrap()
+        .to_rgba8();
+    let right = image::open(dir.join("out/spread_r.png"))
+        .unwrap()
+        .to_rgba8();
+    assert_eq!(left.dimensions(), (20, 16));
+    assert_eq!(right.dimensions(), (20, 16));
+    assert!(left.pixels().all(|pixel| pixel.0 == [255, 0, 0, 255]));
+    assert!(right.pixels().all(|pixel| pixel.0 == [0, 0, 255, 255]));
+}
+
+#[test]
+fn messages_go_through_the_log_facade_and_stdout_stays_clean() {
+    let dir = TempDir::new("log-facade");
+    common::write_image(&dir.join("src/a.png"), 4, 4, common::pattern);
+
+    // At the default info level, messages are logged to stderr and stdout carries nothing.
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("[INFO ]"), "{}", stderr(&output));
+    assert!(output.stdout.is_empty());
+
+    // RUST_LOG filters them by level.
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_rico"))
+        .args(["convert", "-f", "webp", "--overwrite", "-s"])
+        .arg(dir.join("src"))
+        .arg("-o")
+        .arg(dir.join("out"))
+        .env("RUST_LOG", "error")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).is_empty(), "{}", stderr(&output));
+
+    // Data a subcommand prints still goes to stdout, apart from the log.
+    let output = common::rico([
+        "pixel",
+        dir.join("src/a.png").to_str().unwrap(),
+        "--x",
+        "0",
+        "--y",
+        "0",
+    ]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let printed = String::from_utf8_lossy(&output.stdout);
+    assert!(printed.starts_with("(0, 0): Rgba("), "{}", printed);
+    assert!(!printed.contains("[INFO ]"), "{}", printed);
+}
+
+#[test]
+fn min_ssim_re_encodes_low_quality_outputs_at_higher_quality() {
+    let dir = TempDir::new("min-ssim");
+    common::write_image(&dir.join("src/photo.png"), 32, 32, common::pattern);
+
+    let convert = |out: &str, extra: &[&str]| {
+        let mut args = vec!["-f", "jpg", "--jpeg-quality", "5"];
+        args.extend(extra);
+        let output = rico_on("convert", &dir.join("src"), &dir.join(out), &args);
+        assert!(output.status.success(), "{}", stderr(&output));
+        (
+            stderr(&output),
+            fs::metadata(dir.join(out).join("photo.jpg")).unwrap().len(),
+        )
+    };
+    let (_, plain_size) = convert("plain", &[]);
+    let (log, gated_size) = convert("gated", &["--min-ssim", "0.9"]);
+    assert!(
+        log.contains("at quality 5 misses the quality gate"),
+        "{}",
+        log
+    );
+    assert!(gated_size > plain_size, "{} vs {}", gated_size, plain_size);
+
+    // An unreachable gate fails the file instead of writing it.
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("impossible"),
+        &["-f", "jpg", "--min-psnr", "200", "--summary-exit-codes"],
+    );
+    assert_eq!(output.status.code(), Some(3), "{}", stderr(&output));
+    assert!(!dir.join("impossible/photo.jpg").exists());
+}
+
+#[test]
+fn blurhash_of_a_solid_image_decodes_to_its_color() {
+    let dir = TempDir::new("blurhash");
+    common::write_image(&dir.join("src/teal.png"), 32, 24, |_, _| {
+        Rgba([0, 128, 128, 255])
+    });
+    let report = dir.join("report.json");
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &[
+            "-f",
+            "webp",
+            "--blurhash",
+            "--report",
+            report.to_str().unwrap(),
+        ],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let records: Vec<serde_json::Value> =
+        serde_json::from_str(&fs::read_to_string(report).unwrap()).unwrap();
+    let hash = records[0]["blurhash"].as_str().unwrap();
+
+    // The hash stores the average color with a few bits per channel, so allow some slack.
+    let placeholder = blurhash::decode(hash, 4, 4, 1.0).unwrap();
+    for pixel in placeholder.chunks_exact(4) {
+        for (&actual, expected) in pixel.iter().zip([0, 128, 128, 255]) {
+            assert!(actual.abs_diff(expected) <= 8, "{:?}", pixel);
+        }
+    }
+}
+
+#[test]
+fn alpha_filters_select_images_by_their_channels() {
+    let dir = TempDir::new("alpha-filters");
+    common::write_image(&dir.join("src/logo.png"), 4, 4, |_, _| Rgba([0, 0, 0, 100]));
+    image::DynamicImage::ImageRgba8(RgbaImage::from_fn(4, 4, common::pattern))
+        .to_rgb8()
+        .save(dir.join("src/photo.png"))
+        .unwrap();
+
+    for (filter, out, expected) in [
+        ("--only-with-alpha", "with", "logo.webp"),
+        ("--only-without-alpha", "without", "photo.webp"),
+    ] {
+        let output = rico_on(
+            "convert",
+            &dir.join("src"),
+            &dir.join(out),
+            &["-f", "webp", filter],
+        );
+        assert!(output.status.success(), "{}", stderr(&output));
+        assert_eq!(common::files_in(&dir.join(out)), [PathBuf::from(expected)]);
+    }
+}
+
+#[test]
+fn summary_only_emits_just_the_summary_block() {
+    let dir = TempDir::new("summary-only");
+    for name in ["a", "b", "c"] {
+        common::write_image(
+            &dir.join(format!("src/{}.png", name)),
+            4,
+            4,
+            common::pattern,
+        );
+    }
+    fs::create_dir_all(dir.join("out")).unwrap();
+    fs::write(dir.join("out/c.webp"), b"earlier output").unwrap();
+
+    // Without RUST_LOG, so the flag chooses the level.
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_rico"))
+        .args(["convert", "-f", "webp", "--summary-only", "-s"])
+        .arg(dir.join("src"))
+        .arg("-o")
+        .arg(dir.join("out"))
+        .env_remove("RUST_LOG")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let log = stderr(&output);
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", log);
+    assert!(
+        lines[0].starts_with("[INFO ] Summary: 3 files in ")
+            && lines[0].ends_with(": 2 ok, 0 planned, 1 skipped, 0 failed"),
+        "{}",
+        log
+    );
+    assert_eq!(lines[1], "[INFO ]   skipped (output already exists): 1");
+}
+
+#[test]
+fn fallback_format_is_written_when_the_encoder_fails() {
+    let dir = TempDir::new("fallback-format");
+    // WebP cannot store images wider than 16384 pixels, so its encoder rejects this one.
+    common::write_image(&dir.join("src/panorama.png"), 16385, 1, common::pattern);
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp", "--fallback-format", "png"],
+    );
+    let log = stderr(&output);
+    assert!(output.status.success(), "{}", log);
+    assert!(log.contains("writing png instead"), "{}", log);
+    assert_eq!(
+        common::files_in(&dir.join("out")),
+        [PathBuf::from("panorama.png")]
+    );
+    let fallback = image::open(dir.join("out/panorama.png")).unwrap();
+    assert_eq!((fallback.width(), fallback.height()), (16385, 1));
+}
+
+#[test]
+fn graphics_are_written_losslessly_under_a_lossy_target() {
+    let dir = TempDir::new("prefer-lossless");
+    // A three-color logo with hard edges.
+    let logo = |x: u32, y: u32| match (x < 8, y < 8) {
+        (true, true) => Rgba([200, 30, 30, 255]),
+        (false, false) => Rgba([30, 30, 200, 255]),
+        _ => Rgba([255, 255, 255, 255]),
+    };
+    common::write_image(&dir.join("src/logo.png"), 16, 16, logo);
+    common::write_image(&dir.join("src/photo.png"), 64, 64, common::pattern);
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "jpg", "--prefer-lossless-for-graphics"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        common::files_in(&dir.join("out")),
+        [PathBuf::from("logo.png"), PathBuf::from("photo.jpg")]
+    );
+    // The logo keeps its exact colors.
+    let written = image::open(dir.join("out/logo.png")).unwrap().to_rgba8();
+    assert!(written
+        .enumerate_pixels()
+        .all(|(x, y, pixel)| *pixel == logo(x, y)));
+}
+
+#[test]
+fn small_io_and_compute_pools_produce_the_same_outputs() {
+    let dir = TempDir::new("jobs-io");
+    for i in 0..6 {
+        common::write_image(
+            &dir.join(format!("src/{}.png", i)),
+            8 + i,
+            8,
+            common::pattern,
+        );
+    }
+
+    for (out, pools) in [
+        ("default", &[][..]),
+        ("small", &["--jobs", "1", "--jobs-io", "1"][..]),
+        ("mixed", &["--jobs", "2", "--jobs-io", "1"][..]),
+    ] {
+        let mut args = vec!["-f", "webp"];
+        args.extend(pools);
+        let output = rico_on("convert", &dir.join("src"), &dir.join(out), &args);
+        assert!(output.status.success(), "{}", stderr(&output));
+    }
+
+    let files = common::files_in(&dir.join("default"));
+    assert_eq!(files.len(), 6);
+    for out in ["small", "mixed"] {
+        assert_eq!(common::files_in(&dir.join(out)), files);
+        for file in &files {
+            assert_eq!(
+                fs::read(dir.join(out).join(file)).unwrap(),
+                fs::read(dir.join("default").join(file)).unwrap(),
+                "{:?}",
+                file
+            );
+        }
+    }
+}
+
+#[test]
+fn fix_extensions_renames_mislabeled_sources_without_collisions() {
+    let dir = TempDir::new("fix-extensions");
+    // Two PNG files, one of them saved as .jpg next to a real PNG of the same stem.
+    common::write_image(&dir.join("src/logo.png"), 4, 4, common::pattern);
+    common::write_image(&dir.join("src/mislabeled.png"), 4, 4, common::pattern);
+    fs::rename(dir.join("src/mislabeled.png"), dir.join("src/logo.jpg")).unwrap();
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp", "--fix-extensions"],
+    );
+    let log = stderr(&output);
+    assert!(output.status.success(), "{}", log);
+    assert!(log.contains("Renamed mislabeled"), "{}", log);
+    assert_eq!(
+        common::files_in(&dir.join("src")),
+        [PathBuf::from("logo-1.png"), PathBuf::from("logo.png")]
+    );
+    assert_eq!(
+        common::files_in(&dir.join("out")),
+        [PathBuf::from("logo-1.webp"), PathBuf::from("logo.webp")]
+    );
+}
+
+#[test]
+fn fallback_decoder_recovers_a_truncated_jpeg() {
+    let dir = TempDir::new("fallback-decoder");
+    let mut jpeg = Vec::new();
+    image::DynamicImage::ImageRgba8(RgbaImage::from_fn(32, 32, gradient))
+        .to_rgb8()
+        .write_to(
+            &mut std::io::Cursor::new(&mut jpeg),
+            image::ImageFormat::Jpeg,
+        )
+        .unwrap();
+    // Cut off the end of the scan and the end-of-image marker.
+    jpeg.truncate(jpeg.len() - 10);
+    fs::create_dir_all(dir.join("src")).unwrap();
+    fs::write(dir.join("src/photo.jpg"), &jpeg).unwrap();
+
+    let strict = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("strict"),
+        &["-f", "png", "--summary-exit-codes"],
+    );
+    assert_eq!(strict.status.code(), Some(3), "{}", stderr(&strict));
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "png", "--fallback-decoder", "--summary-exit-codes"],
+    );
+    let log = stderr(&output);
+    assert!(output.status.success(), "{}", log);
+    assert!(log.contains("with the fallback JPEG decoder"), "{}", log);
+    let recovered = image::open(dir.join("out/photo.png")).unwrap();
+    assert_eq!((recovered.width(), recovered.height()), (32, 32));
+}
+
+#[test]
+fn slug_normalizes_output_stems() {
+    let dir = TempDir::new("slug");
+    fs::create_dir_all(dir.join("src")).unwrap();
+    image::DynamicImage::ImageRgba8(RgbaImage::from_fn(4, 4, common::pattern))
+        .to_rgb8()
+        .save_with_format(dir.join("src/My Photo (1).jpg"), image::ImageFormat::Jpeg)
+        .unwrap();
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp", "--slug"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        common::files_in(&dir.join("out")),
+        [PathBuf::from("my-photo-1.webp")]
+    );
+}
+
+#[test]
+fn phash_of_a_recompressed_copy_stays_close() {
+    let dir = TempDir::new("phash");
+    // A bright disc off-center on a darker background, with some texture.
+    let scene = |x: u32, y: u32| {
+        let (dx, dy) = (x as i32 - 22, y as i32 - 40);
+        let level = if dx * dx + dy * dy < 14 * 14 { 220 } else { 40 } + (x * 7 + y * 3) % 16;
+        Rgba([level as u8, level as u8, (level / 2) as u8, 255])
+    };
+    common::write_image(&dir.join("src/scene.png"), 64, 64, scene);
+    image::DynamicImage::ImageRgba8(RgbaImage::from_fn(64, 64, scene))
+        .to_rgb8()
+        .save_with_format(dir.join("src/scene-copy.jpg"), image::ImageFormat::Jpeg)
+        .unwrap();
+    common::write_image(&dir.join("src/checkers.png"), 64, 64, |x, y| {
+        if (x / 16 + y / 16) % 2 == 0 {
+            Rgba([0, 0, 0, 255])
+        } else {
+            Rgba([255, 255, 255, 255])
+        }
+    });
+    let report = dir.join("report.json");
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &[
+            "-f",
+            "webp",
+            "--phash",
+            "--report",
+            report.to_str().unwrap(),
+        ],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let records: Vec<serde_json::Value> =
+        serde_json::from_str(&fs::read_to_string(report).unwrap()).unwrap();
+    let hash = |name: &str| {
+        let record = records
+            .iter()
+            .find(|record| record["input"].as_str().unwrap().ends_with(name))
+            .unwrap();
+        u64::from_str_radix(record["phash"].as_str().unwrap(), 16).unwrap()
+    };
+    let distance = |a: &str, b: &str| (hash(a) ^ hash(b)).count_ones();
+
+    assert!(distance("scene.png", "scene-copy.jpg") <= 4);
+    assert!(distance("scene.png", "checkers.png") > 16);
+}
+
+#[test]
+fn aspect_center_crops_to_the_ratio() {
+    let dir = TempDir::new("aspect");
+    // Red margins of 50px either side of a blue center square.
+    common::write_image(&dir.join("src/wide.png"), 200, 100, |x, _| {
+        if (50..150).contains(&x) {
+            Rgba([0, 0, 255, 255])
+        } else {
+            Rgba([255, 0, 0, 255])
+        }
+    });
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "png", "--aspect", "1:1"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let cropped = image::open(dir.join("out/wide.png")).unwrap().to_rgba8();
+    assert_eq!(cropped.dimensions(), (100, 100));
+    assert!(cropped.pixels().all(|pixel| pixel.0 == [0, 0, 255, 255]));
+}
+
+#[test]
+fn mirror_dirs_recreates_empty_and_skipped_directories() {
+    let dir = TempDir::new("mirror-dirs");
+    common::write_image(&dir.join("src/photos/a.png"), 4, 4, common::pattern);
+    fs::create_dir_all(dir.join("src/empty/nested")).unwrap();
+    fs::create_dir_all(dir.join("src/docs")).unwrap();
+    fs::write(dir.join("src/docs/notes.txt"), "not an image").unwrap();
+
+    for (out, extra) in [("plain", None), ("mirrored", Some("--mirror-dirs"))] {
+        let mut args = vec!["-f", "webp"];
+        args.extend(extra);
+        let output = rico_on("convert", &dir.join("src"), &dir.join(out), &args);
+        assert!(output.status.success(), "{}", stderr(&output));
+    }
+    assert!(!dir.join("plain/empty").exists());
+    assert!(dir.join("mirrored/empty/nested").is_dir());
+    assert!(dir.join("mirrored/docs").is_dir());
+    assert_eq!(
+        common::files_in(&dir.join("mirrored")),
+        [PathBuf::from("photos/a.webp")]
+    );
+}
+
+#[test]
+fn progress_json_emits_one_event_per_file_and_a_summary() {
+    let dir = TempDir::new("progress-json");
+    for name in ["a", "b", "c"] {
+        common::write_image(
+            &dir.join(format!("src/{}.png", name)),
+            4,
+            4,
+            common::pattern,
+        );
+    }
+    fs::create_dir_all(dir.join("out")).unwrap();
+    fs::write(dir.join("out/c.webp"), b"earlier output").unwrap();
+
+    // Without RUST_LOG, so the flag keeps log lines out of the event stream.
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_rico"))
+        .args(["convert", "-f", "webp", "--progress-json", "-s"])
+        .arg(dir.join("src"))
+        .arg("-o")
+        .arg(dir.join("out"))
+        .env_remove("RUST_LOG")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    let events: Vec<serde_json::Value> = stderr(&output)
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let count = |kind: &str| events.iter().filter(|event| event["event"] == kind).count();
+    assert_eq!(count("started"), 3);
+    assert_eq!(count("finished"), 2);
+    assert_eq!(count("skipped"), 1);
+
+    let summary = events.last().unwrap();
+    assert_eq!(summary["event"], "summary");
+    assert_eq!(
+        (summary["ok"].as_u64(), summary["skipped"].as_u64()),
+        (Some(2), Some(1))
+    );
+    assert_eq!(count("summary"), 1);
+}
+
+#[test]
+fn allow_downscale_shrinks_outputs_that_miss_the_budget_at_any_quality() {
+    let dir = TempDir::new("allow-downscale");
+    common::write_image(&dir.join("src/photo.png"), 256, 256, common::pattern);
+
+    let convert = |out: &str, extra: &[&str]| {
+        let mut args = vec![
+            "-f",
+            "jpg",
+            "--target-bytes",
+            "2000",
+            "--summary-exit-codes",
+        ];
+        args.extend(extra);
+        rico_on("convert", &dir.join("src"), &dir.join(out), &args)
+    };
+    // Even the lowest quality is too big at full size.
+    let output = convert("full-size", &[]);
+    assert_eq!(output.status.code(), Some(3), "{}", stderr(&output));
+
+    let output = convert("out", &["--allow-downscale"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let written = dir.join("out/photo.jpg");
+    assert!(fs::metadata(&written).unwrap().len() <= 2000);
+    let img = image::open(&written).unwrap();
+    assert!(img.width() < 256 && img.height() < 256);
+    // The aspect ratio survives the downscale.
+    assert_eq!(img.width(), img.height());
+}
+
+#[cfg(unix)]
+#[test]
+fn postprocess_runs_once_per_output_and_failures_do_not_abort() {
+    let dir = TempDir::new("postprocess");
+    for name in ["a", "b", "c"] {
+        common::write_image(
+            &dir.join(format!("src/{}.png", name)),
+            4,
+            4,
+            common::pattern,
+        );
+    }
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp", "--postprocess", "touch {output}.done"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        common::files_in(&dir.join("out")),
+        [
+            "a.webp",
+            "a.webp.done",
+            "b.webp",
+            "b.webp.done",
+            "c.webp",
+            "c.webp.done"
+        ]
+        .map(PathBuf::from)
+    );
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("failing"),
+        &["-f", "webp", "--postprocess", "false {output}"],
+    );
+    let log = stderr(&output);
+    assert!(output.status.success(), "{}", log);
+    assert_eq!(log.matches("Post-processing").count(), 3, "{}", log);
+    assert_eq!(common::files_in(&dir.join("failing")).len(), 3);
+}
+
+#[test]
+fn skip_solid_skips_blank_images_and_can_move_them_aside() {
+    let dir = TempDir::new("skip-solid");
+    common::write_image(&dir.join("src/blank.png"), 8, 8, |_, _| {
+        Rgba([255, 255, 255, 255])
+    });
+    common::write_image(&dir.join("src/photo.png"), 8, 8, common::pattern);
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp", "--skip-solid"],
+    );
+    let log = stderr(&output);
+    assert!(output.status.success(), "{}", log);
+    assert!(log.contains("solid color"), "{}", log);
+    assert_eq!(
+        common::files_in(&dir.join("out")),
+        [PathBuf::from("photo.webp")]
+    );
+
+    let solid = dir.join("solid");
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("moved"),
+        &[
+            "-f",
+            "webp",
+            "--skip-solid",
+            "--solid-dir",
+            solid.to_str().unwrap(),
+        ],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(common::files_in(&solid), [PathBuf::from("blank.png")]);
+    assert!(!dir.join("src/blank.png").exists());
+}
+
+#[test]
+fn map_writes_each_row_to_its_exact_output_path() {
+    let dir = TempDir::new("map");
+    common::write_image(&dir.join("in/logo.png"), 4, 4, common::pattern);
+    common::write_image(&dir.join("in/photo.png"), 6, 6, common::pattern);
+    let map = dir.join("map.csv");
+    fs::write(
+        &map,
+        format!(
+            "{},{}\n\n{},{},jpeg\n",
+            dir.join("in/logo.png").display(),
+            dir.join("web/brand/Logo Final.webp").display(),
+            dir.join("in/photo.png").display(),
+            dir.join("print/photo.out").display(),
+        ),
+    )
+    .unwrap();
+
+    let output = common::rico(["convert", "--map", map.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    // The format comes from the extension, or from the third column.
+    let logo = image::open(dir.join("web/brand/Logo Final.webp")).unwrap();
+    assert_eq!(logo.width(), 4);
+    let photo = fs::read(dir.join("print/photo.out")).unwrap();
+    assert_eq!(
+        image::guess_format(&photo).unwrap(),
+        image::ImageFormat::Jpeg
+    );
+    assert_eq!(
+        common::files_in(&dir.join("print")),
+        [PathBuf::from("photo.out")]
+    );
+}
+
+#[test]
+fn only_format_skips_files_whose_content_does_not_match() {
+    let dir = TempDir::new("only-format");
+    common::write_image(&dir.join("src/real.jpg"), 8, 8, |_, _| {
+        Rgba([200, 100, 50, 255])
+    });
+    common::write_image(&dir.join("src/fake.png"), 8, 8, common::pattern);
+    fs::rename(dir.join("src/fake.png"), dir.join("src/fake.jpg")).unwrap();
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp", "--only-format", "jpeg"],
+    );
+    let log = stderr(&output);
+    assert!(output.status.success(), "{}", log);
+    assert!(log.contains("not in --only-format"), "{}", log);
+    assert_eq!(
+        common::files_in(&dir.join("out")),
+        [PathBuf::from("real.webp")]
+    );
+}
+
+#[test]
+fn mmap_decodes_large_inputs_the_same_as_buffered_reads() {
+    let dir = TempDir::new("mmap");
+    // An uncompressed BMP well over the 1 MiB threshold, so `--mmap` actually maps it.
+    let source = dir.join("src/large.bmp");
+    common::write_image(&source, 700, 700, common::pattern);
+    assert!(fs::metadata(&source).unwrap().len() > 1024 * 1024);
+
+    let buffered = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("buffered"),
+        &["-f", "png"],
+    );
+    assert!(buffered.status.success(), "{}", stderr(&buffered));
+    let mapped = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("mapped"),
+        &["-f", "png", "--mmap"],
+    );
+    assert!(mapped.status.success(), "{}", stderr(&mapped));
+    assert_eq!(
+        image::open(dir.join("mapped/large.png"))
+            .unwrap()
+            .to_rgba8(),
+        image::open(dir.join("buffered/large.png"))
+            .unwrap()
+            .to_rgba8()
+    );
+}
+
+#[test]
+fn require_matches_fails_when_the_filters_exclude_everything() {
+    let dir = TempDir::new("require-matches");
+    common::write_image(
+        &dir.join("src/node_modules/icon.png"),
+        4,
+        4,
+        common::pattern,
+    );
+
+    let args = ["-f", "webp", "--exclude-dir", "node_modules"];
+    let output = rico_on("convert", &dir.join("src"), &dir.join("out"), &args);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &[&args[..], &["--require-matches"]].concat(),
+    );
+    let log = stderr(&output);
+    assert_eq!(output.status.code(), Some(1), "{}", log);
+    assert!(log.contains("passed the filters"), "{}", log);
+}
+
+#[test]
+fn resize_modes_fill_contain_and_cover_a_square_target() {
+    let dir = TempDir::new("resize-mode");
+    // Red, green and blue bands; cover keeps only the green middle of the 200x100 source.
+    common::write_image(&dir.join("src/bands.png"), 200, 100, |x, _| match x {
+        0..=49 => Rgba([255, 0, 0, 255]),
+        50..=149 => Rgba([0, 255, 0, 255]),
+        _ => Rgba([0, 0, 255, 255]),
+    });
+
+    for (mode, size) in [
+        ("fill", (100, 100)),
+        ("contain", (100, 50)),
+        ("cover", (100, 100)),
+    ] {
+        let out = dir.join(mode);
+        let output = rico_on(
+            "convert",
+            &dir.join("src"),
+            &out,
+            &[
+                "-f",
+                "png",
+                "--width",
+                "100",
+                "--height",
+                "100",
+                "--resize-mode",
+                mode,
+            ],
+        );
+        assert!(output.status.success(), "{}", stderr(&output));
+        let img = image::open(out.join("bands.png")).unwrap().to_rgba8();
+        assert_eq!(img.dimensions(), size, "{}", mode);
+        let left = img.get_pixel(2, size.1 / 2);
+        let right = img.get_pixel(size.0 - 3, size.1 / 2);
+        if mode == "cover" {
+            assert_eq!((left[1], right[1]), (255, 255), "{}", mode);
+        } else {
+            assert_eq!((left[0], right[2]), (255, 255), "{}", mode);
+        }
+    }
+}
+
+#[test]
+fn jobs_file_converts_each_work_item_to_its_output() {
+    let dir = TempDir::new("jobs-file");
+    common::write_image(&dir.join("in/a.png"), 20, 10, common::pattern);
+    common::write_image(&dir.join("in/b.png"), 8, 8, common::pattern);
+    let jobs = dir.join("jobs.jsonl");
+    let items = [
+        serde_json::json!({
+            "input": dir.join("in/a.png"),
+            "output": dir.join("out/small.webp"),
+            "transforms": {"width": 10},
+        }),
+        serde_json::json!({
+            "input": dir.join("in/b.png"),
+            "output": dir.join("out/b.bin"),
+            "format": "jpeg",
+        }),
+    ];
+    fs::write(&jobs, items.map(|item| item.to_string()).join("\n")).unwrap();
+
+    let output = common::rico(["convert", "--jobs-file", jobs.to_str().unwrap()]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let small = image::open(dir.join("out/small.webp")).unwrap();
+    assert_eq!((small.width(), small.height()), (10, 5));
+    let bin = fs::read(dir.join("out/b.bin")).unwrap();
+    assert_eq!(image::guess_format(&bin).unwrap(), image::ImageFormat::Jpeg);
+}
+
+#[test]
+fn embed_thumbnail_writes_a_small_exif_thumbnail_into_jpegs() {
+    let dir = TempDir::new("embed-thumbnail");
+    common::write_image(&dir.join("src/photo.png"), 640, 320, common::pattern);
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "jpg", "--embed-thumbnail"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let bytes = fs::read(dir.join("out/photo.jpg")).unwrap();
+    let exif = exif::Reader::new()
+        .read_from_container(&mut std::io::Cursor::new(&bytes))
+        .unwrap();
+    let field = |tag| {
+        exif.get_field(tag, exif::In::THUMBNAIL)
+            .and_then(|field| field.value.get_uint(0))
+            .unwrap() as usize
+    };
+    let offset = field(exif::Tag::JPEGInterchangeFormat);
+    let length = field(exif::Tag::JPEGInterchangeFormatLength);
+    let thumbnail = image::load_from_memory(&exif.buf()[offset..offset + length]).unwrap();
+    assert_eq!((thumbnail.width(), thumbnail.height()), (160, 80));
+}
+
+/// Encodes a solid 8x8 baseline JPEG of one CMYK color, stored as-is without an Adobe APP14
+/// marker. Each component is a single block with only a DC coefficient, so the whole scan is
+/// a DC code, its magnitude bits and an end-of-block per component.
+fn plain_cmyk_jpeg(cmyk: [u8; 4]) -> Vec<u8> {
+    let mut jpeg = vec![0xFF, 0xD8];
+    let mut segment = |marker: u8, contents: &[u8]| {
+        jpeg.extend([0xFF, marker]);
+        jpeg.extend(((contents.len() + 2) as u16).to_be_bytes());
+        jpeg.extend(contents);
+    };
+    // One quantization table of all ones.
+    segment(0xDB, &[[0].as_slice(), &[1; 64]].concat());
+    // Frame header: 8 bits, 8x8, four components without subsampling.
+    let mut frame = vec![8, 0, 8, 0, 8, 4];
+    for id in 1..=4 {
+        frame.extend([id, 0x11, 0]);
+    }
+    segment(0xC0, &frame);
+    // DC table: categories 0-11 as 4-bit codes. AC table: only end-of-block, coded as `0`.
+    let mut dc = vec![0x00, 0, 0, 0, 12];
+    dc.extend([0; 12]);
+    dc.extend(0..12);
+    segment(0xC4, &dc);
+    segment(0xC4, &[[0x10, 1].as_slice(), &[0; 15], &[0]].concat());
+    let mut scan = vec![4];
+    for id in 1..=4 {
+        scan.extend([id, 0x00]);
+    }
+    scan.extend([0, 63, 0]);
+    segment(0xDA, &scan);
+
+    let mut bits = Vec::new();
+    for value in cmyk {
+        // A flat block's DC coefficient is eight times its level-shifted value.
+        let dc = 8 * (i32::from(value) - 128);
+        let category = 32 - dc.unsigned_abs().leading_zeros();
+        let magnitude = if dc < 0 { dc - 1 } else { dc };
+        bits.extend((0..4).rev().map(|i| (category >> i) & 1 == 1));
+        bits.extend((0..category).rev().map(|i| (magnitude >> i) & 1 == 1));
+        bits.push(false);
+    }
+    bits.resize(bits.len().div_ceil(8) * 8, true);
+    for byte in bits.chunks(8) {
+        let byte = byte.iter().fold(0u8, |acc, &bit| acc << 1 | u8::from(bit));
+        jpeg.push(byte);
+        if byte == 0xFF {
+            jpeg.push(0);
+        }
+    }
+    jpeg.extend([0xFF, 0xD9]);
+    jpeg
+}
+
+#[test]
+fn plain_cmyk_jpegs_keep_their_colors() {
+    let dir = TempDir::new("cmyk");
+    // Full magenta and yellow ink over white paper: red, which inverted would be cyan.
+    fs::create_dir_all(dir.join("src")).unwrap();
+    fs::write(dir.join("src/red.jpg"), plain_cmyk_jpeg([0, 255, 255, 0])).unwrap();
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "png"],
+    );
+    let log = stderr(&output);
+    assert!(output.status.success(), "{}", log);
+    assert!(log.contains("Corrected the CMYK inversion"), "{}", log);
+    let img = image::open(dir.join("out/red.png")).unwrap().to_rgba8();
+    let pixel = img.get_pixel(4, 4);
+    assert!(
+        pixel[0] > 250 && pixel[1] < 5 && pixel[2] < 5,
+        "{:?}",
+        pixel
+    );
+}
+
+#[test]
+fn trim_crops_a_uniform_border_to_the_content() {
+    let dir = TempDir::new("trim");
+    // A red 12x6 object at (10, 20) on a gray field, with a little noise in the gray.
+    common::write_image(&dir.join("src/object.png"), 40, 40, |x, y| {
+        if (10..22).contains(&x) && (20..26).contains(&y) {
+            Rgba([255, 0, 0, 255])
+        } else {
+            let noise = ((x + y) % 3) as u8;
+            Rgba([128 + noise, 128, 128 - noise, 255])
+        }
+    });
+
+    let output = rico_on(
+        "trim",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["--tolerance", "5"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let img = image::open(dir.join("out/object.png")).unwrap().to_rgba8();
+    assert_eq!(img.dimensions(), (12, 6));
+    assert!(img.pixels().all(|pixel| *pixel == Rgba([255, 0, 0, 255])));
+}
+
+#[test]
+fn summary_exit_codes_tell_clean_empty_and_partial_runs_apart() {
+    let dir = TempDir::new("summary-exit-codes");
+    common::write_image(&dir.join("clean/a.png"), 4, 4, common::pattern);
+    fs::create_dir_all(dir.join("empty")).unwrap();
+    common::write_image(&dir.join("partial/a.png"), 4, 4, common::pattern);
+    fs::write(dir.join("partial/broken.png"), b"not an image").unwrap();
+
+    for (source, code) in [("clean", 0), ("empty", 2), ("partial", 3)] {
+        let output = rico_on(
+            "convert",
+            &dir.join(source),
+            &dir.join("out").join(source),
+            &["-f", "webp", "--summary-exit-codes"],
+        );
+        assert_eq!(output.status.code(), Some(code), "{}", stderr(&output));
+    }
+}
+
+#[test]
+fn info_sidecar_describes_each_output() {
+    let dir = TempDir::new("info-sidecar");
+    common::write_image(&dir.join("src/a.png"), 30, 20, common::pattern);
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp", "--info-sidecar"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let written = dir.join("out/a.webp");
+    let size = fs::metadata(&written).unwrap().len();
+    assert_eq!(
+        fs::read_to_string(dir.join("out/a.webp.txt")).unwrap(),
+        format!("30x20 WEBP {}\n", size)
+    );
+}
+
+#[test]
+fn align_pads_or_crops_resized_outputs_to_multiples_of_16() {
+    let dir = TempDir::new("align");
+    common::write_image(&dir.join("src/a.png"), 100, 70, common::pattern);
+
+    for (mode, size) in [("pad", (64, 48)), ("crop", (48, 32))] {
+        let out = dir.join(mode);
+        let output = rico_on(
+            "convert",
+            &dir.join("src"),
+            &out,
+            &[
+                "-f",
+                "png",
+                "--width",
+                "50",
+                "--align",
+                "16",
+                "--align-mode",
+                mode,
+            ],
+        );
+        assert!(output.status.success(), "{}", stderr(&output));
+        let (width, height) = image::image_dimensions(out.join("a.png")).unwrap();
+        assert_eq!((width % 16, height % 16), (0, 0), "{}", mode);
+        assert_eq!((width, height), size, "{}", mode);
+    }
+}
+
+#[test]
+fn smart_crop_keeps_an_off_center_subject() {
+    let dir = TempDir::new("smart-crop");
+    // A busy checkerboard in the top-left corner of an otherwise flat gray square.
+    common::write_image(&dir.join("src/a.png"), 100, 100, |x, y| {
+        if x < 30 && y < 30 {
+            let v = if (x / 2 + y / 2) % 2 == 0 { 0 } else { 255 };
+            Rgba([v, v, v, 255])
+        } else {
+            Rgba([128, 128, 128, 255])
+        }
+    });
+
+    let crop = |out: &str, extra: &[&str]| {
+        let mut args = vec!["-f", "png", "--aspect", "1:2"];
+        args.extend(extra);
+        let output = rico_on("convert", &dir.join("src"), &dir.join(out), &args);
+        assert!(output.status.success(), "{}", stderr(&output));
+        image::open(dir.join(out).join("a.png")).unwrap().to_rgba8()
+    };
+    let centered = crop("centered", &[]);
+    let smart = crop("smart", &["--smart-crop"]);
+    assert_eq!(smart.dimensions(), (50, 100));
+    // The center crop starts at x = 25, past most of the checkerboard.
+    assert_eq!(centered.get_pixel(10, 10).0, [128, 128, 128, 255]);
+    assert_eq!(smart.get_pixel(0, 0).0, [0, 0, 0, 255]);
+    assert_eq!(smart.get_pixel(2, 0).0, [255, 255, 255, 255]);
+}
+
+#[test]
+fn decode_threads_of_one_decodes_jpegs_like_the_default_pool() {
+    let dir = TempDir::new("decode-threads");
+    fs::create_dir_all(dir.join("src")).unwrap();
+    image::DynamicImage::ImageRgba8(RgbaImage::from_fn(64, 48, common::pattern))
+        .to_rgb8()
+        .save(dir.join("src/photo.jpg"))
+        .unwrap();
+
+    let convert = |out: &str, threads: &str| {
+        let args = ["-f", "png", "--decode-threads", threads];
+        let output = rico_on("convert", &dir.join("src"), &dir.join(out), &args);
+        assert!(output.status.success(), "{}", stderr(&output));
+        image::open(dir.join(out).join("photo.png"))
+            .unwrap()
+            .to_rgba8()
+    };
+    let single = convert("single", "1");
+    assert_eq!(single.dimensions(), (64, 48));
+    assert_eq!(single, convert("per-core", "0"));
+}
+
+#[test]
+fn compare_output_puts_the_source_and_result_side_by_side() {
+    let dir = TempDir::new("compare-output");
+    common::write_image(&dir.join("src/a.png"), 40, 30, common::pattern);
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp", "--compare-output"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let (width, height) = image::image_dimensions(dir.join("out/a_compare.png")).unwrap();
+    // Both halves plus a thin divider.
+    assert!((80..=90).contains(&width), "{}", width);
+    assert_eq!(height, 30);
+}
+
+#[test]
+fn map_format_picks_the_target_per_source_extension() {
+    let dir = TempDir::new("map-format");
+    for name in ["a.png", "b.bmp", "c.jpg"] {
+        common::write_image(&dir.join("src").join(name), 8, 8, |_, _| {
+            Rgba([90, 120, 200, 255])
+        });
+    }
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["--map-format", "png=webp,bmp=png"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        common::files_in(&dir.join("out")),
+        ["a.webp", "b.png", "c.jpg"].map(PathBuf::from)
+    );
+}
+
+#[test]
+fn max_alloc_rejects_small_files_that_decode_to_huge_images() {
+    let dir = TempDir::new("decode-limits");
+    // A few KiB of PNG that would take 16 MB to decode.
+    common::write_image(&dir.join("src/bomb.png"), 2000, 2000, |_, _| {
+        Rgba([0, 0, 0, 255])
+    });
+    assert!(fs::metadata(dir.join("src/bomb.png")).unwrap().len() < 100_000);
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("tight"),
+        &["-f", "webp", "--max-alloc-mb", "1", "--summary-exit-codes"],
+    );
+    let log = stderr(&output);
+    assert_eq!(output.status.code(), Some(3), "{}", log);
+    assert!(log.contains("exceed the decode limits"), "{}", log);
+    assert!(!dir.join("tight/bomb.webp").exists());
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "webp"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(dir.join("out/bomb.webp").exists());
+}
+
+#[test]
+fn posterize_leaves_n_levels_per_channel() {
+    let dir = TempDir::new("posterize");
+    common::write_image(&dir.join("src/a.png"), 256, 4, |x, y| {
+        Rgba([
+            x as u8,
+            255 - x as u8,
+            (x as u8).wrapping_mul(3),
+            200 + y as u8,
+        ])
+    });
+
+    let output = rico_on(
+        "convert",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-f", "png", "--posterize", "4"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let img = image::open(dir.join("out/a.png")).unwrap().to_rgba8();
+    for channel in 0..3 {
+        let levels: HashSet<u8> = img.pixels().map(|pixel| pixel[channel]).collect();
+        assert_eq!(levels.len(), 4, "channel {}: {:?}", channel, levels);
+    }
+}
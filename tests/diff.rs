@@ -0,0 +1,59 @@
+mod common;
+
+use common::{rico, stderr, TempDir};
+use image::Rgba;
+use std::path::Path;
+
+fn diff(reference: &Path, candidate: &Path) -> std::process::Output {
+    rico([
+        "diff",
+        reference.to_str().unwrap(),
+        candidate.to_str().unwrap(),
+    ])
+}
+
+#[test]
+fn reports_psnr_and_ssim_of_known_images() {
+    let dir = TempDir::new("diff");
+    let (reference, brighter) = (dir.join("gray.png"), dir.join("brighter.png"));
+    common::write_image(&reference, 16, 16, |_, _| Rgba([100, 100, 100, 255]));
+    // Every channel off by 10: an MSE of 100, so a PSNR of 10 * log10(255^2 / 100) dB.
+    common::write_image(&brighter, 16, 16, |_, _| Rgba([110, 110, 110, 255]));
+
+    let output = diff(&reference, &reference);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let printed = String::from_utf8_lossy(&output.stdout);
+    assert!(printed.contains("PSNR: inf dB"), "{}", printed);
+    assert!(printed.contains("SSIM: 1.0000"), "{}", printed);
+
+    let output = diff(&reference, &brighter);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let printed = String::from_utf8_lossy(&output.stdout);
+    assert!(printed.contains("PSNR: 28.13 dB"), "{}", printed);
+    assert!(!printed.contains("SSIM: 1.0000"), "{}", printed);
+    assert!(printed.contains("Alpha: 0.00% of pixels"), "{}", printed);
+}
+
+#[test]
+fn mismatched_or_missing_images_exit_non_zero() {
+    let dir = TempDir::new("diff-mismatch");
+    let (reference, smaller) = (dir.join("a.png"), dir.join("small.png"));
+    common::write_image(&reference, 16, 16, common::pattern);
+    common::write_image(&smaller, 8, 8, common::pattern);
+
+    let output = diff(&reference, &smaller);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("differ in size (16x16 vs 8x8)"),
+        "{}",
+        stderr(&output)
+    );
+
+    let output = diff(&reference, &dir.join("missing.png"));
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("Error comparing images"),
+        "{}",
+        stderr(&output)
+    );
+}
@@ -0,0 +1,82 @@
+mod common;
+
+use common::{rico_on, stderr, TempDir};
+use serde_json::Value;
+
+#[test]
+fn atlas_rects_fit_the_sheet_without_overlapping() {
+    let dir = TempDir::new("pack");
+    let sizes = [(8, 8), (12, 4), (5, 9), (7, 7), (3, 3), (10, 2)];
+    for (i, (width, height)) in sizes.iter().enumerate() {
+        common::write_image(
+            &dir.join(format!("src/sprite{}.png", i)),
+            *width,
+            *height,
+            common::pattern,
+        );
+    }
+
+    let output = rico_on(
+        "pack",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["--max-width", "20", "--padding", "1"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let (sheet_width, sheet_height) =
+        image::image_dimensions(dir.join("out/spritesheet.png")).unwrap();
+    let atlas: Value =
+        serde_json::from_str(&std::fs::read_to_string(dir.join("out/spritesheet.json")).unwrap())
+            .unwrap();
+    let rects: Vec<[u64; 4]> = atlas
+        .as_object()
+        .unwrap()
+        .values()
+        .map(|rect| ["x", "y", "w", "h"].map(|key| rect[key].as_u64().unwrap()))
+        .collect();
+    assert_eq!(rects.len(), sizes.len());
+
+    // Every rect lies on the sheet, and the sheet is no larger than its rects need.
+    assert!(sheet_width <= 20);
+    for [x, y, w, h] in &rects {
+        assert!(x + w <= sheet_width as u64 && y + h <= sheet_height as u64);
+    }
+    assert_eq!(
+        rects.iter().map(|[x, _, w, _]| x + w).max(),
+        Some(sheet_width as u64)
+    );
+    assert_eq!(
+        rects.iter().map(|[_, y, _, h]| y + h).max(),
+        Some(sheet_height as u64)
+    );
+    for (i, [ax, ay, aw, ah]) in rects.iter().enumerate() {
+        for [bx, by, bw, bh] in &rects[i + 1..] {
+            let apart = ax + aw <= *bx || bx + bw <= *ax || ay + ah <= *by || by + bh <= *ay;
+            assert!(
+                apart,
+                "{:?} overlaps another rect in {}",
+                [ax, ay, aw, ah],
+                atlas
+            );
+        }
+    }
+}
+
+#[test]
+fn failed_packs_exit_non_zero() {
+    let dir = TempDir::new("pack-too-wide");
+    common::write_image(&dir.join("src/wide.png"), 30, 4, common::pattern);
+
+    let output = rico_on(
+        "pack",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["--max-width", "20"],
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("exceeds the maximum sheet width"),
+        "{}",
+        stderr(&output)
+    );
+}
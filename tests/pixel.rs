@@ -0,0 +1,45 @@
+mod common;
+
+use common::{rico, stderr, TempDir};
+
+#[test]
+fn prints_the_pixel_and_its_neighborhood() {
+    let dir = TempDir::new("pixel");
+    let file = dir.join("a.png");
+    common::write_image(&file, 4, 4, common::pattern);
+
+    let output = rico([
+        "pixel",
+        file.to_str().unwrap(),
+        "--x",
+        "1",
+        "--y",
+        "2",
+        "--neighborhood",
+    ]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let printed = String::from_utf8_lossy(&output.stdout);
+    let [r, g, b, a] = common::pattern(1, 2).0;
+    assert!(
+        printed.starts_with(&format!("(1, 2): Rgba([{}, {}, {}, {}])", r, g, b, a)),
+        "{}",
+        printed
+    );
+    // The pixel itself plus its eight neighbors.
+    assert_eq!(printed.lines().count(), 10, "{}", printed);
+}
+
+#[test]
+fn out_of_bounds_pixels_exit_non_zero() {
+    let dir = TempDir::new("pixel-out-of-bounds");
+    let file = dir.join("a.png");
+    common::write_image(&file, 4, 4, common::pattern);
+
+    let output = rico(["pixel", file.to_str().unwrap(), "--x", "10", "--y", "10"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("outside the 4x4 image"),
+        "{}",
+        stderr(&output)
+    );
+}
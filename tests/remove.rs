@@ -0,0 +1,366 @@
+mod common;
+
+use common::{rico_on, stderr, TempDir};
+use image::{ColorType, GrayImage, Luma, Rgba};
+
+/// A dark square in the middle of an otherwise near-white (245) grayscale image.
+fn gray_square(x: u32, y: u32) -> Luma<u8> {
+    if (4..12).contains(&x) && (4..12).contains(&y) {
+        Luma([40])
+    } else {
+        Luma([245])
+    }
+}
+
+#[test]
+fn grayscale_inputs_come_out_as_rgba_cutouts() {
+    let dir = TempDir::new("remove-grayscale");
+    std::fs::create_dir_all(dir.join("src")).unwrap();
+    GrayImage::from_fn(16, 16, gray_square)
+        .save(dir.join("src/scan.png"))
+        .unwrap();
+
+    let output = rico_on("remove", &dir.join("src"), &dir.join("out"), &["-b"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let cutout = image::open(dir.join("out/scan.png")).unwrap();
+    assert_eq!(cutout.color(), ColorType::Rgba8);
+    let cutout = cutout.to_rgba8();
+    assert_eq!(cutout.get_pixel(0, 0)[3], 0);
+    assert_eq!(cutout.get_pixel(8, 8).0, [40, 40, 40, 255]);
+}
+
+#[test]
+fn bg_color_removes_a_green_backdrop() {
+    let dir = TempDir::new("remove-green");
+    // A red product on a slightly uneven green backdrop, which the near-white default keeps.
+    common::write_image(&dir.join("src/product.png"), 16, 16, |x, y| {
+        if (4..12).contains(&x) && (4..12).contains(&y) {
+            Rgba([200, 30, 30, 255])
+        } else {
+            Rgba([40 + (x % 3) as u8, 180, 60 - (y % 3) as u8, 255])
+        }
+    });
+
+    let output = rico_on(
+        "remove",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-b", "--bg-color", "#28B43C", "--color-tolerance", "5"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let cutout = image::open(dir.join("out/product.png")).unwrap().to_rgba8();
+    assert_eq!(cutout.get_pixel(0, 0)[3], 0);
+    assert_eq!(cutout.get_pixel(15, 15)[3], 0);
+    assert_eq!(cutout.get_pixel(8, 8).0, [200, 30, 30, 255]);
+
+    // Without --bg-color the green is not background.
+    let output = rico_on("remove", &dir.join("src"), &dir.join("default"), &["-b"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let kept = image::open(dir.join("default/product.png"))
+        .unwrap()
+        .to_rgba8();
+    assert_eq!(kept.get_pixel(0, 0)[3], 255);
+}
+
+/// A dark ring around a near-white hole at the top left and a barely off-white square at the
+/// bottom right, on a near-white background.
+fn ring_and_square(x: u32, y: u32) -> Rgba<u8> {
+    let in_ring = (2..16).contains(&x) && (2..16).contains(&y);
+    let in_hole = (4..14).contains(&x) && (4..14).contains(&y);
+    let in_square = (20..28).contains(&x) && (20..28).contains(&y);
+    match (in_ring && !in_hole, in_square) {
+        (true, _) => Rgba([30, 30, 30, 255]),
+        (_, true) => Rgba([245, 245, 245, 255]),
+        _ => Rgba([250, 250, 250, 255]),
+    }
+}
+
+#[test]
+fn mask_seeds_background_and_protects_foreground() {
+    let dir = TempDir::new("remove-mask");
+    common::write_image(&dir.join("src/object.png"), 32, 32, ring_and_square);
+    // White marks the hole as background, black the square as foreground, gray leaves the rest.
+    GrayImage::from_fn(32, 32, |x, y| {
+        if (4..14).contains(&x) && (4..14).contains(&y) {
+            Luma([255])
+        } else if (20..28).contains(&x) && (20..28).contains(&y) {
+            Luma([0])
+        } else {
+            Luma([128])
+        }
+    })
+    .save(dir.join("mask.png"))
+    .unwrap();
+
+    let output = rico_on("remove", &dir.join("src"), &dir.join("plain"), &["-b"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let plain = image::open(dir.join("plain/object.png"))
+        .unwrap()
+        .to_rgba8();
+    assert_eq!(plain.get_pixel(8, 8)[3], 255);
+    assert_eq!(plain.get_pixel(24, 24)[3], 0);
+
+    let mask = dir.join("mask.png");
+    let output = rico_on(
+        "remove",
+        &dir.join("src"),
+        &dir.join("masked"),
+        &["-b", "--mask", mask.to_str().unwrap()],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let masked = image::open(dir.join("masked/object.png"))
+        .unwrap()
+        .to_rgba8();
+    assert_eq!(masked.get_pixel(8, 8)[3], 0);
+    assert_eq!(masked.get_pixel(24, 24)[3], 255);
+    assert_eq!(masked.get_pixel(0, 0)[3], 0);
+}
+
+#[test]
+fn missing_mask_exits_non_zero() {
+    let dir = TempDir::new("remove-missing-mask");
+    common::write_image(&dir.join("src/a.png"), 4, 4, common::pattern);
+    let mask = dir.join("nope.png");
+
+    let output = rico_on(
+        "remove",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-b", "--mask", mask.to_str().unwrap()],
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("Error reading mask"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(!dir.join("out/a.png").exists());
+}
+
+#[test]
+fn compose_over_places_the_cutout_at_the_position() {
+    let dir = TempDir::new("remove-compose");
+    // A red cutout with a white margin on the right; the outer margin column is removed as
+    // background, the inner one stays as the edge of the cutout.
+    common::write_image(&dir.join("src/cutout.png"), 8, 8, |x, _| {
+        if x >= 6 {
+            Rgba([255, 255, 255, 255])
+        } else {
+            Rgba([255, 0, 0, 255])
+        }
+    });
+    let background = dir.join("blue.png");
+    common::write_image(&background, 32, 32, |_, _| Rgba([0, 0, 255, 255]));
+
+    let output = rico_on(
+        "remove",
+        &dir.join("src"),
+        &dir.join("out"),
+        &[
+            "-b",
+            "--compose-over",
+            background.to_str().unwrap(),
+            "--at",
+            "10,10",
+        ],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let composed = image::open(dir.join("out/cutout.png")).unwrap().to_rgba8();
+    assert_eq!(composed.dimensions(), (32, 32));
+    assert_eq!(composed.get_pixel(10, 10).0, [255, 0, 0, 255]);
+    assert_eq!(composed.get_pixel(15, 17).0, [255, 0, 0, 255]);
+    assert_eq!(composed.get_pixel(9, 10).0, [0, 0, 255, 255]);
+    assert_eq!(composed.get_pixel(17, 10).0, [0, 0, 255, 255]);
+}
+
+#[test]
+fn missing_compose_over_background_exits_non_zero() {
+    let dir = TempDir::new("remove-missing-background");
+    common::write_image(&dir.join("src/a.png"), 4, 4, common::pattern);
+    let background = dir.join("nope.png");
+
+    let output = rico_on(
+        "remove",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-b", "--compose-over", background.to_str().unwrap()],
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("Error reading background"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn bilevel_does_not_reencode_the_emitted_mask() {
+    let dir = TempDir::new("remove-bilevel-mask");
+    std::fs::create_dir_all(dir.join("src")).unwrap();
+    GrayImage::from_fn(16, 16, gray_square)
+        .save(dir.join("src/scan.png"))
+        .unwrap();
+
+    let output = rico_on(
+        "remove",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-b", "--emit-mask", "--bilevel"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let bytes = std::fs::read(dir.join("out/scan_mask.png")).unwrap();
+    // An 8-bit grayscale PNG, not a 1-bit one: bit depth and color type follow the IHDR size.
+    assert_eq!((bytes[24], bytes[25]), (8, 0));
+    let mask = image::load_from_memory(&bytes).unwrap().to_luma8();
+    assert_eq!(mask.get_pixel(0, 0)[0], 0);
+    assert_eq!(mask.get_pixel(8, 8)[0], 255);
+}
+
+/// A horizontal ramp from light gray (200) to white, which a soft threshold fades out gradually.
+fn light_ramp(x: u32, _: u32) -> Rgba<u8> {
+    let value = (200 + x).min(255) as u8;
+    Rgba([value, value, value, 255])
+}
+
+#[test]
+fn emitted_mask_matches_the_cutout_alpha() {
+    let dir = TempDir::new("remove-emit-mask");
+    common::write_image(&dir.join("src/ramp.png"), 64, 4, light_ramp);
+
+    let output = rico_on(
+        "remove",
+        &dir.join("src"),
+        &dir.join("out"),
+        &[
+            "-b",
+            "--background-soft-threshold",
+            "200,250",
+            "--emit-mask",
+            "--info-sidecar",
+        ],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let cutout = image::open(dir.join("out/ramp.png")).unwrap().to_rgba8();
+    let mask = image::open(dir.join("out/ramp_mask.png")).unwrap();
+    assert_eq!(mask.color(), ColorType::L8);
+    let mask = mask.to_luma8();
+    assert_eq!(mask.dimensions(), cutout.dimensions());
+    assert!(cutout
+        .pixels()
+        .zip(mask.pixels())
+        .all(|(pixel, value)| pixel[3] == value[0]));
+    // The ramp fades out, so the mask holds more than black and white.
+    assert!(mask.pixels().any(|value| value[0] != 0 && value[0] != 255));
+    // Output settings such as the sidecar apply to the cutout only.
+    assert!(dir.join("out/ramp.png.txt").exists());
+    assert!(!dir.join("out/ramp_mask.png.txt").exists());
+}
+
+#[test]
+fn auto_bg_removes_within_the_color_tolerance() {
+    let dir = TempDir::new("remove-auto-bg");
+    // A blue-gray backdrop with a band across the middle that is 12 off in red.
+    common::write_image(&dir.join("src/band.png"), 16, 16, |_, y| {
+        if (6..10).contains(&y) {
+            Rgba([112, 150, 200, 255])
+        } else {
+            Rgba([100, 150, 200, 255])
+        }
+    });
+
+    for (tolerance, band_alpha) in [("10", 255), ("15", 0)] {
+        let out = dir.join(format!("out{}", tolerance));
+        let output = rico_on(
+            "remove",
+            &dir.join("src"),
+            &out,
+            &["-b", "--auto-bg", "--color-tolerance", tolerance],
+        );
+        assert!(output.status.success(), "{}", stderr(&output));
+        let cutout = image::open(out.join("band.png")).unwrap().to_rgba8();
+        assert_eq!(cutout.get_pixel(0, 0)[3], 0);
+        assert_eq!(cutout.get_pixel(0, 8)[3], band_alpha);
+    }
+}
+
+#[test]
+fn ciede2000_groups_shades_that_rgb_distance_keeps_apart() {
+    let dir = TempDir::new("remove-color-metric");
+    // Blue stripes in two shades 28 apart in RGB but barely distinguishable by eye,
+    // around a red square.
+    common::write_image(&dir.join("src/product.png"), 16, 16, |x, y| {
+        if (6..10).contains(&x) && (6..10).contains(&y) {
+            Rgba([200, 30, 30, 255])
+        } else if x / 4 % 2 == 0 {
+            Rgba([0, 0, 255, 255])
+        } else {
+            Rgba([20, 20, 255, 255])
+        }
+    });
+
+    for (metric, out) in [("ciede2000", "perceptual"), ("rgb", "euclidean")] {
+        let output = rico_on(
+            "remove",
+            &dir.join("src"),
+            &dir.join(out),
+            &[
+                "-b",
+                "--bg-color",
+                "#0000FF",
+                "--color-metric",
+                metric,
+                "--color-tolerance",
+                "10",
+            ],
+        );
+        assert!(output.status.success(), "{}", stderr(&output));
+    }
+
+    let perceptual = image::open(dir.join("perceptual/product.png"))
+        .unwrap()
+        .to_rgba8();
+    assert_eq!(perceptual.get_pixel(0, 0)[3], 0);
+    assert_eq!(perceptual.get_pixel(4, 0)[3], 0);
+    assert_eq!(perceptual.get_pixel(8, 8).0, [200, 30, 30, 255]);
+
+    let euclidean = image::open(dir.join("euclidean/product.png"))
+        .unwrap()
+        .to_rgba8();
+    assert_eq!(euclidean.get_pixel(0, 0)[3], 0);
+    assert_eq!(euclidean.get_pixel(4, 0)[3], 255);
+}
+
+#[test]
+fn autocrop_records_where_the_cutout_sat_in_the_source() {
+    let dir = TempDir::new("remove-autocrop");
+    // A 6x4 red object at (10, 5) on a white 30x20 field.
+    common::write_image(&dir.join("src/object.png"), 30, 20, |x, y| {
+        if (10..16).contains(&x) && (5..9).contains(&y) {
+            Rgba([200, 30, 30, 255])
+        } else {
+            Rgba([255, 255, 255, 255])
+        }
+    });
+
+    let report = dir.join("report.json");
+    let output = rico_on(
+        "remove",
+        &dir.join("src"),
+        &dir.join("out"),
+        &["-b", "--autocrop", "--report", report.to_str().unwrap()],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    // Edge detection keeps a one-pixel rim around the object, so the box starts one pixel out.
+    assert_eq!(
+        image::image_dimensions(dir.join("out/object.png")).unwrap(),
+        (8, 6)
+    );
+    let cutout = image::open(dir.join("out/object.png")).unwrap().to_rgba8();
+    assert_eq!(cutout.get_pixel(1, 1).0, [200, 30, 30, 255]);
+    let records: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&report).unwrap()).unwrap();
+    assert_eq!(
+        records[0]["crop"],
+        serde_json::json!({"x": 9, "y": 4, "orig_w": 30, "orig_h": 20})
+    );
+}